@@ -0,0 +1,143 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{process::{Command, Stdio}, sync::{Arc, Mutex}, time::{Duration, Instant}};
+
+use anyhow::{Context, Result};
+
+// Runs many configs, like a test suite in CI:
+//
+//   stm32-emulator batch tests/*.yaml --jobs 4 --junit out.xml -- --max-emulated-ms 1000
+//
+// Each config runs in its own emulator process, with the arguments after `--`.
+// A config passes when its emulator exits with 0, which is what the firmware
+// exit code, the expectations and the stop conditions decide. The JUnit
+// report has one test case per config, with the end of the output of the
+// failed ones.
+
+// Lines of output kept in the report of a failed config
+const MAX_FAILURE_LINES: usize = 50;
+
+struct Outcome {
+    config: String,
+    duration: Duration,
+    // None when the emulator didn't exit normally, e.g. killed by a signal
+    exit_code: Option<i32>,
+    output: String,
+}
+
+impl Outcome {
+    fn passed(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+
+    fn failure_desc(&self) -> String {
+        match self.exit_code {
+            Some(code) => format!("exit code {}", code),
+            None => "killed".to_string(),
+        }
+    }
+}
+
+fn run_one(config: &str, args: &[String]) -> Result<Outcome> {
+    let exe = std::env::current_exe().context("Failed to find the emulator executable")?;
+    let start = Instant::now();
+    let result = Command::new(exe)
+        .arg(config)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run {}", config))?;
+
+    let mut output = String::from_utf8_lossy(&result.stdout).into_owned();
+    output += &String::from_utf8_lossy(&result.stderr);
+    Ok(Outcome { config: config.to_string(), duration: start.elapsed(), exit_code: result.status.code(), output })
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            // Not allowed in XML 1.0
+            c if c.is_control() && !matches!(c, '\n' | '\t' | '\r') => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn junit_report(outcomes: &[Outcome]) -> String {
+    let num_failures = outcomes.iter().filter(|o| !o.passed()).count();
+    let total: f64 = outcomes.iter().map(|o| o.duration.as_secs_f64()).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    xml += &format!("<testsuites tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+        outcomes.len(), num_failures, total);
+    xml += &format!("  <testsuite name=\"stm32-emulator\" tests=\"{}\" failures=\"{}\" time=\"{:.3}\">\n",
+        outcomes.len(), num_failures, total);
+    for o in outcomes {
+        xml += &format!("    <testcase name=\"{}\" classname=\"stm32-emulator\" time=\"{:.3}\"",
+            xml_escape(&o.config), o.duration.as_secs_f64());
+        if o.passed() {
+            xml += "/>\n";
+            continue;
+        }
+        let lines = o.output.lines().collect::<Vec<_>>();
+        let tail = lines[lines.len().saturating_sub(MAX_FAILURE_LINES)..].join("\n");
+        xml += ">\n";
+        xml += &format!("      <failure message=\"{}\">{}</failure>\n", xml_escape(&o.failure_desc()), xml_escape(&tail));
+        xml += "    </testcase>\n";
+    }
+    xml += "  </testsuite>\n";
+    xml += "</testsuites>\n";
+    xml
+}
+
+/// Runs the configs, `jobs` at a time. Returns whether they all passed.
+pub fn run(configs: Vec<String>, jobs: usize, args: &[String], junit: Option<&str>) -> Result<bool> {
+    let num_workers = jobs.clamp(1, configs.len().max(1));
+    let next = Arc::new(Mutex::new(configs.into_iter().enumerate()));
+    let outcomes = Arc::new(Mutex::new(Vec::new()));
+
+    let workers = (0..num_workers).map(|_| {
+        let next = next.clone();
+        let outcomes = outcomes.clone();
+        let args = args.to_vec();
+        std::thread::spawn(move || -> Result<()> {
+            loop {
+                let (i, config) = match next.lock().unwrap().next() {
+                    Some(v) => v,
+                    None => return Ok(()),
+                };
+                let outcome = run_one(&config, &args)?;
+                if outcome.passed() {
+                    info!("Passed config={} secs={:.3}", config, outcome.duration.as_secs_f64());
+                } else {
+                    error!("Failed config={} secs={:.3} ({})", config, outcome.duration.as_secs_f64(), outcome.failure_desc());
+                }
+                outcomes.lock().unwrap().push((i, outcome));
+            }
+        })
+    }).collect::<Vec<_>>();
+
+    for worker in workers {
+        worker.join().unwrap()?;
+    }
+
+    // Back in the order of the arguments
+    let mut outcomes = std::mem::take(&mut *outcomes.lock().unwrap());
+    outcomes.sort_by_key(|(i, _)| *i);
+    let outcomes = outcomes.into_iter().map(|(_, o)| o).collect::<Vec<_>>();
+
+    let num_failed = outcomes.iter().filter(|o| !o.passed()).count();
+    if let Some(path) = junit {
+        std::fs::write(path, junit_report(&outcomes)).with_context(|| format!("Failed to write {}", path))?;
+        info!("JUnit report written to {}", path);
+    }
+    info!("Batch done num_configs={} num_failed={}", outcomes.len(), num_failed);
+    Ok(num_failed == 0)
+}
@@ -0,0 +1,29 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+mod nucleo64;
+
+use anyhow::{Result, bail};
+
+use crate::{config::Config, ext_devices::ExtDevices, peripherals::gpio::GpioPorts};
+
+// Boards are reusable definitions of what's around the MCU, selected with
+// `board: <name>` in the config. They fill in the config like the YAML would,
+// and can wire things with code, like GPIOs driven from other GPIOs. The YAML
+// config has the last word: boards only fill in what the config doesn't set.
+
+pub trait Board {
+    /// Adds the regions, peripherals, and devices of the board
+    fn configure(&self, _config: &mut Config) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called once the devices are created, to wire the GPIOs
+    fn wire(&self, _gpio: &mut GpioPorts, _ext_devices: &ExtDevices) {}
+}
+
+pub fn find_board(name: &str) -> Result<Box<dyn Board>> {
+    Ok(match name {
+        "nucleo-64" => Box::new(nucleo64::Nucleo64),
+        _ => bail!("Unknown board {}", name),
+    })
+}
@@ -0,0 +1,35 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::Result;
+
+use crate::{config::Config, ext_devices::{ExtDevices, UsartProbeConfig}, peripherals::gpio::{GpioPorts, Pin}};
+use super::Board;
+
+/// ST Nucleo-64 boards (NUCLEO-F401RE, NUCLEO-F411RE, NUCLEO-L476RG, ...).
+/// The ST-LINK virtual COM port is on USART2, the user LED LD2 on PA5, and
+/// the user button B1 on PC13, pulled up and low when pressed.
+pub struct Nucleo64;
+
+impl Board for Nucleo64 {
+    fn configure(&self, config: &mut Config) -> Result<()> {
+        let devices = config.devices.get_or_insert_with(Default::default);
+        let usart_probes = devices.usart_probe.get_or_insert_with(Vec::new);
+        if !usart_probes.iter().any(|p| p.peripheral == "USART2") {
+            usart_probes.push(UsartProbeConfig { peripheral: "USART2".to_string(), ..Default::default() });
+        }
+        Ok(())
+    }
+
+    fn wire(&self, gpio: &mut GpioPorts, _ext_devices: &ExtDevices) {
+        // The button is released. The console can press it with "write PC13 0".
+        gpio.add_read_callback(Pin::from_str("PC13"), |_sys| true);
+
+        let mut led = None;
+        gpio.add_write_callback(Pin::from_str("PA5"), move |_sys, on| {
+            if led != Some(on) {
+                info!("Nucleo LD2 {}", if on { "on" } else { "off" });
+                led = Some(on);
+            }
+        });
+    }
+}
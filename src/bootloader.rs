@@ -0,0 +1,290 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{rc::Rc, cell::RefCell};
+
+use anyhow::{Result, Context};
+use serde::Deserialize;
+use unicorn_engine::{Unicorn, RegisterARM};
+
+use crate::{ext_devices::ExtDevices, peripherals::Peripherals, system::System, util::UniErr};
+
+// A stand-in for the ST system bootloader, for firmware that jumps to it, e.g.
+// after a reset with a magic value in RAM. Its vector table is in the system
+// memory, entering it is logged, and it speaks the UART protocol (AN3155)
+// with the device connected to the USART:
+//
+//  system_memory:
+//    bootloader:
+//      usart: USART1         # USART1 by default
+//      addr: 0x1FFF0000      # the address of the family by default
+//      pid: 0x413            # answered to Get ID, the one of the family by default
+//      exit: 3               # ends the emulation with this exit code once entered
+//
+// The commands are Get, Get Version, Get ID, Read Memory, Go, Write Memory,
+// and the global Erase and Extended Erase. The page erases are refused. The
+// USB DFU isn't emulated, the bootloader just waits on the USART.
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct BootloaderConfig {
+    pub usart: Option<String>,
+    pub addr: Option<u32>,
+    pub pid: Option<u16>,
+    pub exit: Option<i32>,
+}
+
+const ACK: u8 = 0x79;
+const NACK: u8 = 0x1F;
+const SYNC: u8 = 0x7F;
+// Version 3.1 of the protocol
+const VERSION: u8 = 0x31;
+
+const GET: u8 = 0x00;
+const GET_VERSION: u8 = 0x01;
+const GET_ID: u8 = 0x02;
+const READ_MEMORY: u8 = 0x11;
+const GO: u8 = 0x21;
+const WRITE_MEMORY: u8 = 0x31;
+const ERASE: u8 = 0x43;
+const EXTENDED_ERASE: u8 = 0x44;
+const COMMANDS: [u8; 7] = [GET, GET_VERSION, GET_ID, READ_MEMORY, GO, WRITE_MEMORY, EXTENDED_ERASE];
+
+// The stub: the vector table, then `cpsid i`, and a loop on `nop; b.n` where
+// the protocol is served.
+const ENTRY_OFFSET: u32 = 0x40;
+const LOOP_OFFSET: u32 = 0x42;
+const NUM_VECTORS: u32 = 16;
+// Where the ST bootloaders keep their stack, in the SRAM of all families
+const STACK_TOP: u32 = 0x2000_1000;
+
+/// The content of the system memory at the bootloader address
+pub fn stub(addr: u32) -> Vec<u8> {
+    let mut data = STACK_TOP.to_le_bytes().to_vec();
+    for _ in 1..NUM_VECTORS {
+        data.extend((addr + ENTRY_OFFSET + 1).to_le_bytes());
+    }
+    // cpsid i, nop, b.n to the nop
+    for instr in [0xB672u16, 0xBF00, 0xE7FD] {
+        data.extend(instr.to_le_bytes());
+    }
+    data
+}
+
+#[derive(Clone, Copy)]
+enum State {
+    Sync,
+    Command,
+    Address(u8),
+    ReadCount(u32),
+    WriteData(u32),
+    Erase(u8),
+}
+
+struct Bootloader {
+    usart: String,
+    pid: u16,
+    state: State,
+    rx: Vec<u8>,
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |a, b| a ^ b)
+}
+
+impl Bootloader {
+    /// The number of bytes making the current step of the protocol
+    fn needed(&self) -> usize {
+        match self.state {
+            State::Sync => 1,
+            State::Command | State::ReadCount(_) => 2,
+            State::Address(_) => 5,
+            State::WriteData(_) => self.rx.first().map(|n| *n as usize + 3).unwrap_or(1),
+            State::Erase(ERASE) => match self.rx.first() {
+                Some(0xFF) => 2,
+                Some(n) => *n as usize + 3,
+                None => 1,
+            },
+            State::Erase(_) => match self.rx.get(..2) {
+                Some(n) => match u16::from_be_bytes([n[0], n[1]]) {
+                    // The special erases
+                    n if n >= 0xFFF0 => 3,
+                    n => 2 + 2 * (n as usize + 1) + 1,
+                },
+                None => 2,
+            },
+        }
+    }
+
+    /// Takes a byte from the host. Returns the reply.
+    fn receive(&mut self, uc: &mut Unicorn<()>, v: u8) -> Vec<u8> {
+        self.rx.push(v);
+        if self.rx.len() < self.needed() {
+            return vec![];
+        }
+        let rx = std::mem::take(&mut self.rx);
+        let state = std::mem::replace(&mut self.state, State::Command);
+
+        match state {
+            State::Sync if rx[0] == SYNC => {
+                info!("Bootloader synchronized usart={}", self.usart);
+                vec![ACK]
+            }
+            State::Sync => {
+                self.state = State::Sync;
+                vec![]
+            }
+            State::Command if rx[0] ^ rx[1] != 0xFF => vec![NACK],
+            State::Command => {
+                let cmd = rx[0];
+                trace!("Bootloader command=0x{:02x}", cmd);
+                match cmd {
+                    GET => {
+                        let mut reply = vec![ACK, COMMANDS.len() as u8, VERSION];
+                        reply.extend(COMMANDS);
+                        reply.push(ACK);
+                        reply
+                    }
+                    GET_VERSION => vec![ACK, VERSION, 0, 0, ACK],
+                    GET_ID => {
+                        let [hi, lo] = self.pid.to_be_bytes();
+                        vec![ACK, 1, hi, lo, ACK]
+                    }
+                    READ_MEMORY | GO | WRITE_MEMORY => {
+                        self.state = State::Address(cmd);
+                        vec![ACK]
+                    }
+                    ERASE | EXTENDED_ERASE => {
+                        self.state = State::Erase(cmd);
+                        vec![ACK]
+                    }
+                    _ => vec![NACK],
+                }
+            }
+            State::Address(_) if checksum(&rx[..4]) != rx[4] => vec![NACK],
+            State::Address(cmd) => {
+                let addr = u32::from_be_bytes([rx[0], rx[1], rx[2], rx[3]]);
+                match cmd {
+                    READ_MEMORY if uc.mem_read(addr as u64, &mut [0]).is_err() => vec![NACK],
+                    READ_MEMORY => {
+                        self.state = State::ReadCount(addr);
+                        vec![ACK]
+                    }
+                    WRITE_MEMORY => {
+                        self.state = State::WriteData(addr);
+                        vec![ACK]
+                    }
+                    _ => match Self::go(uc, addr) {
+                        Ok(()) => vec![ACK],
+                        Err(e) => {
+                            warn!("Bootloader go failed addr=0x{:08x} err={:#}", addr, e);
+                            vec![NACK]
+                        }
+                    },
+                }
+            }
+            State::ReadCount(_) if rx[0] ^ rx[1] != 0xFF => vec![NACK],
+            State::ReadCount(addr) => {
+                let mut data = vec![0; rx[0] as usize + 1];
+                match uc.mem_read(addr as u64, &mut data) {
+                    Ok(()) => {
+                        debug!("Bootloader read addr=0x{:08x} len={}", addr, data.len());
+                        [vec![ACK], data].concat()
+                    }
+                    Err(_) => vec![NACK],
+                }
+            }
+            State::WriteData(addr) => {
+                let (data, cks) = (&rx[1..rx.len()-1], rx[rx.len()-1]);
+                if checksum(&rx[..rx.len()-1]) != cks {
+                    return vec![NACK];
+                }
+                debug!("Bootloader write addr=0x{:08x} len={}", addr, data.len());
+                match uc.mem_write(addr as u64, data) {
+                    Ok(()) => vec![ACK],
+                    Err(_) => vec![NACK],
+                }
+            }
+            State::Erase(cmd) => {
+                let global = match cmd {
+                    ERASE => rx == [0xFF, 0x00],
+                    _ => rx == [0xFF, 0xFF, 0x00],
+                };
+                if !global {
+                    warn!("Bootloader page erase not supported, only the global erase is");
+                    return vec![NACK];
+                }
+                match Self::mass_erase(uc) {
+                    Ok(()) => vec![ACK],
+                    Err(e) => {
+                        warn!("Bootloader erase failed err={:#}", e);
+                        vec![NACK]
+                    }
+                }
+            }
+        }
+    }
+
+    fn mass_erase(uc: &mut Unicorn<()>) -> Result<()> {
+        let flash = uc.mem_regions().map_err(UniErr)?.into_iter()
+            .find(|r| (r.begin..=r.end).contains(&(crate::system::FLASH_START as u64)))
+            .context("No flash region")?;
+        info!("Bootloader mass erase start=0x{:08x} end=0x{:08x}", flash.begin, flash.end);
+        uc.mem_write(flash.begin, &vec![0xFF; (flash.end - flash.begin + 1) as usize]).map_err(UniErr)?;
+        Ok(())
+    }
+
+    /// Jumps to the application, with the stack pointer and the reset
+    /// handler of the vector table at addr
+    fn go(uc: &mut Unicorn<()>, addr: u32) -> Result<()> {
+        let mut vectors = [0; 8];
+        uc.mem_read(addr as u64, &mut vectors).map_err(UniErr)?;
+        let sp = u32::from_le_bytes(vectors[0..4].try_into().unwrap());
+        let pc = u32::from_le_bytes(vectors[4..8].try_into().unwrap());
+        info!("Bootloader go addr=0x{:08x} sp=0x{:08x} pc=0x{:08x}", addr, sp, pc);
+        uc.reg_write(RegisterARM::SP, sp as u64).map_err(UniErr)?;
+        uc.reg_write(RegisterARM::PRIMASK, 0).map_err(UniErr)?;
+        // Writing the PC also sets the thumb state from bit 0
+        uc.reg_write(RegisterARM::PC, pc as u64).map_err(UniErr)?;
+        Ok(())
+    }
+}
+
+pub fn install(uc: &mut Unicorn<()>, config: BootloaderConfig, device_name: &str, p: Rc<Peripherals>, d: Rc<ExtDevices>) -> Result<()> {
+    let (addr, pid) = crate::system_memory::bootloader(&config, device_name)?;
+    let usart = config.usart.unwrap_or_else(|| "USART1".to_string());
+    let device = d.find_serial_device(&usart);
+    debug!("Bootloader addr=0x{:08x} pid=0x{:03x} usart={}", addr, pid, usart);
+
+    let entry = (addr + ENTRY_OFFSET) as u64;
+    let exit = config.exit;
+    let connected = device.is_some();
+    {
+        let usart = usart.clone();
+        uc.add_code_hook(entry, entry, move |uc, _pc, _size| {
+            let lr = uc.reg_read(RegisterARM::LR).unwrap() as u32;
+            info!("System bootloader entered addr=0x{:08x} lr=0x{:08x} usart={}", addr, lr, usart);
+            if !connected {
+                warn!("Bootloader: no device is connected to {}, the protocol isn't served", usart);
+            }
+            if let Some(code) = exit {
+                crate::exit::exit(uc, code);
+            }
+        }).map_err(UniErr)?;
+    }
+
+    let Some(device) = device else { return Ok(()) };
+    let bootloader = RefCell::new(Bootloader { usart, pid, state: State::Sync, rx: vec![] });
+    let poll = (addr + LOOP_OFFSET) as u64;
+    uc.add_code_hook(poll, poll, move |uc, _pc, _size| {
+        let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
+        let mut device = device.borrow_mut();
+        while device.has_data(&sys) {
+            let v = device.read(&sys, ());
+            let reply = bootloader.borrow_mut().receive(&mut sys.uc.borrow_mut(), v);
+            for v in reply {
+                device.write(&sys, (), v);
+            }
+        }
+    }).map_err(UniErr)?;
+
+    Ok(())
+}
@@ -0,0 +1,93 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::sync::atomic::Ordering;
+
+use unicorn_engine::{Unicorn, RegisterARM};
+use anyhow::{Result, bail};
+
+use crate::{symbols::symbols, util::UniErr};
+
+// --break-at-symbol stops the emulation when a function is entered, like
+// --stop-addr, for several functions. --trace-calls logs the entries of the
+// functions matching a glob, like `HAL_*`, with their r0-r3 arguments, and
+// their returns with r0.
+//
+// A return is when the pc reaches the lr of the entry. Functions that don't
+// return there, like interrupt handlers, only have their entry logged.
+
+// Bounds the pending returns when functions don't return to their lr
+const MAX_DEPTH: usize = 256;
+
+/// Converts a glob with `*` and `?` into an anchored regex
+fn glob_to_regex(glob: &str) -> Result<regex::Regex> {
+    let mut re = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    Ok(regex::Regex::new(&re)?)
+}
+
+pub fn install_breakpoints(uc: &mut Unicorn<()>, names: &[String]) -> Result<()> {
+    for name in names {
+        let addr = symbols().parse_addr(name)? & !1;
+        let name = name.clone();
+        debug!("Installing breakpoint name={} addr=0x{:08x}", name, addr);
+        let ctx = crate::context::current();
+        uc.add_code_hook(addr as u64, addr as u64, move |uc, _pc, _size| {
+            info!("Breakpoint reached name={}", name);
+            ctx.breakpoint_reached.store(true, Ordering::Release);
+            uc.emu_stop().unwrap();
+        }).map_err(UniErr)?;
+    }
+    Ok(())
+}
+
+pub fn install_call_tracing(uc: &mut Unicorn<()>, glob: &str) -> Result<()> {
+    let re = glob_to_regex(glob)?;
+    let functions = symbols().functions()
+        .filter(|s| re.is_match(&s.name))
+        .map(|s| (s.name.clone(), s.addr))
+        .collect::<Vec<_>>();
+    if functions.is_empty() {
+        bail!("No function matches {}", glob);
+    }
+    info!("Tracing calls of num_functions={}", functions.len());
+
+    // Pending returns: function name, return address
+    let stack = std::rc::Rc::new(std::cell::RefCell::new(Vec::<(String, u32)>::new()));
+
+    for (name, addr) in functions {
+        let stack = stack.clone();
+        uc.add_code_hook(addr as u64, addr as u64, move |uc, _pc, _size| {
+            let reg = |r| uc.reg_read(r).unwrap() as u32;
+            let lr = reg(RegisterARM::LR);
+            let mut stack = stack.borrow_mut();
+            info!("Call {}(r0=0x{:08x}, r1=0x{:08x}, r2=0x{:08x}, r3=0x{:08x}) depth={} caller={}",
+                name, reg(RegisterARM::R0), reg(RegisterARM::R1), reg(RegisterARM::R2), reg(RegisterARM::R3),
+                stack.len(), symbols().describe(lr & !1));
+            // EXC_RETURN values are not return addresses
+            if lr < 0xF000_0000 {
+                if stack.len() == MAX_DEPTH {
+                    stack.remove(0);
+                }
+                stack.push((name.clone(), lr & !1));
+            }
+        }).map_err(UniErr)?;
+    }
+
+    uc.add_code_hook(0, u64::MAX, move |uc, pc, _size| {
+        let mut stack = stack.borrow_mut();
+        // Tail calls return to the same address as their caller
+        while stack.last().is_some_and(|(_, ret)| *ret == pc as u32) {
+            let (name, _) = stack.pop().unwrap();
+            info!("Return {} r0=0x{:08x} depth={}", name, uc.reg_read(RegisterARM::R0).unwrap() as u32, stack.len());
+        }
+    }).map_err(UniErr)?;
+
+    Ok(())
+}
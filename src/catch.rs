@@ -0,0 +1,73 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::sync::atomic::Ordering;
+
+use anyhow::{Result, bail};
+use unicorn_engine::{Unicorn, RegisterARM};
+
+use crate::{peripherals::nvic::{Nvic, irq}, symbols::symbols};
+
+// --catch-exceptions hardfault,busfault stops the emulation when one of these
+// exceptions is about to be entered, before its handler runs, like the vector
+// catch of a debugger. Fault handlers are often an infinite loop, the dump
+// shows where the fault comes from instead. The emulation then fails.
+
+const EXCEPTIONS: [(&str, i32); 8] = [
+    ("nmi", irq::NMI),
+    ("hardfault", irq::HARDFAULT),
+    ("memmanage", irq::MEMMANAGE),
+    ("busfault", irq::BUSFAULT),
+    ("usagefault", irq::USAGEFAULT),
+    ("svcall", irq::SVCALL),
+    ("pendsv", irq::PENDSV),
+    ("systick", irq::SYSTICK),
+];
+
+pub fn parse_exception(s: &str) -> Result<i32> {
+    match EXCEPTIONS.iter().find(|(name, _)| name.eq_ignore_ascii_case(s)) {
+        Some((_, irq)) => Ok(*irq),
+        None => {
+            let names = EXCEPTIONS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+            bail!("Unknown exception {}, expected one of {}", s, names.join(", "))
+        }
+    }
+}
+
+fn exception_name(irq: i32) -> &'static str {
+    EXCEPTIONS.iter().find(|(_, i)| *i == irq).map(|(name, _)| *name).unwrap_or("?")
+}
+
+fn describe(addr: u32) -> String {
+    // EXC_RETURN values are not code addresses
+    if addr >= 0xF000_0000 {
+        return "exc_return".to_string();
+    }
+    symbols().describe(addr & !1)
+}
+
+/// Prints the state of the CPU when the exception is caught
+pub fn dump(uc: &Unicorn<()>, irq: i32, nvic: &Nvic) {
+    let reg = |r| uc.reg_read(r).unwrap() as u32;
+    let pc = reg(RegisterARM::PC);
+    let lr = reg(RegisterARM::LR);
+
+    error!("Exception caught exception={} pc=0x{:08x} func={}", exception_name(irq), pc, describe(pc));
+    error!("  lr=0x{:08x} ({}) sp=0x{:08x} xpsr=0x{:08x}",
+        lr, describe(lr), reg(RegisterARM::SP), reg(RegisterARM::XPSR));
+    let regs = [
+        RegisterARM::R0, RegisterARM::R1, RegisterARM::R2, RegisterARM::R3,
+        RegisterARM::R4, RegisterARM::R5, RegisterARM::R6, RegisterARM::R7,
+        RegisterARM::R8, RegisterARM::R9, RegisterARM::R10, RegisterARM::R11,
+        RegisterARM::R12,
+    ];
+    for (i, chunk) in regs.chunks(4).enumerate() {
+        let line = chunk.iter().enumerate()
+            .map(|(j, r)| format!("r{}=0x{:08x}", 4*i + j, reg(*r)))
+            .collect::<Vec<_>>();
+        error!("  {}", line.join(" "));
+    }
+    error!("  cfsr=0x{:08x} hfsr=0x{:08x} mmfar=0x{:08x} bfar=0x{:08x}",
+        nvic.cfsr, nvic.hfsr, nvic.mmfar, nvic.bfar);
+
+    crate::context::current().exception_caught.store(true, Ordering::Release);
+}
@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::HashSet, path::Path};
+
+use anyhow::Result;
+use svd_parser::svd::Device as SvdDevice;
+
+use crate::{config::{Config, Region}, peripherals::Peripherals};
+
+// Cross-checks the config with the SVD file, for setups that are accepted
+// but are most likely mistakes. Each finding is a warning, we don't stop
+// anything. Used with --check-config.
+
+pub fn check_config(mut config: Config, svd_device: &SvdDevice) -> Result<usize> {
+    let mut warnings = 0;
+    let mut warn = |msg: String| {
+        warn!("{}", msg);
+        warnings += 1;
+    };
+
+    let regions = crate::system::memory_regions(&mut config, &svd_device.name)?;
+    check_regions(&regions, &config, &mut warn);
+
+    let mut peripheral_names = svd_device.peripherals.iter()
+        .map(|p| p.name.as_str())
+        .collect::<HashSet<_>>();
+    if let Some(ref p) = config.peripherals {
+        peripheral_names.extend(p.software_spi.iter().flatten().map(|c| c.name.as_str()));
+        peripheral_names.extend(p.software_i2c.iter().flatten().map(|c| c.name.as_str()));
+        peripheral_names.extend(p.software_uart.iter().flatten().map(|c| c.name.as_str()));
+        peripheral_names.extend(p.onewire.iter().flatten().map(|c| c.name.as_str()));
+    }
+
+    let framebuffer_names = config.framebuffers.iter().flatten()
+        .map(|f| f.name.as_str())
+        .collect::<HashSet<_>>();
+    let mut used_framebuffers = HashSet::new();
+    let mut display_peripherals = HashSet::new();
+
+    let connections = config.devices.as_ref().map(|d| d.connections()).unwrap_or_default();
+    for c in connections {
+        if let Some(peripheral) = c.peripheral {
+            // Sub-peripherals are named like FSMC.BANK1
+            let base = peripheral.split('.').next().unwrap();
+            if !peripheral_names.contains(base) {
+                warn(format!("Device {} is connected to peripheral={}, which is not in the SVD file nor a software peripheral",
+                    c.device, peripheral));
+            }
+            // Memory mapped displays can't share a bank, they need their own FSMC.BANK1.NEx
+            if c.device == "display" && !display_peripherals.insert(peripheral) {
+                warn(format!("Several displays are connected to peripheral={}, only the first one is used", peripheral));
+            }
+        }
+
+        if let Some(framebuffer) = c.framebuffer {
+            if !framebuffer_names.contains(framebuffer) {
+                warn(format!("Device {} uses framebuffer={}, which is not defined in framebuffers", c.device, framebuffer));
+            }
+            used_framebuffers.insert(framebuffer);
+        }
+    }
+
+    for name in framebuffer_names.difference(&used_framebuffers) {
+        warn(format!("Framebuffer {} is not used by any device, it will stay blank", name));
+    }
+
+    Ok(warnings)
+}
+
+fn check_regions(regions: &[Region], config: &Config, warn: &mut impl FnMut(String)) {
+    let range = |r: &Region| (r.start as u64, r.start as u64 + r.size as u64);
+
+    for (i, a) in regions.iter().enumerate() {
+        let (start, end) = range(a);
+
+        if a.size == 0 {
+            warn(format!("Region {} has a size of 0", a.name));
+        }
+
+        for (p_start, p_end) in Peripherals::MEMORY_MAPS {
+            if start < p_end as u64 && (p_start as u64) < end {
+                warn(format!("Region {} (0x{:08x}-0x{:08x}) overlaps the peripheral space 0x{:08x}-0x{:08x}, \
+                    peripheral accesses there would hit plain memory", a.name, start, end, p_start, p_end));
+            }
+        }
+
+        for b in &regions[i+1..] {
+            let (b_start, b_end) = range(b);
+            if start < b_end && b_start < end {
+                warn(format!("Regions {} and {} overlap, mapping will fail", a.name, b.name));
+            }
+        }
+
+        if let Some(ref load) = a.load {
+            if !Path::new(load).exists() {
+                warn(format!("Region {} loads file={}, which does not exist", a.name, load));
+            }
+        }
+    }
+
+    let vector_table = config.cpu.vector_table as u64;
+    let boot_alias = crate::system::boot_alias_region(regions, config).map(|r| (0, r.size as u64));
+    if !regions.iter().map(range).chain(boot_alias).any(|(start, end)| start <= vector_table && vector_table < end) {
+        warn(format!("The vector table 0x{:08x} is outside of all memory regions, the CPU can't boot", vector_table));
+    }
+
+    if let Some(ref firmware) = config.cpu.firmware {
+        if !Path::new(firmware).exists() {
+            warn(format!("The firmware file={} does not exist", firmware));
+        }
+    }
+}
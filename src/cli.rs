@@ -0,0 +1,466 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// The command line of the emulator. The binary is only this.
+
+use std::io::prelude::*;
+use std::path::Path;
+use std::ffi::OsString;
+use clap::{Parser, CommandFactory, FromArgMatches};
+use anyhow::{Result, Context};
+use env_logger::fmt::WriteStyle;
+use log::LevelFilter;
+
+use crate::{config::Config, emulator::{self, Emulator}, util::{self, read_file_str}};
+use crate::{batch, catch, check, fat, log_filter, peripherals, trace, warn_context};
+
+/// STM32 Emulator
+#[derive(Parser, Debug)]
+#[clap(subcommand_negates_reqs = true)]
+pub struct Args {
+    /// Config file
+    #[clap(required = true)]
+    pub config: Option<String>,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// Config variable, substituted for ${KEY} in the config files. Can be repeated.
+    #[clap(short='D', long, parse(try_from_str=parse_define))]
+    pub define: Vec<(String, String)>,
+
+    /// Input level of a GPIO pin, e.g. PB2=1. Can be repeated. Same as the `gpio` config section.
+    #[clap(long, parse(try_from_str=parse_gpio))]
+    pub gpio: Vec<(String, bool)>,
+
+    /// Verbosity. Can be repeated. -vvvv is the maximum.
+    #[clap(short, long, parse(from_occurrences))]
+    pub verbose: u8,
+
+    /// Maximum number of instructions to execute, whatever their cycles
+    #[clap(short, long)]
+    pub max_instructions: Option<u64>,
+
+    /// Stop emulation after this many seconds of host time, as a failure
+    #[clap(long)]
+    pub max_seconds: Option<f64>,
+
+    /// Stop emulation after this many milliseconds of emulated time, as a
+    /// failure. The emulated time follows the CPU clock configured in the RCC.
+    #[clap(long)]
+    pub max_emulated_ms: Option<u64>,
+
+    /// Stop emulation when pc reaches this address. Can also be a symbol, or symbol+offset.
+    #[clap(short, long)]
+    pub stop_addr: Option<String>,
+
+    /// Stop emulation when these exceptions are about to be entered, e.g.
+    /// hardfault,busfault, and print where they come from. Fails the emulation.
+    #[clap(long, use_value_delimiter = true, parse(try_from_str=catch::parse_exception))]
+    pub catch_exceptions: Vec<i32>,
+
+    /// Stop emulation when this function is entered. Can be repeated.
+    #[clap(long)]
+    pub break_at_symbol: Vec<String>,
+
+    /// Log the calls of the functions matching this glob, e.g. HAL_*, with
+    /// their arguments r0-r3, and their returns
+    #[clap(long)]
+    pub trace_calls: Option<String>,
+
+    /// Stop emulation when the program reaches a busy loop, including polling
+    /// a peripheral register that never changes. Polling is reported regardless.
+    #[clap(short, long)]
+    pub busy_loop_stop: bool,
+
+    /// Fast-forward the time to the next interrupt when the program is in an
+    /// idle loop, like waiting for the SysTick handler to update a variable
+    #[clap(long)]
+    pub fast_forward_idle: bool,
+
+    /// Colorize output
+    #[clap(short, long, arg_enum, default_value="auto")]
+    pub color: Color,
+
+    /// Log format. json emits one object per line, with the clk, pc, level,
+    /// peripheral, register, value and message fields.
+    #[clap(long, arg_enum, default_value="text")]
+    pub log_format: LogFormat,
+
+    /// Run pending interrupts every N instructions
+    /// Shorter is more correct, but is slower.
+    #[clap(short, long, default_value="1")]
+    pub interrupt_period: u32,
+
+    /// Stress test: delay each interrupt delivery by a random number of
+    /// cycles, up to this one, to shake out the races of RTOS firmwares
+    #[clap(long)]
+    pub irq_jitter: Option<u64>,
+
+    /// Seed of --irq-jitter, to reproduce a run. Random by default, and printed.
+    #[clap(long)]
+    pub irq_jitter_seed: Option<u64>,
+
+    /// Dump stack at the end. Parameter is the number of words to print
+    #[clap(short, long)]
+    pub dump_stack: Option<usize>,
+
+    /// Count the executed instructions per function, and print the top ones at the end
+    #[clap(long)]
+    pub profile: bool,
+
+    /// Write the profile in the callgrind format to this file. Implies --profile.
+    #[clap(long)]
+    pub profile_callgrind: Option<String>,
+
+    /// Listen on this address for commands to program the memory at runtime, e.g. 127.0.0.1:4444
+    #[clap(long)]
+    pub flash_server: Option<String>,
+
+    /// Interactive console to inspect and poke the system. `stdin`, or an address to listen on, e.g. 127.0.0.1:5555
+    #[clap(long)]
+    pub console: Option<String>,
+
+    /// Co-simulation: an external process on this address, e.g. 127.0.0.1:6000,
+    /// steps the emulated time and exchanges the inputs and outputs of the `cosim` config
+    #[clap(long)]
+    pub cosim: Option<String>,
+
+    /// Stop emulation when the firmware requests more system resets than this
+    #[clap(long, default_value="10")]
+    pub max_resets: u32,
+
+    /// What to do on accesses to unmapped memory: skip the instruction,
+    /// deliver a BusFault to the firmware, or stop with a report
+    #[clap(long, arg_enum, default_value="skip")]
+    pub unmapped: emulator::UnmappedAccess,
+
+    /// Check the config against the SVD file for suspicious setups, and exit
+    #[clap(long)]
+    pub check_config: bool,
+
+    /// After each warning, print the pc and caller of the peripheral access in
+    /// progress, and the last N peripheral accesses
+    #[clap(long)]
+    pub warn_context: Option<usize>,
+
+    /// Keep the last N executed instructions, peripheral accesses and
+    /// interrupt entries and exits, and print them when the emulation aborts
+    #[clap(long)]
+    pub flight_recorder: Option<usize>,
+
+    /// Record the branches, peripheral accesses and interrupts to this file,
+    /// to compare runs with the diff-trace command
+    #[clap(long)]
+    pub record_trace: Option<String>,
+
+    /// Report the progress every N seconds: instructions, emulated time, MIPS,
+    /// interrupts and peripheral accesses. Implies --stats.
+    #[clap(long)]
+    pub heartbeat: Option<f64>,
+
+    /// Print the instructions, emulated time, MIPS, interrupts and peripheral
+    /// accesses of the run at exit
+    #[clap(long)]
+    pub stats: bool,
+}
+
+impl Args {
+    /// Options of the emulator from command line arguments, without the
+    /// program name and the config file, e.g. `["--max-emulated-ms", "100"]`.
+    /// For running the emulator as a library.
+    pub fn from_options<I, T>(options: I) -> Result<Self>
+    where I: IntoIterator<Item = T>, T: Into<OsString>
+    {
+        let matches = Self::command()
+            .mut_arg("config", |a| a.required(false))
+            .try_get_matches_from(std::iter::once("stm32-emulator".into()).chain(options.into_iter().map(Into::into)))?;
+        Ok(Self::from_arg_matches(&matches)?)
+    }
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self::from_options::<_, &str>([]).expect("the options have defaults")
+    }
+}
+
+#[derive(clap::Subcommand, Debug)]
+pub enum Command {
+    /// Build a FAT image from a directory, for the storage devices
+    Mkfs {
+        /// Directory with the files of the image
+        #[clap(long)]
+        dir: String,
+
+        /// Image file to write
+        #[clap(long)]
+        out: String,
+
+        /// Image size, e.g. 64M. FAT16 below 512M, FAT32 above.
+        #[clap(long, parse(try_from_str=parse_size))]
+        size: u64,
+
+        /// Volume label
+        #[clap(long)]
+        label: Option<String>,
+    },
+
+    /// Extract the files of a FAT image, e.g. after an emulation
+    Extract {
+        /// Image file to read
+        #[clap(long)]
+        image: String,
+
+        /// Directory to write the files to
+        #[clap(long)]
+        out: String,
+    },
+
+    /// Report the first divergence between two traces recorded with --record-trace
+    DiffTrace {
+        /// Trace of the reference run
+        a: String,
+
+        /// Trace of the other run
+        b: String,
+    },
+
+    /// Run many configs, e.g. tests/*.yaml, and report which ones fail
+    Batch {
+        /// Config files
+        #[clap(required = true)]
+        configs: Vec<String>,
+
+        /// Write a JUnit XML report to this file
+        #[clap(long)]
+        junit: Option<String>,
+
+        /// Number of emulators running in parallel
+        #[clap(short, long, default_value = "1")]
+        jobs: usize,
+
+        /// Arguments of each emulator, after --. E.g. -- --max-emulated-ms 1000
+        #[clap(last = true)]
+        args: Vec<String>,
+    },
+}
+
+fn parse_define(s: &str) -> Result<(String, String)> {
+    let (key, value) = s.split_once('=').context("Expected KEY=VALUE")?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn parse_size(s: &str) -> Result<u64> {
+    let (n, unit) = match s.to_uppercase().chars().last() {
+        Some('K') => (&s[..s.len()-1], 1 << 10),
+        Some('M') => (&s[..s.len()-1], 1 << 20),
+        Some('G') => (&s[..s.len()-1], 1 << 30),
+        _ => (s, 1),
+    };
+    Ok(n.parse::<u64>().context("Expected a size, e.g. 64M")? * unit)
+}
+
+fn run_command(command: Command) -> Result<()> {
+    match command {
+        Command::Mkfs { dir, out, size, label } => {
+            let image = fat::mkfs(Path::new(&dir), size, label.as_deref())?;
+            std::fs::write(&out, image).with_context(|| format!("Failed to write {}", out))?;
+            info!("FAT image written to {}", out);
+        }
+        Command::Extract { image, out } => {
+            let num_files = fat::extract(&util::read_file(&image)?, Path::new(&out))?;
+            info!("Extracted num_files={} to {}", num_files, out);
+        }
+        Command::DiffTrace { a, b } => {
+            if !trace::diff(&a, &b)? {
+                std::process::exit(1);
+            }
+        }
+        Command::Batch { configs, junit, jobs, args } => {
+            if !batch::run(configs, jobs, &args, junit.as_deref())? {
+                std::process::exit(1);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_gpio(s: &str) -> Result<(String, bool)> {
+    let (pin, level) = s.split_once('=').context("Expected PIN=LEVEL")?;
+    peripherals::gpio::Pin::parse(pin).with_context(|| format!("Invalid pin {}", pin))?;
+    let level = match level {
+        "0" | "low" => false,
+        "1" | "high" => true,
+        _ => anyhow::bail!("Invalid level {}, expected 0 or 1", level),
+    };
+    Ok((pin.to_string(), level))
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug)]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+impl std::convert::From<Color> for WriteStyle {
+    fn from(c: Color) -> Self {
+        match c {
+            Color::Always => WriteStyle::Always,
+            Color::Never => WriteStyle::Never,
+            Color::Auto => WriteStyle::Auto,
+        }
+    }
+}
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A log record as a JSON line. The peripheral accesses are logged as
+/// `peri=GPIOA ... reg=MODER read=0x...`, we pick these keys from the message.
+fn json_record(num_instructions: u64, pc: u32, level: log::Level, message: &str) -> String {
+    let field = |keys: &[&str]| message.split_whitespace()
+        .filter_map(|kv| kv.split_once('='))
+        .find(|(k, v)| keys.contains(k) && !v.starts_with('?'))
+        .map(|(_, v)| json_string(v))
+        .unwrap_or_else(|| "null".to_string());
+
+    format!(r#"{{"clk":{},"pc":{},"level":"{}","peripheral":{},"register":{},"value":{},"message":{}}}"#,
+        num_instructions, pc, level, field(&["peri"]), field(&["reg"]),
+        field(&["read", "write", "value", "v"]), json_string(message))
+}
+
+static mut VERBOSE: u8 = 0;
+
+pub fn verbose() -> u8 {
+    unsafe { VERBOSE }
+}
+
+fn init_logging(args: &Args) {
+    unsafe { VERBOSE = args.verbose };
+
+    let lf = match args.verbose {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+
+    static mut LAST_NUM_INSTRUCTIONS: u64 = 0;
+
+    let log_format = args.log_format;
+    let write_style = if log_format == LogFormat::Json { WriteStyle::Never } else { args.color.into() };
+
+    // log_filter decides of the level
+    let logger = env_logger::Builder::new()
+        .filter_level(LevelFilter::Trace)
+        .write_style(write_style)
+        .target(env_logger::Target::Stdout)
+        .format(move |buf, record| {
+            use env_logger::fmt::Color;
+            let num_instructions = crate::context::now();
+            //let delta_instructions = num_instructions - unsafe { LAST_NUM_INSTRUCTIONS };
+            unsafe { LAST_NUM_INSTRUCTIONS = num_instructions };
+            let pc = crate::context::last_pc();
+
+            let context = if record.level() == log::Level::Warn && warn_context::enabled() {
+                warn_context::lines()
+            } else {
+                vec![]
+            };
+
+            if log_format == LogFormat::Json {
+                let mut message = record.args().to_string();
+                for line in &context {
+                    message = message + "\n" + line;
+                }
+                return writeln!(buf, "{}", json_record(num_instructions, pc, record.level(), &message));
+            }
+
+            let mut style = buf.style();
+            let level = match record.level() {
+                log::Level::Error => style.set_color(Color::Red).set_intense(true).value("ERROR"),
+                log::Level::Warn =>  style.set_color(Color::Yellow).set_intense(true).value("WARN "),
+                log::Level::Info =>  style.set_color(Color::Green).set_intense(true).value("INFO "),
+                log::Level::Debug => style.set_color(Color::Cyan).set_intense(true).value("DEBUG"),
+                log::Level::Trace => style.set_color(Color::Blue).set_intense(true).value("TRACE"),
+            };
+
+            let mut style = buf.style();
+            style.set_color(Color::Black).set_intense(true);
+            //let header = format!("[tsc={:08} dtsc=+{:08} pc=0x{:08x}]", num_instructions, delta_instructions, pc);
+            let header = format!("[clk={:08} pc=0x{:08x}]", num_instructions, pc);
+            let header = style.value(header);
+
+            writeln!(buf, "{} {} {}", header, level, record.args())?;
+            for line in context {
+                writeln!(buf, "{}", line)?;
+            }
+            Ok(())
+        })
+        .build();
+    log_filter::install(logger, lf);
+}
+
+pub fn main() -> Result<()> {
+    let args = Args::parse();
+    init_logging(&args);
+    if let Some(n) = args.warn_context {
+        warn_context::init(n);
+    }
+
+    if let Some(command) = args.command {
+        return run_command(command);
+    }
+
+    let mut config = Config::load(args.config.as_deref().context("Missing the config file")?, &args.define)?;
+    if let Some(ref components) = config.log {
+        log_filter::init(components);
+    }
+    if !args.gpio.is_empty() {
+        let gpio = config.peripherals.get_or_insert_with(Default::default)
+            .gpio.get_or_insert_with(Default::default);
+        for (pin, level) in &args.gpio {
+            gpio.push(peripherals::gpio::GpioInputConfig { pin: pin.clone(), level: Some(*level), ..Default::default() });
+        }
+    }
+
+    if args.check_config {
+        let device = svd_parser::parse(&read_file_str(&config.cpu.svd)?)
+            .with_context(|| format!("Failed to parse {}", config.cpu.svd))?;
+        let warnings = check::check_config(config, &device)?;
+        info!("Config check done num_warnings={}", warnings);
+        return Ok(());
+    }
+
+    let mut emulator = Emulator::new(config, args)?;
+    while emulator.step()? {}
+    let exit_code = emulator.exit_code();
+    emulator.finish()?;
+
+    // The firmware decides how the emulation went
+    if let Some(code) = exit_code {
+        std::process::exit(code);
+    }
+    Ok(())
+}
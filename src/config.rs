@@ -1,6 +1,11 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::{collections::HashMap, path::Path};
 use serde::Deserialize;
+use serde_yaml::Value;
+use anyhow::{Context, Result, bail};
+
+use crate::util::read_file_str;
 
 #[derive(Debug, Deserialize)]
 pub struct Region {
@@ -8,26 +13,165 @@ pub struct Region {
    pub start: u32,
    pub size: u32,
    pub load: Option<String>,
+   /// Cycles taken by the instructions executed from this region, e.g. 1 +
+   /// the flash wait states. Defaults to 1.
+   pub cycles_per_instruction: Option<u32>,
+   /// Name of another region whose memory this one aliases, like a mirror
+   /// of the SRAM. The size can't exceed the other region's.
+   pub mirror_of: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Patch {
-   pub start: u32,
+   pub start: Option<u32>,
+   /// Alternative to start. Can be `symbol+offset`.
+   pub symbol: Option<String>,
    pub data: Vec<u8>,
 }
 
+impl Patch {
+    pub fn resolve_addr(&self) -> Result<u32> {
+        match (self.start, self.symbol.as_ref()) {
+            (Some(start), None) => Ok(start),
+            (None, Some(symbol)) => crate::symbols::symbols().parse_addr(symbol),
+            _ => bail!("Patches must have exactly one of start or symbol"),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct Cpu {
     pub svd: String,
+    /// cortex-m0, cortex-m0+, cortex-m3, cortex-m4, cortex-m7, or cortex-m33.
+    /// Defaults to the CPU of the SVD file.
+    pub model: Option<String>,
+    /// Family like STM32G4, or layout (F1, F4, F7, H7), selecting the register
+    /// layout of the peripherals. Defaults to the family of the SVD device.
+    pub family: Option<String>,
     pub vector_table: u32,
+    /// Firmware binary, loaded at the vector table address. Convenient when
+    /// the regions are derived from the device.
+    pub firmware: Option<String>,
+    /// Add the standard flash and SRAM regions of the device that don't
+    /// overlap with the configured regions. Implied when regions are omitted.
+    pub auto_regions: Option<bool>,
+    /// Alias the flash at address 0, like the default boot mapping does.
+    /// Defaults to true.
+    pub boot_alias: Option<bool>,
+    /// Enforce the MPU region permissions. This hooks all memory accesses,
+    /// which slows down the emulation.
+    pub mpu: Option<bool>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
+   /// A board defined in the emulator, filling in the rest of the config
+   pub board: Option<String>,
    pub cpu: Cpu,
-   pub regions: Vec<Region>,
+   pub regions: Option<Vec<Region>>,
    pub patches: Option<Vec<Patch>>,
    pub peripherals: Option<crate::peripherals::PeripheralsConfig>,
    pub devices: Option<crate::ext_devices::ExtDevicesConfig>,
    pub framebuffers: Option<Vec<crate::framebuffers::FramebufferConfig>>,
+   /// ELF file of the firmware, to get its symbols
+   pub elf: Option<String>,
+   /// Additional symbols, useful when we don't have the ELF file
+   pub symbols: Option<HashMap<String, u32>>,
+   pub rtos: Option<crate::rtos::RtosConfig>,
+   pub memcheck: Option<crate::memcheck::MemCheckConfig>,
+   pub stubs: Option<Vec<crate::stubs::StubConfig>>,
+   /// Firmware variables logged periodically
+   pub watch_values: Option<Vec<crate::watch::WatchValueConfig>>,
+   /// Expectations on the peripheral traffic, checked at the end
+   pub expect: Option<Vec<crate::expect::ExpectConfig>>,
+   /// Stop emulation when pc reaches this address or symbol. --stop-addr takes precedence.
+   pub stop_at: Option<String>,
+   /// How the firmware ends the emulation with an exit code
+   pub exit: Option<crate::exit::ExitConfig>,
+   /// Log levels per peripheral or device
+   pub log: Option<HashMap<String, crate::log_filter::LogLevel>>,
+   /// Inputs and outputs exchanged with --cosim
+   pub cosim: Option<crate::cosim::CosimConfig>,
+   /// Unique ID, flash size, and option bytes
+   pub system_memory: Option<crate::system_memory::SystemMemoryConfig>,
+}
+
+// Config files can include other config files with `include: [file, ...]`.
+// Included files are merged first, and the including file overlays them:
+// mappings are merged recursively, sequences are appended, and scalars are replaced.
+// `${name}` is substituted with variables given on the command line.
+
+impl Config {
+    pub fn load(path: &str, vars: &[(String, String)]) -> Result<Self> {
+        let mut value = Self::load_value(Path::new(path), vars, 0)?;
+        if let Some(wiring) = value.as_mapping_mut().and_then(|m| m.remove(&Value::from("wiring"))) {
+            crate::ext_devices::wiring::apply(value.get_mut("devices"), wiring)
+                .with_context(|| format!("Failed to wire the devices of {}", path))?;
+        }
+        let mut config: Self = serde_yaml::from_value(value)
+            .with_context(|| format!("Failed to parse {}", path))?;
+        if let Some(name) = config.board.clone() {
+            crate::boards::find_board(&name)?.configure(&mut config)?;
+            info!("Board name={}", name);
+        }
+        Ok(config)
+    }
+
+    fn load_value(path: &Path, vars: &[(String, String)], depth: usize) -> Result<Value> {
+        if depth > 16 {
+            bail!("Too many nested includes in {}", path.display());
+        }
+
+        let content = substitute_vars(&read_file_str(&path.to_string_lossy())?, vars)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        let mut value: Value = serde_yaml::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        let includes = match value.as_mapping_mut().and_then(|m| m.remove(&Value::from("include"))) {
+            None => vec![],
+            Some(Value::String(include)) => vec![include],
+            Some(Value::Sequence(includes)) => includes.into_iter()
+                .map(|v| v.as_str().map(|s| s.to_string())
+                    .with_context(|| format!("Invalid include in {}", path.display())))
+                .collect::<Result<_>>()?,
+            Some(_) => bail!("Invalid include in {}", path.display()),
+        };
+
+        let mut merged = Value::Null;
+        for include in includes {
+            // Relative to the including file
+            let include = path.parent().unwrap_or_else(|| Path::new("")).join(include);
+            merge_values(&mut merged, Self::load_value(&include, vars, depth + 1)?);
+        }
+        merge_values(&mut merged, value);
+
+        Ok(merged)
+    }
+}
+
+fn substitute_vars(content: &str, vars: &[(String, String)]) -> Result<String> {
+    let re = regex::Regex::new(r"\$\{(\w+)\}").unwrap();
+
+    if let Some(c) = re.captures_iter(content).find(|c| !vars.iter().any(|(k, _)| *k == c[1])) {
+        bail!("Undefined variable {}, set it with -D {}=...", &c[1], &c[1]);
+    }
+
+    Ok(re.replace_all(content, |c: &regex::Captures| {
+        vars.iter().rev().find(|(k, _)| *k == c[1]).unwrap().1.clone()
+    }).to_string())
+}
+
+fn merge_values(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (k, v) in overlay {
+                match base.get_mut(&k) {
+                    Some(b) => merge_values(b, v),
+                    None => { base.insert(k, v); }
+                }
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(overlay)) => base.extend(overlay),
+        (base, overlay) => *base = overlay,
+    }
 }
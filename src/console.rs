@@ -0,0 +1,281 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{io::{prelude::*, BufReader}, net::TcpListener};
+
+use anyhow::{Context as _, Result, bail};
+use unicorn_engine::RegisterARM;
+
+use crate::{control::EmulatorHandle, framebuffers::Framebuffers, peripherals::{Peripherals, gpio::Pin}, system::System, util::UniErr};
+
+// An interactive console to poke the emulated system, on stdin or on a
+// socket (`--console stdin` or `--console 127.0.0.1:5555`):
+//
+//   read <addr> [count]     Reads 32-bit words. Peripheral registers are
+//                           decoded, and read like the firmware would.
+//   read <pin>              Level of a GPIO pin, e.g. "read PB5"
+//   write <addr> <value>    Writes a 32-bit word
+//   write <pin> <0|1>       Drives a GPIO input, e.g. "write PB5 1"
+//   release <pin>           Stops driving a GPIO input from the console
+//   regs                    Prints the CPU registers
+//   bt                      Backtrace, guessed from the return addresses on the stack
+//   irq <n>                 Sets an interrupt pending. Negative for system exceptions.
+//   pause                   Pauses the emulation, until continue
+//   continue
+//   reset                   Requests a system reset
+//   reload                  Reloads the firmware files from disk, and resets
+//   screenshot [name]       Writes the framebuffers, or the named one, to timestamped PNG files
+//   encoder <timer> [set <position> | turn <counts> | velocity <counts/s>]
+//                           Moves the virtual encoder of a timer, and prints its position
+//
+// Addresses can be symbols. Commands are executed in between instructions,
+// with the emulation stopped.
+
+// Number of stack words scanned for return addresses
+const BACKTRACE_STACK_WORDS: u32 = 256;
+
+pub struct Console;
+
+/// What the emulation does after a command
+pub enum Command {
+    Done(String),
+    Pause,
+    Continue,
+    Reset,
+    Reload,
+}
+
+impl Console {
+    /// The commands go through the control channel of the emulation
+    pub fn spawn(source: &str, handle: EmulatorHandle) -> Result<()> {
+        if source == "stdin" {
+            info!("Console reading commands from stdin");
+            Self::spawn_thread(move || {
+                Self::serve(BufReader::new(std::io::stdin()), std::io::stdout(), &handle);
+            })
+        } else {
+            let listener = TcpListener::bind(source)
+                .with_context(|| format!("Failed to listen on {}", source))?;
+            info!("Console listening on {}", source);
+            Self::spawn_thread(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("Console accept failed: {}", e);
+                            return;
+                        }
+                    };
+                    let reader = match stream.try_clone() {
+                        Ok(s) => BufReader::new(s),
+                        Err(_) => continue,
+                    };
+                    if !Self::serve(reader, stream, &handle) {
+                        return;
+                    }
+                }
+            })
+        }
+    }
+
+    fn spawn_thread(f: impl FnOnce() + Send + 'static) -> Result<()> {
+        std::thread::Builder::new()
+            .name("console".to_string())
+            .spawn(f)
+            .context("Failed to spawn thread for console")?;
+        Ok(())
+    }
+
+    /// Returns false when the emulation is over
+    fn serve(reader: impl BufRead, mut writer: impl Write, handle: &EmulatorHandle) -> bool {
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let reply = match handle.console_command(line) {
+                Ok(reply) => reply,
+                Err(_) => return false,
+            };
+            if writeln!(writer, "{}", reply).and_then(|_| writer.flush()).is_err() {
+                break;
+            }
+        }
+        true
+    }
+
+    pub fn execute(sys: &System, framebuffers: &Framebuffers, line: &str) -> Result<Command> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let parse_addr = |s: Option<&&str>| -> Result<u32> {
+            crate::symbols::symbols().parse_addr(s.context("Missing argument")?)
+        };
+        let parse_num = |s: Option<&&str>| -> Result<u32> {
+            let s = s.context("Missing argument")?;
+            clap_num::maybe_hex::<u32>(s).map_err(|e| anyhow::anyhow!(e))
+        };
+
+        let reply = match args[0] {
+            "read" if args.get(1).and_then(|s| Pin::parse(s)).is_some() => {
+                let pin = Pin::from_str(args[1]);
+                let level = sys.p.gpio.borrow_mut().read_pin(sys, pin);
+                format!("{} level={}", args[1].to_uppercase(), level as u8)
+            }
+            "read" => {
+                let addr = parse_addr(args.get(1))?;
+                let count = args.get(2).map(|_| parse_num(args.get(2))).transpose()?.unwrap_or(1);
+                (0..count)
+                    .map(|i| Self::read_word(sys, addr + 4*i))
+                    .collect::<Result<Vec<_>>>()?
+                    .join("\n")
+            }
+            "write" => {
+                let target = args.get(1).context("Missing argument")?;
+                let value = parse_num(args.get(2))?;
+                if let Some(pin) = Pin::parse(target) {
+                    sys.p.gpio.borrow_mut().force_input(pin, value != 0);
+                    format!("{} input={}", target.to_uppercase(), (value != 0) as u8)
+                } else {
+                    let addr = parse_addr(args.get(1))?;
+                    Self::write_word(sys, addr, value)?;
+                    format!("0x{:08x} = 0x{:08x}", addr, value)
+                }
+            }
+            "release" => {
+                let target = args.get(1).context("Missing argument")?;
+                let pin = Pin::parse(target).context("Invalid pin")?;
+                sys.p.gpio.borrow_mut().release_input(pin);
+                format!("{} released", target.to_uppercase())
+            }
+            "regs" => Self::regs(sys),
+            "bt" => Self::backtrace(sys),
+            "irq" => {
+                let irq = args.get(1).context("Missing argument")?
+                    .parse::<i32>().context("Invalid irq")?;
+                if !(-14..=111).contains(&irq) {
+                    bail!("irq={} is out of range", irq);
+                }
+                sys.p.nvic.borrow_mut().set_intr_pending(irq);
+                format!("irq={} pending", irq)
+            }
+            "pause" => return Ok(Command::Pause),
+            "continue" => return Ok(Command::Continue),
+            "reset" => return Ok(Command::Reset),
+            "reload" => return Ok(Command::Reload),
+            "screenshot" => {
+                let files = framebuffers.screenshot(args.get(1).copied())?;
+                if files.is_empty() {
+                    "No framebuffers".to_string()
+                } else {
+                    files.join("\n")
+                }
+            }
+            "encoder" => Self::encoder(sys, &args[1..])?,
+            "help" => "Commands: read <addr|pin> [count], write <addr|pin> <value>, release <pin>, regs, bt, irq <n>, pause, continue, reset, reload, screenshot [name], encoder <timer> [set|turn|velocity <value>]".to_string(),
+            cmd => bail!("Unknown command {}, try help", cmd),
+        };
+
+        Ok(Command::Done(reply))
+    }
+
+    fn encoder(sys: &System, args: &[&str]) -> Result<String> {
+        let timer = args.first().context("Missing argument")?;
+        let hclk = sys.p.rcc.borrow().hclk() as u64;
+        let mut timers = sys.p.timers.borrow_mut();
+        let encoder = timers.encoder(timer).with_context(|| format!("No encoder on {}", timer))?;
+        if let Some(cmd) = args.get(1) {
+            let value = args.get(2).context("Missing argument")?;
+            match *cmd {
+                "set" => encoder.set_position(value.parse().context("Invalid position")?, hclk),
+                "turn" => encoder.turn(value.parse().context("Invalid counts")?, hclk),
+                "velocity" => encoder.set_velocity(value.parse().context("Invalid velocity")?, hclk),
+                _ => bail!("Unknown encoder command {}, expected set, turn or velocity", cmd),
+            }
+        }
+        Ok(format!("{} encoder position={}", timer.to_uppercase(), encoder.position(hclk)))
+    }
+
+    fn is_peripheral(addr: u32) -> bool {
+        Peripherals::MEMORY_MAPS.iter().any(|(start, end)| (*start..*end).contains(&addr))
+    }
+
+    fn read_word(sys: &System, addr: u32) -> Result<String> {
+        if Self::is_peripheral(addr) {
+            let v = sys.p.read(sys, addr, 4);
+            Ok(format!("{} = 0x{:08x}{}", sys.p.addr_desc(addr), v,
+                sys.p.fields_desc(addr, v, svd_parser::svd::Usage::Read)))
+        } else {
+            let mut v = [0; 4];
+            sys.uc.borrow().mem_read(addr as u64, &mut v).map_err(UniErr)?;
+            Ok(format!("0x{:08x} = 0x{:08x}", addr, u32::from_le_bytes(v)))
+        }
+    }
+
+    fn write_word(sys: &System, addr: u32, value: u32) -> Result<()> {
+        if Self::is_peripheral(addr) {
+            sys.p.write(sys, addr, 4, value);
+        } else {
+            sys.uc.borrow_mut().mem_write(addr as u64, &value.to_le_bytes()).map_err(UniErr)?;
+        }
+        Ok(())
+    }
+
+    fn func_desc(addr: u32) -> String {
+        crate::symbols::symbols().find_function(addr & !1)
+            .map(|s| format!(" {}+0x{:x}", s.name, (addr & !1) - s.addr))
+            .unwrap_or_default()
+    }
+
+    fn regs(sys: &System) -> String {
+        let uc = sys.uc.borrow();
+        let regs = [
+            ("r0", RegisterARM::R0), ("r1", RegisterARM::R1), ("r2", RegisterARM::R2), ("r3", RegisterARM::R3),
+            ("r4", RegisterARM::R4), ("r5", RegisterARM::R5), ("r6", RegisterARM::R6), ("r7", RegisterARM::R7),
+            ("r8", RegisterARM::R8), ("r9", RegisterARM::R9), ("r10", RegisterARM::R10), ("r11", RegisterARM::R11),
+            ("r12", RegisterARM::R12), ("sp", RegisterARM::SP), ("lr", RegisterARM::LR), ("pc", RegisterARM::PC),
+            ("xpsr", RegisterARM::XPSR), ("msp", RegisterARM::MSP), ("psp", RegisterARM::PSP),
+            ("control", RegisterARM::CONTROL), ("primask", RegisterARM::PRIMASK), ("basepri", RegisterARM::BASEPRI),
+        ];
+        regs.iter()
+            .map(|(name, reg)| {
+                let v = uc.reg_read(*reg).unwrap() as u32;
+                let func = if matches!(name, &"lr" | &"pc") { Self::func_desc(v) } else { String::new() };
+                format!("{:>7} 0x{:08x}{}", name, v, func)
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    fn backtrace(sys: &System) -> String {
+        let uc = sys.uc.borrow();
+        let pc = uc.reg_read(RegisterARM::PC).unwrap() as u32;
+        let lr = uc.reg_read(RegisterARM::LR).unwrap() as u32;
+        let sp = uc.reg_read(RegisterARM::SP).unwrap() as u32;
+
+        let mut frames = vec![format!("#0 pc=0x{:08x}{}", pc, Self::func_desc(pc))];
+        frames.push(format!("#1 lr=0x{:08x}{}", lr, Self::func_desc(lr)));
+
+        // Return addresses have the thumb bit set, and land in a function
+        let has_symbols = crate::symbols::symbols().len() > 0;
+        for i in 0..BACKTRACE_STACK_WORDS {
+            let addr = sp + 4*i;
+            let mut v = [0; 4];
+            if uc.mem_read(addr as u64, &mut v).is_err() {
+                break;
+            }
+            let v = u32::from_le_bytes(v);
+            let is_return_addr = v & 1 != 0 && if has_symbols {
+                crate::symbols::symbols().find_function(v & !1).is_some()
+            } else {
+                (0x0800_0000..0x0810_0000).contains(&v)
+            };
+            if is_return_addr {
+                frames.push(format!("#{} 0x{:08x}{} (sp=0x{:08x})", frames.len(), v, Self::func_desc(v), addr));
+            }
+        }
+
+        frames.join("\n")
+    }
+}
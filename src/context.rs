@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{cell::RefCell, sync::{Arc, atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering}}};
+
+use crate::symbols::Symbols;
+
+// The state of an emulator shared by its hooks, peripherals and devices: the
+// emulated time, the requests that stop the emulation, and the symbols of the
+// firmware. Each Emulator has its own. The hooks capture it, and the emulator
+// makes it current on its thread while it runs, for the code that has no
+// access to the emulator, like the clocks of the peripherals and the logger.
+
+pub struct Context {
+    /// The emulated time, in cycles
+    pub num_instructions: AtomicU64,
+    /// The number of instructions executed, for --max-instructions
+    pub num_executed: AtomicU64,
+    // PC | size << 32 of the last instruction
+    last_instruction: AtomicU64,
+    pub continue_execution: AtomicBool,
+    pub busy_loop_reached: AtomicBool,
+    pub stop_requested: AtomicBool,
+    pub reset_requested: AtomicBool,
+    /// Reloads the firmware files from disk, and resets the system
+    pub reload_requested: AtomicBool,
+    pub lockup: AtomicBool,
+    pub time_limit_reached: AtomicBool,
+    pub exited: AtomicBool,
+    pub exit_code: AtomicI32,
+    /// With --catch-exceptions
+    pub exception_caught: AtomicBool,
+    /// With --break-at-symbol
+    pub breakpoint_reached: AtomicBool,
+    /// The emulated time at which the current step of the co-simulation, or
+    /// of run_for(), ends. u64::MAX when there's none.
+    pub step_end: AtomicU64,
+    /// Set by the emulator handles, the console and the flash server when
+    /// a request is waiting. Checked on each instruction.
+    pub request_pending: AtomicBool,
+    pub symbols: Arc<Symbols>,
+}
+
+impl Context {
+    pub fn new(symbols: Symbols) -> Self {
+        Self {
+            num_instructions: AtomicU64::new(0),
+            num_executed: AtomicU64::new(0),
+            last_instruction: AtomicU64::new(0),
+            continue_execution: AtomicBool::new(false),
+            busy_loop_reached: AtomicBool::new(false),
+            stop_requested: AtomicBool::new(false),
+            reset_requested: AtomicBool::new(false),
+            reload_requested: AtomicBool::new(false),
+            lockup: AtomicBool::new(false),
+            time_limit_reached: AtomicBool::new(false),
+            exited: AtomicBool::new(false),
+            exit_code: AtomicI32::new(0),
+            exception_caught: AtomicBool::new(false),
+            breakpoint_reached: AtomicBool::new(false),
+            step_end: AtomicU64::new(u64::MAX),
+            request_pending: AtomicBool::new(false),
+            symbols: Arc::new(symbols),
+        }
+    }
+
+    /// PC and size of the last instruction
+    pub fn last_instruction(&self) -> (u32, u8) {
+        let v = self.last_instruction.load(Ordering::Relaxed);
+        (v as u32, (v >> 32) as u8)
+    }
+
+    pub fn set_last_instruction(&self, pc: u32, size: u8) {
+        self.last_instruction.store(pc as u64 | (size as u64) << 32, Ordering::Relaxed);
+    }
+}
+
+thread_local! {
+    // Empty until an emulator is created on the thread
+    static CURRENT: RefCell<Arc<Context>> = RefCell::new(Arc::new(Context::new(Symbols::default())));
+}
+
+/// Makes the context of an emulator the current one of the thread
+pub fn enter(ctx: &Arc<Context>) {
+    CURRENT.with(|c| {
+        if !Arc::ptr_eq(&c.borrow(), ctx) {
+            *c.borrow_mut() = ctx.clone();
+        }
+    });
+}
+
+/// The context of the emulator running on this thread
+pub fn current() -> Arc<Context> {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+/// The emulated time of the emulator running on this thread, in cycles
+pub fn now() -> u64 {
+    CURRENT.with(|c| c.borrow().num_instructions.load(Ordering::Relaxed))
+}
+
+/// The PC of the last instruction of the emulator running on this thread
+pub fn last_pc() -> u32 {
+    CURRENT.with(|c| c.borrow().last_instruction().0)
+}
@@ -0,0 +1,287 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::sync::{Arc, Weak, atomic::Ordering, mpsc::{self, Sender, Receiver}};
+
+use anyhow::{Result, anyhow, bail};
+use unicorn_engine::RegisterARM;
+
+use crate::{console::{Console, Command}, context::Context, flash_server::FlashServer, framebuffers::Framebuffers, system::System, util::UniErr};
+
+// Controls an emulation from other threads, like a debugger: pause, resume,
+// single-step, and inspect the registers and the memory. The emulation thread
+// serves the requests: the emulation stops for them, and waits for the next
+// ones while paused.
+//
+//   let handle = emulator.handle();
+//   std::thread::spawn(move || -> Result<()> {
+//       handle.pause()?;
+//       let pc = handle.read_reg(RegisterARM::PC)?;
+//       handle.step()?;
+//       handle.resume()
+//   });
+//   emulator.run()?;
+//
+// A paused emulation resumes when all its handles are dropped.
+//
+// The console and the flash server send their commands on this channel as
+// well, from their threads, with their own handles. The emulation stops on a
+// single pending flag, and a pause of the console is one of the emulation.
+
+enum Request {
+    Pause,
+    Resume,
+    Step,
+    State,
+    ReadReg(RegisterARM),
+    WriteReg(RegisterARM, u32),
+    ReadMem(u32, usize),
+    WriteMem(u32, Vec<u8>),
+    Console(String),
+    FlashServer(String),
+}
+
+enum Reply {
+    Done,
+    Value(u32),
+    Data(Vec<u8>),
+    State(EmulatorState),
+    Text(String),
+    Error(String),
+}
+
+type Channel = Sender<(Request, Sender<Reply>)>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct EmulatorState {
+    pub pc: u32,
+    /// The emulated time, in CPU cycles
+    pub num_cycles: u64,
+    pub paused: bool,
+}
+
+/// Controls the emulation from another thread. From Emulator::handle().
+#[derive(Clone)]
+pub struct EmulatorHandle {
+    tx: Arc<Channel>,
+    // Stops the emulation to serve the requests
+    ctx: Arc<Context>,
+}
+
+impl EmulatorHandle {
+    /// Blocks until the emulation serves the request
+    fn request(&self, request: Request) -> Result<Reply> {
+        let (tx, rx) = mpsc::channel();
+        self.tx.send((request, tx)).map_err(|_| anyhow!("The emulation is over"))?;
+        self.ctx.request_pending.store(true, Ordering::Release);
+        match rx.recv() {
+            Ok(Reply::Error(e)) => bail!(e),
+            Ok(reply) => Ok(reply),
+            Err(_) => bail!("The emulation is over"),
+        }
+    }
+
+    /// The emulation stays on the current instruction until resumed
+    pub fn pause(&self) -> Result<()> {
+        self.request(Request::Pause).map(drop)
+    }
+
+    pub fn resume(&self) -> Result<()> {
+        self.request(Request::Resume).map(drop)
+    }
+
+    /// Executes one instruction of the paused emulation
+    pub fn step(&self) -> Result<()> {
+        self.request(Request::Step).map(drop)
+    }
+
+    pub fn state(&self) -> Result<EmulatorState> {
+        match self.request(Request::State)? {
+            Reply::State(state) => Ok(state),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn read_reg(&self, reg: RegisterARM) -> Result<u32> {
+        match self.request(Request::ReadReg(reg))? {
+            Reply::Value(v) => Ok(v),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_reg(&self, reg: RegisterARM, value: u32) -> Result<()> {
+        self.request(Request::WriteReg(reg, value)).map(drop)
+    }
+
+    pub fn read_mem(&self, addr: u32, len: usize) -> Result<Vec<u8>> {
+        match self.request(Request::ReadMem(addr, len))? {
+            Reply::Data(data) => Ok(data),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write_mem(&self, addr: u32, data: &[u8]) -> Result<()> {
+        self.request(Request::WriteMem(addr, data.to_vec())).map(drop)
+    }
+
+    /// Returns the reply of the console
+    pub(crate) fn console_command(&self, line: String) -> Result<String> {
+        match self.request(Request::Console(line))? {
+            Reply::Text(reply) => Ok(reply),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the reply of the flash server
+    pub(crate) fn flash_server_command(&self, line: String) -> Result<String> {
+        match self.request(Request::FlashServer(line))? {
+            Reply::Text(reply) => Ok(reply),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The emulation side of the handles
+pub struct Control {
+    rx: Receiver<(Request, Sender<Reply>)>,
+    // The handles hold the sender. The channel is gone when they all are.
+    tx: Weak<Channel>,
+    paused: bool,
+    // Replied once the instruction is executed
+    step_reply: Option<Sender<Reply>>,
+    ctx: Arc<Context>,
+}
+
+impl Control {
+    pub fn new(ctx: Arc<Context>) -> Self {
+        let (_, rx) = mpsc::channel();
+        Self { rx, tx: Weak::new(), paused: false, step_reply: None, ctx }
+    }
+
+    pub fn handle(&mut self) -> EmulatorHandle {
+        let tx = self.tx.upgrade().unwrap_or_else(|| {
+            let (tx, rx) = mpsc::channel();
+            let tx = Arc::new(tx);
+            self.rx = rx;
+            self.tx = Arc::downgrade(&tx);
+            tx
+        });
+        EmulatorHandle { tx, ctx: self.ctx.clone() }
+    }
+
+    /// The emulation runs one instruction at a time
+    pub fn is_stepping(&self) -> bool {
+        self.step_reply.is_some()
+    }
+
+    /// The emulation serves the requests after each stop
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Serves the pending requests. Blocks while the emulation is paused.
+    /// Returns true when a reset is requested.
+    pub fn process(&mut self, sys: &System, framebuffers: &Framebuffers) -> bool {
+        if let Some(reply) = self.step_reply.take() {
+            let _ = reply.send(Reply::Done);
+        }
+
+        loop {
+            let (request, reply) = if self.paused {
+                match self.rx.recv() {
+                    Ok(r) => r,
+                    Err(_) => {
+                        info!("Emulation resumed, its handles are gone");
+                        self.paused = false;
+                        return false;
+                    }
+                }
+            } else {
+                match self.rx.try_recv() {
+                    Ok(r) => r,
+                    Err(_) => return false,
+                }
+            };
+
+            let (result, reset) = match request {
+                Request::Step if self.paused => {
+                    self.step_reply = Some(reply);
+                    return false;
+                }
+                Request::Console(line) => self.console_command(sys, framebuffers, &line),
+                Request::FlashServer(line) => {
+                    let (text, reset) = FlashServer::process(&mut sys.uc.borrow_mut(), &line);
+                    (Reply::Text(text), reset)
+                }
+                request => (self.execute(sys, request).unwrap_or_else(|e| Reply::Error(format!("{:#}", e))), false),
+            };
+            let _ = reply.send(result);
+            if reset {
+                self.paused = false;
+                return true;
+            }
+        }
+    }
+
+    fn console_command(&mut self, sys: &System, framebuffers: &Framebuffers, line: &str) -> (Reply, bool) {
+        debug!("Console cmd='{}'", line.trim());
+        let (text, reset) = match Console::execute(sys, framebuffers, line) {
+            Ok(Command::Done(reply)) => (reply, false),
+            Ok(Command::Pause) => {
+                self.paused = true;
+                ("Paused".to_string(), false)
+            }
+            Ok(Command::Continue) => {
+                self.paused = false;
+                ("Running".to_string(), false)
+            }
+            Ok(Command::Reset) => ("Reset".to_string(), true),
+            Ok(Command::Reload) => {
+                self.ctx.reload_requested.store(true, Ordering::Release);
+                ("Reloading".to_string(), true)
+            }
+            Err(e) => (format!("Error: {:#}", e), false),
+        };
+        (Reply::Text(text), reset)
+    }
+
+    fn execute(&mut self, sys: &System, request: Request) -> Result<Reply> {
+        let mut uc = sys.uc.borrow_mut();
+        Ok(match request {
+            Request::Pause => {
+                if !self.paused {
+                    info!("Emulation paused pc=0x{:08x}", uc.reg_read(RegisterARM::PC).map_err(UniErr)?);
+                }
+                self.paused = true;
+                Reply::Done
+            }
+            Request::Resume => {
+                if self.paused {
+                    info!("Emulation resumed");
+                }
+                self.paused = false;
+                Reply::Done
+            }
+            Request::Step => bail!("The emulation isn't paused"),
+            Request::Console(_) | Request::FlashServer(_) => unreachable!(),
+            Request::State => Reply::State(EmulatorState {
+                pc: uc.reg_read(RegisterARM::PC).map_err(UniErr)? as u32,
+                num_cycles: self.ctx.num_instructions.load(Ordering::Relaxed),
+                paused: self.paused,
+            }),
+            Request::ReadReg(reg) => Reply::Value(uc.reg_read(reg).map_err(UniErr)? as u32),
+            Request::WriteReg(reg, value) => {
+                uc.reg_write(reg, value as u64).map_err(UniErr)?;
+                Reply::Done
+            }
+            Request::ReadMem(addr, len) => {
+                let mut data = vec![0; len];
+                uc.mem_read(addr as u64, &mut data).map_err(UniErr)?;
+                Reply::Data(data)
+            }
+            Request::WriteMem(addr, data) => {
+                uc.mem_write(addr as u64, &data).map_err(UniErr)?;
+                Reply::Done
+            }
+        })
+    }
+}
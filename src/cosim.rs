@@ -0,0 +1,265 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{io::{prelude::*, BufReader}, net::TcpListener, sync::{atomic::Ordering, mpsc::{Sender, Receiver}}};
+
+use anyhow::{Context as _, Result, bail};
+use serde::Deserialize;
+
+use crate::{context::Context, ext_devices::background::Background, peripherals::gpio::Pin, symbols::symbols, system::System, util::UniErr, watch::WatchType};
+
+// Co-simulation with an external process, like a physics simulator, with
+// `--cosim 127.0.0.1:6000`. The simulator drives the emulated time: the
+// emulation only runs the steps it asks for, and they exchange the sensor
+// inputs and the actuator outputs in between. One line per step:
+//
+//   > step <clk> [input=value ...]
+//   < clk=<now> [output=value ...]
+//
+// The inputs are applied, the emulation runs for <clk> CPU cycles, and the
+// outputs are reported. `quit` ends the emulation. The inputs and outputs are
+// GPIO pins, or firmware variables:
+//
+//  cosim:
+//    inputs:
+//      - name: endstop
+//        pin: PB2
+//      - name: temp
+//        symbol: g_adc_temp
+//        type: u16
+//    outputs:
+//      - name: heater
+//        pin: PA5
+//      - name: fan
+//        addr: 0x20000100
+//        type: float
+
+/// The emulated time at which the current step ends. The emulation stops
+/// there, and sleeping doesn't skip past it.
+pub fn step_end(ctx: &Context) -> Option<u64> {
+    Some(ctx.step_end.load(Ordering::Relaxed)).filter(|t| *t != u64::MAX)
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CosimConfig {
+    pub inputs: Option<Vec<CosimValueConfig>>,
+    pub outputs: Option<Vec<CosimValueConfig>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CosimValueConfig {
+    pub name: String,
+    pub pin: Option<String>,
+    pub addr: Option<u32>,
+    /// Alternative to addr. Can be `symbol+offset`.
+    pub symbol: Option<String>,
+    /// For variables
+    pub r#type: Option<WatchType>,
+}
+
+enum Target {
+    Pin(Pin),
+    Memory(u32, WatchType),
+}
+
+struct Value {
+    name: String,
+    target: Target,
+}
+
+impl Value {
+    fn new(config: CosimValueConfig) -> Result<Self> {
+        let target = match (config.pin, config.addr, config.symbol, config.r#type) {
+            (Some(pin), None, None, None) => {
+                Target::Pin(Pin::parse(&pin).with_context(|| format!("Invalid cosim pin {}", pin))?)
+            }
+            (None, addr, symbol, Some(type_)) if addr.is_some() != symbol.is_some() => {
+                if matches!(type_, WatchType::Cstring) {
+                    bail!("cosim value {} can't be a cstring", config.name);
+                }
+                let addr = match (addr, symbol) {
+                    (Some(addr), _) => addr,
+                    (_, Some(symbol)) => symbols().parse_addr(&symbol)?,
+                    _ => unreachable!(),
+                };
+                Target::Memory(addr, type_)
+            }
+            _ => bail!("cosim value {} needs a pin, or an addr or symbol with a type", config.name),
+        };
+        Ok(Self { name: config.name, target })
+    }
+
+    fn size(type_: WatchType) -> usize {
+        match type_ {
+            WatchType::U8 | WatchType::I8 => 1,
+            WatchType::U16 | WatchType::I16 => 2,
+            _ => 4,
+        }
+    }
+
+    fn read(&self, sys: &System) -> Result<String> {
+        Ok(match self.target {
+            Target::Pin(pin) => (sys.p.gpio.borrow_mut().read_pin(sys, pin) as u8).to_string(),
+            Target::Memory(addr, type_) => {
+                let mut buf = [0u8; 4];
+                sys.uc.borrow().mem_read(addr as u64, &mut buf[..Self::size(type_)]).map_err(UniErr)?;
+                let v = u32::from_le_bytes(buf);
+                match type_ {
+                    WatchType::I8 => (v as i8).to_string(),
+                    WatchType::I16 => (v as i16).to_string(),
+                    WatchType::I32 => (v as i32).to_string(),
+                    WatchType::Float => f32::from_bits(v).to_string(),
+                    _ => v.to_string(),
+                }
+            }
+        })
+    }
+
+    fn write(&self, sys: &System, value: &str) -> Result<()> {
+        match self.target {
+            Target::Pin(pin) => {
+                let level = match value {
+                    "0" => false,
+                    "1" => true,
+                    _ => bail!("Invalid level {}={}", self.name, value),
+                };
+                sys.p.gpio.borrow_mut().force_input(pin, level);
+            }
+            Target::Memory(addr, type_) => {
+                let v = match type_ {
+                    WatchType::Float => value.parse::<f32>().map(f32::to_bits).ok(),
+                    _ => value.parse::<i64>().ok().map(|v| v as u32),
+                }.with_context(|| format!("Invalid value {}={}", self.name, value))?;
+                let bytes = v.to_le_bytes();
+                sys.uc.borrow_mut().mem_write(addr as u64, &bytes[..Self::size(type_)]).map_err(UniErr)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Cosim {
+    background: Background<String, String>,
+    // The first step is waited for before running anything
+    started: bool,
+    inputs: Vec<Value>,
+    outputs: Vec<Value>,
+}
+
+impl Cosim {
+    pub fn new(addr: &str, config: CosimConfig) -> Result<Self> {
+        let inputs = config.inputs.unwrap_or_default().into_iter().map(Value::new).collect::<Result<_>>()?;
+        let outputs = config.outputs.unwrap_or_default().into_iter().map(Value::new).collect::<Result<_>>()?;
+
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("Failed to listen on {}", addr))?;
+        info!("Co-simulation listening on {}", addr);
+        let background = Background::spawn("cosim", move |rx, tx| Self::serve(listener, rx, tx))?;
+
+        // Waits for the first step
+        crate::context::current().step_end.store(0, Ordering::Relaxed);
+        Ok(Self { background, started: false, inputs, outputs })
+    }
+
+    /// A single simulator drives the emulation. The emulation ends with it.
+    fn serve(listener: TcpListener, rx: Receiver<String>, tx: Sender<String>) {
+        let stream = match listener.accept() {
+            Ok((stream, addr)) => {
+                info!("Co-simulation client connected addr={}", addr);
+                stream
+            }
+            Err(e) => {
+                warn!("Co-simulation accept failed: {}", e);
+                return;
+            }
+        };
+        let reader = match stream.try_clone() {
+            Ok(s) => BufReader::new(s),
+            Err(_) => return,
+        };
+        let mut writer = stream;
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            if tx.send(line).is_err() {
+                return;
+            }
+            let reply = match rx.recv() {
+                Ok(reply) => reply,
+                Err(_) => return,
+            };
+            if writeln!(writer, "{}", reply).and_then(|_| writer.flush()).is_err() {
+                break;
+            }
+        }
+        info!("Co-simulation client disconnected");
+    }
+
+    /// Called when the step is over. Reports the outputs, and blocks until the
+    /// next step. Returns false when the simulator is done.
+    pub fn step(&mut self, sys: &System) -> bool {
+        if std::mem::replace(&mut self.started, true) {
+            let reply = self.outputs_desc(sys);
+            self.background.send(reply);
+        }
+        loop {
+            let line = match self.background.recv() {
+                Some(line) => line,
+                None => return false,
+            };
+            match self.execute(sys, &line) {
+                Ok(true) => return true,
+                Ok(false) => {
+                    self.background.send("Bye".to_string());
+                    return false;
+                }
+                Err(e) => self.background.send(format!("Error: {:#}", e)),
+            }
+        }
+    }
+
+    /// Returns true when a step starts
+    fn execute(&mut self, sys: &System, line: &str) -> Result<bool> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        match args[0] {
+            "step" => {
+                let clk = args.get(1).context("Missing argument")?;
+                let clk = clap_num::maybe_hex::<u64>(clk).map_err(|e| anyhow::anyhow!(e))?;
+                for arg in &args[2..] {
+                    let (name, value) = arg.split_once('=').with_context(|| format!("Invalid input {}", arg))?;
+                    let input = self.inputs.iter().find(|i| i.name == name)
+                        .with_context(|| format!("Unknown input {}", name))?;
+                    input.write(sys, value)?;
+                }
+                let now = crate::context::now();
+                crate::context::current().step_end.store(now + clk, Ordering::Relaxed);
+                trace!("Co-simulation step clk={}", clk);
+                Ok(true)
+            }
+            "quit" => Ok(false),
+            cmd => bail!("Unknown command {}, expected step or quit", cmd),
+        }
+    }
+
+    fn outputs_desc(&self, sys: &System) -> String {
+        let now = crate::context::now();
+        let mut desc = format!("clk={}", now);
+        for o in &self.outputs {
+            let v = o.read(sys).unwrap_or_else(|e| {
+                warn!("Co-simulation failed to read {}: {:#}", o.name, e);
+                "?".to_string()
+            });
+            desc += &format!(" {}={}", o.name, v);
+        }
+        desc
+    }
+
+    /// Whether the emulation reached the end of the step
+    pub fn is_step_done(ctx: &Context) -> bool {
+        step_end(ctx).is_some_and(|end| ctx.num_instructions.load(Ordering::Relaxed) >= end)
+    }
+}
@@ -0,0 +1,104 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{Result, bail};
+use svd_parser::svd::Device as SvdDevice;
+use unicorn_engine::{Unicorn, unicorn_const::uc_error};
+
+use crate::util::UniErr;
+
+// The model is given to Unicorn, which then executes the instruction set of the
+// core, e.g. ARMv6-M on the M0, without the FPU on the M3. The model also
+// drives what we emulate on our side: bitbanding, the FP state on exception
+// entry, and the EXC_RETURN values.
+
+// The bindings don't expose uc_ctl(). The values come from unicorn.h and arm.h.
+extern "C" {
+    fn uc_ctl(uc: *mut std::ffi::c_void, control: u32, ...) -> uc_error;
+}
+// UC_CTL_WRITE(UC_CTL_CPU_MODEL, 1)
+const UC_CTL_WRITE_CPU_MODEL: u32 = 7 | (1 << 26) | (1 << 30);
+const UC_CPU_ARM_CORTEX_M0: i32 = 7;
+const UC_CPU_ARM_CORTEX_M3: i32 = 8;
+const UC_CPU_ARM_CORTEX_M4: i32 = 9;
+const UC_CPU_ARM_CORTEX_M7: i32 = 10;
+const UC_CPU_ARM_CORTEX_M33: i32 = 11;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CpuModel {
+    CortexM0,
+    CortexM0Plus,
+    CortexM3,
+    #[default]
+    CortexM4,
+    CortexM7,
+    CortexM33,
+}
+
+impl CpuModel {
+    /// Parses names like "cortex-m4", "M0+", or "CM33" as found in SVD files
+    pub fn parse(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        let name = name.trim_start_matches("cortex-").trim_start_matches('c').trim_start_matches('m');
+        Some(match name {
+            "0" => Self::CortexM0,
+            "0+" | "0plus" => Self::CortexM0Plus,
+            "3" => Self::CortexM3,
+            "4" => Self::CortexM4,
+            "7" => Self::CortexM7,
+            "33" => Self::CortexM33,
+            _ => return None,
+        })
+    }
+
+    /// The configured model takes precedence over the one of the SVD file.
+    /// Defaults to the Cortex-M4.
+    pub fn from_config(model: Option<&str>, svd_device: &SvdDevice) -> Result<Self> {
+        if let Some(model) = model {
+            return match Self::parse(model) {
+                Some(model) => Ok(model),
+                None => bail!("Unknown cpu model={}. Valid models are cortex-m0, cortex-m0+, cortex-m3, cortex-m4, cortex-m7, cortex-m33", model),
+            };
+        }
+
+        let svd_model = svd_device.cpu.as_ref().map(|cpu| cpu.name.as_str());
+        Ok(svd_model.and_then(Self::parse).unwrap_or_default())
+    }
+
+    /// Selects the core Unicorn emulates. Must be called right after opening
+    /// the engine, without Mode::MCLASS, which forces the Cortex-M33.
+    pub fn configure_unicorn(self, uc: &mut Unicorn<()>) -> Result<()> {
+        let model = match self {
+            Self::CortexM0 | Self::CortexM0Plus => UC_CPU_ARM_CORTEX_M0,
+            Self::CortexM3 => UC_CPU_ARM_CORTEX_M3,
+            Self::CortexM4 => UC_CPU_ARM_CORTEX_M4,
+            Self::CortexM7 => UC_CPU_ARM_CORTEX_M7,
+            Self::CortexM33 => UC_CPU_ARM_CORTEX_M33,
+        };
+        match unsafe { uc_ctl(uc.get_handle(), UC_CTL_WRITE_CPU_MODEL, model) } {
+            uc_error::OK => Ok(()),
+            e => Err(UniErr(e).into()),
+        }
+    }
+
+    /// ARMv6-M cores
+    pub fn is_v6m(self) -> bool {
+        matches!(self, Self::CortexM0 | Self::CortexM0Plus)
+    }
+
+    /// Number of NVIC priority bits of the STM32 parts, when the SVD file doesn't say
+    pub fn nvic_priority_bits(self) -> u32 {
+        if self.is_v6m() { 2 } else { 4 }
+    }
+
+    pub fn is_v8m(self) -> bool {
+        self == Self::CortexM33
+    }
+
+    pub fn has_bitbanding(self) -> bool {
+        matches!(self, Self::CortexM3 | Self::CortexM4)
+    }
+
+    pub fn has_fpu(self) -> bool {
+        matches!(self, Self::CortexM4 | Self::CortexM7 | Self::CortexM33)
+    }
+}
@@ -0,0 +1,280 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// Software implementations of the algorithms of the HASH and CRYP
+// peripherals. Speed doesn't matter much, the firmware feeds the peripherals
+// one word at a time.
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// SHA-1 / SHA-224 / SHA-256
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgo {
+    Sha1,
+    Sha224,
+    Sha256,
+}
+
+impl HashAlgo {
+    pub fn digest_len(&self) -> usize {
+        match self {
+            HashAlgo::Sha1 => 20,
+            HashAlgo::Sha224 => 28,
+            HashAlgo::Sha256 => 32,
+        }
+    }
+}
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+fn sha1_compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 80];
+    for (i, chunk) in block.chunks(4).enumerate() {
+        w[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+    }
+    for i in 16..80 {
+        w[i] = (w[i-3] ^ w[i-8] ^ w[i-14] ^ w[i-16]).rotate_left(1);
+    }
+
+    let [mut a, mut b, mut c, mut d, mut e, ..] = *state;
+    for (i, w) in w.iter().enumerate() {
+        let (f, k) = match i {
+            0..=19 => ((b & c) | (!b & d), 0x5a827999),
+            20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+            40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+            _ => (b ^ c ^ d, 0xca62c1d6),
+        };
+        let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*w);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = t;
+    }
+
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+fn sha256_compress(state: &mut [u32; 8], block: &[u8]) {
+    let mut w = [0u32; 64];
+    for (i, chunk) in block.chunks(4).enumerate() {
+        w[i] = u32::from_be_bytes(chunk.try_into().unwrap());
+    }
+    for i in 16..64 {
+        let s0 = w[i-15].rotate_right(7) ^ w[i-15].rotate_right(18) ^ (w[i-15] >> 3);
+        let s1 = w[i-2].rotate_right(17) ^ w[i-2].rotate_right(19) ^ (w[i-2] >> 10);
+        w[i] = w[i-16].wrapping_add(s0).wrapping_add(w[i-7]).wrapping_add(s1);
+    }
+
+    let mut v = *state;
+    for i in 0..64 {
+        let [a, b, c, d, e, f, g, h] = v;
+        let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+        let ch = (e & f) ^ (!e & g);
+        let t1 = h.wrapping_add(s1).wrapping_add(ch).wrapping_add(SHA256_K[i]).wrapping_add(w[i]);
+        let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+        let maj = (a & b) ^ (a & c) ^ (b & c);
+        let t2 = s0.wrapping_add(maj);
+        v = [t1.wrapping_add(t2), a, b, c, d.wrapping_add(t1), e, f, g];
+    }
+
+    for (s, v) in state.iter_mut().zip(v) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+pub fn hash(algo: HashAlgo, data: &[u8]) -> Vec<u8> {
+    let mut state: [u32; 8] = match algo {
+        HashAlgo::Sha1 => [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0, 0, 0, 0],
+        HashAlgo::Sha224 => [0xc1059ed8, 0x367cd507, 0x3070dd17, 0xf70e5939, 0xffc00b31, 0x68581511, 0x64f98fa7, 0xbefa4fa4],
+        HashAlgo::Sha256 => [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19],
+    };
+
+    // Padding: 0x80, zeros, and the message length in bits
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in msg.chunks(64) {
+        match algo {
+            HashAlgo::Sha1 => sha1_compress(&mut state, block),
+            HashAlgo::Sha224 | HashAlgo::Sha256 => sha256_compress(&mut state, block),
+        }
+    }
+
+    state.iter()
+        .flat_map(|v| v.to_be_bytes())
+        .take(algo.digest_len())
+        .collect()
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////
+// AES-128 / AES-192 / AES-256
+
+const SBOX: [u8; 256] = [
+    0x63, 0x7c, 0x77, 0x7b, 0xf2, 0x6b, 0x6f, 0xc5, 0x30, 0x01, 0x67, 0x2b, 0xfe, 0xd7, 0xab, 0x76,
+    0xca, 0x82, 0xc9, 0x7d, 0xfa, 0x59, 0x47, 0xf0, 0xad, 0xd4, 0xa2, 0xaf, 0x9c, 0xa4, 0x72, 0xc0,
+    0xb7, 0xfd, 0x93, 0x26, 0x36, 0x3f, 0xf7, 0xcc, 0x34, 0xa5, 0xe5, 0xf1, 0x71, 0xd8, 0x31, 0x15,
+    0x04, 0xc7, 0x23, 0xc3, 0x18, 0x96, 0x05, 0x9a, 0x07, 0x12, 0x80, 0xe2, 0xeb, 0x27, 0xb2, 0x75,
+    0x09, 0x83, 0x2c, 0x1a, 0x1b, 0x6e, 0x5a, 0xa0, 0x52, 0x3b, 0xd6, 0xb3, 0x29, 0xe3, 0x2f, 0x84,
+    0x53, 0xd1, 0x00, 0xed, 0x20, 0xfc, 0xb1, 0x5b, 0x6a, 0xcb, 0xbe, 0x39, 0x4a, 0x4c, 0x58, 0xcf,
+    0xd0, 0xef, 0xaa, 0xfb, 0x43, 0x4d, 0x33, 0x85, 0x45, 0xf9, 0x02, 0x7f, 0x50, 0x3c, 0x9f, 0xa8,
+    0x51, 0xa3, 0x40, 0x8f, 0x92, 0x9d, 0x38, 0xf5, 0xbc, 0xb6, 0xda, 0x21, 0x10, 0xff, 0xf3, 0xd2,
+    0xcd, 0x0c, 0x13, 0xec, 0x5f, 0x97, 0x44, 0x17, 0xc4, 0xa7, 0x7e, 0x3d, 0x64, 0x5d, 0x19, 0x73,
+    0x60, 0x81, 0x4f, 0xdc, 0x22, 0x2a, 0x90, 0x88, 0x46, 0xee, 0xb8, 0x14, 0xde, 0x5e, 0x0b, 0xdb,
+    0xe0, 0x32, 0x3a, 0x0a, 0x49, 0x06, 0x24, 0x5c, 0xc2, 0xd3, 0xac, 0x62, 0x91, 0x95, 0xe4, 0x79,
+    0xe7, 0xc8, 0x37, 0x6d, 0x8d, 0xd5, 0x4e, 0xa9, 0x6c, 0x56, 0xf4, 0xea, 0x65, 0x7a, 0xae, 0x08,
+    0xba, 0x78, 0x25, 0x2e, 0x1c, 0xa6, 0xb4, 0xc6, 0xe8, 0xdd, 0x74, 0x1f, 0x4b, 0xbd, 0x8b, 0x8a,
+    0x70, 0x3e, 0xb5, 0x66, 0x48, 0x03, 0xf6, 0x0e, 0x61, 0x35, 0x57, 0xb9, 0x86, 0xc1, 0x1d, 0x9e,
+    0xe1, 0xf8, 0x98, 0x11, 0x69, 0xd9, 0x8e, 0x94, 0x9b, 0x1e, 0x87, 0xe9, 0xce, 0x55, 0x28, 0xdf,
+    0x8c, 0xa1, 0x89, 0x0d, 0xbf, 0xe6, 0x42, 0x68, 0x41, 0x99, 0x2d, 0x0f, 0xb0, 0x54, 0xbb, 0x16,
+];
+
+fn xtime(x: u8) -> u8 {
+    (x << 1) ^ if x & 0x80 != 0 { 0x1b } else { 0 }
+}
+
+fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut r = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            r ^= a;
+        }
+        a = xtime(a);
+        b >>= 1;
+    }
+    r
+}
+
+pub struct Aes {
+    round_keys: Vec<[u8; 16]>,
+    inv_sbox: [u8; 256],
+}
+
+impl Aes {
+    /// The key is 16, 24, or 32 bytes
+    pub fn new(key: &[u8]) -> Self {
+        assert!(matches!(key.len(), 16 | 24 | 32));
+        let nk = key.len() / 4;
+        let num_rounds = nk + 6;
+
+        let mut w = key.chunks(4)
+            .map(|c| <[u8; 4]>::try_from(c).unwrap())
+            .collect::<Vec<_>>();
+        let mut rcon = 1u8;
+        for i in nk..4*(num_rounds+1) {
+            let mut t = w[i-1];
+            if i % nk == 0 {
+                t.rotate_left(1);
+                t = t.map(|b| SBOX[b as usize]);
+                t[0] ^= rcon;
+                rcon = xtime(rcon);
+            } else if nk > 6 && i % nk == 4 {
+                t = t.map(|b| SBOX[b as usize]);
+            }
+            let prev = w[i-nk];
+            w.push([prev[0] ^ t[0], prev[1] ^ t[1], prev[2] ^ t[2], prev[3] ^ t[3]]);
+        }
+
+        let round_keys = w.chunks(4)
+            .map(|words| {
+                let mut k = [0u8; 16];
+                for (i, word) in words.iter().enumerate() {
+                    k[4*i..4*i+4].copy_from_slice(word);
+                }
+                k
+            })
+            .collect();
+
+        let mut inv_sbox = [0u8; 256];
+        for (i, s) in SBOX.iter().enumerate() {
+            inv_sbox[*s as usize] = i as u8;
+        }
+
+        Self { round_keys, inv_sbox }
+    }
+
+    fn add_round_key(state: &mut [u8; 16], key: &[u8; 16]) {
+        for (s, k) in state.iter_mut().zip(key) {
+            *s ^= k;
+        }
+    }
+
+    // The state is in column order: byte i is row i%4, column i/4
+    fn shift_rows(state: &mut [u8; 16], inverse: bool) {
+        let s = *state;
+        for row in 1..4 {
+            for col in 0..4 {
+                let src = if inverse { (col + 4 - row) % 4 } else { (col + row) % 4 };
+                state[4*col + row] = s[4*src + row];
+            }
+        }
+    }
+
+    fn mix_columns(state: &mut [u8; 16], inverse: bool) {
+        let m: [u8; 4] = if inverse { [14, 11, 13, 9] } else { [2, 3, 1, 1] };
+        for col in state.chunks_mut(4) {
+            let c = [col[0], col[1], col[2], col[3]];
+            for (row, v) in col.iter_mut().enumerate() {
+                *v = (0..4).fold(0, |acc, i| acc ^ gmul(m[(i + 4 - row) % 4], c[i]));
+            }
+        }
+    }
+
+    pub fn encrypt_block(&self, block: &mut [u8; 16]) {
+        let num_rounds = self.round_keys.len() - 1;
+        Self::add_round_key(block, &self.round_keys[0]);
+        for round in 1..=num_rounds {
+            for b in block.iter_mut() {
+                *b = SBOX[*b as usize];
+            }
+            Self::shift_rows(block, false);
+            if round != num_rounds {
+                Self::mix_columns(block, false);
+            }
+            Self::add_round_key(block, &self.round_keys[round]);
+        }
+    }
+
+    pub fn decrypt_block(&self, block: &mut [u8; 16]) {
+        let num_rounds = self.round_keys.len() - 1;
+        Self::add_round_key(block, &self.round_keys[num_rounds]);
+        for round in (0..num_rounds).rev() {
+            Self::shift_rows(block, true);
+            for b in block.iter_mut() {
+                *b = self.inv_sbox[*b as usize];
+            }
+            Self::add_round_key(block, &self.round_keys[round]);
+            if round != 0 {
+                Self::mix_columns(block, true);
+            }
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// The HASH and CRYP peripherals process big-endian data. The DATATYPE
+/// field of their CR register tells how to swap the words written by the
+/// firmware: 0 no swap, 1 half-words, 2 bytes, 3 bits.
+pub fn swap_datatype(v: u32, datatype: u32) -> u32 {
+    match datatype & 0b11 {
+        0 => v,
+        1 => v.rotate_left(16),
+        2 => v.swap_bytes(),
+        _ => v.reverse_bits(),
+    }
+}
@@ -1,9 +1,12 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::{mem::MaybeUninit, sync::atomic::{AtomicU64, Ordering, AtomicBool}, cell::RefCell};
-use svd_parser::svd::Device as SvdDevice;
+use std::{mem::MaybeUninit, sync::{Arc, atomic::Ordering}, cell::RefCell, rc::Rc, time::{Duration, Instant}};
 use unicorn_engine::{unicorn_const::{Arch, Mode, HookType, MemType}, Unicorn, RegisterARM};
-use crate::{config::Config, util::UniErr, Args, system::System, framebuffers::sdl_engine::{PUMP_EVENT_INST_INTERVAL, SDL}};
+use crate::{config::Config, cpu::CpuModel, util::{UniErr, read_file_str}, cli::{Args, LogFormat}, system::{System, FirmwareImages, SharedMemory}, framebuffers::{Framebuffers, PUMP_EVENT_INST_INTERVAL}, ext_devices::{ExtDevices, CustomDevices}};
+#[cfg(feature = "sdl")]
+use crate::framebuffers::sdl_engine::SDL;
+use crate::peripherals::{Peripherals, nvic::{Fault, irq, IrqJitter}, mpu::{Mpu, Access, Violation}, pwr::Pwr, comp::Comparators};
+use crate::{rtos::Rtos, memcheck::MemCheck, profile::Profiler, idle::IdleLoopDetector, flash_server::FlashServer, timing::{InstructionTiming, EmulatedClock}, console::Console, flight_recorder::FlightRecorder, trace::TraceWriter, stats::Stats, watch::Watches, expect::Expectations, cosim::Cosim, control::{Control, EmulatorHandle}, context::Context};
 use anyhow::{Context as _, Result, bail};
 use capstone::prelude::*;
 
@@ -24,16 +27,40 @@ impl VectorTable {
     }
 }
 
+/// What to do when the firmware accesses unmapped memory
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnmappedAccess {
+    /// Skip the instruction
+    Skip,
+    /// Deliver a BusFault to the firmware
+    Fault,
+    /// Stop the emulation with a report
+    Stop,
+}
+
+/// Describes an unmapped access: where it comes from, and what's mapped nearby
+fn unmapped_access_report(uc: &Unicorn<()>, type_: MemType, addr: u64, size: usize) -> String {
+    let pc = uc.reg_read(RegisterARM::PC).unwrap() as u32;
+    let symbols = crate::symbols::symbols();
+    let func = symbols.find_function(pc)
+        .map(|s| format!("{}+0x{:x}", s.name, pc - s.addr))
+        .unwrap_or_else(|| "????".to_string());
+
+    let nearest = uc.mem_regions().unwrap_or_default().into_iter()
+        .min_by_key(|r| if addr < r.begin { r.begin - addr } else { addr.saturating_sub(r.end) })
+        .map(|r| format!("0x{:08x}-0x{:08x}", r.begin, r.end))
+        .unwrap_or_else(|| "none".to_string());
+
+    format!("{:?} addr=0x{:08x} size={} pc=0x{:08x} func={} nearest_region={}",
+        type_, addr, size, pc, func, nearest)
+}
+
 fn thumb(pc: u64) -> u64 {
     pc | 1
 }
 
-// PC + instruction size
-pub static mut LAST_INSTRUCTION: (u32, u8) = (0,0);
-pub static NUM_INSTRUCTIONS: AtomicU64 = AtomicU64::new(0);
-static CONTINUE_EXECUTION: AtomicBool = AtomicBool::new(false);
-static BUSY_LOOP_REACHED: AtomicBool = AtomicBool::new(false);
-static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+// How often the time limits are checked, in number of instructions. A power of 2.
+const TIME_LIMIT_CHECK_INTERVAL: u64 = 0x10000;
 
 fn disassemble_instruction(diassembler: &Capstone, uc: &Unicorn<()>, pc: u64) -> String {
     let mut instr = [0; 4];
@@ -72,184 +99,683 @@ pub fn dump_stack(uc: &mut Unicorn<()>, count: usize) {
     }
 }
 
-pub fn run_emulator(config: Config, svd_device: SvdDevice, args: Args) -> Result<()> {
-    let mut uc = Unicorn::new(Arch::ARM, Mode::MCLASS | Mode::LITTLE_ENDIAN)
-        .map_err(UniErr).context("Failed to initialize Unicorn instance")?;
-
-    let vector_table_addr = config.cpu.vector_table;
-
-    let (sys, framebuffers) = crate::system::prepare(&mut uc, config, svd_device)?;
-
-    let diassembler = Capstone::new()
-        .arm()
-        .mode(arch::arm::ArchMode::Thumb)
-        .build()
-        .expect("failed to initialize capstone");
-
-    // We hook on each instructions, but we could skip this.
-    // The slowdown is less than 50%. It's okay for now.
-    {
-        let trace_instructions = crate::verbose() >= 4;
-        let busy_loop_stop = args.busy_loop_stop;
-        let p = sys.p.clone();
-        let d = sys.d.clone();
-        let interrupt_period = args.interrupt_period;
-        sys.uc.borrow_mut().add_code_hook(0, u64::MAX, move |uc, pc, size| {
-            unsafe {
-                if busy_loop_stop && LAST_INSTRUCTION.0 == pc as u32 {
+/// Prints what led to the abort, with --flight-recorder
+fn dump_flight_recorder(p: &Peripherals, log_format: LogFormat) {
+    if let Some(ref recorder) = *p.recorder.borrow() {
+        recorder.dump(p, log_format == LogFormat::Json);
+    }
+}
+
+fn flush_trace(p: &Peripherals) -> Result<()> {
+    if let Some(ref mut trace) = *p.trace.borrow_mut() {
+        trace.flush()?;
+    }
+    Ok(())
+}
+
+/// Unicorn stops the emulation when the CPU halts on WFI, with the pc on the next instruction.
+/// WFE doesn't halt, Unicorn runs it as a no-op, so only WFI is matched.
+fn is_after_wfi(uc: &Unicorn<()>, pc: u64) -> bool {
+    let mut instr = [0; 4];
+    if uc.mem_read(pc.wrapping_sub(4), &mut instr).is_err() {
+        return false;
+    }
+    // 16-bit WFI is 0xBF30. 32-bit WFI.W is 0xF3AF 0x8003.
+    instr[2..] == [0x30, 0xBF] || instr == [0xAF, 0xF3, 0x03, 0x80]
+}
+
+/// Brings the CPU in its reset state. Returns the PC to start from.
+fn reset_cpu(uc: &mut Unicorn<()>, vector_table_addr: u32) -> Result<u64> {
+    let vector_table = VectorTable::from_memory(uc, vector_table_addr)?;
+
+    for reg in [
+        RegisterARM::R0, RegisterARM::R1, RegisterARM::R2, RegisterARM::R3,
+        RegisterARM::R4, RegisterARM::R5, RegisterARM::R6, RegisterARM::R7,
+        RegisterARM::R8, RegisterARM::R9, RegisterARM::R10, RegisterARM::R11,
+        RegisterARM::R12, RegisterARM::PSP, RegisterARM::CONTROL,
+        RegisterARM::PRIMASK, RegisterARM::BASEPRI, RegisterARM::FAULTMASK,
+    ] {
+        uc.reg_write(reg, 0).map_err(UniErr)?;
+    }
+    uc.reg_write(RegisterARM::LR, 0xFFFF_FFFF).map_err(UniErr)?;
+    uc.reg_write(RegisterARM::SP, vector_table.sp.into()).map_err(UniErr)?;
+
+    Ok(vector_table.reset as u64)
+}
+
+/// An emulated system running a firmware. The binary runs one until the end,
+/// test harnesses can run it step by step and look at the memory in between.
+pub struct Emulator {
+    uc: Unicorn<'static, ()>,
+    // Dropped after uc
+    _memory: SharedMemory,
+    ctx: Arc<Context>,
+    peripherals: Rc<Peripherals>,
+    ext_devices: Rc<ExtDevices>,
+    framebuffers: Framebuffers,
+    firmware: FirmwareImages,
+    args: Args,
+    vector_table_addr: u32,
+    stop_addr: Option<u32>,
+    rtos: Option<Rtos>,
+    memcheck: Option<Rc<RefCell<MemCheck>>>,
+    profiler: Option<Rc<RefCell<Profiler>>>,
+    stats: Option<Rc<RefCell<Stats>>>,
+    cosim: Option<Cosim>,
+    control: Control,
+    pc: u64,
+    num_resets: u32,
+}
+
+impl Emulator {
+    pub fn new(config: Config, args: Args) -> Result<Self> {
+        Self::with_devices(config, args, CustomDevices::default())
+    }
+
+    /// With external devices implemented outside of the emulator
+    pub fn with_devices(mut config: Config, args: Args, devices: CustomDevices) -> Result<Self> {
+        let svd_device = svd_parser::parse(&read_file_str(&config.cpu.svd)?)
+            .with_context(|| format!("Failed to parse {}", config.cpu.svd))?;
+        let ctx = Arc::new(Context::new(crate::symbols::load(&config)?));
+        crate::context::enter(&ctx);
+
+        let cpu = CpuModel::from_config(config.cpu.model.as_deref(), &svd_device)?;
+        let mut uc: Unicorn<'static, ()> = Unicorn::new(Arch::ARM, Mode::LITTLE_ENDIAN)
+            .map_err(UniErr).context("Failed to initialize Unicorn instance")?;
+        cpu.configure_unicorn(&mut uc).context("Failed to set the Unicorn CPU model")?;
+
+        let vector_table_addr = config.cpu.vector_table;
+        let mpu_enforce = config.cpu.mpu.unwrap_or_default();
+        let rtos = config.rtos.take().map(Rtos::new);
+        let memcheck = config.memcheck.take();
+        let stubs = config.stubs.take().unwrap_or_default();
+        let watches = config.watch_values.take().map(Watches::new).transpose()?;
+        let expectations = config.expect.take().map(Expectations::new).transpose()?;
+        let exit = config.exit.take().unwrap_or_default();
+        let cosim_config = config.cosim.take().unwrap_or_default();
+        let semihosting = exit.semihosting.unwrap_or_default();
+        let stop_addr = args.stop_addr.as_ref().or(config.stop_at.as_ref())
+            .map(|s| crate::symbols::symbols().parse_addr(s))
+            .transpose()?
+            .map(|addr| addr & !1);
+
+        let bootloader = config.system_memory.as_ref()
+            .filter(|s| s.enabled.unwrap_or(true))
+            .and_then(|s| s.bootloader.clone());
+        let device_name = svd_device.name.clone();
+
+        let mut timing = InstructionTiming::from_config(&config);
+
+        let (sys, framebuffers, firmware, memory) = crate::system::prepare(&mut uc, config, svd_device, cpu, devices)?;
+        let peripherals = sys.p.clone();
+        let ext_devices = sys.d.clone();
+        if let Some(max) = args.irq_jitter {
+            let seed = args.irq_jitter_seed.unwrap_or_else(|| {
+                std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+            });
+            info!("Interrupt jitter max_cycles={} seed={}", max, seed);
+            peripherals.nvic.borrow_mut().jitter = Some(IrqJitter::new(max, seed));
+        }
+        for irq in &args.catch_exceptions {
+            peripherals.nvic.borrow_mut().catch_exception(*irq);
+        }
+        *peripherals.recorder.borrow_mut() = args.flight_recorder.map(FlightRecorder::new);
+        if let Some(ref expectations) = expectations {
+            expectations.install(&peripherals);
+        }
+        *peripherals.expect.borrow_mut() = expectations;
+        *peripherals.trace.borrow_mut() = args.record_trace.as_deref().map(TraceWriter::new).transpose()?;
+
+        let diassembler = Capstone::new()
+            .arm()
+            .mode(arch::arm::ArchMode::Thumb)
+            .build()
+            .expect("failed to initialize capstone");
+
+        let stats = (args.stats || args.heartbeat.is_some())
+            .then(|| Rc::new(RefCell::new(Stats::new(args.heartbeat))));
+
+        // We hook on each instructions, but we could skip this.
+        // The slowdown is less than 50%. It's okay for now.
+        {
+            let trace_instructions = crate::verbose() >= 4;
+            let busy_loop_stop = args.busy_loop_stop;
+            let flight_recorder = args.flight_recorder.is_some();
+            let record_trace = args.record_trace.is_some();
+            let stats = stats.clone();
+            let p = sys.p.clone();
+            let d = sys.d.clone();
+            let ctx = ctx.clone();
+            let interrupt_period = args.interrupt_period;
+            let mut idle_loop_detector = args.fast_forward_idle.then(IdleLoopDetector::default);
+            // num_instructions advances by the cycles of each instruction, and
+            // jumps on idle. The periodic work is based on num_executed instead.
+            let start_time = Instant::now();
+            let max_seconds = args.max_seconds.map(Duration::from_secs_f64);
+            let max_emulated_time = args.max_emulated_ms.map(Duration::from_millis);
+            let mut emulated_clock = EmulatedClock::default();
+            #[cfg(feature = "sdl")]
+            let sdls = framebuffers.sdls.clone();
+            let vncs = framebuffers.vncs.clone();
+            sys.uc.borrow_mut().add_code_hook(0, u64::MAX, move |uc, pc, size| {
+                if busy_loop_stop && ctx.last_instruction().0 == pc as u32 {
                     info!("Busy loop reached");
+                    if let Some((pc, addr, value)) = p.poll.borrow().last_read() {
+                        info!("Last peripheral read pc=0x{:08x} {} value=0x{:08x}", pc, p.addr_desc(addr), value);
+                    }
                     uc.emu_stop().unwrap();
-                    BUSY_LOOP_REACHED.store(true, Ordering::Release);
+                    ctx.busy_loop_reached.store(true, Ordering::Release);
                 }
-                LAST_INSTRUCTION = (pc as u32, size as u8);
-            }
-
-            let n = NUM_INSTRUCTIONS.fetch_add(1, Ordering::Acquire);
+                if busy_loop_stop && std::mem::take(&mut p.poll.borrow_mut().busy_loop_reached) {
+                    uc.emu_stop().unwrap();
+                    ctx.busy_loop_reached.store(true, Ordering::Release);
+                }
+                ctx.set_last_instruction(pc as u32, size as u8);
 
-            if trace_instructions {
-                info!("{}", disassemble_instruction(&diassembler, uc, pc));
-            }
+                if flight_recorder {
+                    if let Some(ref mut recorder) = *p.recorder.borrow_mut() {
+                        recorder.instruction(pc as u32);
+                    }
+                }
+                if record_trace {
+                    if let Some(ref mut trace) = *p.trace.borrow_mut() {
+                        trace.instruction(pc as u32, size as u8);
+                    }
+                }
 
-            if n % interrupt_period as u64 == 0 {
-                let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
-                p.nvic.borrow_mut().run_pending_interrupts(&sys, vector_table_addr);
-            }
+                let n = ctx.num_instructions.fetch_add(timing.cycles(pc as u32) as u64, Ordering::Acquire);
+                let num_executed = ctx.num_executed.fetch_add(1, Ordering::Relaxed) + 1;
 
-            if n & PUMP_EVENT_INST_INTERVAL == 0 {
-                for fb in &framebuffers.sdls {
-                    fb.borrow_mut().maybe_redraw();
+                if n >= ctx.step_end.load(Ordering::Relaxed) {
+                    uc.emu_stop().unwrap();
                 }
-                if !SDL.lock().unwrap().pump_events(&framebuffers.sdls) {
-                    STOP_REQUESTED.store(true, Ordering::Relaxed);
+
+                if ctx.request_pending.load(Ordering::Relaxed) {
                     uc.emu_stop().unwrap();
                 }
-            }
-        }).expect("add_code_hook failed");
-    }
-
-    {
-        let p = sys.p.clone();
-        let d = sys.d.clone();
-        sys.uc.borrow_mut().add_intr_hook(move |uc, exception| {
-            match exception {
-                /*
-                    EXCP_UDEF            1   /* undefined instruction */
-                    EXCP_SWI             2   /* software interrupt */
-                    EXCP_PREFETCH_ABORT  3
-                    EXCP_DATA_ABORT      4
-                    EXCP_IRQ             5
-                    EXCP_FIQ             6
-                    EXCP_BKPT            7
-                    EXCP_EXCEPTION_EXIT  8   /* Return from v7M exception.  */
-                    EXCP_KERNEL_TRAP     9   /* Jumped to kernel code page.  */
-                    EXCP_HVC            11   /* HyperVisor Call */
-                    EXCP_HYP_TRAP       12
-                    EXCP_SMC            13   /* Secure Monitor Call */
-                    EXCP_VIRQ           14
-                    EXCP_VFIQ           15
-                    EXCP_SEMIHOST       16   /* semihosting call */
-                    EXCP_NOCP           17   /* v7M NOCP UsageFault */
-                    EXCP_INVSTATE       18   /* v7M INVSTATE UsageFault */
-                    EXCP_STKOF          19   /* v8M STKOF UsageFault */
-                    EXCP_LAZYFP         20   /* v7M fault during lazy FP stacking */
-                    EXCP_LSERR          21   /* v8M LSERR SecureFault */
-                    EXCP_UNALIGNED      22   /* v7M UNALIGNED UsageFault */
-                    */
-                8 => {
-                    // Return from interrupt
+
+                if trace_instructions {
+                    info!("{}", disassemble_instruction(&diassembler, uc, pc));
+                }
+
+                if let Some(ref watches) = watches {
+                    watches.poll(uc, num_executed);
+                }
+
+                if mpu_enforce && p.mpu.borrow().is_enabled() {
+                    let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
+                    if !Mpu::enforce(&sys, pc as u32, vector_table_addr) {
+                        ctx.lockup.store(true, Ordering::Release);
+                        sys.uc.borrow_mut().emu_stop().unwrap();
+                    }
+                }
+
+                if let Some(ref mut detector) = idle_loop_detector {
+                    if detector.on_instruction(uc, pc as u32) {
+                        let wakeup = [p.nvic.borrow().next_wakeup(), p.next_tick(), crate::cosim::step_end(&ctx)].into_iter().flatten().min();
+                        if let Some(wakeup) = wakeup.filter(|w| *w > n) {
+                            trace!("Idle loop, skipping num_instructions={}", wakeup - n);
+                            ctx.num_instructions.store(wakeup, Ordering::Relaxed);
+                        }
+                    }
+                }
+
+                if num_executed.is_multiple_of(interrupt_period as u64) {
                     let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
-                    p.nvic.borrow_mut().return_from_interrupt(&sys);
+                    p.tick(&sys);
+                    Comparators::poll(&p);
+                    d.update_thermals(&sys);
+                    d.update_hd44780s(false);
                     p.nvic.borrow_mut().run_pending_interrupts(&sys, vector_table_addr);
                 }
-                3 => {
-                    error!("intr_hook intno={:08x}", exception);
+
+                if num_executed & (TIME_LIMIT_CHECK_INTERVAL - 1) == 0 {
+                    let elapsed = start_time.elapsed();
+                    let emulated_time = emulated_clock.update(n, p.rcc.borrow().hclk());
+                    if max_seconds.is_some_and(|max| elapsed >= max) ||
+                       max_emulated_time.is_some_and(|max| emulated_time >= max) {
+                        info!("Time limit reached elapsed_secs={:.3} emulated_ms={}", elapsed.as_secs_f64(), emulated_time.as_millis());
+                        ctx.time_limit_reached.store(true, Ordering::Release);
+                        uc.emu_stop().unwrap();
+                    }
+                    if let Some(ref stats) = stats {
+                        stats.borrow_mut().update(&p, num_executed, emulated_time);
+                    }
                 }
-                _ => {
-                    error!("intr_hook intno={:08x}", exception);
-                    std::process::exit(1);
+
+                if num_executed & PUMP_EVENT_INST_INTERVAL == 0 {
+                    for fb in &vncs {
+                        fb.borrow_mut().maybe_update();
+                    }
+                    #[cfg(feature = "sdl")]
+                    {
+                        for fb in &sdls {
+                            fb.borrow_mut().maybe_redraw(&p);
+                        }
+                        if !SDL.lock().unwrap().pump_events(&sdls, &p) {
+                            ctx.stop_requested.store(true, Ordering::Relaxed);
+                            uc.emu_stop().unwrap();
+                        }
+                    }
+                    if ctx.reload_requested.load(Ordering::Acquire) {
+                        uc.emu_stop().unwrap();
+                    }
                 }
-            }
-        }).expect("add_intr_hook failed");
-    }
+            }).expect("add_code_hook failed");
+        }
 
-    uc.add_mem_hook(HookType::MEM_UNMAPPED, 0, u64::MAX, |uc, type_, addr, size, value| {
-        if type_ == MemType::WRITE_UNMAPPED {
-            warn!("{:?} addr=0x{:08x} size={} value=0x{:08x}", type_, addr, size, value);
-        } else {
-            warn!("{:?} addr=0x{:08x} size={}", type_, addr, size);
+        {
+            let p = sys.p.clone();
+            let d = sys.d.clone();
+            let ctx = ctx.clone();
+            sys.uc.borrow_mut().add_intr_hook(move |uc, exception| {
+                match exception {
+                    /*
+                        EXCP_UDEF            1   /* undefined instruction */
+                        EXCP_SWI             2   /* software interrupt */
+                        EXCP_PREFETCH_ABORT  3
+                        EXCP_DATA_ABORT      4
+                        EXCP_IRQ             5
+                        EXCP_FIQ             6
+                        EXCP_BKPT            7
+                        EXCP_EXCEPTION_EXIT  8   /* Return from v7M exception.  */
+                        EXCP_KERNEL_TRAP     9   /* Jumped to kernel code page.  */
+                        EXCP_HVC            11   /* HyperVisor Call */
+                        EXCP_HYP_TRAP       12
+                        EXCP_SMC            13   /* Secure Monitor Call */
+                        EXCP_VIRQ           14
+                        EXCP_VFIQ           15
+                        EXCP_SEMIHOST       16   /* semihosting call */
+                        EXCP_NOCP           17   /* v7M NOCP UsageFault */
+                        EXCP_INVSTATE       18   /* v7M INVSTATE UsageFault */
+                        EXCP_STKOF          19   /* v8M STKOF UsageFault */
+                        EXCP_LAZYFP         20   /* v7M fault during lazy FP stacking */
+                        EXCP_LSERR          21   /* v8M LSERR SecureFault */
+                        EXCP_UNALIGNED      22   /* v7M UNALIGNED UsageFault */
+                        */
+                    8 => {
+                        // Return from interrupt
+                        let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
+                        p.nvic.borrow_mut().return_from_interrupt(&sys, vector_table_addr);
+                    }
+                    2 => {
+                        // SVC. The PC is already past the instruction.
+                        let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
+                        p.nvic.borrow_mut().run_interrupt(&sys, vector_table_addr, irq::SVCALL);
+                    }
+                    3 | 4 if ctx.continue_execution.load(Ordering::Acquire) => {
+                        // Bad memory access, already handled by the unmapped memory hook.
+                    }
+                    7 if semihosting && crate::exit::semihosting_call(uc) => {
+                        // BKPT 0xAB, handled by the host
+                    }
+                    1 | 3 | 4 | 7 | 17 | 18 | 22 => {
+                        let fault = match exception {
+                            1 => Fault::UndefinedInstruction,
+                            3 => Fault::InstructionBusError,
+                            4 => Fault::DataBusError(None),
+                            7 => Fault::Breakpoint,
+                            17 => Fault::NoCoprocessor,
+                            18 => Fault::InvalidState,
+                            _ => Fault::Unaligned,
+                        };
+                        let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
+                        if !p.nvic.borrow_mut().raise_fault(&sys, vector_table_addr, fault) {
+                            ctx.lockup.store(true, Ordering::Release);
+                            sys.uc.borrow_mut().emu_stop().unwrap();
+                        }
+                    }
+                    _ => {
+                        error!("intr_hook intno={:08x}", exception);
+                        ctx.lockup.store(true, Ordering::Release);
+                        uc.emu_stop().unwrap();
+                    }
+                }
+            }).expect("add_intr_hook failed");
         }
 
-        unsafe {
-            let pc = uc.reg_read(RegisterARM::PC).expect("failed to get pc");
-            assert!(pc as u32 == LAST_INSTRUCTION.0);
-            uc.reg_write(RegisterARM::PC, thumb(pc as u64 + LAST_INSTRUCTION.1 as u64)).unwrap();
+        if mpu_enforce {
+            let p = sys.p.clone();
+            let ctx = ctx.clone();
+            sys.uc.borrow_mut().add_mem_hook(HookType::MEM_READ | HookType::MEM_WRITE, 0, u64::MAX, move |uc, type_, addr, size, _value| {
+                let mut mpu = p.mpu.borrow_mut();
+                if mpu.pending_violation.is_some() {
+                    return true;
+                }
+
+                let access = if type_ == MemType::WRITE { Access::Write } else { Access::Read };
+                if !mpu.check(uc, addr as u32, access) {
+                    // The access goes through regardless. We undo writes when delivering the fault.
+                    let old_data = (access == Access::Write).then(|| {
+                        let mut data = vec![0; size];
+                        uc.mem_read(addr, &mut data).ok().map(|_| data)
+                    }).flatten();
+                    let pc = ctx.last_instruction().0;
+                    trace!("MPU violation access={:?} addr=0x{:08x} size={}", access, addr, size);
+                    mpu.pending_violation = Some(Violation { pc, addr: addr as u32, old_data });
+                }
+                true
+            }).expect("add_mem_hook failed");
+        }
+
+        let memcheck = memcheck.map(|c| MemCheck::install(c, &mut uc)).transpose()?;
+        crate::stubs::install(&mut uc, &stubs)?;
+        crate::calls::install_breakpoints(&mut uc, &args.break_at_symbol)?;
+        if let Some(ref glob) = args.trace_calls {
+            crate::calls::install_call_tracing(&mut uc, glob)?;
+        }
+        crate::exit::install(&mut uc, &exit)?;
+        if let Some(bootloader) = bootloader {
+            crate::bootloader::install(&mut uc, bootloader, &device_name, peripherals.clone(), ext_devices.clone())?;
         }
+        let profiler = (args.profile || args.profile_callgrind.is_some())
+            .then(|| Profiler::install(&mut uc));
+
+        let unmapped_access = args.unmapped;
+        let (p, d, hook_ctx) = (peripherals.clone(), ext_devices.clone(), ctx.clone());
+        uc.add_mem_hook(HookType::MEM_UNMAPPED, 0, u64::MAX, move |uc, type_, addr, size, value| {
+            match unmapped_access {
+                UnmappedAccess::Skip => {}
+                UnmappedAccess::Fault => {
+                    warn!("Bus error {}", unmapped_access_report(uc, type_, addr, size));
+                    let fault = if type_ == MemType::FETCH_UNMAPPED {
+                        Fault::InstructionBusError
+                    } else {
+                        Fault::DataBusError(Some(addr as u32))
+                    };
+                    let sys = System { uc: RefCell::new(uc), p: p.clone(), d: d.clone() };
+                    if !p.nvic.borrow_mut().raise_fault(&sys, vector_table_addr, fault) {
+                        hook_ctx.lockup.store(true, Ordering::Release);
+                    }
+                    // So the interrupt hook doesn't raise another fault
+                    hook_ctx.continue_execution.store(true, Ordering::Release);
+                    return false;
+                }
+                UnmappedAccess::Stop => {
+                    error!("Unmapped memory access {}", unmapped_access_report(uc, type_, addr, size));
+                    hook_ctx.lockup.store(true, Ordering::Release);
+                    hook_ctx.continue_execution.store(true, Ordering::Release);
+                    return false;
+                }
+            }
 
-        CONTINUE_EXECUTION.store(true, Ordering::Release);
+            if type_ == MemType::WRITE_UNMAPPED {
+                warn!("{:?} addr=0x{:08x} size={} value=0x{:08x}", type_, addr, size, value);
+            } else {
+                warn!("{:?} addr=0x{:08x} size={}", type_, addr, size);
+            }
 
-        false
-    }).expect("add_mem_hook failed");
+            let (last_pc, last_size) = hook_ctx.last_instruction();
+            let pc = uc.reg_read(RegisterARM::PC).expect("failed to get pc");
+            assert!(pc as u32 == last_pc);
+            uc.reg_write(RegisterARM::PC, thumb(pc + last_size as u64)).unwrap();
 
-    let vector_table = VectorTable::from_memory(&uc, vector_table_addr)?;
-    let mut pc = vector_table.reset as u64;
-    uc.reg_write(RegisterARM::SP, vector_table.sp.into()).map_err(UniErr)?;
-    //uc.reg_write(RegisterARM::LR, 0xFFFF_FFFF).map_err(UniErr)?;
+            hook_ctx.continue_execution.store(true, Ordering::Release);
 
-    info!("Starting emulation");
+            false
+        }).expect("add_mem_hook failed");
 
-    loop {
-        let max_instructions = args.max_instructions.map(|c|
-            // yes, we want to panic if this goes negative.
-            c - NUM_INSTRUCTIONS.load(Ordering::Relaxed)
+        let mut control = Control::new(ctx.clone());
+        if let Some(ref addr) = args.flash_server {
+            FlashServer::spawn(addr, control.handle())?;
+        }
+        if let Some(ref source) = args.console {
+            Console::spawn(source, control.handle())?;
+        }
+        let cosim = args.cosim.as_deref().map(|addr| Cosim::new(addr, cosim_config)).transpose()?;
+
+        let pc = reset_cpu(&mut uc, vector_table_addr)?;
+
+        info!("Starting emulation");
+
+        Ok(Self {
+            uc, _memory: memory, ctx, peripherals, ext_devices, framebuffers, firmware, args, vector_table_addr, stop_addr,
+            rtos, memcheck, profiler, stats, cosim, control, pc, num_resets: 0,
+        })
+    }
+
+    /// Runs the firmware until the emulation needs attention, like a request
+    /// of the console, a reset, or sleeping on WFI. Returns false once the
+    /// emulation is over, then finish() reports how it went.
+    pub fn step(&mut self) -> Result<bool> {
+        crate::context::enter(&self.ctx);
+        let ctx = self.ctx.clone();
+        let max_instructions = self.args.max_instructions.map(|c|
+            c.saturating_sub(ctx.num_executed.load(Ordering::Relaxed))
         );
         if max_instructions == Some(0) {
             info!("Reached target number of instructions. Done");
-            break;
+            return Ok(false);
         }
 
-        let result = uc.emu_start(
-            pc,
-            args.stop_addr.unwrap_or(0) as u64,
+        let stepping = self.control.is_stepping();
+        let result = self.uc.emu_start(
+            self.pc,
+            self.stop_addr.unwrap_or(0) as u64,
             0,
-            max_instructions.unwrap_or(0) as usize,
+            if stepping { 1 } else { max_instructions.unwrap_or(0) as usize },
         ).map_err(UniErr);
-        pc = uc.reg_read(RegisterARM::PC).expect("failed to get pc");
+        self.pc = self.uc.reg_read(RegisterARM::PC).expect("failed to get pc");
 
-        if STOP_REQUESTED.load(Ordering::Relaxed) {
+        if ctx.stop_requested.load(Ordering::Relaxed) {
             info!("Stop requested");
-            break;
+            return Ok(false);
+        }
+
+        if ctx.breakpoint_reached.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+
+        if ctx.exception_caught.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+
+        if ctx.lockup.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+
+        if crate::exit::exit_code(&ctx).is_some() || ctx.time_limit_reached.load(Ordering::Acquire) {
+            return Ok(false);
+        }
+
+        if ctx.request_pending.swap(false, Ordering::AcqRel) || self.control.is_paused() {
+            let sys = System { uc: RefCell::new(&mut self.uc), p: self.peripherals.clone(), d: self.ext_devices.clone() };
+            if self.control.process(&sys, &self.framebuffers) {
+                ctx.reset_requested.store(true, Ordering::Release);
+            } else {
+                // The pc may have been written
+                self.pc = self.uc.reg_read(RegisterARM::PC).expect("failed to get pc");
+                if result.is_ok() {
+                    self.pc = thumb(self.pc);
+                    return Ok(true);
+                }
+            }
+        }
+
+        if let Some(cosim) = self.cosim.as_mut().filter(|_| Cosim::is_step_done(&ctx)) {
+            let sys = System { uc: RefCell::new(&mut self.uc), p: self.peripherals.clone(), d: self.ext_devices.clone() };
+            if !cosim.step(&sys) {
+                info!("Co-simulation over");
+                return Ok(false);
+            }
+            if result.is_ok() {
+                self.pc = thumb(self.pc);
+                return Ok(true);
+            }
+        }
+
+        if ctx.reload_requested.swap(false, Ordering::AcqRel) {
+            // Like pressing the reset button after flashing. RAM, the backup
+            // domain and the external devices keep their state.
+            ctx.reset_requested.store(false, Ordering::Release);
+            self.firmware.load(&mut self.uc)?;
+            self.num_resets = 0;
+            info!("Firmware reloaded");
+            self.peripherals.reset(&mut self.uc, &self.ext_devices);
+            self.pc = reset_cpu(&mut self.uc, self.vector_table_addr)?;
+            return Ok(true);
+        }
+
+        if ctx.reset_requested.swap(false, Ordering::AcqRel) {
+            if self.num_resets == self.args.max_resets {
+                info!("Reached maximum number of resets. Done");
+                return Ok(false);
+            }
+            self.num_resets += 1;
+            info!("System reset num_resets={}", self.num_resets);
+            self.peripherals.reset(&mut self.uc, &self.ext_devices);
+            self.pc = reset_cpu(&mut self.uc, self.vector_table_addr)?;
+            return Ok(true);
         }
 
         if let Err(e) = result {
-            if CONTINUE_EXECUTION.swap(false, Ordering::AcqRel) {
+            if ctx.continue_execution.swap(false, Ordering::AcqRel) {
                 // This was a bad memory access, we keep going.
                 if crate::verbose() >= 3 {
-                    trace!("Resuming execution pc={:08x}", pc);
+                    trace!("Resuming execution pc={:08x}", self.pc);
                 }
-                pc = thumb(pc);
-                continue;
+                self.pc = thumb(self.pc);
+                return Ok(true);
             } else {
+                dump_flight_recorder(&self.peripherals, self.args.log_format);
+                flush_trace(&self.peripherals)?;
                 bail!(e);
             }
         }
 
-        if args.stop_addr == Some(pc as u32) {
+        if self.stop_addr == Some(self.pc as u32) {
             info!("Stop address reached, stopping");
-            break;
+            return Ok(false);
         }
 
-        if BUSY_LOOP_REACHED.load(Ordering::Relaxed) {
-            break;
+        if ctx.busy_loop_reached.load(Ordering::Relaxed) {
+            return Ok(false);
         }
+
+        if is_after_wfi(&self.uc, self.pc) {
+            let sys = System { uc: RefCell::new(&mut self.uc), p: self.peripherals.clone(), d: self.ext_devices.clone() };
+            if !Pwr::wait_for_interrupt(&sys) {
+                return Ok(false);
+            }
+            self.pc = thumb(self.pc);
+        }
+
+        Ok(true)
     }
 
-    if let Some(n) = args.dump_stack {
-        dump_stack(&mut uc, n);
+    /// Runs the firmware for this many CPU cycles, or less when the emulation
+    /// ends before. Returns false once the emulation is over.
+    pub fn run_for(&mut self, num_cycles: u64) -> Result<bool> {
+        if self.cosim.is_some() {
+            bail!("The co-simulation drives the emulated time");
+        }
+        let end = self.num_cycles() + num_cycles;
+        self.ctx.step_end.store(end, Ordering::Relaxed);
+        let mut running = true;
+        while running && self.num_cycles() < end {
+            running = self.step()?;
+        }
+        self.ctx.step_end.store(u64::MAX, Ordering::Relaxed);
+        Ok(running)
     }
 
-    for fb in framebuffers.images {
-        fb.borrow().write_to_disk()?;
+    /// Runs the firmware until the end of the emulation
+    pub fn run(mut self) -> Result<()> {
+        while self.step()? {}
+        self.finish()
     }
 
-    Ok(())
+    /// Controls the emulation from other threads, while it runs
+    pub fn handle(&mut self) -> EmulatorHandle {
+        self.control.handle()
+    }
+
+    /// The emulated time, in CPU cycles
+    pub fn num_cycles(&self) -> u64 {
+        self.ctx.num_instructions.load(Ordering::Relaxed)
+    }
+
+    /// The exit code given by the firmware, once it exited
+    pub fn exit_code(&self) -> Option<i32> {
+        crate::exit::exit_code(&self.ctx)
+    }
+
+    /// The address of a symbol of the firmware. Can be `symbol+offset`.
+    pub fn symbol(&self, name: &str) -> Result<u32> {
+        self.ctx.symbols.parse_addr(name)
+    }
+
+    pub fn read_mem(&self, addr: u32, buf: &mut [u8]) -> Result<()> {
+        self.uc.mem_read(addr as u64, buf).map_err(UniErr)
+            .with_context(|| format!("Failed to read addr=0x{:08x} size={}", addr, buf.len()))
+    }
+
+    pub fn write_mem(&mut self, addr: u32, data: &[u8]) -> Result<()> {
+        self.uc.mem_write(addr as u64, data).map_err(UniErr)
+            .with_context(|| format!("Failed to write addr=0x{:08x} size={}", addr, data.len()))
+    }
+
+    pub fn reg(&self, reg: RegisterARM) -> Result<u32> {
+        Ok(self.uc.reg_read(reg).map_err(UniErr)? as u32)
+    }
+
+    /// Reports the results of the emulation: the profile, the stats, the
+    /// expectations, and writes back the persistent memories. Fails when the
+    /// CPU locked up, an expectation isn't met, or a time limit was reached.
+    pub fn finish(mut self) -> Result<()> {
+        crate::context::enter(&self.ctx);
+        let ctx = self.ctx.clone();
+        if let Some(n) = self.args.dump_stack {
+            dump_stack(&mut self.uc, n);
+        }
+
+        if let Some(ref profiler) = self.profiler {
+            profiler.borrow().report(self.args.profile_callgrind.as_deref())?;
+        }
+
+        if let Some(ref memcheck) = self.memcheck {
+            memcheck.borrow().report();
+        }
+
+        if let Some(ref stats) = self.stats {
+            stats.borrow_mut().summary(&self.peripherals);
+        }
+
+        if let Some(ref rtos) = self.rtos {
+            rtos.print_tasks(&self.uc);
+        }
+
+        self.ext_devices.update_hd44780s(true);
+        for fb in self.framebuffers.images {
+            fb.borrow().write_to_disk()?;
+        }
+
+        // Persistent memories lose their last writes when the firmware crashed or hung
+        let caught = ctx.exception_caught.load(Ordering::Acquire);
+        let clean_exit = !ctx.lockup.load(Ordering::Acquire) && !ctx.time_limit_reached.load(Ordering::Acquire) && !caught;
+        self.peripherals.backup.borrow().save(clean_exit)?;
+        self.ext_devices.save(clean_exit)?;
+        flush_trace(&self.peripherals)?;
+
+        // Listed even when the firmware crashed
+        let expectations = self.peripherals.expect.borrow().as_ref().map_or(Ok(()), |e| e.check());
+
+        if ctx.lockup.load(Ordering::Acquire) {
+            dump_flight_recorder(&self.peripherals, self.args.log_format);
+            bail!("CPU locked up");
+        }
+
+        if caught {
+            dump_flight_recorder(&self.peripherals, self.args.log_format);
+            bail!("Exception caught");
+        }
+
+        expectations?;
+
+        if ctx.time_limit_reached.load(Ordering::Acquire) {
+            bail!("Time limit reached");
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,150 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::sync::atomic::Ordering;
+
+use serde::Deserialize;
+use unicorn_engine::{Unicorn, RegisterARM, unicorn_const::HookType};
+use anyhow::Result;
+
+use crate::{context::Context, symbols::symbols, util::UniErr};
+
+// The firmware can end the emulation with an exit code, which becomes the
+// exit code of the emulator. That makes the emulator usable as a test runner:
+//
+//  exit:
+//    addr: 0x2001FFF0        # writing a word here exits with that code, 0 is a pass
+//    semihosting: true       # SYS_EXIT and SYS_EXIT_EXTENDED, and the console output calls
+//    panic: rust_begin_unwind  # entering this function prints the panic message, and exits with 1
+//    panic_message: cstr     # r0 points to a C string (cstr, default), or r0/r1 are pointer/length (ptr_len)
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ExitConfig {
+    pub addr: Option<u32>,
+    pub semihosting: Option<bool>,
+    /// Address or symbol of the panic handler. Can be `symbol+offset`.
+    pub panic: Option<String>,
+    pub panic_message: Option<PanicMessage>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum PanicMessage {
+    #[default]
+    Cstr,
+    PtrLen,
+}
+
+// Semihosting operations, in r0
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+// Reason of SYS_EXIT for a normal exit. Anything else is a failure.
+const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+// BKPT 0xAB
+const SEMIHOSTING_BKPT: u16 = 0xBEAB;
+
+const MAX_PANIC_MESSAGE_LEN: usize = 1024;
+
+/// The exit code given by the firmware, once it exited
+pub fn exit_code(ctx: &Context) -> Option<i32> {
+    ctx.exited.load(Ordering::Acquire).then(|| ctx.exit_code.load(Ordering::Relaxed))
+}
+
+pub fn exit(uc: &mut Unicorn<()>, code: i32) {
+    info!("Firmware exit code={}", code);
+    let ctx = crate::context::current();
+    ctx.exit_code.store(code, Ordering::Relaxed);
+    ctx.exited.store(true, Ordering::Release);
+    uc.emu_stop().unwrap();
+}
+
+fn read_string(uc: &Unicorn<()>, addr: u32, len: Option<usize>) -> String {
+    let mut data = vec![0; len.unwrap_or(MAX_PANIC_MESSAGE_LEN).min(MAX_PANIC_MESSAGE_LEN)];
+    // The string may end before the end of the memory region
+    while !data.is_empty() && uc.mem_read(addr as u64, &mut data).is_err() {
+        data.truncate(data.len() / 2);
+    }
+    if len.is_none() {
+        let end = data.iter().position(|c| *c == 0).unwrap_or(data.len());
+        data.truncate(end);
+    }
+    String::from_utf8_lossy(&data).into_owned()
+}
+
+pub fn install(uc: &mut Unicorn<()>, config: &ExitConfig) -> Result<()> {
+    if let Some(addr) = config.addr {
+        debug!("Exit address addr=0x{:08x}", addr);
+        uc.add_mem_hook(HookType::MEM_WRITE, addr as u64, addr as u64 + 3, move |uc, _type, _addr, _size, value| {
+            exit(uc, value as i32);
+            true
+        }).map_err(UniErr)?;
+    }
+
+    if let Some(ref panic) = config.panic {
+        // Function addresses may come with the thumb bit
+        let addr = symbols().parse_addr(panic)? & !1;
+        let panic_message = config.panic_message.unwrap_or_default();
+        debug!("Panic handler addr=0x{:08x}", addr);
+        uc.add_code_hook(addr as u64, addr as u64, move |uc, _pc, _size| {
+            let ptr = uc.reg_read(RegisterARM::R0).unwrap() as u32;
+            let len = match panic_message {
+                PanicMessage::Cstr => None,
+                PanicMessage::PtrLen => Some(uc.reg_read(RegisterARM::R1).unwrap() as usize),
+            };
+            error!("Firmware panic msg={:?}", read_string(uc, ptr, len));
+            exit(uc, 1);
+        }).map_err(UniErr)?;
+    }
+
+    Ok(())
+}
+
+/// Handles a BKPT 0xAB semihosting call. Returns false when the breakpoint
+/// isn't one, and must be delivered to the firmware.
+pub fn semihosting_call(uc: &mut Unicorn<()>) -> bool {
+    let pc = uc.reg_read(RegisterARM::PC).unwrap() as u32 & !1;
+    let mut instr = [0; 2];
+    if uc.mem_read(pc as u64, &mut instr).is_err() || u16::from_le_bytes(instr) != SEMIHOSTING_BKPT {
+        return false;
+    }
+
+    let op = uc.reg_read(RegisterARM::R0).unwrap() as u32;
+    let arg = uc.reg_read(RegisterARM::R1).unwrap() as u32;
+    let read_word = |uc: &Unicorn<()>, addr: u32| {
+        let mut v = [0; 4];
+        uc.mem_read(addr as u64, &mut v).map(|_| u32::from_le_bytes(v)).unwrap_or_default()
+    };
+
+    let ret = match op {
+        SYS_WRITEC => {
+            let mut c = [0];
+            let _ = uc.mem_read(arg as u64, &mut c);
+            print!("{}", c[0] as char);
+            0
+        }
+        SYS_WRITE0 => {
+            print!("{}", read_string(uc, arg, None));
+            0
+        }
+        SYS_EXIT => {
+            // On 32-bit targets, the reason is given directly. There's no exit code.
+            exit(uc, (arg != ADP_STOPPED_APPLICATION_EXIT) as i32);
+            0
+        }
+        SYS_EXIT_EXTENDED => {
+            let (reason, subcode) = (read_word(uc, arg), read_word(uc, arg + 4));
+            let code = if reason == ADP_STOPPED_APPLICATION_EXIT { subcode as i32 } else { 1 };
+            exit(uc, code);
+            0
+        }
+        _ => {
+            warn!("Unsupported semihosting call op=0x{:02x}", op);
+            u32::MAX
+        }
+    };
+
+    uc.reg_write(RegisterARM::R0, ret as u64).unwrap();
+    uc.reg_write(RegisterARM::PC, (pc + 2) as u64 | 1).unwrap();
+    true
+}
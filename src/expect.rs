@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+
+use serde::Deserialize;
+use anyhow::{Context, Result, bail};
+
+use crate::peripherals::{Peripherals, gpio::Pin};
+
+// Expectations on the peripheral traffic, checked when the emulation stops,
+// for running firmwares in CI. The emulator fails when one isn't met:
+//
+//  expect:
+//    - spi: SPI2
+//      bytes: [0x9F]       # transmitted, in a row
+//      within: 1000000     # clk, optional
+//    - usart: USART1
+//      contains: "boot ok"
+//    - gpio: PA5
+//      toggles: 10         # at least
+//
+// The clk is the emulated time in CPU cycles, as shown in the logs.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ExpectConfig {
+    pub spi: Option<String>,
+    pub usart: Option<String>,
+    pub gpio: Option<String>,
+    /// For spi and usart
+    pub bytes: Option<Vec<u8>>,
+    /// For usart, like bytes
+    pub contains: Option<String>,
+    /// For gpio
+    pub toggles: Option<u32>,
+    pub within: Option<u64>,
+}
+
+enum Source {
+    Spi(String),
+    Usart(String),
+    Gpio(String),
+}
+
+enum Condition {
+    Bytes { pattern: Vec<u8>, matched: usize },
+    Toggles { min: u32, count: u32, level: Option<bool> },
+}
+
+struct Expectation {
+    desc: String,
+    source: Source,
+    condition: Condition,
+    within: Option<u64>,
+    met_at: Option<u64>,
+}
+
+impl Expectation {
+    fn new(config: ExpectConfig) -> Result<Self> {
+        let source = match (config.spi, config.usart, config.gpio) {
+            (Some(p), None, None) => Source::Spi(p),
+            (None, Some(p), None) => Source::Usart(p),
+            (None, None, Some(pin)) => {
+                Pin::parse(&pin).with_context(|| format!("Invalid expect gpio pin {}", pin))?;
+                Source::Gpio(pin)
+            }
+            _ => bail!("Expectations must have exactly one of spi, usart or gpio"),
+        };
+        let bytes = config.bytes.or(config.contains.map(String::into_bytes));
+        let (condition, what) = match (&source, bytes, config.toggles) {
+            (Source::Spi(_) | Source::Usart(_), Some(pattern), None) if !pattern.is_empty() => {
+                let what = match std::str::from_utf8(&pattern) {
+                    Ok(s) if s.chars().all(|c| !c.is_control()) => format!("{:?}", s),
+                    _ => format!("{:02x?}", pattern),
+                };
+                (Condition::Bytes { pattern, matched: 0 }, format!("transmits {}", what))
+            }
+            (Source::Gpio(_), None, Some(min)) => {
+                (Condition::Toggles { min, count: 0, level: None }, format!("toggles {} times", min))
+            }
+            _ => bail!("Expectations need bytes or contains on spi and usart, and toggles on gpio"),
+        };
+        let name = match &source {
+            Source::Spi(p) | Source::Usart(p) | Source::Gpio(p) => p,
+        };
+        let mut desc = format!("{} {}", name, what);
+        if let Some(within) = config.within {
+            desc += &format!(" within clk={}", within);
+        }
+        Ok(Self { desc, source, condition, within: config.within, met_at: None })
+    }
+
+    fn on_byte(&mut self, v: u8) {
+        if let Condition::Bytes { ref pattern, ref mut matched } = self.condition {
+            // Restarts on a mismatch. Good enough for the patterns we look for.
+            *matched = if pattern[*matched] == v {
+                *matched + 1
+            } else {
+                (pattern[0] == v) as usize
+            };
+            if *matched == pattern.len() {
+                *matched = 0;
+                self.met();
+            }
+        }
+    }
+
+    fn on_level(&mut self, level: bool) {
+        if let Condition::Toggles { min, ref mut count, level: ref mut last } = self.condition {
+            if last.replace(level).is_some_and(|l| l != level) {
+                *count += 1;
+                if *count >= min {
+                    self.met();
+                }
+            }
+        }
+    }
+
+    fn met(&mut self) {
+        if self.met_at.is_none() {
+            let clk = crate::context::now();
+            debug!("Expectation met: {} clk={}", self.desc, clk);
+            self.met_at = Some(clk);
+        }
+    }
+
+    fn passed(&self) -> bool {
+        self.met_at.is_some_and(|at| self.within.is_none_or(|w| at <= w))
+    }
+}
+
+pub struct Expectations {
+    expectations: Vec<Expectation>,
+}
+
+impl Expectations {
+    pub fn new(configs: Vec<ExpectConfig>) -> Result<Self> {
+        let expectations = configs.into_iter().map(Expectation::new).collect::<Result<_>>()?;
+        Ok(Self { expectations })
+    }
+
+    /// Follows the GPIO pins of the expectations
+    pub fn install(&self, p: &Peripherals) {
+        for e in &self.expectations {
+            if let Source::Gpio(ref pin) = e.source {
+                let name = pin.clone();
+                p.gpio.borrow_mut().add_write_callback(Pin::parse(pin).unwrap(), move |sys, level| {
+                    if let Some(ref mut expect) = *sys.p.expect.borrow_mut() {
+                        expect.on_gpio(&name, level);
+                    }
+                });
+            }
+        }
+    }
+
+    pub fn on_spi(&mut self, peri_name: &str, v: u8) {
+        for e in &mut self.expectations {
+            if matches!(e.source, Source::Spi(ref p) if p == peri_name) {
+                e.on_byte(v);
+            }
+        }
+    }
+
+    pub fn on_usart(&mut self, peri_name: &str, v: u8) {
+        for e in &mut self.expectations {
+            if matches!(e.source, Source::Usart(ref p) if p == peri_name) {
+                e.on_byte(v);
+            }
+        }
+    }
+
+    fn on_gpio(&mut self, pin: &str, level: bool) {
+        for e in &mut self.expectations {
+            if matches!(e.source, Source::Gpio(ref p) if p == pin) {
+                e.on_level(level);
+            }
+        }
+    }
+
+    /// Logs the results, and fails when an expectation isn't met
+    pub fn check(&self) -> Result<()> {
+        let mut num_failed = 0;
+        for e in &self.expectations {
+            if e.passed() {
+                info!("Expectation passed: {} clk={}", e.desc, e.met_at.unwrap());
+            } else {
+                match e.met_at {
+                    Some(at) => error!("Expectation failed: {} (met at clk={})", e.desc, at),
+                    None => error!("Expectation failed: {}", e.desc),
+                }
+                num_failed += 1;
+            }
+        }
+        if num_failed > 0 {
+            bail!("num_failed={} of num_expectations={} expectations", num_failed, self.expectations.len());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,55 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::VecDeque, sync::mpsc::{channel, Sender, Receiver}};
+
+use anyhow::{Context, Result};
+
+/// Emulation side of a device worker running on a background thread. Slow
+/// host I/O (sockets, files) happens on that thread so it doesn't stall the
+/// instruction execution. Data goes through queues in both directions, and
+/// the emulation side never blocks.
+pub struct Background<T, R> {
+    tx: Sender<T>,
+    rx: Receiver<R>,
+    received: VecDeque<R>,
+}
+
+impl<T: Send + 'static, R: Send + 'static> Background<T, R> {
+    /// The worker gets the data sent by the emulation, and a sender to reply.
+    /// It should return when the receiver is closed.
+    pub fn spawn(name: &str, worker: impl FnOnce(Receiver<T>, Sender<R>) + Send + 'static) -> Result<Self> {
+        let (tx, worker_rx) = channel();
+        let (worker_tx, rx) = channel();
+
+        std::thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || worker(worker_rx, worker_tx))
+            .with_context(|| format!("Failed to spawn thread for {}", name))?;
+
+        Ok(Self { tx, rx, received: VecDeque::new() })
+    }
+
+    pub fn send(&self, v: T) {
+        // The worker may have exited on an I/O error. It has logged it already.
+        let _ = self.tx.send(v);
+    }
+
+    fn poll(&mut self) {
+        self.received.extend(self.rx.try_iter());
+    }
+
+    pub fn has_data(&mut self) -> bool {
+        self.poll();
+        !self.received.is_empty()
+    }
+
+    pub fn try_recv(&mut self) -> Option<R> {
+        self.poll();
+        self.received.pop_front()
+    }
+
+    /// Blocks until the worker sends something. Returns None when the worker is gone.
+    pub fn recv(&mut self) -> Option<R> {
+        self.received.pop_front().or_else(|| self.rx.recv().ok())
+    }
+}
@@ -0,0 +1,293 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{rc::Rc, cell::RefCell, collections::HashMap};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::framebuffers::{Framebuffer, Framebuffers, RGB565};
+use crate::peripherals::gpio::{GpioPorts, Pin};
+use crate::system::System;
+
+use super::{ExtDevice, I2cDevice, touchscreen::{touch_position, TouchConfig}};
+
+// A capacitive touch controller on I2C, a FocalTech FT6206 or a Goodix GT911,
+// reporting a single touch point from the framebuffer:
+//
+//  cap_touch:
+//    - peripheral: I2C1
+//      framebuffer: Display
+//      model: gt911          # ft6206 (default) or gt911
+//      int: PB7              # active low
+//      swap_x_y: false
+//      flip_x: false
+//      flip_y: false
+//      touches:              # scripted touches, like the touchscreen
+//        - at: 50000000
+//          x: 120
+//          y: 200
+//
+// The FT6206 holds INT low while the panel is touched. The GT911 holds it low
+// while a report is ready, until the firmware clears the status register.
+// Registers written by the firmware, like the GT911 configuration, are kept
+// but have no effect.
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CapTouchModel {
+    #[default]
+    Ft6206,
+    Gt911,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CapTouchConfig {
+    pub peripheral: String,
+    pub framebuffer: String,
+    pub model: Option<CapTouchModel>,
+    /// Defaults to 0x38 for the FT6206, 0x5D for the GT911
+    pub address: Option<u8>,
+    pub int: Option<String>,
+    pub swap_x_y: Option<bool>,
+    pub flip_x: Option<bool>,
+    pub flip_y: Option<bool>,
+    pub touches: Option<Vec<TouchConfig>>,
+}
+
+// FT6206 registers
+const FT_TD_STATUS: u16 = 0x02;
+const FT_P1_XH: u16 = 0x03;
+const FT_P1_XL: u16 = 0x04;
+const FT_P1_YH: u16 = 0x05;
+const FT_P1_YL: u16 = 0x06;
+const FT_P1_WEIGHT: u16 = 0x07;
+const FT_P1_MISC: u16 = 0x08;
+const FT_CIPHER: u16 = 0xA3;
+const FT_FIRMID: u16 = 0xA6;
+const FT_FOCALTECH_ID: u16 = 0xA8;
+// Event flags, in P1_XH
+const FT_EVENT_PRESS_DOWN: u8 = 0 << 6;
+const FT_EVENT_CONTACT: u8 = 2 << 6;
+
+// GT911 registers
+const GT_PRODUCT_ID: u16 = 0x8140;
+const GT_FIRMWARE_VERSION: u16 = 0x8144;
+const GT_X_RESOLUTION: u16 = 0x8146;
+const GT_Y_RESOLUTION: u16 = 0x8148;
+const GT_VENDOR_ID: u16 = 0x814A;
+const GT_STATUS: u16 = 0x814E;
+const GT_POINT1: u16 = 0x814F;
+const GT_POINT1_END: u16 = 0x8157;
+const GT_STATUS_BUFFER_READY: u8 = 1 << 7;
+
+pub struct CapTouch {
+    pub config: CapTouchConfig,
+    name: String,
+    model: CapTouchModel,
+    framebuffer: Rc<RefCell<dyn Framebuffer<RGB565>>>,
+    touches: Vec<TouchConfig>,
+
+    // Written by the firmware
+    regs: HashMap<u16, u8>,
+    addr: u16,
+    addr_bytes_pending: u8,
+
+    /// The touch reported by the current read transaction
+    report: Option<(u16, u16)>,
+    /// The touch of the previous report
+    reported: Option<(u16, u16)>,
+    /// GT911 buffer status, cleared by the firmware
+    ready: bool,
+}
+
+impl CapTouch {
+    pub fn new(config: CapTouchConfig, gpio: &mut GpioPorts, framebuffers: &Framebuffers) -> Result<Rc<RefCell<Self>>> {
+        let framebuffer = framebuffers.get(&config.framebuffer)?;
+        let int_pin = config.int.as_deref()
+            .map(|pin| Pin::parse(pin).with_context(|| format!("Invalid cap_touch int pin {}", pin)))
+            .transpose()?;
+        let touches = config.touches.clone().unwrap_or_default();
+        let model = config.model.unwrap_or_default();
+
+        let self_ = Rc::new(RefCell::new(Self {
+            config,
+            name: "".to_string(), // filled up in connect_periperhal()
+            model,
+            framebuffer,
+            touches,
+            regs: HashMap::new(),
+            addr: 0,
+            addr_bytes_pending: 0,
+            report: None,
+            reported: None,
+            ready: false,
+        }));
+
+        if let Some(pin) = int_pin {
+            let s = self_.clone();
+            gpio.add_read_callback(pin, move |_sys| !s.borrow_mut().int_asserted());
+        }
+
+        Ok(self_)
+    }
+
+    fn addr_bytes(&self) -> u8 {
+        match self.model {
+            CapTouchModel::Ft6206 => 1,
+            CapTouchModel::Gt911 => 2,
+        }
+    }
+
+    /// The touch position, in the orientation of the panel
+    fn touch(&self) -> Option<(u16, u16)> {
+        let (width, height) = {
+            let fb = self.framebuffer.borrow();
+            let c = fb.get_config();
+            (c.width, c.height)
+        };
+        let (x, y) = touch_position(&self.framebuffer, &self.touches)?;
+        let (x, y, width, height) = if self.config.swap_x_y == Some(true) {
+            (y, x, height, width)
+        } else {
+            (x, y, width, height)
+        };
+        let x = if self.config.flip_x == Some(true) { width.saturating_sub(1) - x } else { x };
+        let y = if self.config.flip_y == Some(true) { height.saturating_sub(1) - y } else { y };
+        Some((x, y))
+    }
+
+    /// The panel resolution, in the orientation of the panel
+    fn resolution(&self) -> (u16, u16) {
+        let fb = self.framebuffer.borrow();
+        let c = fb.get_config();
+        if self.config.swap_x_y == Some(true) { (c.height, c.width) } else { (c.width, c.height) }
+    }
+
+    fn update_ready(&mut self) {
+        // The GT911 reports at its scan rate while touched, and once on release
+        let touch = self.touch();
+        if touch.is_some() || touch != self.report {
+            self.ready = true;
+        }
+    }
+
+    fn int_asserted(&mut self) -> bool {
+        match self.model {
+            CapTouchModel::Ft6206 => self.touch().is_some(),
+            CapTouchModel::Gt911 => {
+                self.update_ready();
+                self.ready
+            }
+        }
+    }
+
+    fn ft6206_reg(&self, addr: u16) -> u8 {
+        let (x, y) = self.report.unwrap_or_default();
+        let event = if self.reported.is_some() { FT_EVENT_CONTACT } else { FT_EVENT_PRESS_DOWN };
+        match addr {
+            FT_TD_STATUS => self.report.is_some() as u8,
+            FT_P1_XH => event | ((x >> 8) as u8 & 0x0F),
+            FT_P1_XL => x as u8,
+            // Touch ID 0
+            FT_P1_YH => (y >> 8) as u8 & 0x0F,
+            FT_P1_YL => y as u8,
+            FT_P1_WEIGHT => if self.report.is_some() { 0x40 } else { 0 },
+            FT_P1_MISC => if self.report.is_some() { 0x10 } else { 0 },
+            FT_CIPHER => 0x06,
+            FT_FIRMID => 0x10,
+            FT_FOCALTECH_ID => 0x11,
+            _ => self.regs.get(&addr).cloned().unwrap_or_default(),
+        }
+    }
+
+    fn gt911_reg(&self, addr: u16) -> u8 {
+        let (x, y) = self.report.unwrap_or_default();
+        let (width, height) = self.resolution();
+        let le = |v: u16, offset: u16| v.to_le_bytes()[offset as usize];
+        match addr {
+            GT_PRODUCT_ID..=0x8143 => b"911\0"[(addr - GT_PRODUCT_ID) as usize],
+            GT_FIRMWARE_VERSION => 0x60,
+            0x8145 => 0x10,
+            GT_X_RESOLUTION | 0x8147 => le(width, addr - GT_X_RESOLUTION),
+            GT_Y_RESOLUTION | 0x8149 => le(height, addr - GT_Y_RESOLUTION),
+            GT_VENDOR_ID => 0,
+            GT_STATUS => {
+                if self.ready {
+                    GT_STATUS_BUFFER_READY | self.report.is_some() as u8
+                } else {
+                    0
+                }
+            }
+            GT_POINT1..=GT_POINT1_END if self.report.is_none() => 0,
+            // Track ID 0, X, Y, size, and a reserved byte
+            GT_POINT1..=GT_POINT1_END => match addr - GT_POINT1 {
+                0 => 0,
+                1 | 2 => le(x, addr - GT_POINT1 - 1),
+                3 | 4 => le(y, addr - GT_POINT1 - 3),
+                5 => 0x20,
+                _ => 0,
+            },
+            _ => self.regs.get(&addr).cloned().unwrap_or_default(),
+        }
+    }
+}
+
+impl ExtDevice<(), u8> for CapTouch {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} cap-touch", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        let v = match self.model {
+            CapTouchModel::Ft6206 => self.ft6206_reg(self.addr),
+            CapTouchModel::Gt911 => self.gt911_reg(self.addr),
+        };
+        trace!("{} read addr=0x{:04x} value=0x{:02x}", self.name, self.addr, v);
+        self.addr = self.addr.wrapping_add(1);
+        v
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        if self.addr_bytes_pending > 0 {
+            self.addr = (self.addr << 8) | v as u16;
+            self.addr_bytes_pending -= 1;
+            return;
+        }
+
+        trace!("{} write addr=0x{:04x} value=0x{:02x}", self.name, self.addr, v);
+        if self.model == CapTouchModel::Gt911 && self.addr == GT_STATUS {
+            // The firmware is done with the report
+            self.ready = false;
+        } else {
+            self.regs.insert(self.addr, v);
+        }
+        self.addr = self.addr.wrapping_add(1);
+    }
+}
+
+impl I2cDevice for CapTouch {
+    fn address(&self) -> u8 {
+        self.config.address.unwrap_or(match self.model {
+            CapTouchModel::Ft6206 => 0x38,
+            CapTouchModel::Gt911 => 0x5D,
+        })
+    }
+
+    fn start(&mut self, _sys: &System, read: bool) {
+        if read {
+            // A consistent report for the whole transaction
+            if self.model == CapTouchModel::Gt911 {
+                self.update_ready();
+            }
+            let touch = self.touch();
+            self.reported = std::mem::replace(&mut self.report, touch);
+            if self.report != self.reported {
+                debug!("{} touch={:?}", self.name, self.report);
+            }
+        } else {
+            self.addr = 0;
+            self.addr_bytes_pending = self.addr_bytes();
+        }
+    }
+}
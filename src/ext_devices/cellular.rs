@@ -0,0 +1,345 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::VecDeque};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::system::System;
+
+use super::{ExtDevice, socket::{TcpSocket, SocketEvent}};
+
+// A SIM800-style cellular modem on a USART. It's registered on the network
+// right away, and the TCP connections opened by the firmware are real host
+// sockets. SMS can be injected at a given instruction count:
+//
+//  cellular_modem:
+//    - peripheral: USART1
+//      ip: 10.64.0.2         # reported by AT+CIFSR
+//      sms:
+//        - at: 5000000
+//          from: "+15551234567"
+//          text: "STATUS"
+//
+// Supported: AT, ATE0/1, ATI, AT+CPIN?, AT+CSQ, AT+CREG?/AT+CGREG?/AT+CEREG?
+// (registered, home), AT+COPS?, AT+CGATT, AT+CGDCONT, AT+CGACT, AT+CSTT,
+// AT+CIICR, AT+CIFSR, AT+CIPSTART="TCP", AT+CIPSEND (with a length, or ended
+// with Ctrl-Z), AT+CIPHEAD, AT+CIPCLOSE, AT+CIPSHUT, and the text mode SMS
+// commands AT+CMGF, AT+CNMI, AT+CMGR, AT+CMGD, AT+CMGS. Other commands
+// answer OK. Only a single connection is supported.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CellularModemConfig {
+    pub peripheral: String,
+    /// Defaults to 10.64.0.2
+    pub ip: Option<String>,
+    pub sms: Option<Vec<SmsConfig>>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct SmsConfig {
+    pub at: u64,
+    pub from: String,
+    pub text: String,
+}
+
+const DEFAULT_IP: &str = "10.64.0.2";
+const CTRL_Z: u8 = 0x1A;
+const ESC: u8 = 0x1B;
+
+#[derive(Default, PartialEq, Eq)]
+enum Mode {
+    #[default]
+    Command,
+    // Waiting for the data to send. Ends after the given length, or on Ctrl-Z.
+    Send(Option<usize>),
+    // Waiting for the SMS text to the given number, ending with Ctrl-Z
+    Sms(String),
+}
+
+#[derive(Default)]
+pub struct CellularModem {
+    pub config: CellularModemConfig,
+    name: String,
+    echo: bool,
+    ip_header: bool,
+    mode: Mode,
+    line: Vec<u8>,
+    send_buf: Vec<u8>,
+    output: VecDeque<u8>,
+    socket: Option<TcpSocket>,
+    // SMS to deliver, sorted by time
+    pending_sms: VecDeque<SmsConfig>,
+    // SMS storage, by index minus 1
+    inbox: Vec<Option<SmsConfig>>,
+    num_sent_sms: u32,
+}
+
+impl CellularModem {
+    pub fn new(mut config: CellularModemConfig) -> Result<Self> {
+        let mut sms = config.sms.take().unwrap_or_default();
+        sms.sort_by_key(|s| s.at);
+        Ok(Self { config, echo: true, pending_sms: sms.into(), ..Self::default() })
+    }
+
+    fn reply(&mut self, s: &str) {
+        self.output.extend(s.as_bytes());
+    }
+
+    fn ok(&mut self) {
+        self.reply("\r\nOK\r\n");
+    }
+
+    fn error(&mut self) {
+        self.reply("\r\nERROR\r\n");
+    }
+
+    /// Splits `"TCP","host",80` into its arguments, without the quotes
+    fn parse_args(args: &str) -> Vec<String> {
+        let mut result = vec![];
+        let mut arg = String::new();
+        let mut quoted = false;
+        for c in args.chars() {
+            match c {
+                '"' => quoted = !quoted,
+                ',' if !quoted => result.push(std::mem::take(&mut arg)),
+                c => arg.push(c),
+            }
+        }
+        result.push(arg);
+        result
+    }
+
+    fn close(&mut self) {
+        // Dropping the queue ends the worker, which closes the socket
+        self.socket = None;
+        if matches!(self.mode, Mode::Send(_)) {
+            self.mode = Mode::Command;
+            self.send_buf.clear();
+        }
+    }
+
+    fn deliver_sms(&mut self) {
+        let now = crate::context::now();
+        while self.pending_sms.front().is_some_and(|s| s.at <= now) {
+            let sms = self.pending_sms.pop_front().unwrap();
+            info!("{} SMS received from={} text={:?}", self.name, sms.from, sms.text);
+            self.inbox.push(Some(sms));
+            self.reply(&format!("\r\n+CMTI: \"SM\",{}\r\n", self.inbox.len()));
+        }
+    }
+
+    fn poll(&mut self) {
+        self.deliver_sms();
+
+        while let Some(event) = self.socket.as_mut().and_then(|s| s.try_recv()) {
+            match event {
+                SocketEvent::Connected => self.reply("\r\nCONNECT OK\r\n"),
+                SocketEvent::Failed => {
+                    self.socket = None;
+                    self.reply("\r\nCONNECT FAIL\r\n");
+                }
+                SocketEvent::Data(data) => {
+                    if self.ip_header {
+                        self.reply(&format!("\r\n+IPD,{}:", data.len()));
+                    }
+                    self.output.extend(data);
+                }
+                SocketEvent::Closed => {
+                    info!("{} connection closed by the remote", self.name);
+                    self.close();
+                    self.reply("\r\nCLOSED\r\n");
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, line: &str) {
+        let (cmd, args) = match line.split_once('=') {
+            Some((cmd, args)) => (cmd, Self::parse_args(args)),
+            None => (line, vec![]),
+        };
+        trace!("{} cmd={} args={:?}", self.name, cmd, args);
+
+        match cmd.to_uppercase().as_str() {
+            "ATE0" => { self.echo = false; self.ok() }
+            "ATE1" => { self.echo = true; self.ok() }
+            "ATI" => {
+                self.reply("\r\nSIM800 R14.18\r\n");
+                self.ok();
+            }
+            "AT+CPIN?" => {
+                self.reply("\r\n+CPIN: READY\r\n");
+                self.ok();
+            }
+            "AT+CSQ" => {
+                self.reply("\r\n+CSQ: 20,0\r\n");
+                self.ok();
+            }
+            "AT+CREG?" | "AT+CGREG?" | "AT+CEREG?" => {
+                // n=0, stat=1: registered, home network
+                self.reply(&format!("\r\n{}: 0,1\r\n", &cmd[2..cmd.len()-1]));
+                self.ok();
+            }
+            "AT+COPS?" => {
+                self.reply("\r\n+COPS: 0,0,\"Emulated\"\r\n");
+                self.ok();
+            }
+            "AT+CGATT?" => {
+                self.reply("\r\n+CGATT: 1\r\n");
+                self.ok();
+            }
+            "AT+CIFSR" => {
+                // Just the address, without OK
+                let ip = self.config.ip.clone().unwrap_or_else(|| DEFAULT_IP.to_string());
+                self.reply(&format!("\r\n{}\r\n", ip));
+            }
+            "AT+CIPHEAD" if args.len() == 1 => {
+                self.ip_header = args[0] == "1";
+                self.ok();
+            }
+            "AT+CIPSTART" if args.len() >= 3 && args[0].eq_ignore_ascii_case("TCP") => {
+                let (host, port) = (args[1].clone(), args[2].trim().parse::<u16>());
+                match port {
+                    Ok(_) if self.socket.is_some() => {
+                        self.error();
+                        self.reply("\r\nALREADY CONNECT\r\n");
+                    }
+                    Ok(port) => {
+                        // CONNECT OK comes once the connection is established
+                        match TcpSocket::connect("cellular-modem", host, port) {
+                            Ok(socket) => {
+                                self.socket = Some(socket);
+                                self.ok();
+                            }
+                            Err(_) => self.error(),
+                        }
+                    }
+                    Err(_) => self.error(),
+                }
+            }
+            "AT+CIPSEND" if self.socket.is_some() => {
+                let len = args.first().and_then(|len| len.trim().parse::<usize>().ok());
+                self.mode = Mode::Send(len);
+                self.reply("\r\n> ");
+            }
+            "AT+CIPCLOSE" if self.socket.is_some() => {
+                self.close();
+                self.reply("\r\nCLOSE OK\r\n");
+            }
+            "AT+CIPSHUT" => {
+                self.close();
+                self.reply("\r\nSHUT OK\r\n");
+            }
+            "AT+CMGR" if args.len() == 1 => {
+                let sms = args[0].trim().parse::<usize>().ok()
+                    .and_then(|i| self.inbox.get(i.wrapping_sub(1)).cloned().flatten());
+                if let Some(sms) = sms {
+                    self.reply(&format!("\r\n+CMGR: \"REC UNREAD\",\"{}\",\"\",\"\"\r\n{}\r\n", sms.from, sms.text));
+                }
+                self.ok();
+            }
+            "AT+CMGD" if !args.is_empty() => {
+                if let Some(sms) = args[0].trim().parse::<usize>().ok()
+                    .and_then(|i| self.inbox.get_mut(i.wrapping_sub(1))) {
+                    *sms = None;
+                }
+                self.ok();
+            }
+            "AT+CMGS" if args.len() == 1 => {
+                self.mode = Mode::Sms(args[0].clone());
+                self.reply("\r\n> ");
+            }
+            "AT+CGATT" | "AT+CGDCONT" | "AT+CGACT" | "AT+CSTT" | "AT+CIICR" | "AT+CMGF" | "AT+CNMI" |
+            "AT+CREG" | "AT+CGREG" | "AT+CEREG" | "AT+CIPMUX" | "AT+CIPMODE" | "AT" => self.ok(),
+            _ => {
+                debug!("{} unsupported cmd='{}', answering OK", self.name, line);
+                self.ok();
+            }
+        }
+    }
+
+    fn write_command(&mut self, v: u8) {
+        if self.echo {
+            self.output.push_back(v);
+        }
+        if v == b'\r' || v == b'\n' {
+            let line = String::from_utf8_lossy(&self.line).trim().to_string();
+            self.line.clear();
+            if !line.is_empty() {
+                self.execute(&line);
+            }
+        } else {
+            self.line.push(v);
+        }
+    }
+
+    fn write_data(&mut self, len: Option<usize>, v: u8) {
+        if len.is_none() && v == ESC {
+            self.send_buf.clear();
+            self.mode = Mode::Command;
+            return;
+        }
+        if len.is_some() || v != CTRL_Z {
+            self.send_buf.push(v);
+        }
+        if len == Some(self.send_buf.len()) || (len.is_none() && v == CTRL_Z) {
+            let data = std::mem::take(&mut self.send_buf);
+            if let Some(ref socket) = self.socket {
+                socket.send(data);
+            }
+            self.reply("\r\nSEND OK\r\n");
+            self.mode = Mode::Command;
+        }
+    }
+
+    fn write_sms(&mut self, to: String, v: u8) {
+        match v {
+            CTRL_Z => {
+                let text = String::from_utf8_lossy(&std::mem::take(&mut self.send_buf)).into_owned();
+                info!("{} SMS sent to={} text={:?}", self.name, to, text);
+                self.num_sent_sms += 1;
+                self.reply(&format!("\r\n+CMGS: {}\r\n", self.num_sent_sms));
+                self.ok();
+                self.mode = Mode::Command;
+            }
+            ESC => {
+                self.send_buf.clear();
+                self.mode = Mode::Command;
+            }
+            v => {
+                self.send_buf.push(v);
+                self.mode = Mode::Sms(to);
+            }
+        }
+    }
+}
+
+impl ExtDevice<(), u8> for CellularModem {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} cellular-modem", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        self.poll();
+        self.output.pop_front().unwrap_or_default()
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        self.poll();
+        match std::mem::take(&mut self.mode) {
+            Mode::Command => self.write_command(v),
+            Mode::Send(len) => {
+                self.mode = Mode::Send(len);
+                self.write_data(len, v);
+            }
+            Mode::Sms(to) => self.write_sms(to, v),
+        }
+    }
+
+    fn has_data(&mut self, _sys: &System) -> bool {
+        self.poll();
+        !self.output.is_empty()
+    }
+}
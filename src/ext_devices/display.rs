@@ -5,9 +5,16 @@ use std::{convert::TryFrom, collections::VecDeque, rc::Rc, cell::RefCell};
 use anyhow::Result;
 use serde::Deserialize;
 
-use crate::{system::System, util::{Rect, Point}, framebuffers::{Framebuffer, Framebuffers, RGB565}};
+use crate::{system::System, util::{Rect, Point}, framebuffers::{Framebuffer, Framebuffers, RGB565}, peripherals::gpio::{GpioPorts, Pin}};
 use super::ExtDevice;
 
+// ILI9341 style controller on the FSMC. Besides drawing in the address window,
+// it supports MADCTL (rotation, mirroring, BGR order), COLMOD (16 or 18-bit
+// pixels), sleep, display on/off, inversion, partial mode, and the software
+// reset. With `reset`, the controller is reset when the firmware drives the
+// pin low. In 18-bit mode, pixels are sent as 3 data writes, one per color
+// component in bits 7:2, as on an 8-bit bus.
+
 #[derive(Debug, Deserialize)]
 pub struct DisplayConfig {
     pub peripheral: String,
@@ -15,6 +22,8 @@ pub struct DisplayConfig {
     pub swap_bytes: Option<bool>,
     pub replies: Option<Vec<ReplyConfig>>,
     pub framebuffer: String,
+    /// Reset pin, active low
+    pub reset: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -34,15 +43,35 @@ pub struct Display {
     width: u16,
     height: u16,
     framebuffer: Rc<RefCell<dyn Framebuffer<RGB565>>>,
+    /// The display memory. The framebuffer shows it, unless the display is off.
+    gram: Vec<RGB565>,
+    madctl: u8,
+    /// 18-bit pixels, with their components collected in `pixel`
+    rgb666: bool,
+    pixel: Vec<u8>,
+    sleeping: bool,
+    display_on: bool,
+    inverted: bool,
+    /// Rows shown in partial mode
+    partial_area: Option<(u16, u16)>,
+    partial_rows: (u16, u16),
+    reset_pin: bool,
 }
 
+// MADCTL bits
+const MADCTL_MY: u8 = 1 << 7;
+const MADCTL_MX: u8 = 1 << 6;
+const MADCTL_MV: u8 = 1 << 5;
+const MADCTL_BGR: u8 = 1 << 3;
+
 impl Display {
-    pub fn new(config: DisplayConfig, framebuffers: &Framebuffers) -> Result<Self> {
+    pub fn new(config: DisplayConfig, gpio: &mut GpioPorts, framebuffers: &Framebuffers) -> Result<Rc<RefCell<Self>>> {
         let framebuffer = framebuffers.get(&config.framebuffer)?;
         let width = framebuffer.borrow().get_config().width;
         let height = framebuffer.borrow().get_config().height;
+        let reset_pin = config.reset.as_deref().map(Pin::from_str);
 
-        Ok(Self {
+        let self_ = Rc::new(RefCell::new(Self {
             name: "?".to_string(), // This is filled out on connect_peripheral()
             draw_region: Rect { left: 0, top: 0, right: width-1, bottom: height-1 },
             cmd: None,
@@ -51,27 +80,128 @@ impl Display {
             current_position: Point::default(),
             width, height,
             framebuffer: framebuffer.clone(),
+            gram: vec![0; width as usize * height as usize],
+            madctl: 0,
+            rgb666: false,
+            pixel: vec![],
+            // Firmware that never wakes up the display still gets a picture
+            sleeping: false,
+            display_on: true,
+            inverted: false,
+            partial_area: None,
+            partial_rows: (0, height-1),
+            reset_pin: true,
             config,
-        })
+        }));
+
+        if let Some(pin) = reset_pin {
+            let s = self_.clone();
+            gpio.add_write_callback(pin, move |_sys, v| {
+                let mut s = s.borrow_mut();
+                if s.reset_pin && !v {
+                    debug!("{} hardware reset", s.name);
+                    s.reset();
+                }
+                s.reset_pin = v;
+            });
+        }
+
+        Ok(self_)
     }
 
+    /// The state after a hardware or software reset. The memory is kept.
+    fn reset(&mut self) {
+        let (width, height) = (self.width, self.height);
+        self.draw_region = Rect { left: 0, top: 0, right: width-1, bottom: height-1 };
+        self.cmd = None;
+        self.reply.clear();
+        self.drawing = false;
+        self.current_position = Point::default();
+        self.madctl = 0;
+        self.rgb666 = false;
+        self.pixel.clear();
+        self.sleeping = true;
+        self.display_on = false;
+        self.inverted = false;
+        self.partial_area = None;
+        self.partial_rows = (0, height-1);
+        self.refresh();
+    }
+
+    /// Maps the address window coordinates to the panel coordinates
     #[inline]
     fn get_framebuffer_pixel_index(&mut self, x: u16, y: u16) -> usize {
-        let x = x.min(self.width-1) as usize;
-        let y = y.min(self.height-1) as usize;
-        x + y * self.width as usize
+        let (mut x, mut y) = if self.madctl & MADCTL_MV != 0 { (y, x) } else { (x, y) };
+        x = x.min(self.width-1);
+        y = y.min(self.height-1);
+        if self.madctl & MADCTL_MX != 0 {
+            x = self.width-1 - x;
+        }
+        if self.madctl & MADCTL_MY != 0 {
+            y = self.height-1 - y;
+        }
+        x as usize + y as usize * self.width as usize
+    }
+
+    /// What the panel shows for a pixel of the memory
+    fn shown_color(&self, i: usize) -> RGB565 {
+        let y = (i / self.width as usize) as u16;
+        let (top, bottom) = self.partial_area.unwrap_or((0, self.height-1));
+        let in_area = if top <= bottom {
+            (top..=bottom).contains(&y)
+        } else {
+            // The partial area wraps around
+            y >= top || y <= bottom
+        };
+
+        if self.sleeping || !self.display_on || !in_area {
+            0
+        } else if self.inverted {
+            !self.gram[i]
+        } else {
+            self.gram[i]
+        }
     }
 
+    /// Redraws the whole framebuffer, when how the memory is shown changes
+    fn refresh(&mut self) {
+        let mut fb = self.framebuffer.borrow_mut();
+        for (i, p) in fb.get_pixels().iter_mut().enumerate().take(self.gram.len()) {
+            *p = self.shown_color(i);
+        }
+    }
+
+    /// Collects the data writes making a pixel
+    fn write_pixel_data(&mut self, v: u16) {
+        if !self.rgb666 {
+            let c = if self.config.swap_bytes.unwrap_or_default() {
+                v.swap_bytes()
+            } else {
+                v
+            };
+            self.draw_pixel(c);
+            return;
+        }
+
+        self.pixel.push(v as u8);
+        if self.pixel.len() == 3 {
+            let [r, g, b] = [self.pixel[0], self.pixel[1], self.pixel[2]].map(|c| c as u16);
+            self.pixel.clear();
+            self.draw_pixel(((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3));
+        }
+    }
 
     fn draw_pixel(&mut self, c: u16) {
-        let c = if self.config.swap_bytes.unwrap_or_default() {
-            c.swap_bytes()
+        let c = if self.madctl & MADCTL_BGR != 0 {
+            ((c & 0x1F) << 11) | (c & 0x07E0) | (c >> 11)
         } else {
             c
         };
 
         let Point { mut x, mut y } = self.current_position;
         let i = self.get_framebuffer_pixel_index(x, y);
+        self.gram[i] = c;
+        let c = self.shown_color(i);
         self.framebuffer.borrow_mut().get_pixels()[i] = c;
 
         x += 1;
@@ -106,12 +236,66 @@ impl Display {
                 }
                 (Some(cmd @ Command::Draw), 0) => {
                     self.drawing = true;
+                    self.pixel.clear();
                     self.current_position = Point {
                         x: self.draw_region.left,
                         y: self.draw_region.top,
                     };
                     debug!("{} cmd={:?}", self.name, cmd);
                 }
+                (Some(cmd @ Command::DrawContinue), 0) => {
+                    self.drawing = true;
+                    self.pixel.clear();
+                    debug!("{} cmd={:?}", self.name, cmd);
+                }
+                (Some(cmd @ Command::SoftwareReset), 0) => {
+                    debug!("{} cmd={:?}", self.name, cmd);
+                    self.reset();
+                }
+                (Some(cmd @ (Command::SleepIn | Command::SleepOut)), 0) => {
+                    self.sleeping = matches!(cmd, Command::SleepIn);
+                    debug!("{} cmd={:?}", self.name, cmd);
+                    self.refresh();
+                }
+                (Some(cmd @ (Command::DisplayOff | Command::DisplayOn)), 0) => {
+                    self.display_on = matches!(cmd, Command::DisplayOn);
+                    debug!("{} cmd={:?}", self.name, cmd);
+                    self.refresh();
+                }
+                (Some(cmd @ (Command::InversionOff | Command::InversionOn)), 0) => {
+                    self.inverted = matches!(cmd, Command::InversionOn);
+                    debug!("{} cmd={:?}", self.name, cmd);
+                    self.refresh();
+                }
+                (Some(cmd @ Command::PartialMode), 0) => {
+                    self.partial_area = Some(self.partial_rows);
+                    debug!("{} cmd={:?} rows={:?}", self.name, cmd, self.partial_rows);
+                    self.refresh();
+                }
+                (Some(cmd @ Command::NormalMode), 0) => {
+                    self.partial_area = None;
+                    debug!("{} cmd={:?}", self.name, cmd);
+                    self.refresh();
+                }
+                (Some(cmd @ Command::PartialArea), 4) => {
+                    let top    = (args[0] << 8) | args[1];
+                    let bottom = (args[2] << 8) | args[3];
+                    self.partial_rows = (top.min(self.height-1), bottom.min(self.height-1));
+                    if self.partial_area.is_some() {
+                        self.partial_area = Some(self.partial_rows);
+                        self.refresh();
+                    }
+                    debug!("{} cmd={:?} top={} bottom={}", self.name, cmd, top, bottom);
+                }
+                (Some(cmd @ Command::MemoryAccessControl), 1) => {
+                    self.madctl = args[0] as u8;
+                    debug!("{} cmd={:?} madctl=0x{:02x}", self.name, cmd, self.madctl);
+                }
+                (Some(cmd @ Command::PixelFormat), 1) => {
+                    // The MCU interface format is in the low bits. 0x5 is 16-bit, 0x6 is 18-bit.
+                    self.rgb666 = args[0] & 0x7 == 0x6;
+                    debug!("{} cmd={:?} format=0x{:02x}", self.name, cmd, args[0]);
+                }
                 _ => {
                     // If we need to reply to a read, put it there.
                     if let Some(replies) = self.config.replies.as_ref() {
@@ -169,7 +353,7 @@ impl ExtDevice<u32, u32> for Display {
             }
             Mode::Data => {
                 if self.drawing {
-                    self.draw_pixel(value as u16);
+                    self.write_pixel_data(value as u16);
                 } else if let Some((_cmd, args)) = self.cmd.as_mut() {
                     args.push(value as u16);
                 }
@@ -178,6 +362,20 @@ impl ExtDevice<u32, u32> for Display {
 
         self.handle_cmd();
     }
+
+    fn write_block(&mut self, sys: &System, addr: u32, values: &[u16]) {
+        // The pixels of a blit go straight to the framebuffer
+        if self.drawing && Mode::from_addr(self.config.cmd_addr_bit, addr) == Mode::Data {
+            trace!("{} WRITE {:?} num_values={}", self.name, Mode::Data, values.len());
+            for v in values {
+                self.write_pixel_data(*v);
+            }
+        } else {
+            for v in values {
+                self.write(sys, addr, *v as u32);
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -190,9 +388,22 @@ enum Mode {
 #[derive(Clone, Copy, Debug, num_enum::TryFromPrimitive)]
 #[repr(u8)]
 enum Command {
+    SoftwareReset = 0x01,
+    SleepIn = 0x10,
+    SleepOut = 0x11,
+    PartialMode = 0x12,
+    NormalMode = 0x13,
+    InversionOff = 0x20,
+    InversionOn = 0x21,
+    DisplayOff = 0x28,
+    DisplayOn = 0x29,
     SetHoriRegion = 0x2A,
     SetVertRegion = 0x2B,
     Draw = 0x2C,
+    PartialArea = 0x30,
+    MemoryAccessControl = 0x36,
+    PixelFormat = 0x3A,
+    DrawContinue = 0x3C,
 }
 
 impl Mode {
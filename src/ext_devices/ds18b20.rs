@@ -0,0 +1,267 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::system::System;
+
+use super::{ExtDevice, OneWireDevice};
+
+// Implements a DS18B20 1-Wire temperature sensor
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Ds18b20Config {
+    pub peripheral: String,
+    /// 48-bit serial number. The family code and CRC are added to form the ROM.
+    pub serial: u64,
+    /// In Celsius
+    pub temperature: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum State {
+    /// Not selected. Waiting for a reset.
+    #[default]
+    Idle,
+    RomCommand,
+    MatchRom(usize),
+    /// 64 bits, each in 3 steps: send bit, send complement, receive the master choice.
+    SearchRom(u8, u8),
+    FunctionCommand,
+    WriteScratchpad(usize),
+    /// Read time slots return 1. Used to signal that a conversion or a copy is done.
+    Done,
+}
+
+#[derive(Default)]
+pub struct Ds18b20 {
+    pub config: Ds18b20Config,
+    name: String,
+    rom: [u8; 8],
+    scratchpad: [u8; 9],
+    // TH, TL, config
+    eeprom: [u8; 3],
+
+    state: State,
+    // State to go to after we are done sending tx_bits
+    next_state: State,
+    rx_byte: u8,
+    rx_bits: u8,
+    tx_bits: VecDeque<bool>,
+}
+
+const FAMILY_CODE: u8 = 0x28;
+
+impl Ds18b20 {
+    pub fn new(config: Ds18b20Config) -> Result<Self> {
+        let mut rom = [0; 8];
+        rom[0] = FAMILY_CODE;
+        rom[1..7].copy_from_slice(&config.serial.to_le_bytes()[0..6]);
+        rom[7] = crc8(&rom[0..7]);
+
+        let eeprom = [0x4B, 0x46, 0x7F];
+        let mut self_ = Self { config, rom, eeprom, ..Self::default() };
+        self_.recall_eeprom();
+        // Power-on value of the temperature register is +85C
+        self_.set_scratchpad_temperature(85.0);
+        Ok(self_)
+    }
+
+    fn recall_eeprom(&mut self) {
+        self.scratchpad[2..5].copy_from_slice(&self.eeprom);
+        self.scratchpad[5] = 0xFF;
+        self.scratchpad[6] = 0x0C;
+        self.scratchpad[7] = 0x10;
+        self.update_scratchpad_crc();
+    }
+
+    fn update_scratchpad_crc(&mut self) {
+        self.scratchpad[8] = crc8(&self.scratchpad[0..8]);
+    }
+
+    fn set_scratchpad_temperature(&mut self, temperature: f32) {
+        // 9 to 12 bits of resolution. Unused LSBs are zeros.
+        let resolution = 9 + ((self.scratchpad[4] >> 5) & 0b11);
+        let raw = (temperature * 16.0).round() as i16;
+        let raw = raw & !((1 << (12 - resolution)) - 1);
+        self.scratchpad[0..2].copy_from_slice(&raw.to_le_bytes());
+        self.update_scratchpad_crc();
+    }
+
+    fn send(&mut self, data: &[u8], next_state: State) {
+        self.tx_bits = data.iter()
+            .flat_map(|b| (0..8).map(move |i| (b >> i) & 1 != 0))
+            .collect();
+        self.next_state = next_state;
+    }
+
+    fn rom_bit(&self, i: u8) -> bool {
+        (self.rom[i as usize / 8] >> (i % 8)) & 1 != 0
+    }
+
+    fn receive_byte(&mut self, v: u8) {
+        match self.state {
+            State::RomCommand => {
+                match v {
+                    0x33 => {
+                        debug!("{} cmd=ReadRom", self.name);
+                        let rom = self.rom;
+                        self.send(&rom, State::FunctionCommand);
+                    }
+                    0x55 => self.state = State::MatchRom(0),
+                    0xCC => {
+                        trace!("{} cmd=SkipRom", self.name);
+                        self.state = State::FunctionCommand;
+                    }
+                    0xF0 => {
+                        trace!("{} cmd=SearchRom", self.name);
+                        self.state = State::SearchRom(0, 0);
+                    }
+                    _ => {
+                        // Includes alarm search. We never have alarms.
+                        debug!("{} unhandled rom cmd={:02x}", self.name, v);
+                        self.state = State::Idle;
+                    }
+                }
+            }
+            State::MatchRom(i) => {
+                self.state = if self.rom[i] != v {
+                    State::Idle
+                } else if i == 7 {
+                    trace!("{} cmd=MatchRom", self.name);
+                    State::FunctionCommand
+                } else {
+                    State::MatchRom(i+1)
+                };
+            }
+            State::FunctionCommand => {
+                match v {
+                    0x44 => {
+                        debug!("{} cmd=ConvertT temperature={}", self.name, self.config.temperature);
+                        self.set_scratchpad_temperature(self.config.temperature);
+                        self.state = State::Done;
+                    }
+                    0xBE => {
+                        debug!("{} cmd=ReadScratchpad data={:02x?}", self.name, self.scratchpad);
+                        let scratchpad = self.scratchpad;
+                        self.send(&scratchpad, State::Idle);
+                    }
+                    0x4E => self.state = State::WriteScratchpad(0),
+                    0x48 => {
+                        debug!("{} cmd=CopyScratchpad", self.name);
+                        self.eeprom.copy_from_slice(&self.scratchpad[2..5]);
+                        self.state = State::Done;
+                    }
+                    0xB8 => {
+                        debug!("{} cmd=RecallEeprom", self.name);
+                        self.recall_eeprom();
+                        self.state = State::Done;
+                    }
+                    0xB4 => {
+                        // Read power supply. 1 means externally powered.
+                        self.state = State::Done;
+                    }
+                    _ => {
+                        warn!("{} unknown cmd={:02x}", self.name, v);
+                        self.state = State::Idle;
+                    }
+                }
+            }
+            State::WriteScratchpad(i) => {
+                self.scratchpad[2+i] = v;
+                if i == 2 {
+                    debug!("{} cmd=WriteScratchpad data={:02x?}", self.name, &self.scratchpad[2..5]);
+                    self.update_scratchpad_crc();
+                    self.state = State::Idle;
+                } else {
+                    self.state = State::WriteScratchpad(i+1);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl ExtDevice<(), bool> for Ds18b20 {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} ds18b20", peri_name);
+        self.name.clone()
+    }
+
+    /// What we drive on the line during the next time slot
+    fn read(&mut self, _sys: &System, _addr: ()) -> bool {
+        if let Some(bit) = self.tx_bits.front() {
+            return *bit;
+        }
+
+        match self.state {
+            State::SearchRom(i, 0) => self.rom_bit(i),
+            State::SearchRom(i, 1) => !self.rom_bit(i),
+            _ => true,
+        }
+    }
+
+    /// The resolved bit of the time slot
+    fn write(&mut self, _sys: &System, _addr: (), bit: bool) {
+        if self.tx_bits.pop_front().is_some() {
+            if self.tx_bits.is_empty() {
+                self.state = self.next_state;
+            }
+            return;
+        }
+
+        match self.state {
+            State::Idle | State::Done => {}
+            State::SearchRom(i, step) if step < 2 => {
+                self.state = State::SearchRom(i, step+1);
+            }
+            State::SearchRom(i, _) => {
+                self.state = if bit != self.rom_bit(i) {
+                    // The master went the other way. We are out of the search.
+                    State::Idle
+                } else if i == 63 {
+                    debug!("{} found by search rom", self.name);
+                    State::FunctionCommand
+                } else {
+                    State::SearchRom(i+1, 0)
+                };
+            }
+            _ => {
+                self.rx_byte = (self.rx_byte >> 1) | if bit { 0x80 } else { 0 };
+                self.rx_bits += 1;
+                if self.rx_bits == 8 {
+                    self.rx_bits = 0;
+                    self.receive_byte(self.rx_byte);
+                }
+            }
+        }
+    }
+}
+
+impl OneWireDevice for Ds18b20 {
+    fn reset(&mut self, _sys: &System) -> bool {
+        self.state = State::RomCommand;
+        self.rx_bits = 0;
+        self.tx_bits.clear();
+        true
+    }
+}
+
+/// Dallas/Maxim CRC8
+fn crc8(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for b in data {
+        let mut b = *b;
+        for _ in 0..8 {
+            let mix = (crc ^ b) & 1;
+            crc >>= 1;
+            if mix != 0 {
+                crc ^= 0x8C;
+            }
+            b >>= 1;
+        }
+    }
+    crc
+}
@@ -0,0 +1,277 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::VecDeque;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::system::System;
+
+use super::{ExtDevice, socket::{TcpSocket, SocketEvent}};
+
+// An ESP8266/ESP32 running the AT firmware, on a USART. The TCP connections
+// opened by the firmware are real host sockets:
+//
+//  esp_at:
+//    - peripheral: USART2
+//      ip: 192.168.1.50      # reported by AT+CIFSR
+//
+// Supported: AT, ATE0/1, AT+RST, AT+GMR, AT+CWMODE, AT+CWJAP (always
+// succeeds), AT+CWQAP, AT+CIFSR, AT+CIPMUX=0, AT+CIPSTART="TCP",
+// AT+CIPSEND=<len>, AT+CIPMODE=1 with AT+CIPSEND for the passthrough mode
+// (ended with +++), and AT+CIPCLOSE. Received data comes as +IPD,<len>:,
+// or raw in passthrough mode. Only a single connection is supported.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct EspAtConfig {
+    pub peripheral: String,
+    /// Defaults to 192.168.4.2
+    pub ip: Option<String>,
+}
+
+const DEFAULT_IP: &str = "192.168.4.2";
+const PASSTHROUGH_ESCAPE: &[u8] = b"+++";
+
+#[derive(Default, PartialEq, Eq)]
+enum Mode {
+    #[default]
+    Command,
+    // Waiting for the given number of bytes to send, after AT+CIPSEND=<len>
+    Send(usize),
+    Passthrough,
+}
+
+#[derive(Default)]
+pub struct EspAt {
+    pub config: EspAtConfig,
+    name: String,
+    echo: bool,
+    passthrough_enabled: bool,
+    mode: Mode,
+    line: Vec<u8>,
+    send_buf: Vec<u8>,
+    output: VecDeque<u8>,
+    socket: Option<TcpSocket>,
+    connected: bool,
+}
+
+impl EspAt {
+    pub fn new(config: EspAtConfig) -> Result<Self> {
+        Ok(Self { config, echo: true, ..Self::default() })
+    }
+
+    fn reply(&mut self, s: &str) {
+        self.output.extend(s.as_bytes());
+    }
+
+    fn ok(&mut self) {
+        self.reply("\r\nOK\r\n");
+    }
+
+    fn error(&mut self) {
+        self.reply("\r\nERROR\r\n");
+    }
+
+    /// Splits `"TCP","host",80` into its arguments, without the quotes
+    fn parse_args(args: &str) -> Vec<String> {
+        let mut result = vec![];
+        let mut arg = String::new();
+        let mut quoted = false;
+        for c in args.chars() {
+            match c {
+                '"' => quoted = !quoted,
+                ',' if !quoted => result.push(std::mem::take(&mut arg)),
+                c => arg.push(c),
+            }
+        }
+        result.push(arg);
+        result
+    }
+
+    fn close(&mut self) {
+        // Dropping the queue ends the worker, which closes the socket
+        self.socket = None;
+        self.connected = false;
+        self.mode = Mode::Command;
+        self.send_buf.clear();
+    }
+
+    fn poll_socket(&mut self) {
+        while let Some(event) = self.socket.as_mut().and_then(|s| s.try_recv()) {
+            match event {
+                SocketEvent::Connected => {
+                    self.connected = true;
+                    self.reply("CONNECT\r\n");
+                    self.ok();
+                }
+                SocketEvent::Failed => {
+                    self.socket = None;
+                    self.reply("CLOSED\r\n");
+                    self.error();
+                }
+                SocketEvent::Data(data) => {
+                    if self.mode != Mode::Passthrough {
+                        self.reply(&format!("\r\n+IPD,{}:", data.len()));
+                    }
+                    self.output.extend(data);
+                }
+                SocketEvent::Closed => {
+                    info!("{} connection closed by the remote", self.name);
+                    self.close();
+                    self.reply("CLOSED\r\n");
+                }
+            }
+        }
+    }
+
+    fn execute(&mut self, line: &str) {
+        let (cmd, args) = match line.split_once('=') {
+            Some((cmd, args)) => (cmd, Some(Self::parse_args(args))),
+            None => (line, None),
+        };
+        let args = args.unwrap_or_default();
+        trace!("{} cmd={} args={:?}", self.name, cmd, args);
+
+        match cmd.to_uppercase().as_str() {
+            "AT" => self.ok(),
+            "ATE0" => { self.echo = false; self.ok() }
+            "ATE1" => { self.echo = true; self.ok() }
+            "AT+RST" => {
+                self.close();
+                self.passthrough_enabled = false;
+                self.ok();
+                self.reply("\r\nready\r\n");
+            }
+            "AT+GMR" => {
+                self.reply("AT version:1.7.4.0\r\nSDK version:3.0.4\r\ncompile time:emulated\r\n");
+                self.ok();
+            }
+            "AT+CWMODE" | "AT+CWMODE_CUR" | "AT+CWMODE_DEF" => self.ok(),
+            "AT+CWJAP" | "AT+CWJAP_CUR" | "AT+CWJAP_DEF" if !args.is_empty() => {
+                info!("{} joined ssid={}", self.name, args[0]);
+                self.reply("WIFI CONNECTED\r\nWIFI GOT IP\r\n");
+                self.ok();
+            }
+            "AT+CWQAP" => {
+                self.close();
+                self.reply("WIFI DISCONNECT\r\n");
+                self.ok();
+            }
+            "AT+CIFSR" => {
+                let ip = self.config.ip.clone().unwrap_or_else(|| DEFAULT_IP.to_string());
+                self.reply(&format!("+CIFSR:STAIP,\"{}\"\r\n", ip));
+                self.ok();
+            }
+            "AT+CIPMUX" if args.first().map(String::as_str) == Some("0") => self.ok(),
+            "AT+CIPMODE" if args.len() == 1 => {
+                self.passthrough_enabled = args[0] == "1";
+                self.ok();
+            }
+            "AT+CIPSTART" if args.len() >= 3 && args[0].eq_ignore_ascii_case("TCP") => {
+                let (host, port) = (args[1].clone(), args[2].trim().parse::<u16>());
+                match port {
+                    Ok(_) if self.socket.is_some() => self.reply("ALREADY CONNECTED\r\n\r\nERROR\r\n"),
+                    Ok(port) => {
+                        // We reply once the connection is established
+                        match TcpSocket::connect("esp-at", host, port) {
+                            Ok(socket) => self.socket = Some(socket),
+                            Err(_) => self.error(),
+                        }
+                    }
+                    Err(_) => self.error(),
+                }
+            }
+            "AT+CIPSEND" if self.connected => {
+                if args.is_empty() && self.passthrough_enabled {
+                    self.mode = Mode::Passthrough;
+                    self.reply("\r\nOK\r\n\r\n>");
+                } else if let Some(len) = args.first().and_then(|len| len.trim().parse::<usize>().ok()) {
+                    self.mode = Mode::Send(len);
+                    self.reply("\r\nOK\r\n> ");
+                } else {
+                    self.error();
+                }
+            }
+            "AT+CIPCLOSE" if self.socket.is_some() => {
+                self.close();
+                self.reply("CLOSED\r\n");
+                self.ok();
+            }
+            _ => {
+                debug!("{} unsupported cmd='{}'", self.name, line);
+                self.error();
+            }
+        }
+    }
+
+    fn send_to_socket(&mut self, data: Vec<u8>) {
+        if let Some(ref socket) = self.socket {
+            socket.send(data);
+        }
+    }
+
+    fn write_command(&mut self, v: u8) {
+        if self.echo {
+            self.output.push_back(v);
+        }
+        if v == b'\n' {
+            let line = String::from_utf8_lossy(&self.line).trim().to_string();
+            self.line.clear();
+            if !line.is_empty() {
+                self.execute(&line);
+            }
+        } else {
+            self.line.push(v);
+        }
+    }
+
+    fn write_data(&mut self, len: usize, v: u8) {
+        self.send_buf.push(v);
+        if self.send_buf.len() == len {
+            let data = std::mem::take(&mut self.send_buf);
+            self.reply(&format!("\r\nRecv {} bytes\r\n", data.len()));
+            self.send_to_socket(data);
+            self.reply("\r\nSEND OK\r\n");
+            self.mode = Mode::Command;
+        }
+    }
+
+    fn write_passthrough(&mut self, v: u8) {
+        // The escape sequence is held back, in case it completes
+        self.send_buf.push(v);
+        if self.send_buf == PASSTHROUGH_ESCAPE {
+            self.send_buf.clear();
+            self.mode = Mode::Command;
+            debug!("{} passthrough mode exited", self.name);
+        } else if !PASSTHROUGH_ESCAPE.starts_with(&self.send_buf) {
+            let data = std::mem::take(&mut self.send_buf);
+            self.send_to_socket(data);
+        }
+    }
+}
+
+impl ExtDevice<(), u8> for EspAt {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} esp-at", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        self.poll_socket();
+        self.output.pop_front().unwrap_or_default()
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        self.poll_socket();
+        match self.mode {
+            Mode::Command => self.write_command(v),
+            Mode::Send(len) => self.write_data(len, v),
+            Mode::Passthrough => self.write_passthrough(v),
+        }
+    }
+
+    fn has_data(&mut self, _sys: &System) -> bool {
+        self.poll_socket();
+        !self.output.is_empty()
+    }
+}
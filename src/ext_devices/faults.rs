@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use serde::Deserialize;
+
+use crate::util::Rng;
+
+// Fault injection for the storage devices, to test the error handling of the
+// firmware. The faults are deterministic, the random bit flips use a fixed seed:
+//
+//  spi_flash:
+//    - peripheral: SPI2
+//      ...
+//      faults:
+//        read_error_after: 1000  # reads fail after this many commands
+//        timeout_after: 5000     # the device stops answering after this many commands
+//        bit_flips:              # flipped when read
+//          - addr: 0x1000
+//            bit: 3
+//        random_flip_rate: 0.0001  # probability of a bit flip per byte read
+//        seed: 42
+
+#[derive(Debug, Deserialize, Default)]
+pub struct FaultsConfig {
+    pub read_error_after: Option<u64>,
+    pub timeout_after: Option<u64>,
+    pub bit_flips: Option<Vec<BitFlipConfig>>,
+    pub random_flip_rate: Option<f64>,
+    /// Defaults to 1
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct BitFlipConfig {
+    pub addr: u64,
+    pub bit: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Fault {
+    #[default]
+    None,
+    ReadError,
+    Timeout,
+}
+
+#[derive(Default)]
+pub struct Faults {
+    config: FaultsConfig,
+    num_ops: u64,
+    fault: Fault,
+    rng: Rng,
+}
+
+impl Faults {
+    pub fn new(config: Option<FaultsConfig>) -> Self {
+        let config = config.unwrap_or_default();
+        let rng = Rng::new(config.seed.unwrap_or_default());
+        Self { config, rng, ..Self::default() }
+    }
+
+    /// Counts a command of the device, and returns the fault to apply to it
+    pub fn operation(&mut self, name: &str) -> Fault {
+        self.num_ops += 1;
+        let after = |n: Option<u64>| n.is_some_and(|n| self.num_ops > n);
+        let fault = if after(self.config.timeout_after) {
+            Fault::Timeout
+        } else if after(self.config.read_error_after) {
+            Fault::ReadError
+        } else {
+            Fault::None
+        };
+        if fault != self.fault {
+            info!("{} injecting fault={:?} num_ops={}", name, fault, self.num_ops);
+            self.fault = fault;
+        }
+        fault
+    }
+
+    /// The fault of the current command
+    pub fn fault(&self) -> Fault {
+        self.fault
+    }
+
+    /// Applies the bit flips to a byte read at the given address
+    pub fn corrupt(&mut self, name: &str, addr: u64, mut v: u8) -> u8 {
+        for flip in self.config.bit_flips.iter().flatten().filter(|f| f.addr == addr) {
+            v ^= 1 << (flip.bit & 7);
+        }
+        if let Some(rate) = self.config.random_flip_rate.filter(|r| *r > 0.0) {
+            let r = (self.rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+            if r < rate {
+                let bit = self.rng.next_u64() % 8;
+                debug!("{} random bit flip addr=0x{:x} bit={}", name, addr, bit);
+                v ^= 1 << bit;
+            }
+        }
+        v
+    }
+}
@@ -0,0 +1,95 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{util::{self, PersistPolicy}, system::System};
+
+use super::ExtDevice;
+
+// Plain memory on an FSMC bank, like an external SRAM, optionally loaded from
+// a file:
+//
+//  fsmc_ram:
+//    - peripheral: FSMC.BANK1.NE3
+//      size: 0x100000
+//      file: assets.bin    # initial content, zeros past its end
+//      persist: on_exit    # write the content back. Defaults to never.
+//
+// A RAM has no write transactions, so `always` writes the file back when the
+// emulation stops, even after a lockup. The addresses wrap around the size, as
+// the upper address lines are not connected.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct FsmcRamConfig {
+    pub peripheral: String,
+    pub size: usize,
+    pub file: Option<String>,
+    pub persist: Option<PersistPolicy>,
+}
+
+#[derive(Default)]
+pub struct FsmcRam {
+    pub config: FsmcRamConfig,
+    name: String,
+    content: Vec<u8>,
+    dirty: bool,
+}
+
+impl FsmcRam {
+    pub fn new(config: FsmcRamConfig) -> Result<Self> {
+        if config.size == 0 {
+            anyhow::bail!("fsmc_ram on {} needs a size", config.peripheral);
+        }
+        let mut content = match config.file {
+            Some(ref file) if std::path::Path::new(file).exists() => {
+                util::read_file(file)
+                    .with_context(|| format!("Failed to read {}", file))?
+            }
+            _ => vec![],
+        };
+        if content.len() > config.size {
+            warn!("{} is larger than the fsmc_ram size={}, it is truncated", config.file.as_deref().unwrap_or_default(), config.size);
+        }
+        content.resize(config.size, 0);
+
+        Ok(Self { config, content, ..Self::default() })
+    }
+
+    /// Called when the emulation stops
+    pub fn save(&mut self, clean_exit: bool) -> Result<()> {
+        let persist = self.config.persist.unwrap_or(PersistPolicy::Never);
+        if let (Some(file), true) = (&self.config.file, self.dirty && persist.on_exit(clean_exit)) {
+            util::write_file_atomic(file, &self.content)?;
+            self.dirty = false;
+            info!("{} saved to {}", self.name, file);
+        }
+        Ok(())
+    }
+}
+
+impl ExtDevice<u32, u32> for FsmcRam {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} ram", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, addr: u32) -> u32 {
+        let mut v = [0; 4];
+        for (i, b) in v.iter_mut().enumerate() {
+            *b = self.content[(addr as usize + i) % self.config.size];
+        }
+        u32::from_le_bytes(v)
+    }
+
+    fn write(&mut self, sys: &System, addr: u32, v: u32) {
+        self.write_sized(sys, addr, v, 4)
+    }
+
+    fn write_sized(&mut self, _sys: &System, addr: u32, v: u32, size: u8) {
+        for (i, b) in v.to_le_bytes().into_iter().enumerate().take(size as usize) {
+            self.content[(addr as usize + i) % self.config.size] = b;
+        }
+        self.dirty = true;
+    }
+}
@@ -0,0 +1,373 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{rc::Rc, cell::RefCell};
+
+use anyhow::{Result, bail};
+use serde::Deserialize;
+
+use crate::framebuffers::{Framebuffer, Framebuffers, RGB565};
+use crate::peripherals::gpio::{GpioPorts, Pin};
+use crate::system::System;
+
+use super::{ExtDevice, I2cDevice};
+
+// Implements a HD44780 character LCD.
+// It is either wired directly on GPIOs in 4-bit mode, or through a PCF8574 I2C backpack.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Hd44780Config {
+    /// I2C bus of the backpack. Leave empty when wired on GPIOs.
+    pub peripheral: Option<String>,
+    /// I2C address of the backpack
+    pub address: Option<u8>,
+    pub pins: Option<Hd44780Pins>,
+    pub columns: u8,
+    pub rows: u8,
+    pub framebuffer: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Hd44780Pins {
+    pub rs: String,
+    pub e: String,
+    pub d4: String,
+    pub d5: String,
+    pub d6: String,
+    pub d7: String,
+}
+
+const DDRAM_SIZE: usize = 0x80;
+const CGRAM_SIZE: usize = 0x40;
+// The text is written in bursts. It's logged once nothing was written for
+// that many cycles.
+const LOG_IDLE_CYCLES: u64 = 100_000;
+
+pub struct Hd44780 {
+    pub config: Hd44780Config,
+    name: String,
+
+    ddram: [u8; DDRAM_SIZE],
+    cgram: [u8; CGRAM_SIZE],
+    addr: usize,
+    cgram_selected: bool,
+    increment: bool,
+    display_on: bool,
+    four_bit_mode: bool,
+    // First nibble of a byte in 4-bit mode
+    high_nibble: Option<u8>,
+
+    // Pin levels. data holds D4-D7.
+    rs: bool,
+    e: bool,
+    data: u8,
+
+    dirty: bool,
+    last_write: u64,
+    last_logged: Vec<String>,
+
+    framebuffer: Option<Rc<RefCell<dyn Framebuffer<RGB565>>>>,
+}
+
+impl Hd44780 {
+    pub fn new(config: Hd44780Config, gpio: &mut GpioPorts, framebuffers: &Framebuffers) -> Result<Rc<RefCell<Self>>> {
+        // Rows 2 and 3 follow rows 0 and 1 in the DDRAM
+        let max_columns = if config.rows > 2 { DDRAM_SIZE / 4 } else { DDRAM_SIZE / 2 };
+        if !(1..=4).contains(&config.rows) || config.columns == 0 || config.columns as usize > max_columns {
+            bail!("HD44780 with {} rows and {} columns isn't supported. It has 1 to 4 rows, and up to {} columns",
+                config.rows, config.columns, max_columns);
+        }
+
+        let framebuffer = config.framebuffer.as_ref()
+            .map(|fb| framebuffers.get(fb))
+            .transpose()?;
+
+        let pins = config.pins.as_ref().map(|p| {
+            [&p.rs, &p.e, &p.d4, &p.d5, &p.d6, &p.d7].map(|p| Pin::from_str(p))
+        });
+
+        let self_ = Rc::new(RefCell::new(Self {
+            config,
+            name: "GPIO hd44780".to_string(), // Replaced in connect_peripheral() when on I2C
+            ddram: [b' '; DDRAM_SIZE],
+            cgram: [0; CGRAM_SIZE],
+            addr: 0,
+            cgram_selected: false,
+            increment: true,
+            display_on: false,
+            four_bit_mode: false,
+            high_nibble: None,
+            rs: false,
+            e: false,
+            data: 0,
+            dirty: false,
+            last_write: 0,
+            last_logged: vec![],
+            framebuffer,
+        }));
+
+        if let Some(pins) = pins {
+            for (i, pin) in pins.into_iter().enumerate() {
+                let s = self_.clone();
+                gpio.add_write_callback(pin, move |_sys, v| {
+                    let mut s = s.borrow_mut();
+                    match i {
+                        0 => s.rs = v,
+                        1 => s.write_e(v),
+                        _ => {
+                            let bit = 1 << (i-2);
+                            if v { s.data |= bit } else { s.data &= !bit }
+                        }
+                    }
+                });
+            }
+        }
+
+        Ok(self_)
+    }
+
+    fn write_e(&mut self, e: bool) {
+        // Data is latched on the falling edge
+        if self.e && !e {
+            self.latch_nibble(self.rs, self.data);
+        }
+        self.e = e;
+    }
+
+    fn latch_nibble(&mut self, rs: bool, nibble: u8) {
+        if !self.four_bit_mode {
+            // 8-bit mode, but only D4-D7 are wired. This happens during initialization.
+            self.high_nibble = None;
+            self.write_byte(rs, nibble << 4);
+        } else if let Some(high) = self.high_nibble.take() {
+            self.write_byte(rs, (high << 4) | nibble);
+        } else {
+            self.high_nibble = Some(nibble);
+        }
+    }
+
+    fn write_byte(&mut self, rs: bool, v: u8) {
+        if rs {
+            if self.cgram_selected {
+                self.cgram[self.addr % CGRAM_SIZE] = v;
+            } else {
+                self.ddram[self.addr % DDRAM_SIZE] = v;
+                self.dirty = true;
+                self.last_write = crate::context::now();
+            }
+            self.advance_addr();
+            return;
+        }
+
+        trace!("{} cmd={:02x}", self.name, v);
+
+        if v & 0x80 != 0 {
+            self.maybe_log_content();
+            self.cgram_selected = false;
+            self.addr = (v & 0x7F) as usize;
+        } else if v & 0x40 != 0 {
+            self.cgram_selected = true;
+            self.addr = (v & 0x3F) as usize;
+        } else if v & 0x20 != 0 {
+            // Function set
+            self.four_bit_mode = v & 0x10 == 0;
+            self.high_nibble = None;
+            debug!("{} function set 4bit={} lines={}", self.name, self.four_bit_mode, if v & 0x08 != 0 { 2 } else { 1 });
+        } else if v & 0x10 != 0 {
+            // Cursor/display shift. Ignored.
+        } else if v & 0x08 != 0 {
+            self.display_on = v & 0x04 != 0;
+            debug!("{} display_on={}", self.name, self.display_on);
+            self.redraw();
+        } else if v & 0x04 != 0 {
+            self.increment = v & 0x02 != 0;
+        } else if v & 0x02 != 0 {
+            self.maybe_log_content();
+            self.addr = 0;
+            self.cgram_selected = false;
+        } else if v & 0x01 != 0 {
+            self.maybe_log_content();
+            self.ddram = [b' '; DDRAM_SIZE];
+            self.addr = 0;
+            self.increment = true;
+            self.cgram_selected = false;
+            self.dirty = true;
+        }
+    }
+
+    fn advance_addr(&mut self) {
+        self.addr = if self.increment {
+            self.addr.wrapping_add(1)
+        } else {
+            self.addr.wrapping_sub(1)
+        } % DDRAM_SIZE;
+    }
+
+    /// Returns the DDRAM address of the first character of a row
+    fn row_addr(&self, row: u8) -> usize {
+        let cols = self.config.columns as usize;
+        match row {
+            0 => 0x00,
+            1 => 0x40,
+            2 => cols,
+            _ => 0x40 + cols,
+        }
+    }
+
+    fn row_chars(&self, row: u8) -> &[u8] {
+        let start = self.row_addr(row);
+        &self.ddram[start..start + self.config.columns as usize]
+    }
+
+    fn content(&self) -> Vec<String> {
+        (0..self.config.rows).map(|row| {
+            self.row_chars(row).iter()
+                .map(|c| if (0x20..0x7F).contains(c) { *c as char } else { '?' })
+                .collect()
+        }).collect()
+    }
+
+    /// Logs and redraws the text written since the last commands, once the
+    /// firmware is done writing it
+    pub fn update(&mut self) {
+        let now = crate::context::now();
+        if self.dirty && now >= self.last_write + LOG_IDLE_CYCLES {
+            self.maybe_log_content();
+        }
+    }
+
+    pub fn maybe_log_content(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        self.dirty = false;
+
+        let content = self.content();
+        if content != self.last_logged {
+            for (row, line) in content.iter().enumerate() {
+                info!("{} row={} '{}'", self.name, row, line);
+            }
+            self.last_logged = content;
+            self.redraw();
+        }
+    }
+
+    fn redraw(&mut self) {
+        let fb = match self.framebuffer.as_ref() {
+            Some(fb) => fb.clone(),
+            None => return,
+        };
+        let mut fb = fb.borrow_mut();
+
+        const BACKGROUND: RGB565 = 0x9FE0;
+        const PIXEL_ON: RGB565 = 0x0140;
+        const PIXEL_OFF: RGB565 = 0x8F60;
+        // 5x8 characters, with 1 pixel of spacing
+        const CELL_W: usize = 6;
+        const CELL_H: usize = 9;
+
+        let width = fb.get_config().width as usize;
+        let height = fb.get_config().height as usize;
+        let cols = self.config.columns as usize;
+        let rows = self.config.rows as usize;
+        let scale = (width / (cols * CELL_W + 1)).min(height / (rows * CELL_H + 1)).max(1);
+
+        let pixels = fb.get_pixels();
+        pixels.fill(BACKGROUND);
+
+        for row in 0..rows {
+            let chars = self.row_chars(row as u8).to_vec();
+            for (col, c) in chars.into_iter().enumerate() {
+                let glyph = self.glyph(c);
+                for (y, glyph_row) in glyph.iter().enumerate() {
+                    for x in 0..5 {
+                        let on = self.display_on && glyph_row & (0x10 >> x) != 0;
+                        let color = if on { PIXEL_ON } else { PIXEL_OFF };
+                        let px = (1 + col * CELL_W + x) * scale;
+                        let py = (1 + row * CELL_H + y) * scale;
+                        for dy in 0..scale {
+                            for dx in 0..scale {
+                                if px + dx < width && py + dy < height {
+                                    pixels[(py + dy) * width + px + dx] = color;
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns 8 rows of 5 bits
+    fn glyph(&self, c: u8) -> [u8; 8] {
+        let mut rows = [0; 8];
+        if c < 0x10 {
+            // Custom characters from CGRAM
+            let i = (c as usize % 8) * 8;
+            rows.copy_from_slice(&self.cgram[i..i+8]);
+        } else if (0x20..0x80).contains(&c) {
+            // Font is stored in columns
+            let columns = &FONT_5X7[(c - 0x20) as usize];
+            for (x, column) in columns.iter().enumerate() {
+                for (y, row) in rows.iter_mut().enumerate().take(7) {
+                    if column & (1 << y) != 0 {
+                        *row |= 0x10 >> x;
+                    }
+                }
+            }
+        }
+        rows
+    }
+}
+
+impl ExtDevice<(), u8> for Hd44780 {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} hd44780", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        // Busy flag is never set
+        0x0F
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        // PCF8574 backpack: P0=RS, P1=RW, P2=E, P3=backlight, P4-P7=D4-D7
+        self.rs = v & 0x01 != 0;
+        self.data = v >> 4;
+        self.write_e(v & 0x04 != 0);
+    }
+}
+
+impl I2cDevice for Hd44780 {
+    fn address(&self) -> u8 {
+        self.config.address.unwrap_or(0x27)
+    }
+}
+
+// Characters 0x20 to 0x7F, 5 columns each, LSB at the top.
+const FONT_5X7: [[u8; 5]; 96] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00], [0x00, 0x00, 0x5F, 0x00, 0x00], [0x00, 0x07, 0x00, 0x07, 0x00], [0x14, 0x7F, 0x14, 0x7F, 0x14],
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12], [0x23, 0x13, 0x08, 0x64, 0x62], [0x36, 0x49, 0x55, 0x22, 0x50], [0x00, 0x05, 0x03, 0x00, 0x00],
+    [0x00, 0x1C, 0x22, 0x41, 0x00], [0x00, 0x41, 0x22, 0x1C, 0x00], [0x08, 0x2A, 0x1C, 0x2A, 0x08], [0x08, 0x08, 0x3E, 0x08, 0x08],
+    [0x00, 0x50, 0x30, 0x00, 0x00], [0x08, 0x08, 0x08, 0x08, 0x08], [0x00, 0x60, 0x60, 0x00, 0x00], [0x20, 0x10, 0x08, 0x04, 0x02],
+    [0x3E, 0x51, 0x49, 0x45, 0x3E], [0x00, 0x42, 0x7F, 0x40, 0x00], [0x42, 0x61, 0x51, 0x49, 0x46], [0x21, 0x41, 0x45, 0x4B, 0x31],
+    [0x18, 0x14, 0x12, 0x7F, 0x10], [0x27, 0x45, 0x45, 0x45, 0x39], [0x3C, 0x4A, 0x49, 0x49, 0x30], [0x01, 0x71, 0x09, 0x05, 0x03],
+    [0x36, 0x49, 0x49, 0x49, 0x36], [0x06, 0x49, 0x49, 0x29, 0x1E], [0x00, 0x36, 0x36, 0x00, 0x00], [0x00, 0x56, 0x36, 0x00, 0x00],
+    [0x08, 0x14, 0x22, 0x41, 0x00], [0x14, 0x14, 0x14, 0x14, 0x14], [0x00, 0x41, 0x22, 0x14, 0x08], [0x02, 0x01, 0x51, 0x09, 0x06],
+    [0x32, 0x49, 0x79, 0x41, 0x3E], [0x7E, 0x11, 0x11, 0x11, 0x7E], [0x7F, 0x49, 0x49, 0x49, 0x36], [0x3E, 0x41, 0x41, 0x41, 0x22],
+    [0x7F, 0x41, 0x41, 0x22, 0x1C], [0x7F, 0x49, 0x49, 0x49, 0x41], [0x7F, 0x09, 0x09, 0x01, 0x01], [0x3E, 0x41, 0x41, 0x51, 0x32],
+    [0x7F, 0x08, 0x08, 0x08, 0x7F], [0x00, 0x41, 0x7F, 0x41, 0x00], [0x20, 0x40, 0x41, 0x3F, 0x01], [0x7F, 0x08, 0x14, 0x22, 0x41],
+    [0x7F, 0x40, 0x40, 0x40, 0x40], [0x7F, 0x02, 0x04, 0x02, 0x7F], [0x7F, 0x04, 0x08, 0x10, 0x7F], [0x3E, 0x41, 0x41, 0x41, 0x3E],
+    [0x7F, 0x09, 0x09, 0x09, 0x06], [0x3E, 0x41, 0x51, 0x21, 0x5E], [0x7F, 0x09, 0x19, 0x29, 0x46], [0x46, 0x49, 0x49, 0x49, 0x31],
+    [0x01, 0x01, 0x7F, 0x01, 0x01], [0x3F, 0x40, 0x40, 0x40, 0x3F], [0x1F, 0x20, 0x40, 0x20, 0x1F], [0x7F, 0x20, 0x18, 0x20, 0x7F],
+    [0x63, 0x14, 0x08, 0x14, 0x63], [0x03, 0x04, 0x78, 0x04, 0x03], [0x61, 0x51, 0x49, 0x45, 0x43], [0x00, 0x00, 0x7F, 0x41, 0x41],
+    [0x02, 0x04, 0x08, 0x10, 0x20], [0x41, 0x41, 0x7F, 0x00, 0x00], [0x04, 0x02, 0x01, 0x02, 0x04], [0x40, 0x40, 0x40, 0x40, 0x40],
+    [0x00, 0x01, 0x02, 0x04, 0x00], [0x20, 0x54, 0x54, 0x54, 0x78], [0x7F, 0x48, 0x44, 0x44, 0x38], [0x38, 0x44, 0x44, 0x44, 0x20],
+    [0x38, 0x44, 0x44, 0x48, 0x7F], [0x38, 0x54, 0x54, 0x54, 0x18], [0x08, 0x7E, 0x09, 0x01, 0x02], [0x08, 0x14, 0x54, 0x54, 0x3C],
+    [0x7F, 0x08, 0x04, 0x04, 0x78], [0x00, 0x44, 0x7D, 0x40, 0x00], [0x20, 0x40, 0x44, 0x3D, 0x00], [0x00, 0x7F, 0x10, 0x28, 0x44],
+    [0x00, 0x41, 0x7F, 0x40, 0x00], [0x7C, 0x04, 0x18, 0x04, 0x78], [0x7C, 0x08, 0x04, 0x04, 0x78], [0x38, 0x44, 0x44, 0x44, 0x38],
+    [0x7C, 0x14, 0x14, 0x14, 0x08], [0x08, 0x14, 0x14, 0x18, 0x7C], [0x7C, 0x08, 0x04, 0x04, 0x08], [0x48, 0x54, 0x54, 0x54, 0x20],
+    [0x04, 0x3F, 0x44, 0x40, 0x20], [0x3C, 0x40, 0x40, 0x20, 0x7C], [0x1C, 0x20, 0x40, 0x20, 0x1C], [0x3C, 0x40, 0x30, 0x40, 0x3C],
+    [0x44, 0x28, 0x10, 0x28, 0x44], [0x0C, 0x50, 0x50, 0x50, 0x3C], [0x44, 0x64, 0x54, 0x4C, 0x44], [0x00, 0x08, 0x36, 0x41, 0x00],
+    [0x00, 0x00, 0x7F, 0x00, 0x00], [0x00, 0x41, 0x36, 0x08, 0x00], [0x08, 0x08, 0x2A, 0x1C, 0x08], [0x08, 0x1C, 0x2A, 0x08, 0x08],
+];
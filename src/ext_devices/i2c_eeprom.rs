@@ -0,0 +1,91 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{util, system::System};
+
+use super::{ExtDevice, I2cDevice};
+
+// Implements a 24Cxx I2C EEPROM
+
+#[derive(Debug, Deserialize, Default)]
+pub struct I2cEepromConfig {
+    pub peripheral: String,
+    pub address: Option<u8>,
+    pub size: usize,
+    pub file: Option<String>,
+    /// Number of bytes used to address the memory. Defaults to 1 for sizes up to 2KB, 2 otherwise.
+    pub addr_bytes: Option<u8>,
+}
+
+#[derive(Default)]
+pub struct I2cEeprom {
+    pub config: I2cEepromConfig,
+    name: String,
+    content: Vec<u8>,
+
+    addr: usize,
+    // Number of address bytes we still expect in the current write transaction
+    addr_bytes_pending: u8,
+}
+
+impl I2cEeprom {
+    pub fn new(config: I2cEepromConfig) -> Result<Self> {
+        let mut content = if let Some(ref file) = config.file {
+            util::read_file(file)
+                .with_context(|| format!("Failed to read {}", file))?
+        } else {
+            vec![]
+        };
+
+        content.resize(config.size, 0xFF);
+
+        Ok(Self { config, content, ..Self::default() })
+    }
+
+    fn addr_bytes(&self) -> u8 {
+        self.config.addr_bytes.unwrap_or(if self.config.size > 2048 { 2 } else { 1 })
+    }
+}
+
+impl ExtDevice<(), u8> for I2cEeprom {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} i2c-eeprom", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        let v = self.content[self.addr];
+        self.addr = (self.addr + 1) % self.config.size;
+        v
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        if self.addr_bytes_pending > 0 {
+            self.addr = (self.addr << 8) | v as usize;
+            self.addr_bytes_pending -= 1;
+            if self.addr_bytes_pending == 0 {
+                self.addr %= self.config.size;
+                trace!("{} addr=0x{:04x}", self.name, self.addr);
+            }
+        } else {
+            trace!("{} write addr=0x{:04x} value={:02x}", self.name, self.addr, v);
+            self.content[self.addr] = v;
+            self.addr = (self.addr + 1) % self.config.size;
+        }
+    }
+}
+
+impl I2cDevice for I2cEeprom {
+    fn address(&self) -> u8 {
+        self.config.address.unwrap_or(0x50)
+    }
+
+    fn start(&mut self, _sys: &System, read: bool) {
+        if !read {
+            self.addr = 0;
+            self.addr_bytes_pending = self.addr_bytes();
+        }
+    }
+}
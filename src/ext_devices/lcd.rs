@@ -12,6 +12,8 @@ use super::ExtDevice;
 pub struct LcdConfig {
     pub peripheral: String,
     pub framebuffer: String,
+    /// Chip select pin, or "nss", when sharing the bus
+    pub cs: Option<String>,
 }
 
 pub struct Lcd {
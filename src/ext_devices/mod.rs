@@ -5,18 +5,54 @@ mod usart_probe;
 mod display;
 mod lcd;
 mod touchscreen;
+mod cap_touch;
+mod i2c_eeprom;
+mod ds18b20;
+mod hd44780;
+mod waveform;
+mod modbus;
+mod esp_at;
+mod cellular;
+mod nrf24l01;
+mod w5500;
+mod spi_sd_card;
+mod nvram;
+mod fsmc_ram;
+mod spi_master;
+mod socket;
+mod faults;
+mod thermal;
+mod thermocouple;
+pub mod wiring;
+pub mod background;
 
 use spi_flash::{SpiFlashConfig, SpiFlash};
-use usart_probe::{UsartProbeConfig, UsartProbe};
+pub use usart_probe::{UsartProbeConfig, UsartProbe};
 use display::{DisplayConfig, Display};
 use lcd::{LcdConfig, Lcd};
 use touchscreen::{TouchscreenConfig, Touchscreen};
+use cap_touch::{CapTouchConfig, CapTouch};
+use i2c_eeprom::{I2cEepromConfig, I2cEeprom};
+use ds18b20::{Ds18b20Config, Ds18b20};
+use hd44780::{Hd44780Config, Hd44780};
+use waveform::{WaveformConfig, Waveform};
+use modbus::{ModbusSlaveConfig, ModbusSlave};
+use esp_at::{EspAtConfig, EspAt};
+use cellular::{CellularModemConfig, CellularModem};
+use nrf24l01::{Nrf24l01Config, Nrf24l01};
+use w5500::{W5500Config, W5500};
+use spi_sd_card::{SpiSdCardConfig, SpiSdCard};
+use nvram::{NvramConfig, Nvram};
+use fsmc_ram::{FsmcRamConfig, FsmcRam};
+pub use spi_master::{SpiMasterConfig, SpiMaster};
+use thermal::{ThermalConfig, Thermal};
+use thermocouple::{ThermocoupleConfig, Thermocouple};
 
-use std::{rc::Rc, cell::RefCell};
+use std::{rc::Rc, cell::{Cell, RefCell}};
 use serde::Deserialize;
-use anyhow::Result;
+use anyhow::{Context, Result};
 
-use crate::{system::System, framebuffers::Framebuffers, peripherals::gpio::GpioPorts};
+use crate::{system::System, framebuffers::Framebuffers, peripherals::gpio::{GpioPorts, Pin}};
 
 
 #[derive(Debug, Deserialize, Default)]
@@ -26,6 +62,22 @@ pub struct ExtDevicesConfig {
     pub display: Option<Vec<DisplayConfig>>,
     pub lcd: Option<Vec<LcdConfig>>,
     pub touchscreen: Option<Vec<TouchscreenConfig>>,
+    pub cap_touch: Option<Vec<CapTouchConfig>>,
+    pub i2c_eeprom: Option<Vec<I2cEepromConfig>>,
+    pub ds18b20: Option<Vec<Ds18b20Config>>,
+    pub hd44780: Option<Vec<Hd44780Config>>,
+    pub waveform: Option<Vec<WaveformConfig>>,
+    pub modbus_slave: Option<Vec<ModbusSlaveConfig>>,
+    pub esp_at: Option<Vec<EspAtConfig>>,
+    pub cellular_modem: Option<Vec<CellularModemConfig>>,
+    pub nrf24l01: Option<Vec<Nrf24l01Config>>,
+    pub w5500: Option<Vec<W5500Config>>,
+    pub spi_sd_card: Option<Vec<SpiSdCardConfig>>,
+    pub nvram: Option<Vec<NvramConfig>>,
+    pub fsmc_ram: Option<Vec<FsmcRamConfig>>,
+    pub spi_master: Option<Vec<SpiMasterConfig>>,
+    pub thermal: Option<Vec<ThermalConfig>>,
+    pub thermocouple: Option<Vec<ThermocoupleConfig>>,
 }
 
 pub struct ExtDevices {
@@ -34,9 +86,88 @@ pub struct ExtDevices {
     pub displays: Vec<Rc<RefCell<Display>>>,
     pub lcds: Vec<Rc<RefCell<Lcd>>>,
     pub touchscreens: Vec<Rc<RefCell<Touchscreen>>>,
+    pub cap_touches: Vec<Rc<RefCell<CapTouch>>>,
+    pub i2c_eeproms: Vec<Rc<RefCell<I2cEeprom>>>,
+    pub ds18b20s: Vec<Rc<RefCell<Ds18b20>>>,
+    pub hd44780s: Vec<Rc<RefCell<Hd44780>>>,
+    pub waveforms: Vec<Rc<RefCell<Waveform>>>,
+    pub modbus_slaves: Vec<Rc<RefCell<ModbusSlave>>>,
+    pub esp_ats: Vec<Rc<RefCell<EspAt>>>,
+    pub cellular_modems: Vec<Rc<RefCell<CellularModem>>>,
+    pub nrf24l01s: Vec<Rc<RefCell<Nrf24l01>>>,
+    pub w5500s: Vec<Rc<RefCell<W5500>>>,
+    pub spi_sd_cards: Vec<Rc<RefCell<SpiSdCard>>>,
+    pub nvrams: Vec<Rc<RefCell<Nvram>>>,
+    pub fsmc_rams: Vec<Rc<RefCell<FsmcRam>>>,
+    pub spi_masters: Vec<Rc<RefCell<SpiMaster>>>,
+    pub thermals: Vec<Rc<RefCell<Thermal>>>,
+    pub thermocouples: Vec<Rc<RefCell<Thermocouple>>>,
+    // peripheral name, device
+    pub spi_devices: Vec<(String, SpiBusDevice)>,
+    pub custom: CustomDevices,
+}
+
+type SerialDevice = Rc<RefCell<dyn ExtDevice<(), u8>>>;
+
+/// External devices implemented by the users of the library, connected like
+/// the ones of the config
+#[derive(Default)]
+pub struct CustomDevices {
+    // peripheral name, device
+    serial: Vec<(String, SerialDevice)>,
+    i2c: Vec<(String, Rc<RefCell<dyn I2cDevice>>)>,
+}
+
+impl CustomDevices {
+    /// A device on a USART, or alone on a SPI bus. It's returned to look at
+    /// its state during the emulation.
+    pub fn add_serial<D: ExtDevice<(), u8> + 'static>(&mut self, peripheral: &str, device: D) -> Rc<RefCell<D>> {
+        let device = Rc::new(RefCell::new(device));
+        self.serial.push((peripheral.to_string(), device.clone()));
+        device
+    }
+
+    /// A device on an I2C bus, at its address
+    pub fn add_i2c<D: I2cDevice + 'static>(&mut self, peripheral: &str, device: D) -> Rc<RefCell<D>> {
+        let device = Rc::new(RefCell::new(device));
+        self.i2c.push((peripheral.to_string(), device.clone()));
+        device
+    }
+}
+
+/// How a device on a SPI bus is selected
+#[derive(Clone)]
+pub enum ChipSelect {
+    /// Always selected, when it's alone on the bus
+    Always,
+    /// Selected while the GPIO pin is driven low. The level is tracked with a write callback.
+    Pin(Rc<Cell<bool>>),
+    /// Selected by the NSS output of the SPI peripheral, when SSOE is set
+    Nss,
+}
+
+#[derive(Clone)]
+pub struct SpiBusDevice {
+    pub device: Rc<RefCell<dyn ExtDevice<(), u8>>>,
+    pub cs: ChipSelect,
 }
 
 impl ExtDevices {
+    pub fn add_custom(&mut self, custom: CustomDevices) {
+        for (peripheral, device) in &custom.serial {
+            self.spi_devices.push((peripheral.clone(), SpiBusDevice { device: device.clone(), cs: ChipSelect::Always }));
+        }
+        self.custom = custom;
+    }
+
+    /// Several devices can share a SPI bus, they are selected by their chip select pin (`cs`).
+    pub fn find_spi_devices(&self, peri_name: &str) -> Vec<SpiBusDevice> {
+        self.spi_devices.iter()
+            .filter(|(peripheral, _)| peripheral == peri_name)
+            .map(|(_, d)| d.clone())
+            .collect()
+    }
+
     pub fn find_serial_device(&self, peri_name: &str) -> Option<Rc<RefCell<dyn ExtDevice<(), u8>>>> {
         self.spi_flashes.iter()
             .filter(|d| d.borrow().config.peripheral == peri_name)
@@ -60,6 +191,46 @@ impl ExtDevices {
             .next()
             .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<(), u8>>>)
        )
+        .or_else(||
+        self.modbus_slaves.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<(), u8>>>)
+       )
+        .or_else(||
+        self.esp_ats.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<(), u8>>>)
+       )
+        .or_else(||
+        self.cellular_modems.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<(), u8>>>)
+       )
+        .or_else(||
+        self.nrf24l01s.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<(), u8>>>)
+       )
+        .or_else(||
+        self.w5500s.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<(), u8>>>)
+       )
+        .or_else(||
+        self.spi_sd_cards.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<(), u8>>>)
+       )
+        .or_else(||
+        self.nvrams.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name && d.borrow().config.cs.is_some())
+            .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<(), u8>>>)
+       )
+        .or_else(||
+        self.custom.serial.iter()
+            .find(|(peripheral, _)| peripheral == peri_name)
+            .map(|(_, d)| d.clone())
+       )
     }
 
     pub fn find_mem_device(&self, peri_name: &str) -> Option<Rc<RefCell<dyn ExtDevice<u32, u32>>>> {
@@ -67,32 +238,288 @@ impl ExtDevices {
             .filter(|d| d.borrow().config.peripheral == peri_name)
             .next()
             .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<u32, u32>>>)
+        .or_else(||
+        self.fsmc_rams.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<u32, u32>>>)
+       )
+    }
+
+    /// The master clocking a SPI peripheral in slave mode
+    pub fn find_spi_master(&self, peri_name: &str) -> Option<Rc<RefCell<SpiMaster>>> {
+        self.spi_masters.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name)
+            .cloned()
+    }
+
+    /// Several I2C devices can share the same bus, they are selected by their address.
+    pub fn find_i2c_devices(&self, peri_name: &str) -> Vec<Rc<RefCell<dyn I2cDevice>>> {
+        let i2c_eeproms = self.i2c_eeproms.iter()
+            .filter(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn I2cDevice>>);
+
+        let hd44780s = self.hd44780s.iter()
+            .filter(|d| d.borrow().config.peripheral.as_deref() == Some(peri_name))
+            .map(|d| d.clone() as Rc<RefCell<dyn I2cDevice>>);
+
+        let nvrams = self.nvrams.iter()
+            .filter(|d| d.borrow().config.peripheral == peri_name && d.borrow().config.cs.is_none())
+            .map(|d| d.clone() as Rc<RefCell<dyn I2cDevice>>);
+
+        let cap_touches = self.cap_touches.iter()
+            .filter(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn I2cDevice>>);
+
+        let custom = self.custom.i2c.iter()
+            .filter(|(peripheral, _)| peripheral == peri_name)
+            .map(|(_, d)| d.clone());
+
+        i2c_eeproms.chain(hd44780s).chain(nvrams).chain(cap_touches).chain(custom).collect()
+    }
+
+    pub fn find_analog_device(&self, peri_name: &str) -> Option<Rc<RefCell<dyn ExtDevice<u8, u16>>>> {
+        self.waveforms.iter()
+            .find(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn ExtDevice<u8, u16>>>)
+    }
+
+    /// A thermal model, by name
+    pub fn find_thermal(&self, name: &str) -> Option<Rc<RefCell<Thermal>>> {
+        self.thermals.iter()
+            .find(|d| d.borrow().config.name == name)
+            .cloned()
+    }
+
+    /// Integrates the thermal models, and writes their outputs
+    pub fn update_thermals(&self, sys: &System) {
+        for d in &self.thermals {
+            let mut d = d.borrow_mut();
+            if let Err(e) = d.update(sys) {
+                warn!("Thermal {} failed to write its output: {:#}", d.config.name, e);
+            }
+        }
+    }
+
+    /// Shows the text of the character LCDs. When `flush`, the last text
+    /// is shown even if the firmware may still be writing it.
+    pub fn update_hd44780s(&self, flush: bool) {
+        for d in &self.hd44780s {
+            let mut d = d.borrow_mut();
+            if flush {
+                d.maybe_log_content();
+            } else {
+                d.update();
+            }
+        }
+    }
+
+    pub fn find_onewire_devices(&self, peri_name: &str) -> Vec<Rc<RefCell<dyn OneWireDevice>>> {
+        self.ds18b20s.iter()
+            .filter(|d| d.borrow().config.peripheral == peri_name)
+            .map(|d| d.clone() as Rc<RefCell<dyn OneWireDevice>>)
+            .collect()
+    }
+
+    /// Writes back the persistent memories when the emulation stops
+    pub fn save(&self, clean_exit: bool) -> Result<()> {
+        for d in &self.nvrams {
+            d.borrow_mut().save(clean_exit)?;
+        }
+        for d in &self.fsmc_rams {
+            d.borrow_mut().save(clean_exit)?;
+        }
+        Ok(())
     }
 }
 
+/// How a device is wired, used for validating the config
+pub struct Connection<'a> {
+    pub device: &'static str,
+    pub peripheral: Option<&'a str>,
+    pub framebuffer: Option<&'a str>,
+}
+
 impl ExtDevicesConfig {
+    pub fn connections(&self) -> Vec<Connection<'_>> {
+        let mut connections = vec![];
+
+        macro_rules! add {
+            ($field:ident, $device:expr, |$c:ident| $peripheral:expr, $framebuffer:expr) => {
+                for $c in self.$field.iter().flatten() {
+                    connections.push(Connection { device: $device, peripheral: $peripheral, framebuffer: $framebuffer });
+                }
+            };
+        }
+
+        add!(spi_flash, "spi_flash", |c| Some(&c.peripheral), None);
+        add!(usart_probe, "usart_probe", |c| Some(&c.peripheral), None);
+        add!(display, "display", |c| Some(&c.peripheral), Some(&c.framebuffer));
+        add!(lcd, "lcd", |c| Some(&c.peripheral), Some(&c.framebuffer));
+        add!(touchscreen, "touchscreen", |c| Some(&c.peripheral), Some(&c.framebuffer));
+        add!(cap_touch, "cap_touch", |c| Some(&c.peripheral), Some(&c.framebuffer));
+        add!(i2c_eeprom, "i2c_eeprom", |c| Some(&c.peripheral), None);
+        add!(ds18b20, "ds18b20", |c| Some(&c.peripheral), None);
+        add!(hd44780, "hd44780", |c| c.peripheral.as_deref(), c.framebuffer.as_deref());
+        add!(waveform, "waveform", |c| Some(&c.peripheral), None);
+        add!(modbus_slave, "modbus_slave", |c| Some(&c.peripheral), None);
+        add!(esp_at, "esp_at", |c| Some(&c.peripheral), None);
+        add!(cellular_modem, "cellular_modem", |c| Some(&c.peripheral), None);
+        add!(nrf24l01, "nrf24l01", |c| Some(&c.peripheral), None);
+        add!(w5500, "w5500", |c| Some(&c.peripheral), None);
+        add!(spi_sd_card, "spi_sd_card", |c| Some(&c.peripheral), None);
+        add!(nvram, "nvram", |c| Some(&c.peripheral), None);
+        add!(fsmc_ram, "fsmc_ram", |c| Some(&c.peripheral), None);
+        add!(spi_master, "spi_master", |c| Some(&c.peripheral), None);
+        add!(thermocouple, "thermocouple", |c| Some(&c.peripheral), None);
+
+        connections
+    }
+
     pub fn into_ext_devices(self, gpio: &mut GpioPorts, framebuffers: &Framebuffers) -> Result<ExtDevices> {
-        let spi_flashes = self.spi_flash.unwrap_or_default().into_iter()
+        let spi_flashes: Vec<Rc<RefCell<SpiFlash>>> = self.spi_flash.unwrap_or_default().into_iter()
             .map(|config| SpiFlash::new(config).map(RefCell::new).map(Rc::new))
             .collect::<Result<_>>()?;
 
-        let usart_probes = self.usart_probe.unwrap_or_default().into_iter()
+        let usart_probes: Vec<Rc<RefCell<UsartProbe>>> = self.usart_probe.unwrap_or_default().into_iter()
             .map(|config| UsartProbe::new(config).map(RefCell::new).map(Rc::new))
             .collect::<Result<_>>()?;
 
         let displays = self.display.unwrap_or_default().into_iter()
-            .map(|config| Display::new(config, framebuffers).map(RefCell::new).map(Rc::new))
+            .map(|config| Display::new(config, gpio, framebuffers))
             .collect::<Result<_>>()?;
 
-        let lcds = self.lcd.unwrap_or_default().into_iter()
+        let lcds: Vec<Rc<RefCell<Lcd>>> = self.lcd.unwrap_or_default().into_iter()
             .map(|config| Lcd::new(config, framebuffers).map(RefCell::new).map(Rc::new))
             .collect::<Result<_>>()?;
 
-        let touchscreens = self.touchscreen.unwrap_or_default().into_iter()
+        let touchscreens: Vec<Rc<RefCell<Touchscreen>>> = self.touchscreen.unwrap_or_default().into_iter()
             .map(|config| Touchscreen::new(config, gpio, framebuffers).map(RefCell::new).map(Rc::new))
             .collect::<Result<_>>()?;
 
-        Ok(ExtDevices { spi_flashes, usart_probes, displays, lcds, touchscreens })
+        let cap_touches = self.cap_touch.unwrap_or_default().into_iter()
+            .map(|config| CapTouch::new(config, gpio, framebuffers))
+            .collect::<Result<_>>()?;
+
+        let i2c_eeproms = self.i2c_eeprom.unwrap_or_default().into_iter()
+            .map(|config| I2cEeprom::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let ds18b20s = self.ds18b20.unwrap_or_default().into_iter()
+            .map(|config| Ds18b20::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let hd44780s = self.hd44780.unwrap_or_default().into_iter()
+            .map(|config| Hd44780::new(config, gpio, framebuffers))
+            .collect::<Result<_>>()?;
+
+        let waveforms = self.waveform.unwrap_or_default().into_iter()
+            .map(|config| Waveform::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let modbus_slaves = self.modbus_slave.unwrap_or_default().into_iter()
+            .map(|config| ModbusSlave::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let esp_ats = self.esp_at.unwrap_or_default().into_iter()
+            .map(|config| EspAt::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let cellular_modems = self.cellular_modem.unwrap_or_default().into_iter()
+            .map(|config| CellularModem::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let nrf24l01s: Vec<Rc<RefCell<Nrf24l01>>> = self.nrf24l01.unwrap_or_default().into_iter()
+            .map(|config| Nrf24l01::new(config, gpio))
+            .collect::<Result<_>>()?;
+
+        let w5500s: Vec<Rc<RefCell<W5500>>> = self.w5500.unwrap_or_default().into_iter()
+            .map(|config| W5500::new(config, gpio))
+            .collect::<Result<_>>()?;
+
+        let spi_sd_cards: Vec<Rc<RefCell<SpiSdCard>>> = self.spi_sd_card.unwrap_or_default().into_iter()
+            .map(|config| SpiSdCard::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let nvrams: Vec<Rc<RefCell<Nvram>>> = self.nvram.unwrap_or_default().into_iter()
+            .map(|config| Nvram::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let fsmc_rams = self.fsmc_ram.unwrap_or_default().into_iter()
+            .map(|config| FsmcRam::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let spi_masters = self.spi_master.unwrap_or_default().into_iter()
+            .map(|config| SpiMaster::new(config).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let thermals = self.thermal.unwrap_or_default().into_iter()
+            .map(|config| Thermal::new(config, gpio).map(RefCell::new).map(Rc::new))
+            .collect::<Result<Vec<_>>>()?;
+
+        let thermocouples: Vec<Rc<RefCell<Thermocouple>>> = self.thermocouple.unwrap_or_default().into_iter()
+            .map(|config| Thermocouple::new(config, &thermals).map(RefCell::new).map(Rc::new))
+            .collect::<Result<_>>()?;
+
+        let mut spi_devices = vec![];
+        let mut add_spi_device = |peripheral: &str, cs: Option<&String>, device: Rc<RefCell<dyn ExtDevice<(), u8>>>| -> Result<()> {
+            let cs = match cs.map(|cs| cs.as_str()) {
+                None => ChipSelect::Always,
+                Some(cs) if cs.eq_ignore_ascii_case("nss") => ChipSelect::Nss,
+                Some(cs) => {
+                    let pin = Pin::parse(cs).with_context(|| format!("Invalid chip select pin {}", cs))?;
+                    // Deselected until the firmware drives the pin low
+                    let high = Rc::new(Cell::new(true));
+                    let (h, d) = (high.clone(), device.clone());
+                    gpio.add_write_callback(pin, move |sys, v| {
+                        if h.replace(v) != v {
+                            d.borrow_mut().select(sys, !v);
+                        }
+                    });
+                    ChipSelect::Pin(high)
+                }
+            };
+            spi_devices.push((peripheral.to_string(), SpiBusDevice { device, cs }));
+            Ok(())
+        };
+
+        for d in &spi_flashes {
+            let c = &d.borrow().config;
+            add_spi_device(&c.peripheral, c.cs.as_ref(), d.clone())?;
+        }
+        for d in &lcds {
+            let c = &d.borrow().config;
+            add_spi_device(&c.peripheral, c.cs.as_ref(), d.clone())?;
+        }
+        for d in &touchscreens {
+            let c = &d.borrow().config;
+            add_spi_device(&c.peripheral, c.cs.as_ref(), d.clone())?;
+        }
+        for d in &nrf24l01s {
+            let c = &d.borrow().config;
+            add_spi_device(&c.peripheral, Some(&c.cs), d.clone())?;
+        }
+        for d in &w5500s {
+            let c = &d.borrow().config;
+            add_spi_device(&c.peripheral, Some(&c.cs), d.clone())?;
+        }
+        for d in &spi_sd_cards {
+            let c = &d.borrow().config;
+            add_spi_device(&c.peripheral, c.cs.as_ref(), d.clone())?;
+        }
+        for d in &thermocouples {
+            let c = &d.borrow().config;
+            add_spi_device(&c.peripheral, c.cs.as_ref(), d.clone())?;
+        }
+        for d in nvrams.iter().filter(|d| d.borrow().config.cs.is_some()) {
+            let c = &d.borrow().config;
+            add_spi_device(&c.peripheral, c.cs.as_ref(), d.clone())?;
+        }
+        // Probes sniff the bus
+        for d in &usart_probes {
+            add_spi_device(&d.borrow().config.peripheral, None, d.clone())?;
+        }
+
+        Ok(ExtDevices { spi_flashes, usart_probes, displays, lcds, touchscreens, cap_touches, i2c_eeproms, ds18b20s, hd44780s, waveforms, modbus_slaves, esp_ats, cellular_modems, nrf24l01s, w5500s, spi_sd_cards, nvrams, fsmc_rams, spi_masters, thermals, thermocouples, spi_devices, custom: Default::default() })
     }
 }
 
@@ -103,4 +530,34 @@ pub trait ExtDevice<A, T> {
     fn connect_peripheral<'a>(&mut self, peri_name: &str) -> String;
     fn read(&mut self, sys: &System, addr: A) -> T;
     fn write(&mut self, sys: &System, addr: A, v: T);
+    /// For memory devices. Only the low `size` bytes of `v` are written.
+    fn write_sized(&mut self, sys: &System, addr: A, v: T, _size: u8) { self.write(sys, addr, v) }
+    /// For memory devices. Halfwords written in a row at the same address,
+    /// like the pixels of a DMA transfer to a display.
+    fn write_block(&mut self, sys: &System, addr: A, values: &[u16]) where A: Copy, T: From<u16> {
+        for v in values {
+            self.write_sized(sys, addr, T::from(*v), 2);
+        }
+    }
+    /// For serial devices. Returns true when the device has data to send to the peripheral.
+    fn has_data(&mut self, _sys: &System) -> bool { false }
+    /// For SPI devices. Called when the chip select changes.
+    fn select(&mut self, _sys: &System, _selected: bool) {}
+}
+
+pub trait I2cDevice: ExtDevice<(), u8> {
+    /// 7-bit address the device responds to
+    fn address(&self) -> u8;
+    /// Called when the device gets addressed after a START condition
+    fn start(&mut self, _sys: &System, _read: bool) {}
+    /// Called on the STOP condition
+    fn stop(&mut self, _sys: &System) {}
+}
+
+/// 1-Wire devices work at the bit level. read() returns what the device drives
+/// during the next time slot (false pulls the line low), and write() receives
+/// the resolved bit of the time slot.
+pub trait OneWireDevice: ExtDevice<(), bool> {
+    /// Returns true to signal presence
+    fn reset(&mut self, sys: &System) -> bool;
 }
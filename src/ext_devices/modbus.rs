@@ -0,0 +1,221 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::{BTreeMap, VecDeque}};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::system::System;
+
+use super::ExtDevice;
+
+// A Modbus RTU slave on a USART, serving holding registers from the config:
+//
+//  modbus_slave:
+//    - peripheral: USART3
+//      address: 1
+//      baud: 9600            # for the frame gaps, and pacing the responses
+//      holding_registers:
+//        0: 1234
+//        1: 0x00FF
+//
+// Read holding registers (0x03), write single register (0x06), and write
+// multiple registers (0x10) are supported. Requests to the broadcast
+// address 0 are executed without a response.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ModbusSlaveConfig {
+    pub peripheral: String,
+    pub address: u8,
+    /// Defaults to 9600
+    pub baud: Option<u32>,
+    pub holding_registers: Option<BTreeMap<u16, u16>>,
+}
+
+const DEFAULT_BAUD: u32 = 9600;
+// Start, 8 data bits, parity (or a second stop bit), and stop
+const BITS_PER_CHAR: u64 = 11;
+const BROADCAST_ADDRESS: u8 = 0;
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const WRITE_SINGLE_REGISTER: u8 = 0x06;
+const WRITE_MULTIPLE_REGISTERS: u8 = 0x10;
+
+const ILLEGAL_FUNCTION: u8 = 0x01;
+const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+const ILLEGAL_DATA_VALUE: u8 = 0x03;
+
+// Maximum number of registers in a read request
+const MAX_READ_COUNT: u16 = 125;
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFF;
+    for b in data {
+        crc ^= *b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
+fn now() -> u64 {
+    crate::context::now()
+}
+
+#[derive(Default)]
+pub struct ModbusSlave {
+    pub config: ModbusSlaveConfig,
+    name: String,
+    registers: BTreeMap<u16, u16>,
+    request: Vec<u8>,
+    last_rx_at: u64,
+    response: VecDeque<u8>,
+    // Emulated time at which the next response byte arrives
+    next_tx_at: u64,
+}
+
+impl ModbusSlave {
+    pub fn new(mut config: ModbusSlaveConfig) -> Result<Self> {
+        let registers = config.holding_registers.take().unwrap_or_default();
+        Ok(Self { config, registers, ..Self::default() })
+    }
+
+    /// Duration of a character, in CPU cycles
+    fn char_time(&self, sys: &System) -> u64 {
+        let baud = self.config.baud.filter(|b| *b > 0).unwrap_or(DEFAULT_BAUD) as u64;
+        BITS_PER_CHAR * sys.p.rcc.borrow().hclk() as u64 / baud
+    }
+
+    /// Length of the request frame, CRC included, once we have enough of it to tell
+    fn request_len(&self) -> Option<usize> {
+        match *self.request.get(1)? {
+            READ_HOLDING_REGISTERS | WRITE_SINGLE_REGISTER => Some(8),
+            WRITE_MULTIPLE_REGISTERS => Some(9 + *self.request.get(6)? as usize),
+            // We can't tell, the frame ends with the silent interval
+            _ => None,
+        }
+    }
+
+    fn reg(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.request[offset], self.request[offset+1]])
+    }
+
+    /// Returns the response PDU, or an exception code
+    fn execute(&mut self) -> Result<Vec<u8>, u8> {
+        let function = self.request[1];
+        let pdu = match function {
+            READ_HOLDING_REGISTERS => {
+                let (addr, count) = (self.reg(2), self.reg(4));
+                if count == 0 || count > MAX_READ_COUNT {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                let values = (addr..addr.saturating_add(count))
+                    .map(|a| self.registers.get(&a).copied().ok_or(ILLEGAL_DATA_ADDRESS))
+                    .collect::<Result<Vec<_>, _>>()?;
+                debug!("{} read addr={} count={} values={:?}", self.name, addr, count, values);
+                let mut pdu = vec![function, (2*count) as u8];
+                pdu.extend(values.iter().flat_map(|v| v.to_be_bytes()));
+                pdu
+            }
+            WRITE_SINGLE_REGISTER => {
+                let (addr, value) = (self.reg(2), self.reg(4));
+                let reg = self.registers.get_mut(&addr).ok_or(ILLEGAL_DATA_ADDRESS)?;
+                *reg = value;
+                debug!("{} write addr={} value={}", self.name, addr, value);
+                self.request[1..6].to_vec()
+            }
+            WRITE_MULTIPLE_REGISTERS => {
+                let (addr, count) = (self.reg(2), self.reg(4));
+                if count == 0 || self.request[6] as u16 != 2*count {
+                    return Err(ILLEGAL_DATA_VALUE);
+                }
+                if !(addr..addr.saturating_add(count)).all(|a| self.registers.contains_key(&a)) {
+                    return Err(ILLEGAL_DATA_ADDRESS);
+                }
+                for i in 0..count {
+                    let value = self.reg(7 + 2*i as usize);
+                    self.registers.insert(addr + i, value);
+                }
+                debug!("{} write addr={} count={}", self.name, addr, count);
+                self.request[1..6].to_vec()
+            }
+            _ => return Err(ILLEGAL_FUNCTION),
+        };
+        Ok(pdu)
+    }
+
+    fn process_request(&mut self, sys: &System) {
+        let len = self.request.len();
+        let crc = u16::from_le_bytes([self.request[len-2], self.request[len-1]]);
+        if crc16(&self.request[..len-2]) != crc {
+            warn!("{} CRC error, request dropped frame={:02x?}", self.name, self.request);
+            return;
+        }
+
+        let address = self.request[0];
+        if address != self.config.address && address != BROADCAST_ADDRESS {
+            return;
+        }
+
+        let pdu = self.execute().unwrap_or_else(|code| {
+            debug!("{} exception function=0x{:02x} code={}", self.name, self.request[1], code);
+            vec![self.request[1] | 0x80, code]
+        });
+
+        if address == BROADCAST_ADDRESS {
+            return;
+        }
+
+        let mut frame = vec![self.config.address];
+        frame.extend(pdu);
+        frame.extend(crc16(&frame).to_le_bytes());
+        self.response.extend(frame);
+        // The slave waits for the silent interval before answering
+        self.next_tx_at = now() + 4 * self.char_time(sys);
+    }
+
+    /// A silent interval of 3.5 characters ends the frame. Requests with a
+    /// known length are processed as soon as they're complete.
+    fn check_frame_end(&mut self, sys: &System) {
+        if !self.request.is_empty() && now().saturating_sub(self.last_rx_at) * 2 > 7 * self.char_time(sys) {
+            if self.request.len() >= 4 && self.request_len().is_none() {
+                self.process_request(sys);
+            } else {
+                debug!("{} incomplete request dropped frame={:02x?}", self.name, self.request);
+            }
+            self.request.clear();
+        }
+    }
+}
+
+impl ExtDevice<(), u8> for ModbusSlave {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} modbus-slave", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, sys: &System, _addr: ()) -> u8 {
+        if !self.has_data(sys) {
+            return 0;
+        }
+        self.next_tx_at = now() + self.char_time(sys);
+        self.response.pop_front().unwrap_or_default()
+    }
+
+    fn write(&mut self, sys: &System, _addr: (), v: u8) {
+        self.check_frame_end(sys);
+        self.last_rx_at = now();
+        self.request.push(v);
+
+        if self.request_len() == Some(self.request.len()) {
+            self.process_request(sys);
+            self.request.clear();
+        }
+    }
+
+    fn has_data(&mut self, sys: &System) -> bool {
+        self.check_frame_end(sys);
+        !self.response.is_empty() && now() >= self.next_tx_at
+    }
+}
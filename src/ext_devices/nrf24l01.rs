@@ -0,0 +1,420 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{cell::RefCell, collections::VecDeque, net::UdpSocket, rc::Rc};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{system::System, peripherals::gpio::{GpioPorts, Pin}};
+
+use super::ExtDevice;
+
+// An nRF24L01(+) radio on a SPI bus. The transmitted packets are looped back
+// to the radio itself, or exchanged with another emulator over UDP:
+//
+//  nrf24l01:
+//    - peripheral: SPI2
+//      cs: PB12
+//      ce: PB0               # without it, the radio behaves as if CE was high
+//      irq: PB1              # active low
+//      udp_bind: 127.0.0.1:7001
+//      udp_peer: 127.0.0.1:7002
+//
+// The other emulator uses the same config with the two addresses swapped.
+// Packets are received when their address matches an enabled pipe, and
+// transmissions always succeed (there's no retransmission). The RF channel
+// and data rate are ignored, as are ACK payloads.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct Nrf24l01Config {
+    pub peripheral: String,
+    /// Chip select pin, or "nss". Raising it ends the commands.
+    pub cs: String,
+    pub ce: Option<String>,
+    pub irq: Option<String>,
+    /// Local UDP address. Without it, the transmitted packets are looped back.
+    pub udp_bind: Option<String>,
+    pub udp_peer: Option<String>,
+}
+
+// Commands
+const R_REGISTER: u8 = 0x00;
+const W_REGISTER: u8 = 0x20;
+const REGISTER_MASK: u8 = 0x1F;
+const R_RX_PL_WID: u8 = 0x60;
+const R_RX_PAYLOAD: u8 = 0x61;
+const W_TX_PAYLOAD: u8 = 0xA0;
+const W_TX_PAYLOAD_NO_ACK: u8 = 0xB0;
+const FLUSH_TX: u8 = 0xE1;
+const FLUSH_RX: u8 = 0xE2;
+const REUSE_TX_PL: u8 = 0xE3;
+const NOP: u8 = 0xFF;
+
+// Registers
+const CONFIG: u8 = 0x00;
+const SETUP_AW: u8 = 0x03;
+const EN_RXADDR: u8 = 0x02;
+const STATUS: u8 = 0x07;
+const RX_ADDR_P0: u8 = 0x0A;
+const RX_ADDR_P1: u8 = 0x0B;
+const TX_ADDR: u8 = 0x10;
+const RX_PW_P0: u8 = 0x11;
+const FIFO_STATUS: u8 = 0x17;
+const DYNPD: u8 = 0x1C;
+const FEATURE: u8 = 0x1D;
+const NUM_REGISTERS: usize = 0x1E;
+
+const CONFIG_PRIM_RX: u8 = 1 << 0;
+const CONFIG_PWR_UP: u8 = 1 << 1;
+const STATUS_TX_FULL: u8 = 1 << 0;
+const STATUS_RX_P_NO_EMPTY: u8 = 0b111 << 1;
+const STATUS_TX_DS: u8 = 1 << 5;
+const STATUS_RX_DR: u8 = 1 << 6;
+const STATUS_IRQS: u8 = 0x70;
+const FEATURE_EN_DPL: u8 = 1 << 2;
+
+const ADDR_LEN: usize = 5;
+const FIFO_LEN: usize = 3;
+const MAX_PAYLOAD_LEN: usize = 32;
+
+const RESET_REGISTERS: [(u8, u8); 8] = [
+    (CONFIG, 0x08), (0x01, 0x3F), (EN_RXADDR, 0x03), (SETUP_AW, 0x03),
+    (0x04, 0x03), (0x05, 0x02), (0x06, 0x0E), (STATUS, 0x0E),
+];
+
+type Addr = [u8; ADDR_LEN];
+
+/// What the bytes following the command are
+#[derive(Debug, Default)]
+enum Transaction {
+    #[default]
+    Command,
+    ReadRegister(u8, usize),
+    WriteRegister(u8, Vec<u8>),
+    ReadPayload(usize),
+    ReadPayloadWidth,
+    WritePayload(Vec<u8>),
+    Ignore,
+}
+
+struct Packet {
+    pipe: u8,
+    payload: Vec<u8>,
+}
+
+#[derive(Default)]
+pub struct Nrf24l01 {
+    pub config: Nrf24l01Config,
+    name: String,
+    regs: [u8; NUM_REGISTERS],
+    // The 5 byte addresses, least significant byte first. Pipes 2-5 only have
+    // their own least significant byte, in regs.
+    rx_addr_p0: Addr,
+    rx_addr_p1: Addr,
+    tx_addr: Addr,
+    ce: bool,
+    transaction: Transaction,
+    tx_fifo: VecDeque<Vec<u8>>,
+    rx_fifo: VecDeque<Packet>,
+    // Packets on the air, with their destination address
+    incoming: VecDeque<(Addr, Vec<u8>)>,
+    socket: Option<UdpSocket>,
+}
+
+impl Nrf24l01 {
+    pub fn new(config: Nrf24l01Config, gpio: &mut GpioPorts) -> Result<Rc<RefCell<Self>>> {
+        let socket = match (&config.udp_bind, &config.udp_peer) {
+            (Some(bind), Some(peer)) => {
+                let socket = UdpSocket::bind(bind)
+                    .with_context(|| format!("Failed to bind the nRF24L01 link to {}", bind))?;
+                socket.connect(peer)
+                    .with_context(|| format!("Invalid nRF24L01 peer {}", peer))?;
+                socket.set_nonblocking(true)?;
+                Some(socket)
+            }
+            (None, None) => None,
+            _ => anyhow::bail!("nRF24L01 link needs both udp_bind and udp_peer"),
+        };
+
+        let ce_pin = config.ce.as_deref().map(Pin::from_str);
+        let irq_pin = config.irq.as_deref().map(Pin::from_str);
+
+        let self_ = Rc::new(RefCell::new(Self {
+            config,
+            socket,
+            // Always enabled without a CE pin
+            ce: ce_pin.is_none(),
+            ..Self::default()
+        }));
+        self_.borrow_mut().reset();
+
+        if let Some(pin) = ce_pin {
+            let s = self_.clone();
+            gpio.add_write_callback(pin, move |_sys, v| {
+                let mut s = s.borrow_mut();
+                s.ce = v;
+                s.update();
+            });
+        }
+
+        if let Some(pin) = irq_pin {
+            let s = self_.clone();
+            gpio.add_read_callback(pin, move |_sys| {
+                let mut s = s.borrow_mut();
+                s.update();
+                !s.irq_asserted()
+            });
+        }
+
+        Ok(self_)
+    }
+
+    fn reset(&mut self) {
+        self.regs = [0; NUM_REGISTERS];
+        for (reg, v) in RESET_REGISTERS {
+            self.regs[reg as usize] = v;
+        }
+        for (i, v) in [0xC3, 0xC4, 0xC5, 0xC6].into_iter().enumerate() {
+            self.regs[RX_ADDR_P1 as usize + 1 + i] = v;
+        }
+        self.rx_addr_p0 = [0xE7; ADDR_LEN];
+        self.rx_addr_p1 = [0xC2; ADDR_LEN];
+        self.tx_addr = [0xE7; ADDR_LEN];
+    }
+
+    fn reg(&self, reg: u8) -> u8 {
+        self.regs[reg as usize]
+    }
+
+    fn addr_len(&self) -> usize {
+        // 1: 3 bytes, 2: 4 bytes, 3: 5 bytes
+        (self.reg(SETUP_AW) & 0x3).max(1) as usize + 2
+    }
+
+    fn pipe_addr(&self, pipe: u8) -> Addr {
+        match pipe {
+            0 => self.rx_addr_p0,
+            1 => self.rx_addr_p1,
+            _ => {
+                let mut addr = self.rx_addr_p1;
+                addr[0] = self.reg(RX_ADDR_P0 + pipe);
+                addr
+            }
+        }
+    }
+
+    fn irq_asserted(&self) -> bool {
+        // The mask bits of CONFIG are at the same positions as the STATUS flags
+        self.reg(STATUS) & STATUS_IRQS & !self.reg(CONFIG) != 0
+    }
+
+    fn status(&self) -> u8 {
+        let rx_p_no = match self.rx_fifo.front() {
+            Some(p) => p.pipe << 1,
+            None => STATUS_RX_P_NO_EMPTY,
+        };
+        let tx_full = if self.tx_fifo.len() >= FIFO_LEN { STATUS_TX_FULL } else { 0 };
+        (self.reg(STATUS) & STATUS_IRQS) | rx_p_no | tx_full
+    }
+
+    fn fifo_status(&self) -> u8 {
+        let mut v = 0;
+        if self.rx_fifo.is_empty() { v |= 1 << 0 }
+        if self.rx_fifo.len() >= FIFO_LEN { v |= 1 << 1 }
+        if self.tx_fifo.is_empty() { v |= 1 << 4 }
+        if self.tx_fifo.len() >= FIFO_LEN { v |= 1 << 5 }
+        v
+    }
+
+    fn read_register(&self, reg: u8, offset: usize) -> u8 {
+        match reg {
+            STATUS => self.status(),
+            FIFO_STATUS => self.fifo_status(),
+            RX_ADDR_P0 => self.rx_addr_p0.get(offset).copied().unwrap_or_default(),
+            RX_ADDR_P1 => self.rx_addr_p1.get(offset).copied().unwrap_or_default(),
+            TX_ADDR => self.tx_addr.get(offset).copied().unwrap_or_default(),
+            _ => self.regs.get(reg as usize).copied().unwrap_or_default(),
+        }
+    }
+
+    fn write_register(&mut self, reg: u8, data: &[u8]) {
+        let Some(&v) = data.first() else { return };
+        debug!("{} write reg=0x{:02x} value={:02x?}", self.name, reg, data);
+
+        let set_addr = |addr: &mut Addr| {
+            let len = data.len().min(ADDR_LEN);
+            addr[..len].copy_from_slice(&data[..len]);
+        };
+
+        match reg {
+            // Write 1 to clear
+            STATUS => self.regs[STATUS as usize] &= !(v & STATUS_IRQS),
+            RX_ADDR_P0 => set_addr(&mut self.rx_addr_p0),
+            RX_ADDR_P1 => set_addr(&mut self.rx_addr_p1),
+            TX_ADDR => set_addr(&mut self.tx_addr),
+            FIFO_STATUS => {}
+            r if (r as usize) < NUM_REGISTERS => self.regs[r as usize] = v,
+            _ => {}
+        }
+    }
+
+    fn payload_width(&self, pipe: u8) -> Option<usize> {
+        let dynamic = self.reg(FEATURE) & FEATURE_EN_DPL != 0 && self.reg(DYNPD) & (1 << pipe) != 0;
+        if dynamic {
+            return None;
+        }
+        Some((self.reg(RX_PW_P0 + pipe) & 0x3F) as usize)
+    }
+
+    /// Moves the packets on the air to the RX FIFO, and transmits the TX FIFO
+    fn update(&mut self) {
+        if let Some(ref socket) = self.socket {
+            let mut buf = [0; ADDR_LEN + MAX_PAYLOAD_LEN];
+            while let Ok(len) = socket.recv(&mut buf) {
+                if len > ADDR_LEN {
+                    let mut addr = [0; ADDR_LEN];
+                    addr.copy_from_slice(&buf[..ADDR_LEN]);
+                    self.incoming.push_back((addr, buf[ADDR_LEN..len].to_vec()));
+                }
+            }
+        }
+
+        let powered = self.reg(CONFIG) & CONFIG_PWR_UP != 0;
+        let rx_mode = self.reg(CONFIG) & CONFIG_PRIM_RX != 0;
+        if !powered || !self.ce {
+            return;
+        }
+
+        if rx_mode {
+            while let Some((addr, payload)) = self.incoming.pop_front() {
+                self.receive(addr, payload);
+            }
+        } else {
+            while let Some(payload) = self.tx_fifo.pop_front() {
+                self.transmit(payload);
+            }
+        }
+    }
+
+    fn receive(&mut self, addr: Addr, mut payload: Vec<u8>) {
+        let len = self.addr_len();
+        let pipe = (0..6)
+            .filter(|p| self.reg(EN_RXADDR) & (1 << p) != 0)
+            .find(|p| self.pipe_addr(*p)[..len] == addr[..len]);
+        let Some(pipe) = pipe else {
+            trace!("{} packet ignored addr={:02x?}", self.name, &addr[..len]);
+            return;
+        };
+
+        if let Some(width) = self.payload_width(pipe) {
+            if width == 0 {
+                return;
+            }
+            payload.resize(width, 0);
+        }
+
+        if self.rx_fifo.len() >= FIFO_LEN {
+            debug!("{} RX FIFO full, packet lost pipe={}", self.name, pipe);
+            return;
+        }
+
+        debug!("{} rx pipe={} payload={:02x?}", self.name, pipe, payload);
+        self.rx_fifo.push_back(Packet { pipe, payload });
+        self.regs[STATUS as usize] |= STATUS_RX_DR;
+    }
+
+    fn transmit(&mut self, payload: Vec<u8>) {
+        debug!("{} tx addr={:02x?} payload={:02x?}", self.name, &self.tx_addr[..self.addr_len()], payload);
+        match self.socket {
+            Some(ref socket) => {
+                let mut packet = self.tx_addr.to_vec();
+                packet.extend(&payload);
+                if let Err(e) = socket.send(&packet) {
+                    trace!("{} UDP send failed err={}", self.name, e);
+                }
+            }
+            None => self.incoming.push_back((self.tx_addr, payload)),
+        }
+        self.regs[STATUS as usize] |= STATUS_TX_DS;
+    }
+
+    fn end_transaction(&mut self) {
+        match std::mem::take(&mut self.transaction) {
+            Transaction::WriteRegister(reg, data) => self.write_register(reg, &data),
+            Transaction::ReadPayload(_) => { self.rx_fifo.pop_front(); }
+            Transaction::WritePayload(payload) => {
+                if self.tx_fifo.len() < FIFO_LEN {
+                    self.tx_fifo.push_back(payload);
+                } else {
+                    debug!("{} TX FIFO full, payload dropped", self.name);
+                }
+            }
+            _ => {}
+        }
+        self.update();
+    }
+}
+
+impl ExtDevice<(), u8> for Nrf24l01 {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} nrf24l01", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        match self.transaction {
+            // STATUS is shifted out while the command is shifted in
+            Transaction::Command => {
+                self.update();
+                self.status()
+            }
+            Transaction::ReadRegister(reg, ref mut offset) => {
+                *offset += 1;
+                let offset = *offset - 1;
+                self.read_register(reg, offset)
+            }
+            Transaction::ReadPayload(ref mut offset) => {
+                *offset += 1;
+                let offset = *offset - 1;
+                self.rx_fifo.front().and_then(|p| p.payload.get(offset)).copied().unwrap_or_default()
+            }
+            Transaction::ReadPayloadWidth => {
+                self.rx_fifo.front().map(|p| p.payload.len() as u8).unwrap_or_default()
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        match self.transaction {
+            Transaction::Command => {
+                trace!("{} cmd=0x{:02x}", self.name, v);
+                self.transaction = match v {
+                    _ if v & !REGISTER_MASK == R_REGISTER => Transaction::ReadRegister(v & REGISTER_MASK, 0),
+                    _ if v & !REGISTER_MASK == W_REGISTER => Transaction::WriteRegister(v & REGISTER_MASK, vec![]),
+                    R_RX_PL_WID => Transaction::ReadPayloadWidth,
+                    R_RX_PAYLOAD => Transaction::ReadPayload(0),
+                    W_TX_PAYLOAD | W_TX_PAYLOAD_NO_ACK => Transaction::WritePayload(vec![]),
+                    FLUSH_TX => { self.tx_fifo.clear(); Transaction::Ignore }
+                    FLUSH_RX => { self.rx_fifo.clear(); Transaction::Ignore }
+                    REUSE_TX_PL | NOP => Transaction::Ignore,
+                    _ => {
+                        debug!("{} unsupported cmd=0x{:02x}", self.name, v);
+                        Transaction::Ignore
+                    }
+                };
+            }
+            Transaction::WriteRegister(_, ref mut data) => data.push(v),
+            Transaction::WritePayload(ref mut data) if data.len() < MAX_PAYLOAD_LEN => data.push(v),
+            _ => {}
+        }
+    }
+
+    fn select(&mut self, _sys: &System, selected: bool) {
+        // Raising the chip select ends the command
+        if !selected {
+            self.end_transaction();
+        }
+        self.transaction = Transaction::Command;
+    }
+}
@@ -0,0 +1,293 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{util::{self, PersistPolicy}, system::System};
+
+use super::{ExtDevice, I2cDevice};
+
+// A non-volatile memory whose content survives across runs in a file. This can
+// be an FRAM (FM24 on I2C, FM25 on SPI), or a battery-backed SRAM (23LCV on SPI):
+//
+//  nvram:
+//    - peripheral: SPI2
+//      cs: PB12            # SPI when set, I2C otherwise
+//      size: 8192
+//      file: settings.bin  # created on the first run
+//      persist: always     # always, on_exit (default), or never
+//
+// With `always`, the file is written back at the end of every write
+// transaction, so stopping the emulator at any point behaves like a power loss.
+// With `on_exit`, the file is written back when the emulation stops cleanly,
+// and the writes of a run that locks up or reaches the time limit are lost.
+// The file is replaced atomically, it is never left half written.
+//
+// On SPI, the supported commands are WREN, WRDI, RDSR, WRSR, READ and WRITE.
+// The block protection bits of the status register are honored.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct NvramConfig {
+    pub peripheral: String,
+    /// Chip select pin, or "nss", for a SPI memory
+    pub cs: Option<String>,
+    /// I2C address. Defaults to 0x50.
+    pub address: Option<u8>,
+    pub size: usize,
+    pub file: Option<String>,
+    pub persist: Option<PersistPolicy>,
+    /// Number of bytes used to address the memory. Defaults to 1 for sizes up
+    /// to 256 bytes, 2 up to 64KB, 3 otherwise.
+    pub addr_bytes: Option<u8>,
+    /// SPI writes need a WREN first. Defaults to true. Battery-backed SRAMs don't need it.
+    pub wren: Option<bool>,
+}
+
+// SPI commands
+const WRSR: u8 = 0x01;
+const WRITE: u8 = 0x02;
+const READ: u8 = 0x03;
+const WRDI: u8 = 0x04;
+const RDSR: u8 = 0x05;
+const WREN: u8 = 0x06;
+
+// Status register
+const SR_WEL: u8 = 1 << 1;
+const SR_BP: u8 = 0b11 << 2;
+const SR_WPEN: u8 = 1 << 7;
+
+#[derive(Debug, Default)]
+enum Spi {
+    #[default]
+    Command,
+    Address { write: bool },
+    Read,
+    Write,
+    ReadStatus,
+    WriteStatus,
+    /// The rest of the transaction is ignored
+    Done,
+}
+
+#[derive(Default)]
+pub struct Nvram {
+    pub config: NvramConfig,
+    name: String,
+    content: Vec<u8>,
+    persist: PersistPolicy,
+    /// Written since the last write back
+    dirty: bool,
+
+    addr: usize,
+    // Number of address bytes we still expect in the current transaction
+    addr_bytes_pending: u8,
+
+    spi: Spi,
+    cmd: u8,
+    status: u8,
+}
+
+impl Nvram {
+    pub fn new(config: NvramConfig) -> Result<Self> {
+        let mut content = match config.file {
+            // The file doesn't exist on the first run
+            Some(ref file) if std::path::Path::new(file).exists() => {
+                util::read_file(file)
+                    .with_context(|| format!("Failed to read {}", file))?
+            }
+            _ => vec![],
+        };
+        content.resize(config.size, 0);
+
+        let persist = config.persist.unwrap_or_default();
+
+        Ok(Self { config, content, persist, ..Self::default() })
+    }
+
+    fn is_spi(&self) -> bool {
+        self.config.cs.is_some()
+    }
+
+    fn addr_bytes(&self) -> u8 {
+        self.config.addr_bytes.unwrap_or(
+            if self.config.size <= 0x100 { 1 } else if self.config.size <= 0x10000 { 2 } else { 3 }
+        )
+    }
+
+    fn next_addr(&mut self) -> usize {
+        let a = self.addr;
+        self.addr = (self.addr + 1) % self.config.size;
+        a
+    }
+
+    fn write_protected(&self, addr: usize) -> bool {
+        let size = self.config.size;
+        let protected = match (self.status & SR_BP) >> 2 {
+            0 => 0,
+            1 => size / 4,
+            2 => size / 2,
+            _ => size,
+        };
+        addr >= size - protected
+    }
+
+    fn write_byte(&mut self, v: u8) {
+        let addr = self.next_addr();
+        if self.write_protected(addr) {
+            trace!("{} write addr=0x{:04x} protected", self.name, addr);
+            return;
+        }
+        trace!("{} write addr=0x{:04x} value={:02x}", self.name, addr, v);
+        self.content[addr] = v;
+        self.dirty = true;
+    }
+
+    fn write_addr_byte(&mut self, v: u8) -> bool {
+        self.addr = (self.addr << 8) | v as usize;
+        self.addr_bytes_pending -= 1;
+        if self.addr_bytes_pending == 0 {
+            self.addr %= self.config.size;
+            trace!("{} addr=0x{:04x}", self.name, self.addr);
+        }
+        self.addr_bytes_pending == 0
+    }
+
+    fn write_enabled(&self) -> bool {
+        !self.config.wren.unwrap_or(true) || self.status & SR_WEL != 0
+    }
+
+    fn spi_command(&mut self, cmd: u8) {
+        self.cmd = cmd;
+        self.spi = match cmd {
+            WREN => {
+                self.status |= SR_WEL;
+                Spi::Done
+            }
+            WRDI => {
+                self.status &= !SR_WEL;
+                Spi::Done
+            }
+            RDSR => Spi::ReadStatus,
+            WRSR if self.write_enabled() => Spi::WriteStatus,
+            READ | WRITE if cmd == READ || self.write_enabled() => {
+                self.addr = 0;
+                self.addr_bytes_pending = self.addr_bytes();
+                Spi::Address { write: cmd == WRITE }
+            }
+            WRSR | WRITE => {
+                debug!("{} cmd=0x{:02x} ignored, writes are disabled", self.name, cmd);
+                Spi::Done
+            }
+            _ => {
+                debug!("{} unsupported cmd=0x{:02x}", self.name, cmd);
+                Spi::Done
+            }
+        };
+    }
+
+    /// With the `always` policy, the file is written back after each write transaction
+    fn end_transaction(&mut self) {
+        if self.dirty && self.persist == PersistPolicy::Always {
+            if let Err(e) = self.write_back() {
+                warn!("{} {:#}", self.name, e);
+            }
+        }
+    }
+
+    fn write_back(&mut self) -> Result<()> {
+        if let Some(ref file) = self.config.file {
+            util::write_file_atomic(file, &self.content)?;
+            trace!("{} saved to {}", self.name, file);
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Called when the emulation stops
+    pub fn save(&mut self, clean_exit: bool) -> Result<()> {
+        if self.dirty && self.config.file.is_some() && self.persist.on_exit(clean_exit) {
+            self.write_back()?;
+            info!("{} saved to {}", self.name, self.config.file.as_deref().unwrap_or_default());
+        }
+        Ok(())
+    }
+}
+
+impl ExtDevice<(), u8> for Nvram {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} nvram", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        if !self.is_spi() {
+            let a = self.next_addr();
+            return self.content[a];
+        }
+
+        match self.spi {
+            Spi::Read => {
+                let a = self.next_addr();
+                self.content[a]
+            }
+            Spi::ReadStatus => self.status,
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        if !self.is_spi() {
+            if self.addr_bytes_pending > 0 {
+                self.write_addr_byte(v);
+            } else {
+                self.write_byte(v);
+            }
+            return;
+        }
+
+        match self.spi {
+            Spi::Command => self.spi_command(v),
+            Spi::Address { write } => {
+                if self.write_addr_byte(v) {
+                    self.spi = if write { Spi::Write } else { Spi::Read };
+                }
+            }
+            Spi::Write => self.write_byte(v),
+            Spi::WriteStatus => {
+                self.status = (self.status & SR_WEL) | (v & (SR_WPEN | SR_BP));
+                debug!("{} status=0x{:02x}", self.name, self.status);
+                self.spi = Spi::Done;
+            }
+            Spi::Read | Spi::ReadStatus | Spi::Done => {}
+        }
+    }
+
+    fn select(&mut self, _sys: &System, selected: bool) {
+        if !selected {
+            // The write latch is cleared when a write completes
+            if matches!(self.cmd, WRITE | WRSR) {
+                self.status &= !SR_WEL;
+            }
+            self.end_transaction();
+        }
+        self.cmd = 0;
+        self.spi = Spi::Command;
+    }
+}
+
+impl I2cDevice for Nvram {
+    fn address(&self) -> u8 {
+        self.config.address.unwrap_or(0x50)
+    }
+
+    fn start(&mut self, _sys: &System, read: bool) {
+        if !read {
+            self.addr = 0;
+            self.addr_bytes_pending = self.addr_bytes();
+        }
+    }
+
+    fn stop(&mut self, _sys: &System) {
+        self.end_transaction();
+    }
+}
@@ -0,0 +1,100 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{io::prelude::*, net::{TcpListener, TcpStream, Shutdown}, sync::mpsc::{Sender, Receiver, TryRecvError}, time::Duration};
+
+use anyhow::Result;
+
+use super::background::Background;
+
+/// What happens on a host TCP connection opened for the firmware, like by a
+/// modem device
+pub enum SocketEvent {
+    Connected,
+    Failed,
+    Data(Vec<u8>),
+    Closed,
+}
+
+/// A host TCP connection. Connecting and the I/O happen on a background
+/// thread. Data sent is written to the socket, and the events come back.
+/// Dropping it closes the connection.
+pub type TcpSocket = Background<Vec<u8>, SocketEvent>;
+
+impl TcpSocket {
+    pub fn connect(name: &str, host: String, port: u16) -> Result<Self> {
+        let name = name.to_string();
+        Background::spawn(&name.clone(), move |rx, tx| Self::serve(&name, host, port, rx, tx))
+    }
+
+    /// Waits for a connection on the host port. Connected comes once a
+    /// client connected, and Failed when the port can't be bound.
+    pub fn listen(name: &str, port: u16) -> Result<Self> {
+        let name = name.to_string();
+        Background::spawn(&name.clone(), move |rx, tx| Self::accept(&name, port, rx, tx))
+    }
+
+    fn serve(name: &str, host: String, port: u16, rx: Receiver<Vec<u8>>, tx: Sender<SocketEvent>) {
+        let stream = match TcpStream::connect((host.as_str(), port)) {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!("{} failed to connect to {}:{}: {}", name, host, port, e);
+                let _ = tx.send(SocketEvent::Failed);
+                return;
+            }
+        };
+        info!("{} connected to {}:{}", name, host, port);
+        Self::transfer(stream, rx, tx);
+    }
+
+    fn accept(name: &str, port: u16, rx: Receiver<Vec<u8>>, tx: Sender<SocketEvent>) {
+        let listener = match TcpListener::bind(("0.0.0.0", port)).and_then(|l| l.set_nonblocking(true).map(|_| l)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("{} failed to listen on port={}: {}", name, port, e);
+                let _ = tx.send(SocketEvent::Failed);
+                return;
+            }
+        };
+        info!("{} listening on port={}", name, port);
+
+        // Polled, so the port is released when the firmware stops listening
+        let stream = loop {
+            match listener.accept() {
+                Ok((stream, addr)) => {
+                    info!("{} connection from {}", name, addr);
+                    break stream;
+                }
+                Err(_) if matches!(rx.try_recv(), Err(TryRecvError::Disconnected)) => return,
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        if stream.set_nonblocking(false).is_ok() {
+            Self::transfer(stream, rx, tx);
+        }
+    }
+
+    fn transfer(mut stream: TcpStream, rx: Receiver<Vec<u8>>, tx: Sender<SocketEvent>) {
+        let _ = tx.send(SocketEvent::Connected);
+
+        // Remote to firmware
+        if let Ok(mut reader) = stream.try_clone() {
+            std::thread::spawn(move || {
+                let mut buf = [0; 1024];
+                while let Ok(n @ 1..) = reader.read(&mut buf) {
+                    if tx.send(SocketEvent::Data(buf[..n].to_vec())).is_err() {
+                        return;
+                    }
+                }
+                let _ = tx.send(SocketEvent::Closed);
+            });
+        }
+
+        // Firmware to remote, until the connection is closed on our side
+        while let Ok(data) = rx.recv() {
+            if stream.write_all(&data).is_err() {
+                break;
+            }
+        }
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+}
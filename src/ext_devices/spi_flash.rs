@@ -8,7 +8,7 @@ use serde::Deserialize;
 
 use crate::{util, system::System};
 
-use super::ExtDevice;
+use super::{ExtDevice, faults::{Faults, FaultsConfig, Fault}};
 
 #[derive(Debug, Deserialize, Default)]
 pub struct SpiFlashConfig {
@@ -16,6 +16,9 @@ pub struct SpiFlashConfig {
     pub jedec_id: u32,
     pub file: String,
     pub size: usize,
+    /// Chip select pin, or "nss", when sharing the bus
+    pub cs: Option<String>,
+    pub faults: Option<FaultsConfig>,
 }
 
 #[derive(Default)]
@@ -27,16 +30,18 @@ pub struct SpiFlash {
     reply: Option<Reply>,
     /// Command and arguments
     cmd: Option<(Command, Vec<u8>)>,
+    faults: Faults,
 }
 
 impl SpiFlash {
-    pub fn new(config: SpiFlashConfig) -> Result<Self> {
+    pub fn new(mut config: SpiFlashConfig) -> Result<Self> {
         let mut content = util::read_file(&config.file)
             .with_context(|| format!("Failed to read {}", &config.file))?;
 
         content.resize(config.size, 0);
+        let faults = Faults::new(config.faults.take());
 
-        Ok(Self { config, content, ..Self::default() })
+        Ok(Self { config, content, faults, ..Self::default() })
     }
 }
 
@@ -47,15 +52,22 @@ impl ExtDevice<(), u8> for SpiFlash {
     }
 
     fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        // The data line floats high when the device doesn't answer
+        match self.faults.fault() {
+            Fault::Timeout => return 0xFF,
+            Fault::ReadError if matches!(self.reply, Some(Reply::FileContent(_))) => return 0xFF,
+            _ => {}
+        }
+
         match self.reply.as_mut() {
             Some(Reply::Data(d)) => {
                 d.pop_front().unwrap_or_default()
             }
 
             Some(Reply::FileContent(addr)) => {
-                let c = self.content[*addr];
+                let a = *addr;
                 *addr = (*addr + 1) % self.config.size;
-                c
+                self.faults.corrupt(&self.name, a as u64, self.content[a])
             }
             None => 0,
         }
@@ -72,6 +84,7 @@ impl ExtDevice<(), u8> for SpiFlash {
             }
         } else if let Some(cmd) = Command::try_from(v).ok() {
             // We are receiving a new command
+            self.faults.operation(&self.name);
             if let Some(reply) = self.try_process_command(cmd, &[]) {
                 self.reply = Some(reply);
             } else {
@@ -81,6 +94,14 @@ impl ExtDevice<(), u8> for SpiFlash {
             debug!("{} unknown cmd={:02x}", self.name, v);
         }
     }
+
+    fn select(&mut self, _sys: &System, selected: bool) {
+        // Raising the chip select ends the command
+        if !selected {
+            self.cmd = None;
+            self.reply = None;
+        }
+    }
 }
 
 impl SpiFlash {
@@ -0,0 +1,159 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::VecDeque, io::prelude::*, net::TcpListener, sync::mpsc::{Sender, Receiver}};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use super::background::Background;
+
+// An external master clocking a SPI peripheral configured as a slave, for
+// co-processor firmwares that only answer a host:
+//
+//  spi_master:
+//    - peripheral: SPI2
+//      every: 2000             # clk between frames, 1000 by default
+//      start: 1000000          # clk of the first frame, optional
+//      data: [0x9F, 0x00, 0x00]
+//      repeat: true            # loops over data
+//    - peripheral: SPI3
+//      tcp: "127.0.0.1:4001"   # the bytes sent by the client are clocked in,
+//                              # and the replies of the firmware sent back
+//
+// The master selects the slave for the whole run. A frame of 16 bits takes
+// two bytes.
+
+const DEFAULT_EVERY: u64 = 1000;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct SpiMasterConfig {
+    pub peripheral: String,
+    pub every: Option<u64>,
+    pub start: Option<u64>,
+    pub data: Option<Vec<u8>>,
+    pub repeat: Option<bool>,
+    pub tcp: Option<String>,
+}
+
+#[derive(Default)]
+pub struct SpiMaster {
+    pub config: SpiMasterConfig,
+    name: String,
+    script: VecDeque<u8>,
+    tcp: Option<Background<u8, u8>>,
+    // Emulated time of the next frame
+    next_at: u64,
+}
+
+impl SpiMaster {
+    pub fn new(config: SpiMasterConfig) -> Result<Self> {
+        if config.data.is_some() == config.tcp.is_some() {
+            bail!("spi_master of {} must have exactly one of data or tcp", config.peripheral);
+        }
+        if config.every == Some(0) {
+            bail!("spi_master of {} needs a non-zero every", config.peripheral);
+        }
+
+        let tcp = config.tcp.as_ref().map(|addr| {
+            let listener = TcpListener::bind(addr)
+                .with_context(|| format!("Failed to listen on {}", addr))?;
+            info!("spi-master listening on {}", addr);
+            Background::spawn("spi-master", move |rx, tx| Self::serve_tcp(listener, rx, tx))
+        }).transpose()?;
+
+        let script = config.data.clone().unwrap_or_default().into();
+        let next_at = config.start.unwrap_or_default();
+        Ok(Self { config, name: "spi-master".to_string(), script, tcp, next_at })
+    }
+
+    pub fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} spi-master", peri_name);
+        self.name.clone()
+    }
+
+    /// When the next frame is clocked. None once the script is over.
+    pub fn next_frame_at(&self) -> Option<u64> {
+        (self.tcp.is_some() || !self.script.is_empty()).then_some(self.next_at)
+    }
+
+    /// Whether a frame of `num_bytes` is clocked at `now`
+    pub fn is_ready(&mut self, now: u64, num_bytes: usize) -> bool {
+        if now < self.next_at {
+            return false;
+        }
+        match self.tcp {
+            Some(ref mut tcp) => {
+                // The client bytes go in the script as they come
+                while let Some(v) = tcp.try_recv() {
+                    self.script.push_back(v);
+                }
+                if self.script.len() < num_bytes {
+                    // Polled again a frame later
+                    self.frame_done(now);
+                    return false;
+                }
+                true
+            }
+            None => !self.script.is_empty(),
+        }
+    }
+
+    /// Exchanges a byte of the frame. `tx` is what the slave sends back.
+    pub fn exchange(&mut self, tx: u8) -> u8 {
+        let v = self.script.pop_front().unwrap_or_default();
+        if self.config.repeat.unwrap_or_default() && self.tcp.is_none() {
+            self.script.push_back(v);
+        }
+        if let Some(ref tcp) = self.tcp {
+            tcp.send(tx);
+        }
+        trace!("{} mosi={:02x} miso={:02x}", self.name, v, tx);
+        v
+    }
+
+    /// Called once the frame is exchanged
+    pub fn frame_done(&mut self, now: u64) {
+        self.next_at = now + self.config.every.unwrap_or(DEFAULT_EVERY);
+    }
+
+    fn serve_tcp(listener: TcpListener, rx: Receiver<u8>, tx: Sender<u8>) {
+        loop {
+            let mut stream = match listener.accept() {
+                Ok((stream, addr)) => {
+                    info!("spi-master client connected addr={}", addr);
+                    stream
+                }
+                Err(e) => {
+                    warn!("spi-master accept failed: {}", e);
+                    return;
+                }
+            };
+
+            // Client to firmware
+            if let Ok(mut reader) = stream.try_clone() {
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    let mut buf = [0; 256];
+                    while let Ok(n @ 1..) = reader.read(&mut buf) {
+                        if buf[..n].iter().any(|b| tx.send(*b).is_err()) {
+                            break;
+                        }
+                    }
+                });
+            }
+
+            // Firmware to client
+            loop {
+                let v = match rx.recv() {
+                    Ok(v) => v,
+                    // The emulation is over
+                    Err(_) => return,
+                };
+                if stream.write_all(&[v]).is_err() {
+                    info!("spi-master client disconnected");
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,333 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::VecDeque, fs::File, io::{Seek, SeekFrom, Write}};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::{util, system::System};
+
+use super::{ExtDevice, faults::{Faults, FaultsConfig, Fault}};
+
+// An SDHC card in SPI mode, backed by an image file:
+//
+//  spi_sd_card:
+//    - peripheral: SPI3
+//      cs: PA15
+//      file: sd.img
+//      write_back: true      # writes go to the image file, instead of memory only
+//      faults:               # see faults.rs
+//        read_error_after: 100
+//
+// The image is padded to a multiple of 512KB, the capacity granularity of the
+// CSD. Supported: CMD0, CMD1, CMD8, CMD9 (CSD), CMD10 (CID), CMD12, CMD13,
+// CMD16, CMD17, CMD18, CMD24, CMD25, CMD55, CMD58, ACMD41. The card is ready as
+// soon as ACMD41 is sent. A read error answers the read commands with an error
+// token, and a timeout leaves all the commands unanswered.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct SpiSdCardConfig {
+    pub peripheral: String,
+    /// Chip select pin, or "nss", when sharing the bus
+    pub cs: Option<String>,
+    pub file: String,
+    pub write_back: Option<bool>,
+    pub faults: Option<FaultsConfig>,
+}
+
+const BLOCK_LEN: usize = 512;
+const CAPACITY_UNIT: usize = 512*1024;
+const CMD_LEN: usize = 6;
+
+// R1 response
+const R1_READY: u8 = 0x00;
+const R1_IDLE: u8 = 0x01;
+const R1_ILLEGAL_COMMAND: u8 = 0x04;
+const R1_ADDRESS_ERROR: u8 = 0x20;
+
+const START_BLOCK: u8 = 0xFE;
+const START_BLOCK_MULTIPLE: u8 = 0xFC;
+const STOP_TRAN: u8 = 0xFD;
+const DATA_ACCEPTED: u8 = 0x05;
+// Data error token, with the error bit
+const DATA_ERROR: u8 = 0x01;
+// Powered up, SDHC, 2.7-3.6V
+const OCR: u32 = 0xC0FF_8000;
+
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc = 0u8;
+    for b in data {
+        for i in (0..8).rev() {
+            let bit = ((b >> i) & 1) ^ (crc >> 6);
+            crc = (crc << 1) & 0x7F;
+            if bit != 0 {
+                crc ^= 0x09;
+            }
+        }
+    }
+    crc
+}
+
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0u16;
+    for b in data {
+        crc ^= (*b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+#[derive(Debug, Default)]
+enum State {
+    #[default]
+    Command,
+    /// Sending blocks until CMD12
+    ReadMultiple(usize),
+    /// Waiting for the data token, then the block and its CRC
+    Write { block: usize, multiple: bool, data: Option<Vec<u8>> },
+}
+
+#[derive(Default)]
+pub struct SpiSdCard {
+    pub config: SpiSdCardConfig,
+    name: String,
+    content: Vec<u8>,
+    file: Option<File>,
+    idle: bool,
+    app_cmd: bool,
+    state: State,
+    cmd: Vec<u8>,
+    output: VecDeque<u8>,
+    faults: Faults,
+}
+
+impl SpiSdCard {
+    pub fn new(mut config: SpiSdCardConfig) -> Result<Self> {
+        let mut content = util::read_file(&config.file)
+            .with_context(|| format!("Failed to read {}", &config.file))?;
+        content.resize(util::round_up(content.len().max(1), CAPACITY_UNIT), 0);
+
+        let file = if config.write_back.unwrap_or_default() {
+            Some(File::options().write(true).open(&config.file)
+                .with_context(|| format!("Failed to open {} for writing", &config.file))?)
+        } else {
+            None
+        };
+
+        let faults = Faults::new(config.faults.take());
+
+        Ok(Self { config, content, file, faults, idle: true, ..Self::default() })
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.content.len() / BLOCK_LEN
+    }
+
+    fn r1(&self) -> u8 {
+        if self.idle { R1_IDLE } else { R1_READY }
+    }
+
+    /// A data block, after a gap byte, with its start token and CRC
+    fn data_block(data: &[u8]) -> impl Iterator<Item=u8> + '_ {
+        [0xFF, START_BLOCK].into_iter()
+            .chain(data.iter().copied())
+            .chain(crc16(data).to_be_bytes())
+    }
+
+    /// The data of a block read, or the error token
+    fn read_block(&mut self, block: usize) -> Vec<u8> {
+        if self.faults.fault() == Fault::ReadError {
+            self.state = State::Command;
+            return vec![0xFF, DATA_ERROR];
+        }
+        let data = (block*BLOCK_LEN..(block+1)*BLOCK_LEN)
+            .map(|addr| self.faults.corrupt(&self.name, addr as u64, self.content[addr]))
+            .collect::<Vec<_>>();
+        Self::data_block(&data).collect()
+    }
+
+    fn csd(&self) -> [u8; 16] {
+        // CSD version 2.0. The capacity is (C_SIZE+1) * 512KB.
+        let c_size = (self.content.len() / CAPACITY_UNIT - 1) as u32;
+        let mut csd = [
+            0x40, 0x0E, 0x00, 0x32, 0x5B, 0x59, 0x00,
+            (c_size >> 16) as u8 & 0x3F, (c_size >> 8) as u8, c_size as u8,
+            0x7F, 0x80, 0x0A, 0x40, 0x00, 0x00,
+        ];
+        csd[15] = (crc7(&csd[..15]) << 1) | 1;
+        csd
+    }
+
+    fn cid(&self) -> [u8; 16] {
+        let mut cid = [
+            0x03, b'E', b'M', b'E', b'M', b'U', b'S', b'D',
+            0x10, 0x12, 0x34, 0x56, 0x78, 0x01, 0x64, 0x00,
+        ];
+        cid[15] = (crc7(&cid[..15]) << 1) | 1;
+        cid
+    }
+
+    fn execute(&mut self, cmd: u8, arg: u32) {
+        let app_cmd = std::mem::take(&mut self.app_cmd);
+        trace!("{} cmd={}{} arg=0x{:08x}", self.name, if app_cmd { "ACMD" } else { "CMD" }, cmd, arg);
+
+        self.output.clear();
+        if self.faults.operation(&self.name) == Fault::Timeout {
+            return;
+        }
+        // A byte of Ncr before the response
+        self.output.push_back(0xFF);
+
+        let block = arg as usize;
+        let in_range = block < self.num_blocks();
+
+        match (app_cmd, cmd) {
+            (_, 0) => {
+                self.idle = true;
+                self.state = State::Command;
+                self.output.push_back(R1_IDLE);
+            }
+            (false, 1) | (true, 41) => {
+                self.idle = false;
+                self.output.push_back(R1_READY);
+            }
+            (_, 8) => {
+                // Echoes the voltage and the check pattern
+                self.output.push_back(self.r1());
+                self.output.extend([0x00, 0x00, (arg >> 8) as u8 & 0x0F, arg as u8]);
+            }
+            (_, 9) => {
+                self.output.push_back(self.r1());
+                let csd = self.csd();
+                self.output.extend(Self::data_block(&csd));
+            }
+            (_, 10) => {
+                self.output.push_back(self.r1());
+                let cid = self.cid();
+                self.output.extend(Self::data_block(&cid));
+            }
+            (_, 12) => {
+                self.state = State::Command;
+                // A stuff byte, the response, and a busy byte
+                self.output.extend([0xFF, self.r1(), 0x00]);
+            }
+            (_, 13) => self.output.extend([self.r1(), 0x00]),
+            (_, 16) => self.output.push_back(self.r1()),
+            (_, 17) | (_, 18) | (_, 24) | (_, 25) if !in_range => {
+                debug!("{} cmd={} block={} out of range", self.name, cmd, block);
+                self.output.push_back(self.r1() | R1_ADDRESS_ERROR);
+            }
+            (_, 17) => {
+                debug!("{} read block={}", self.name, block);
+                self.output.push_back(self.r1());
+                let data = self.read_block(block);
+                self.output.extend(data);
+            }
+            (_, 18) => {
+                debug!("{} read multiple block={}", self.name, block);
+                self.output.push_back(self.r1());
+                self.state = State::ReadMultiple(block);
+            }
+            (_, 24) | (_, 25) => {
+                self.output.push_back(self.r1());
+                self.state = State::Write { block, multiple: cmd == 25, data: None };
+            }
+            (_, 55) => {
+                self.app_cmd = true;
+                self.output.push_back(self.r1());
+            }
+            (_, 58) => {
+                self.output.push_back(self.r1());
+                self.output.extend(OCR.to_be_bytes());
+            }
+            _ => {
+                debug!("{} unsupported cmd={}{}", self.name, if app_cmd { "ACMD" } else { "CMD" }, cmd);
+                self.output.push_back(self.r1() | R1_ILLEGAL_COMMAND);
+            }
+        }
+    }
+
+    fn write_block(&mut self, block: usize, data: &[u8]) {
+        debug!("{} write block={}", self.name, block);
+        self.content[block*BLOCK_LEN..(block+1)*BLOCK_LEN].copy_from_slice(data);
+        if let Some(ref mut file) = self.file {
+            let result = file.seek(SeekFrom::Start((block*BLOCK_LEN) as u64))
+                .and_then(|_| file.write_all(data));
+            if let Err(e) = result {
+                warn!("{} failed to write {}: {}", self.name, self.config.file, e);
+            }
+        }
+    }
+
+    fn write_data(&mut self, v: u8) {
+        let State::Write { block, multiple, ref mut data } = self.state else { return };
+        match data {
+            None if v == START_BLOCK || (multiple && v == START_BLOCK_MULTIPLE) => *data = Some(vec![]),
+            None if multiple && v == STOP_TRAN => {
+                // A busy byte, then ready
+                self.output.extend([0xFF, 0x00]);
+                self.state = State::Command;
+            }
+            None => {}
+            Some(data) => {
+                data.push(v);
+                // The block, and its CRC which isn't checked
+                if data.len() == BLOCK_LEN + 2 {
+                    let data = std::mem::take(data);
+                    self.write_block(block, &data[..BLOCK_LEN]);
+                    self.output.extend([DATA_ACCEPTED, 0x00]);
+                    self.state = if multiple && block + 1 < self.num_blocks() {
+                        State::Write { block: block + 1, multiple, data: None }
+                    } else {
+                        State::Command
+                    };
+                }
+            }
+        }
+    }
+}
+
+impl ExtDevice<(), u8> for SpiSdCard {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} sd-card", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        if let State::ReadMultiple(ref mut block) = self.state {
+            if self.output.is_empty() && *block < self.content.len() / BLOCK_LEN {
+                let b = *block;
+                *block += 1;
+                let data = self.read_block(b);
+                self.output.extend(data);
+            }
+        }
+        self.output.pop_front().unwrap_or(0xFF)
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        if matches!(self.state, State::Write { .. }) {
+            self.write_data(v);
+            return;
+        }
+
+        // Commands start with 0b01, the host sends 0xFF otherwise
+        if self.cmd.is_empty() && v & 0xC0 != 0x40 {
+            return;
+        }
+        self.cmd.push(v);
+        if self.cmd.len() == CMD_LEN {
+            let cmd = std::mem::take(&mut self.cmd);
+            let arg = u32::from_be_bytes([cmd[1], cmd[2], cmd[3], cmd[4]]);
+            self.execute(cmd[0] & 0x3F, arg);
+        }
+    }
+
+    fn select(&mut self, _sys: &System, selected: bool) {
+        if !selected {
+            self.cmd.clear();
+        }
+    }
+}
@@ -0,0 +1,211 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{rc::Rc, cell::RefCell};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::{peripherals::{Peripherals, gpio::{GpioPorts, Pin, PinDuty}}, symbols::symbols, system::System, util::UniErr, watch::WatchType};
+
+// A heater and its thermal mass, like the hotend or the bed of a 3D printer,
+// to close the temperature control loop of the firmware. The heater is driven
+// by a GPIO pin or a timer PWM output, and the temperature is written back
+// where the firmware reads its sensor, e.g. the ADC DMA buffer or a scripted
+// ADC data register:
+//
+//  thermal:
+//    - name: hotend
+//      heater: PA5             # on/off or software PWM, or pwm: TIM3.CH4
+//      power: 40               # W at full duty
+//      heat_capacity: 12       # J/K
+//      loss: 0.12              # W/K to the ambient
+//      ambient: 25             # C, the initial temperature by default
+//      output:
+//        symbol: g_adc_hotend  # or addr: 0x4001204C
+//        type: u16
+//        thermistor:           # ADC counts of a thermistor with a pull-up
+//          r25: 100000
+//          beta: 3950
+//          pullup: 4700
+//          adc_max: 4095
+//
+// The temperature follows dT/dt = (power*duty - loss*(T - ambient)) / heat_capacity,
+// with the duty measured over each period (10ms of emulated time by default).
+// Without a thermistor, the output is the temperature scaled by `scale` and
+// shifted by `offset`. Other devices can read the temperature by the name of
+// the model.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ThermalConfig {
+    pub name: String,
+    pub heater: Option<String>,
+    /// Alternative to heater, like TIM3.CH4
+    pub pwm: Option<String>,
+    pub active_low: Option<bool>,
+    pub power: f64,
+    pub heat_capacity: f64,
+    pub loss: f64,
+    pub ambient: Option<f64>,
+    pub temperature: Option<f64>,
+    pub period_ms: Option<f64>,
+    pub output: Option<ThermalOutputConfig>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ThermalOutputConfig {
+    pub addr: Option<u32>,
+    /// Alternative to addr. Can be `symbol+offset`.
+    pub symbol: Option<String>,
+    /// float by default
+    pub r#type: Option<WatchType>,
+    pub thermistor: Option<ThermistorConfig>,
+    pub scale: Option<f64>,
+    pub offset: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ThermistorConfig {
+    /// Resistance at 25C, in ohms
+    pub r25: f64,
+    pub beta: f64,
+    /// Between the ADC input and the reference, in ohms
+    pub pullup: f64,
+    pub adc_max: Option<u32>,
+}
+
+const DEFAULT_AMBIENT: f64 = 25.0;
+const DEFAULT_PERIOD_MS: f64 = 10.0;
+const KELVIN: f64 = 273.15;
+
+enum Heater {
+    Pin(Rc<RefCell<PinDuty>>),
+    Pwm { timer: String, channel: u8 },
+}
+
+pub struct Thermal {
+    pub config: ThermalConfig,
+    heater: Heater,
+    output: Option<(u32, WatchType)>,
+    temperature: f64,
+    last_update: u64,
+}
+
+impl Thermal {
+    pub fn new(config: ThermalConfig, gpio: &mut GpioPorts) -> Result<Self> {
+        if config.heat_capacity <= 0.0 || config.loss < 0.0 {
+            bail!("{}: heat_capacity must be positive, and loss not negative", config.name);
+        }
+
+        let heater = match (&config.heater, &config.pwm) {
+            (Some(pin), None) => {
+                let pin = Pin::parse(pin).with_context(|| format!("Invalid heater pin {}", pin))?;
+                let duty = Rc::new(RefCell::new(PinDuty::default()));
+                let d = duty.clone();
+                gpio.add_write_callback(pin, move |_sys, v| d.borrow_mut().set_level(v));
+                Heater::Pin(duty)
+            }
+            (None, Some(pwm)) => {
+                let (timer, channel) = pwm.split_once(".CH")
+                    .and_then(|(t, c)| Some((t.to_string(), c.parse().ok()?)))
+                    .with_context(|| format!("Invalid heater pwm {}, expected like TIM3.CH4", pwm))?;
+                Heater::Pwm { timer, channel }
+            }
+            _ => bail!("{}: the thermal model needs either a heater pin or a pwm", config.name),
+        };
+
+        let output = match config.output {
+            Some(ref o) => {
+                let addr = match (o.addr, &o.symbol) {
+                    (Some(addr), None) => addr,
+                    (None, Some(symbol)) => symbols().parse_addr(symbol)?,
+                    _ => bail!("{}: the output needs either an addr or a symbol", config.name),
+                };
+                let type_ = o.r#type.unwrap_or(WatchType::Float);
+                if matches!(type_, WatchType::Cstring) {
+                    bail!("{}: the output can't be a cstring", config.name);
+                }
+                Some((addr, type_))
+            }
+            None => None,
+        };
+
+        let temperature = config.temperature.or(config.ambient).unwrap_or(DEFAULT_AMBIENT);
+        Ok(Self { config, heater, output, temperature, last_update: 0 })
+    }
+
+    /// In Celsius
+    pub fn temperature(&self) -> f64 {
+        self.temperature
+    }
+
+    fn duty(&self, p: &Peripherals) -> f64 {
+        let duty = match self.heater {
+            Heater::Pin(ref duty) => duty.borrow_mut().measure(),
+            // An unconfigured timer leaves the pin low
+            Heater::Pwm { ref timer, channel } => p.timers.borrow().get(timer)
+                .and_then(|t| t.pwm_duty(timer, channel))
+                .unwrap_or_default(),
+        } as f64;
+        if self.config.active_low.unwrap_or_default() { 1.0 - duty } else { duty }
+    }
+
+    /// Integrates the model up to now, once per period
+    pub fn update(&mut self, sys: &System) -> Result<()> {
+        let now = crate::context::now();
+        let hclk = sys.p.rcc.borrow().hclk() as f64;
+        let period = self.config.period_ms.unwrap_or(DEFAULT_PERIOD_MS) / 1000.0;
+        let dt = now.saturating_sub(self.last_update) as f64 / hclk;
+        if dt < period {
+            return Ok(());
+        }
+        self.last_update = now;
+
+        // The exact solution with a constant duty over the period
+        let c = &self.config;
+        let ambient = c.ambient.unwrap_or(DEFAULT_AMBIENT);
+        let heat = c.power * self.duty(&sys.p);
+        self.temperature = if c.loss > 0.0 {
+            let target = ambient + heat / c.loss;
+            target + (self.temperature - target) * (-dt * c.loss / c.heat_capacity).exp()
+        } else {
+            self.temperature + heat * dt / c.heat_capacity
+        };
+        trace!("Thermal {} temperature={:.2} heat={:.2}", c.name, self.temperature, heat);
+
+        self.write_output(sys)
+    }
+
+    fn output_value(&self, o: &ThermalOutputConfig) -> f64 {
+        match o.thermistor {
+            Some(ref t) => {
+                let kelvin = self.temperature + KELVIN;
+                let r = t.r25 * (t.beta * (1.0 / kelvin - 1.0 / (25.0 + KELVIN))).exp();
+                let adc_max = t.adc_max.unwrap_or(4095) as f64;
+                (adc_max * r / (r + t.pullup)).round().clamp(0.0, adc_max)
+            }
+            None => self.temperature * o.scale.unwrap_or(1.0) + o.offset.unwrap_or_default(),
+        }
+    }
+
+    fn write_output(&self, sys: &System) -> Result<()> {
+        let (Some(o), Some((addr, type_))) = (&self.config.output, self.output) else {
+            return Ok(());
+        };
+        let value = self.output_value(o);
+        let (v, size) = match type_ {
+            WatchType::Float => ((value as f32).to_bits(), 4),
+            WatchType::U8 | WatchType::I8 => (value.round() as i64 as u32, 1),
+            WatchType::U16 | WatchType::I16 => (value.round() as i64 as u32, 2),
+            _ => (value.round() as i64 as u32, 4),
+        };
+
+        let is_peripheral = Peripherals::MEMORY_MAPS.iter().any(|(start, end)| (*start..*end).contains(&addr));
+        if is_peripheral {
+            sys.p.write(sys, addr, size, v);
+        } else {
+            let bytes = v.to_le_bytes();
+            sys.uc.borrow_mut().mem_write(addr as u64, &bytes[..size as usize]).map_err(UniErr)?;
+        }
+        Ok(())
+    }
+}
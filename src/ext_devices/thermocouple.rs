@@ -0,0 +1,184 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{rc::Rc, cell::RefCell};
+
+use anyhow::{Result, bail};
+use serde::Deserialize;
+
+use crate::system::System;
+
+use super::{ExtDevice, thermal::Thermal};
+
+// MAX31855 and MAX6675 thermocouple converters, read-only SPI devices. A
+// conversion is latched when the chip is selected, then shifted out MSB first:
+//
+//  thermocouple:
+//    - peripheral: SPI2
+//      cs: PB12
+//      model: max31855       # or max6675
+//      temperature: 200      # C, fixed
+//      thermal: hotend       # or follows a thermal model
+//      cold_junction: 25     # internal temperature of the MAX31855
+//      events:               # at a given instruction count
+//        - at: 1000000
+//          temperature: 220
+//        - at: 2000000
+//          fault: open       # open, short_gnd, short_vcc, or none
+//
+// The MAX31855 sends 32 bits: the thermocouple temperature in 0.25C steps,
+// the fault bit, the cold junction temperature in 0.0625C steps, and the
+// SCV/SCG/OC fault bits. The MAX6675 sends 16 bits: the temperature in
+// 0.25C steps from 0 to 1023.75C, and the open thermocouple bit.
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ThermocoupleModel {
+    #[default]
+    Max31855,
+    Max6675,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ThermocoupleFault {
+    #[default]
+    None,
+    Open,
+    ShortGnd,
+    ShortVcc,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ThermocoupleConfig {
+    pub peripheral: String,
+    /// Chip select pin, or "nss", when sharing the bus
+    pub cs: Option<String>,
+    pub model: Option<ThermocoupleModel>,
+    /// In Celsius
+    pub temperature: Option<f64>,
+    /// Alternative to temperature, the name of a thermal model
+    pub thermal: Option<String>,
+    pub cold_junction: Option<f64>,
+    pub fault: Option<ThermocoupleFault>,
+    pub events: Option<Vec<ThermocoupleEventConfig>>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ThermocoupleEventConfig {
+    pub at: u64,
+    pub temperature: Option<f64>,
+    pub fault: Option<ThermocoupleFault>,
+}
+
+const DEFAULT_TEMPERATURE: f64 = 25.0;
+
+pub struct Thermocouple {
+    pub config: ThermocoupleConfig,
+    name: String,
+    model: ThermocoupleModel,
+    thermal: Option<Rc<RefCell<Thermal>>>,
+    temperature: f64,
+    fault: ThermocoupleFault,
+    next_event: usize,
+    // The latched conversion, and the bytes already shifted out
+    frame: Vec<u8>,
+    pos: usize,
+}
+
+impl Thermocouple {
+    pub fn new(mut config: ThermocoupleConfig, thermals: &[Rc<RefCell<Thermal>>]) -> Result<Self> {
+        let thermal = match config.thermal {
+            Some(ref name) => match thermals.iter().find(|t| &t.borrow().config.name == name) {
+                Some(t) => Some(t.clone()),
+                None => bail!("Thermocouple on {}: no thermal model named {}", config.peripheral, name),
+            },
+            None => None,
+        };
+        if let Some(ref mut events) = config.events {
+            events.sort_by_key(|e| e.at);
+        }
+        let model = config.model.unwrap_or_default();
+        let temperature = config.temperature.unwrap_or(DEFAULT_TEMPERATURE);
+        let fault = config.fault.unwrap_or_default();
+        Ok(Self { config, name: String::new(), model, thermal, temperature, fault, next_event: 0, frame: vec![], pos: 0 })
+    }
+
+    fn apply_events(&mut self) {
+        let now = crate::context::now();
+        let events = self.config.events.as_deref().unwrap_or_default();
+        while let Some(e) = events.get(self.next_event).filter(|e| e.at <= now) {
+            if let Some(temperature) = e.temperature {
+                self.temperature = temperature;
+            }
+            if let Some(fault) = e.fault {
+                self.fault = fault;
+            }
+            debug!("{} temperature={} fault={:?}", self.name, self.temperature, self.fault);
+            self.next_event += 1;
+        }
+    }
+
+    fn temperature(&self) -> f64 {
+        match self.thermal {
+            Some(ref t) => t.borrow().temperature(),
+            None => self.temperature,
+        }
+    }
+
+    /// Latches a conversion
+    fn convert(&mut self) {
+        self.apply_events();
+        let temperature = self.temperature();
+        self.frame = match self.model {
+            ThermocoupleModel::Max31855 => {
+                let tc = ((temperature * 4.0).round().clamp(-8192.0, 8191.0) as i32 as u32) & 0x3FFF;
+                let cold = self.config.cold_junction.unwrap_or(DEFAULT_TEMPERATURE);
+                let cold = ((cold * 16.0).round().clamp(-2048.0, 2047.0) as i32 as u32) & 0xFFF;
+                let fault_bits = match self.fault {
+                    ThermocoupleFault::None => 0,
+                    ThermocoupleFault::Open => 1 << 0,
+                    ThermocoupleFault::ShortGnd => 1 << 1,
+                    ThermocoupleFault::ShortVcc => 1 << 2,
+                };
+                let fault = (fault_bits != 0) as u32;
+                ((tc << 18) | (fault << 16) | (cold << 4) | fault_bits).to_be_bytes().to_vec()
+            }
+            ThermocoupleModel::Max6675 => {
+                let tc = (temperature * 4.0).round().clamp(0.0, 4095.0) as u16;
+                // Only an open thermocouple is detected
+                let open = (self.fault == ThermocoupleFault::Open) as u16;
+                ((tc << 3) | (open << 2)).to_be_bytes().to_vec()
+            }
+        };
+        self.pos = 0;
+    }
+}
+
+impl ExtDevice<(), u8> for Thermocouple {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        let model = match self.model {
+            ThermocoupleModel::Max31855 => "max31855",
+            ThermocoupleModel::Max6675 => "max6675",
+        };
+        self.name = format!("{} {}", peri_name, model);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        // Without a chip select, a new conversion starts once the frame is read
+        if self.pos >= self.frame.len() && self.config.cs.is_none() {
+            self.convert();
+        }
+        let v = self.frame.get(self.pos).copied().unwrap_or(0);
+        self.pos += 1;
+        v
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), _v: u8) {}
+
+    fn select(&mut self, _sys: &System, selected: bool) {
+        if selected {
+            self.convert();
+        }
+    }
+}
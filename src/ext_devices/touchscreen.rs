@@ -10,10 +10,43 @@ use serde::Deserialize;
 use crate::framebuffers::{Framebuffer, Framebuffers, RGB565};
 use crate::peripherals::gpio::{GpioPorts, Pin};
 use crate::system::System;
+use crate::util::Rng;
 
 use super::ExtDevice;
 
 // Implements a ADS7846 controller
+//
+// Touches come from the mouse on the SDL window of the framebuffer, or from a
+// script, which works headless as well:
+//
+//  touchscreen:
+//    - peripheral: SPI1
+//      framebuffer: Display
+//      touches:
+//        - at: 50000000      # number of instructions emulated
+//          x: 120
+//          y: 200
+//          duration: 2000000 # defaults to 1000000 instructions
+//
+// The flip_x, flip_y, swap_x_y and scale_down flags cover the simple cases.
+// Otherwise, a calibration matrix maps the framebuffer coordinates to the
+// 12-bit values measured by the controller, like the ones computed by the
+// calibration routine of the firmware:
+//
+//      calibration:
+//        - [0.0, 13.5, 200]  # X = 0.0*x + 13.5*y + 200
+//        - [-11.2, 0.0, 3900] # Y = -11.2*x + 0.0*y + 3900
+//
+// Firmware usually takes several samples and checks the pressure. The Z1 and
+// Z2 measures can be set, and noise added to every conversion:
+//
+//      z1: 400
+//      z2: 2000
+//      noise: 8            # +/- 8 LSB, uniformly distributed
+//      seed: 42
+//
+// While the chip is selected, the pen stays at the same position, so all the
+// samples of a burst measure the same touch.
 
 #[derive(Debug, Deserialize, Default)]
 pub struct TouchscreenConfig {
@@ -24,6 +57,40 @@ pub struct TouchscreenConfig {
     pub swap_x_y: Option<bool>,
     pub touch_detected_pin: Option<String>,
     pub scale_down: Option<u32>,
+    /// Chip select pin, or "nss", when sharing the bus
+    pub cs: Option<String>,
+    pub touches: Option<Vec<TouchConfig>>,
+    /// Affine transform from framebuffer coordinates to measured values.
+    /// The flags above are ignored when set.
+    pub calibration: Option<[[f32; 3]; 2]>,
+    /// Pressure measures. Both default to 10.
+    pub z1: Option<u16>,
+    pub z2: Option<u16>,
+    /// Maximum noise added to each conversion, in LSB
+    pub noise: Option<u16>,
+    /// Defaults to 1
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct TouchConfig {
+    pub at: u64,
+    pub x: u16,
+    pub y: u16,
+    pub duration: Option<u64>,
+}
+
+const DEFAULT_TOUCH_DURATION: u64 = 1_000_000;
+const MAX: u32 = 0xfff;
+
+/// The touch position, from the script when a scripted touch is ongoing,
+/// from the framebuffer otherwise.
+pub fn touch_position(framebuffer: &RefCell<dyn Framebuffer<RGB565>>, touches: &[TouchConfig]) -> Option<(u16, u16)> {
+    let now = crate::context::now();
+    touches.iter()
+        .find(|t| (t.at..t.at + t.duration.unwrap_or(DEFAULT_TOUCH_DURATION)).contains(&now))
+        .map(|t| (t.x, t.y))
+        .or_else(|| framebuffer.borrow().get_touch_position())
 }
 
 pub struct Touchscreen {
@@ -31,28 +98,63 @@ pub struct Touchscreen {
     name: String,
 
     framebuffer: Rc<RefCell<dyn Framebuffer<RGB565>>>,
+    touches: Rc<Vec<TouchConfig>>,
     reply: Option<VecDeque<u8>>,
+    /// The pen position of the current chip select burst
+    pen: Option<Option<(u16, u16)>>,
+    rng: Rng,
 }
 
 impl Touchscreen {
     pub fn new(config: TouchscreenConfig, gpio: &mut GpioPorts, framebuffers: &Framebuffers) -> Result<Self> {
         let framebuffer = framebuffers.get(&config.framebuffer)?;
+        let touches = Rc::new(config.touches.clone().unwrap_or_default());
+
+        if config.calibration.is_some() &&
+           ([config.flip_x, config.flip_y, config.swap_x_y].contains(&Some(true)) || config.scale_down.is_some()) {
+            warn!("Touchscreen {} has a calibration, flip_x, flip_y, swap_x_y and scale_down are ignored", config.framebuffer);
+        }
 
         if let Some(ref touch_detected_pin) = config.touch_detected_pin {
             let touch_detected_pin = Pin::from_str(touch_detected_pin);
             let framebuffer = framebuffer.clone();
+            let touches = touches.clone();
             gpio.add_read_callback(touch_detected_pin, move |_sys| {
-                framebuffer.borrow().get_touch_position().is_none()
+                touch_position(&framebuffer, &touches).is_none()
             });
         }
 
         Ok(Self {
+            rng: Rng::new(config.seed.unwrap_or_default()),
             config,
             name: "".to_string(), // filled up in connect_periperhal()
             framebuffer,
+            touches,
             reply: None,
+            pen: None,
         })
     }
+
+    fn pen_position(&mut self) -> Option<(u16, u16)> {
+        if let Some(pen) = self.pen {
+            return pen;
+        }
+        let pen = touch_position(&self.framebuffer, &self.touches);
+        // Without a chip select, there's no burst to hold the pen for
+        if self.config.cs.is_some() {
+            self.pen = Some(pen);
+        }
+        pen
+    }
+
+    fn add_noise(&mut self, v: u32) -> u32 {
+        let noise = self.config.noise.unwrap_or_default() as i64;
+        if noise == 0 {
+            return v;
+        }
+        let r = (self.rng.next_u64() >> 32) as i64;
+        (v as i64 + r % (2*noise + 1) - noise).clamp(0, MAX as i64) as u32
+    }
 }
 
 impl ExtDevice<(), u8> for Touchscreen {
@@ -71,34 +173,20 @@ impl ExtDevice<(), u8> for Touchscreen {
 
     fn write(&mut self, _sys: &System, _addr: (), v: u8) {
         if let Some(cmd) = Command::try_from(v).ok() {
-            let fb = self.framebuffer.borrow();
-            const MAX: u32 = 0xfff;
-            if let Some(pos) = fb.get_touch_position() {
-                let op = match (self.config.swap_x_y, cmd.op) {
-                    (Some(true), Operation::MeasureX) => Operation::MeasureY,
-                    (Some(true), Operation::MeasureY) => Operation::MeasureX,
-                    _ => cmd.op,
-                };
-
-                let v = match op {
-                    Operation::MeasureX => (pos.0 as u32 * MAX) / fb.get_config().width as u32,
-                    Operation::MeasureY => (pos.1 as u32 * MAX) / fb.get_config().height as u32,
-                    Operation::MeasureZ1 => 10,
-                    Operation::MeasureZ2 => 10,
-                };
-
-                let v = match (op, self.config.flip_x, self.config.flip_y) {
-                    (Operation::MeasureX, Some(true), _) => (MAX - v),
-                    (Operation::MeasureY, _, Some(true)) => (MAX - v),
-                    _ => v,
-                };
-
-                // Not sure why we need this
-                let v = if let Some(scale_down) = self.config.scale_down {
-                    v / scale_down
+            if let Some(pos) = self.pen_position() {
+                let v = if let Some(m) = self.config.calibration {
+                    let (x, y) = (pos.0 as f32, pos.1 as f32);
+                    let apply = |r: [f32; 3]| (r[0]*x + r[1]*y + r[2]).round().clamp(0.0, MAX as f32) as u32;
+                    match cmd.op {
+                        Operation::MeasureX => apply(m[0]),
+                        Operation::MeasureY => apply(m[1]),
+                        Operation::MeasureZ1 => self.z1(),
+                        Operation::MeasureZ2 => self.z2(),
+                    }
                 } else {
-                    v
+                    self.uncalibrated_measure(cmd.op, pos)
                 };
+                let v = self.add_noise(v);
 
                 // We don't care if we are doing a 12bit or 8bit convertion as MSB comes first.
                 // 0000AABB CCDDEEFF -> AABBCCDD EEFF0000
@@ -111,6 +199,53 @@ impl ExtDevice<(), u8> for Touchscreen {
 
         }
     }
+
+    fn select(&mut self, _sys: &System, selected: bool) {
+        if !selected {
+            self.reply = None;
+            self.pen = None;
+        }
+    }
+}
+
+impl Touchscreen {
+    fn z1(&self) -> u32 {
+        self.config.z1.unwrap_or(10) as u32
+    }
+
+    fn z2(&self) -> u32 {
+        self.config.z2.unwrap_or(10) as u32
+    }
+
+    /// The measure from the flip, swap and scale_down flags
+    fn uncalibrated_measure(&self, op: Operation, pos: (u16, u16)) -> u32 {
+        let fb = self.framebuffer.borrow();
+        let op = match (self.config.swap_x_y, op) {
+            (Some(true), Operation::MeasureX) => Operation::MeasureY,
+            (Some(true), Operation::MeasureY) => Operation::MeasureX,
+            _ => op,
+        };
+
+        let v = match op {
+            Operation::MeasureX => (pos.0 as u32 * MAX) / fb.get_config().width as u32,
+            Operation::MeasureY => (pos.1 as u32 * MAX) / fb.get_config().height as u32,
+            Operation::MeasureZ1 => self.z1(),
+            Operation::MeasureZ2 => self.z2(),
+        };
+
+        let v = match (op, self.config.flip_x, self.config.flip_y) {
+            (Operation::MeasureX, Some(true), _) => MAX - v,
+            (Operation::MeasureY, _, Some(true)) => MAX - v,
+            _ => v,
+        };
+
+        // Not sure why we need this
+        if let Some(scale_down) = self.config.scale_down {
+            v / scale_down
+        } else {
+            v
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy, num_enum::TryFromPrimitive)]
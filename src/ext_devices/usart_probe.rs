@@ -1,15 +1,27 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use anyhow::Result;
+use std::{io::{prelude::*, BufWriter}, net::TcpListener, fs::{File, OpenOptions}, sync::mpsc::{Sender, Receiver}};
+
+use anyhow::{Context, Result};
 use serde::Deserialize;
 
 use crate::system::System;
 
-use super::ExtDevice;
+use super::{ExtDevice, background::Background};
 
 #[derive(Debug, Deserialize, Default)]
 pub struct UsartProbeConfig {
     pub peripheral: String,
+    /// Listen on this address, e.g. "127.0.0.1:4000". The firmware output is
+    /// sent to the connected client, and what the client sends is received by the firmware.
+    pub tcp: Option<String>,
+    /// Bytes received by the firmware are read from this file, or named FIFO
+    pub rx_file: Option<String>,
+    /// Baud rate at which the rx_file bytes arrive, with 10 bits per byte.
+    /// Without it, they are there as soon as the firmware reads them.
+    pub rx_baud: Option<u32>,
+    /// Bytes sent by the firmware are appended to this file, or named FIFO
+    pub tx_file: Option<String>,
 }
 
 #[derive(Default)]
@@ -17,11 +29,134 @@ pub struct UsartProbe {
     pub config: UsartProbeConfig,
     name: String,
     rx: Vec<u8>,
+    tcp: Option<Background<u8, u8>>,
+    rx_file: Option<Background<(), u8>>,
+    tx_file: Option<Background<u8, ()>>,
+    // Emulated time at which the next rx_file byte arrives
+    next_rx_at: u64,
 }
 
 impl UsartProbe {
     pub fn new(config: UsartProbeConfig) -> Result<Self> {
-        Ok(Self { config, ..Self::default() })
+        let tcp = config.tcp.as_ref().map(|addr| {
+            let listener = TcpListener::bind(addr)
+                .with_context(|| format!("Failed to listen on {}", addr))?;
+            info!("usart-probe listening on {}", addr);
+            Background::spawn("usart-probe", move |rx, tx| Self::serve_tcp(listener, rx, tx))
+        }).transpose()?;
+
+        // Opening a FIFO blocks until the other side opens it, so it's done on the worker thread
+        let rx_file = config.rx_file.clone().map(|path| {
+            Background::spawn("usart-probe-rx", move |_rx, tx| Self::read_file(&path, tx))
+        }).transpose()?;
+
+        let tx_file = config.tx_file.clone().map(|path| {
+            Background::spawn("usart-probe-tx", move |rx, _tx| Self::write_file(&path, rx))
+        }).transpose()?;
+
+        Ok(Self { config, tcp, rx_file, tx_file, ..Self::default() })
+    }
+
+    fn read_file(path: &str, tx: Sender<u8>) {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("usart-probe failed to open file={}: {}", path, e);
+                return;
+            }
+        };
+        info!("usart-probe reading file={}", path);
+
+        let mut buf = [0; 256];
+        while let Ok(n @ 1..) = file.read(&mut buf) {
+            if buf[..n].iter().any(|b| tx.send(*b).is_err()) {
+                return;
+            }
+        }
+        debug!("usart-probe end of file={}", path);
+    }
+
+    fn write_file(path: &str, rx: Receiver<u8>) {
+        let file = match OpenOptions::new().create(true).append(true).open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                warn!("usart-probe failed to open file={}: {}", path, e);
+                return;
+            }
+        };
+        info!("usart-probe writing file={}", path);
+
+        let mut file = BufWriter::new(file);
+        while let Ok(v) = rx.recv() {
+            // Flush once we've caught up with the firmware
+            let pending = std::iter::once(v).chain(rx.try_iter()).collect::<Vec<_>>();
+            if let Err(e) = file.write_all(&pending).and_then(|_| file.flush()) {
+                warn!("usart-probe failed to write file={}: {}", path, e);
+                return;
+            }
+        }
+    }
+
+    fn now() -> u64 {
+        crate::context::now()
+    }
+
+    /// Whether the next rx_file byte has arrived
+    fn has_file_data(&mut self) -> bool {
+        Self::now() >= self.next_rx_at &&
+            self.rx_file.as_mut().is_some_and(|f| f.has_data())
+    }
+
+    fn read_file_byte(&mut self, sys: &System) -> Option<u8> {
+        if !self.has_file_data() {
+            return None;
+        }
+        if let Some(baud) = self.config.rx_baud.filter(|b| *b > 0) {
+            let hclk = sys.p.rcc.borrow().hclk() as u64;
+            self.next_rx_at = Self::now() + 10 * hclk / baud as u64;
+        }
+        self.rx_file.as_mut().and_then(|f| f.try_recv())
+    }
+
+    fn serve_tcp(listener: TcpListener, rx: Receiver<u8>, tx: Sender<u8>) {
+        loop {
+            let mut stream = match listener.accept() {
+                Ok((stream, addr)) => {
+                    info!("usart-probe client connected addr={}", addr);
+                    stream
+                }
+                Err(e) => {
+                    warn!("usart-probe accept failed: {}", e);
+                    return;
+                }
+            };
+
+            // Client to firmware
+            if let Ok(mut reader) = stream.try_clone() {
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    let mut buf = [0; 256];
+                    while let Ok(n @ 1..) = reader.read(&mut buf) {
+                        if buf[..n].iter().any(|b| tx.send(*b).is_err()) {
+                            break;
+                        }
+                    }
+                });
+            }
+
+            // Firmware to client
+            loop {
+                let v = match rx.recv() {
+                    Ok(v) => v,
+                    // The emulation is over
+                    Err(_) => return,
+                };
+                if stream.write_all(&[v]).is_err() {
+                    info!("usart-probe client disconnected");
+                    break;
+                }
+            }
+        }
     }
 }
 
@@ -31,11 +166,21 @@ impl ExtDevice<(), u8> for UsartProbe {
         self.name.clone()
     }
 
-    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
-        0
+    fn read(&mut self, sys: &System, _addr: ()) -> u8 {
+        self.tcp.as_mut()
+            .and_then(|tcp| tcp.try_recv())
+            .or_else(|| self.read_file_byte(sys))
+            .unwrap_or_default()
     }
 
     fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        if let Some(ref tcp) = self.tcp {
+            tcp.send(v);
+        }
+        if let Some(ref tx_file) = self.tx_file {
+            tx_file.send(v);
+        }
+
         if v == 0x0a {
             // EOL
             let line = String::from_utf8_lossy(&self.rx);
@@ -46,4 +191,8 @@ impl ExtDevice<(), u8> for UsartProbe {
             self.rx.push(v);
         }
     }
+
+    fn has_data(&mut self, _sys: &System) -> bool {
+        self.tcp.as_mut().is_some_and(|tcp| tcp.has_data()) || self.has_file_data()
+    }
 }
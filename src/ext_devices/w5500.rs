@@ -0,0 +1,500 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{cell::RefCell, collections::VecDeque, net::{Ipv4Addr, UdpSocket}, rc::Rc};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::{system::System, peripherals::gpio::{GpioPorts, Pin}};
+
+use super::{ExtDevice, socket::{TcpSocket, SocketEvent}};
+
+// A WIZnet W5500 Ethernet controller on a SPI bus. The sockets of the chip are
+// host sockets: TCP connections are made from the host, TCP servers listen on
+// the host, and UDP sockets are bound on the host:
+//
+//  w5500:
+//    - peripheral: SPI1
+//      cs: PA4
+//      int: PC4              # active low
+//      port_offset: 8000     # a server on port 80 is reachable on the host port 8080
+//
+// The link is always up. The network configuration of the firmware (MAC, IP,
+// gateway) is kept, but not used. DHCP and DNS work when the host network
+// answers them. MACRAW mode, and the keep-alive and retransmission timers are
+// not supported.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct W5500Config {
+    pub peripheral: String,
+    /// Chip select pin, or "nss". Raising it ends the variable length frames.
+    pub cs: String,
+    pub int: Option<String>,
+    /// Added to the ports the firmware listens on. Low ports usually need privileges.
+    pub port_offset: Option<u16>,
+}
+
+const NUM_SOCKETS: usize = 8;
+const COMMON_REGS_LEN: usize = 0x40;
+const SOCKET_REGS_LEN: usize = 0x30;
+const BUFFER_LEN: usize = 16*1024;
+const MAX_UDP_PAYLOAD_LEN: usize = 1472;
+// IP, port and length before each UDP payload in the RX buffer
+const UDP_HEADER_LEN: usize = 8;
+
+// Common registers
+const MR: usize = 0x00;
+const IR: usize = 0x15;
+const SIR: usize = 0x17;
+const SIMR: usize = 0x18;
+const PHYCFGR: usize = 0x2E;
+const VERSIONR: usize = 0x39;
+const MR_RST: u8 = 1 << 7;
+// Link up, 100M full duplex
+const PHYCFGR_LINK: u8 = 0x07;
+
+// Socket registers
+const SN_MR: usize = 0x00;
+const SN_CR: usize = 0x01;
+const SN_IR: usize = 0x02;
+const SN_SR: usize = 0x03;
+const SN_PORT: usize = 0x04;
+const SN_DIPR: usize = 0x0C;
+const SN_DPORT: usize = 0x10;
+const SN_RXBUF_SIZE: usize = 0x1E;
+const SN_TXBUF_SIZE: usize = 0x1F;
+const SN_TX_FSR: usize = 0x20;
+const SN_TX_RD: usize = 0x22;
+const SN_TX_WR: usize = 0x24;
+const SN_RX_RSR: usize = 0x26;
+const SN_RX_RD: usize = 0x28;
+const SN_RX_WR: usize = 0x2A;
+const SN_IMR: usize = 0x2C;
+
+const SN_MR_TCP: u8 = 0x01;
+const SN_MR_UDP: u8 = 0x02;
+
+const CR_OPEN: u8 = 0x01;
+const CR_LISTEN: u8 = 0x02;
+const CR_CONNECT: u8 = 0x04;
+const CR_DISCON: u8 = 0x08;
+const CR_CLOSE: u8 = 0x10;
+const CR_SEND: u8 = 0x20;
+const CR_SEND_MAC: u8 = 0x21;
+const CR_RECV: u8 = 0x40;
+
+const IR_CON: u8 = 1 << 0;
+const IR_DISCON: u8 = 1 << 1;
+const IR_RECV: u8 = 1 << 2;
+const IR_TIMEOUT: u8 = 1 << 3;
+const IR_SENDOK: u8 = 1 << 4;
+
+const SR_CLOSED: u8 = 0x00;
+const SR_INIT: u8 = 0x13;
+const SR_LISTEN: u8 = 0x14;
+const SR_SYNSENT: u8 = 0x15;
+const SR_ESTABLISHED: u8 = 0x17;
+const SR_CLOSE_WAIT: u8 = 0x1C;
+const SR_UDP: u8 = 0x22;
+
+const COMMON_BLOCK: u8 = 0;
+
+/// The 3 bytes header of a SPI frame is collected, then the data phase
+/// accesses consecutive addresses.
+#[derive(Debug)]
+enum Frame {
+    Header(Vec<u8>),
+    Data { block: u8, write: bool, addr: u16, remaining: Option<usize> },
+}
+
+impl Default for Frame {
+    fn default() -> Self { Frame::Header(vec![]) }
+}
+
+struct Socket {
+    regs: [u8; SOCKET_REGS_LEN],
+    tx_buf: Vec<u8>,
+    rx_buf: Vec<u8>,
+    tcp: Option<TcpSocket>,
+    udp: Option<UdpSocket>,
+    // Received TCP data that doesn't fit in the RX buffer yet
+    pending: VecDeque<u8>,
+}
+
+impl Default for Socket {
+    fn default() -> Self {
+        let mut regs = [0; SOCKET_REGS_LEN];
+        regs[0x06..0x0C].fill(0xFF); // Sn_DHAR
+        regs[0x16] = 0x80; // Sn_TTL
+        regs[SN_RXBUF_SIZE] = 2;
+        regs[SN_TXBUF_SIZE] = 2;
+        regs[SN_IMR] = 0xFF;
+        regs[0x2D] = 0x40; // Sn_FRAG
+        Self {
+            regs,
+            tx_buf: vec![0; BUFFER_LEN],
+            rx_buf: vec![0; BUFFER_LEN],
+            tcp: None,
+            udp: None,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+impl Socket {
+    fn reg16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.regs[offset], self.regs[offset+1]])
+    }
+
+    fn set_reg16(&mut self, offset: usize, v: u16) {
+        self.regs[offset..offset+2].copy_from_slice(&v.to_be_bytes());
+    }
+
+    fn tx_size(&self) -> usize {
+        (self.regs[SN_TXBUF_SIZE] as usize * 1024).min(BUFFER_LEN)
+    }
+
+    fn rx_size(&self) -> usize {
+        (self.regs[SN_RXBUF_SIZE] as usize * 1024).min(BUFFER_LEN)
+    }
+
+    fn rx_used(&self) -> usize {
+        self.reg16(SN_RX_WR).wrapping_sub(self.reg16(SN_RX_RD)) as usize
+    }
+
+    fn tx_free(&self) -> u16 {
+        let used = self.reg16(SN_TX_WR).wrapping_sub(self.reg16(SN_TX_RD)) as usize;
+        self.tx_size().saturating_sub(used) as u16
+    }
+
+    fn read_reg(&self, offset: usize) -> u8 {
+        match offset {
+            // Commands are executed right away
+            SN_CR => 0,
+            SN_TX_FSR | 0x21 => self.tx_free().to_be_bytes()[offset - SN_TX_FSR],
+            SN_RX_RSR | 0x27 => (self.rx_used() as u16).to_be_bytes()[offset - SN_RX_RSR],
+            _ => self.regs.get(offset).copied().unwrap_or_default(),
+        }
+    }
+
+    /// Appends to the RX buffer, when there's room for all of it
+    fn push_rx(&mut self, data: &[u8]) -> bool {
+        let size = self.rx_size();
+        if size == 0 || self.rx_used() + data.len() > size {
+            return false;
+        }
+        let wr = self.reg16(SN_RX_WR);
+        for (i, b) in data.iter().enumerate() {
+            self.rx_buf[(wr as usize + i) & (size-1)] = *b;
+        }
+        self.set_reg16(SN_RX_WR, wr.wrapping_add(data.len() as u16));
+        self.regs[SN_IR] |= IR_RECV;
+        true
+    }
+
+    fn close(&mut self) {
+        // Dropping the queue ends the worker, which closes the host socket
+        self.tcp = None;
+        self.udp = None;
+        self.pending.clear();
+        self.regs[SN_SR] = SR_CLOSED;
+    }
+}
+
+pub struct W5500 {
+    pub config: W5500Config,
+    name: String,
+    common: [u8; COMMON_REGS_LEN],
+    sockets: Vec<Socket>,
+    frame: Frame,
+}
+
+impl W5500 {
+    pub fn new(config: W5500Config, gpio: &mut GpioPorts) -> Result<Rc<RefCell<Self>>> {
+        let int_pin = config.int.as_deref().map(Pin::from_str);
+
+        let self_ = Rc::new(RefCell::new(Self {
+            config,
+            name: String::new(),
+            common: [0; COMMON_REGS_LEN],
+            sockets: vec![],
+            frame: Frame::default(),
+        }));
+        self_.borrow_mut().reset();
+
+        if let Some(pin) = int_pin {
+            let s = self_.clone();
+            gpio.add_read_callback(pin, move |_sys| {
+                let mut s = s.borrow_mut();
+                s.update();
+                s.socket_interrupts() & s.common[SIMR] == 0
+            });
+        }
+
+        Ok(self_)
+    }
+
+    fn reset(&mut self) {
+        self.common = [0; COMMON_REGS_LEN];
+        self.common[0x19..0x1B].copy_from_slice(&0x07D0u16.to_be_bytes()); // RTR
+        self.common[0x1B] = 0x08; // RCR
+        self.common[0x1C] = 0x28; // PTIMER
+        self.common[PHYCFGR] = 0xB8;
+        self.common[VERSIONR] = 0x04;
+        self.sockets = (0..NUM_SOCKETS).map(|_| Socket::default()).collect();
+    }
+
+    /// SIR, the sockets with an enabled interrupt pending
+    fn socket_interrupts(&self) -> u8 {
+        self.sockets.iter().enumerate()
+            .filter(|(_, s)| s.regs[SN_IR] & s.regs[SN_IMR] != 0)
+            .fold(0, |sir, (n, _)| sir | (1 << n))
+    }
+
+    fn local_port(&self, n: usize) -> u16 {
+        self.sockets[n].reg16(SN_PORT).wrapping_add(self.config.port_offset.unwrap_or_default())
+    }
+
+    fn destination(&self, n: usize) -> (Ipv4Addr, u16) {
+        let s = &self.sockets[n];
+        let ip: [u8; 4] = s.regs[SN_DIPR..SN_DIPR+4].try_into().unwrap();
+        (ip.into(), s.reg16(SN_DPORT))
+    }
+
+    fn command(&mut self, n: usize, cmd: u8) {
+        let name = format!("{} socket={}", self.name, n);
+        let (sr, mode) = (self.sockets[n].regs[SN_SR], self.sockets[n].regs[SN_MR] & 0x0F);
+        debug!("{} cmd=0x{:02x} status=0x{:02x}", name, cmd, sr);
+
+        match cmd {
+            CR_OPEN => {
+                let port = self.local_port(n);
+                let s = &mut self.sockets[n];
+                s.close();
+                for offset in [SN_TX_RD, SN_TX_WR, SN_RX_RD, SN_RX_WR] {
+                    s.set_reg16(offset, 0);
+                }
+                match mode {
+                    SN_MR_TCP => s.regs[SN_SR] = SR_INIT,
+                    SN_MR_UDP => {
+                        let socket = UdpSocket::bind(("0.0.0.0", port))
+                            .or_else(|e| {
+                                warn!("{} failed to bind UDP port={}: {}, using any port", name, port, e);
+                                UdpSocket::bind(("0.0.0.0", 0))
+                            })
+                            .and_then(|u| { u.set_nonblocking(true)?; u.set_broadcast(true)?; Ok(u) });
+                        match socket {
+                            Ok(socket) => {
+                                s.udp = Some(socket);
+                                s.regs[SN_SR] = SR_UDP;
+                            }
+                            Err(e) => warn!("{} failed to open UDP socket: {}", name, e),
+                        }
+                    }
+                    _ => warn!("{} unsupported mode=0x{:x}", name, mode),
+                }
+            }
+            CR_LISTEN if sr == SR_INIT => {
+                let port = self.local_port(n);
+                let s = &mut self.sockets[n];
+                if let Ok(tcp) = TcpSocket::listen(&name, port) {
+                    s.tcp = Some(tcp);
+                    s.regs[SN_SR] = SR_LISTEN;
+                }
+            }
+            CR_CONNECT if sr == SR_INIT => {
+                let (ip, port) = self.destination(n);
+                let s = &mut self.sockets[n];
+                if let Ok(tcp) = TcpSocket::connect(&name, ip.to_string(), port) {
+                    s.tcp = Some(tcp);
+                    s.regs[SN_SR] = SR_SYNSENT;
+                }
+            }
+            CR_DISCON if mode == SN_MR_TCP => {
+                let s = &mut self.sockets[n];
+                s.close();
+                s.regs[SN_IR] |= IR_DISCON;
+            }
+            CR_CLOSE => self.sockets[n].close(),
+            CR_SEND | CR_SEND_MAC => {
+                let dest = self.destination(n);
+                let s = &mut self.sockets[n];
+                let (rd, wr) = (s.reg16(SN_TX_RD), s.reg16(SN_TX_WR));
+                let mask = s.tx_size().saturating_sub(1);
+                let data = (0..wr.wrapping_sub(rd))
+                    .map(|i| s.tx_buf[rd.wrapping_add(i) as usize & mask])
+                    .collect::<Vec<_>>();
+                trace!("{} send len={}", name, data.len());
+                if let Some(ref tcp) = s.tcp {
+                    tcp.send(data);
+                } else if let Some(ref udp) = s.udp {
+                    if let Err(e) = udp.send_to(&data, dest) {
+                        warn!("{} failed to send to {}:{}: {}", name, dest.0, dest.1, e);
+                    }
+                }
+                s.set_reg16(SN_TX_RD, wr);
+                s.regs[SN_IR] |= IR_SENDOK;
+            }
+            CR_RECV => {
+                // The firmware has moved Sn_RX_RD. Data left comes with another RECV.
+                let s = &mut self.sockets[n];
+                if s.rx_used() > 0 {
+                    s.regs[SN_IR] |= IR_RECV;
+                }
+            }
+            _ => debug!("{} unsupported cmd=0x{:02x} status=0x{:02x}", name, cmd, sr),
+        }
+        self.update();
+    }
+
+    /// Moves the data received on the host sockets into the RX buffers
+    fn update(&mut self) {
+        for s in &mut self.sockets {
+            while let Some(event) = s.tcp.as_mut().and_then(|t| t.try_recv()) {
+                match event {
+                    SocketEvent::Connected => {
+                        s.regs[SN_SR] = SR_ESTABLISHED;
+                        s.regs[SN_IR] |= IR_CON;
+                    }
+                    SocketEvent::Failed => {
+                        s.close();
+                        s.regs[SN_IR] |= IR_TIMEOUT;
+                    }
+                    SocketEvent::Data(data) => s.pending.extend(data),
+                    SocketEvent::Closed => {
+                        s.regs[SN_SR] = SR_CLOSE_WAIT;
+                        s.regs[SN_IR] |= IR_DISCON;
+                    }
+                }
+            }
+
+            if !s.pending.is_empty() {
+                let free = s.rx_size().saturating_sub(s.rx_used());
+                let data = s.pending.drain(..free.min(s.pending.len())).collect::<Vec<_>>();
+                s.push_rx(&data);
+            }
+
+            let mut buf = [0; MAX_UDP_PAYLOAD_LEN];
+            while let Some((len, from)) = s.udp.as_ref().and_then(|u| u.recv_from(&mut buf).ok()) {
+                let std::net::SocketAddr::V4(from) = from else { continue };
+                let mut packet = Vec::with_capacity(UDP_HEADER_LEN + len);
+                packet.extend(from.ip().octets());
+                packet.extend(from.port().to_be_bytes());
+                packet.extend((len as u16).to_be_bytes());
+                packet.extend(&buf[..len]);
+                if !s.push_rx(&packet) {
+                    trace!("{} RX buffer full, UDP packet dropped", self.name);
+                }
+            }
+        }
+    }
+
+    fn read_mem(&mut self, block: u8, addr: u16) -> u8 {
+        let addr = addr as usize;
+        if block == COMMON_BLOCK {
+            return match addr {
+                SIR => self.socket_interrupts(),
+                PHYCFGR => self.common[PHYCFGR] | PHYCFGR_LINK,
+                _ => self.common.get(addr).copied().unwrap_or_default(),
+            };
+        }
+        let Some(s) = self.sockets.get((block as usize - 1) / 4) else { return 0 };
+        match (block - 1) % 4 {
+            0 => s.read_reg(addr),
+            1 => s.tx_buf[addr & s.tx_size().saturating_sub(1)],
+            2 => s.rx_buf[addr & s.rx_size().saturating_sub(1)],
+            _ => 0,
+        }
+    }
+
+    fn write_mem(&mut self, block: u8, addr: u16, v: u8) {
+        let addr = addr as usize;
+        if block == COMMON_BLOCK {
+            match addr {
+                MR if v & MR_RST != 0 => {
+                    debug!("{} reset", self.name);
+                    self.reset();
+                }
+                // Write 1 to clear
+                IR => self.common[IR] &= !v,
+                SIR | VERSIONR => {}
+                PHYCFGR => self.common[PHYCFGR] = v & 0xF8,
+                _ if addr < COMMON_REGS_LEN => self.common[addr] = v,
+                _ => {}
+            }
+            return;
+        }
+        let n = (block as usize - 1) / 4;
+        if n < NUM_SOCKETS && (block - 1) & 3 == 0 && addr == SN_CR {
+            self.command(n, v);
+            return;
+        }
+        let Some(s) = self.sockets.get_mut(n) else { return };
+        match (block - 1) % 4 {
+            0 => match addr {
+                SN_IR => s.regs[SN_IR] &= !v,
+                // Read-only
+                SN_SR | SN_TX_FSR | 0x21 | SN_TX_RD | 0x23 | SN_RX_RSR | 0x27 | SN_RX_WR | 0x2B => {}
+                _ if addr < SOCKET_REGS_LEN => s.regs[addr] = v,
+                _ => {}
+            }
+            1 => { let mask = s.tx_size().saturating_sub(1); s.tx_buf[addr & mask] = v }
+            2 => { let mask = s.rx_size().saturating_sub(1); s.rx_buf[addr & mask] = v }
+            _ => {}
+        }
+    }
+}
+
+impl ExtDevice<(), u8> for W5500 {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} w5500", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _addr: ()) -> u8 {
+        match self.frame {
+            // The chip shifts out 1, 2, 3 during the header
+            Frame::Header(ref header) => header.len() as u8 + 1,
+            Frame::Data { block, write: false, addr, .. } => self.read_mem(block, addr),
+            Frame::Data { .. } => 0,
+        }
+    }
+
+    fn write(&mut self, _sys: &System, _addr: (), v: u8) {
+        match self.frame {
+            Frame::Header(ref mut header) => {
+                header.push(v);
+                if let [a1, a2, control] = header[..] {
+                    // Variable length with the chip select, or 1, 2, 4 bytes
+                    let remaining = match control & 0x3 {
+                        0 => None,
+                        om => Some(1 << (om - 1)),
+                    };
+                    self.frame = Frame::Data {
+                        block: control >> 3,
+                        write: control & 0x4 != 0,
+                        addr: u16::from_be_bytes([a1, a2]),
+                        remaining,
+                    };
+                    trace!("{} frame={:?}", self.name, self.frame);
+                }
+            }
+            Frame::Data { block, write, ref mut addr, ref mut remaining } => {
+                let a = *addr;
+                *addr = addr.wrapping_add(1);
+                let done = remaining.as_mut().map(|r| { *r -= 1; *r == 0 }).unwrap_or(false);
+                if done {
+                    self.frame = Frame::default();
+                }
+                if write {
+                    self.write_mem(block, a, v);
+                }
+            }
+        }
+    }
+
+    fn select(&mut self, _sys: &System, _selected: bool) {
+        self.frame = Frame::default();
+        self.update();
+    }
+}
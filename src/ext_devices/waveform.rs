@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{fs::File, io::{prelude::*, BufWriter}};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::system::System;
+
+use super::ExtDevice;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct WaveformConfig {
+    pub peripheral: String,
+    /// CSV file receiving the samples: clk,channel,value
+    pub file: Option<String>,
+}
+
+/// Captures the output of an analog peripheral, like the DAC. The address is
+/// the channel.
+#[derive(Default)]
+pub struct Waveform {
+    pub config: WaveformConfig,
+    name: String,
+    file: Option<BufWriter<File>>,
+}
+
+impl Waveform {
+    pub fn new(config: WaveformConfig) -> Result<Self> {
+        let file = config.file.as_ref().map(|path| -> Result<_> {
+            let mut file = BufWriter::new(File::create(path)
+                .with_context(|| format!("Failed to create {}", path))?);
+            writeln!(file, "clk,channel,value")?;
+            Ok(file)
+        }).transpose()?;
+
+        Ok(Self { config, file, ..Self::default() })
+    }
+}
+
+impl ExtDevice<u8, u16> for Waveform {
+    fn connect_peripheral(&mut self, peri_name: &str) -> String {
+        self.name = format!("{} waveform", peri_name);
+        self.name.clone()
+    }
+
+    fn read(&mut self, _sys: &System, _channel: u8) -> u16 {
+        0
+    }
+
+    fn write(&mut self, _sys: &System, channel: u8, v: u16) {
+        let clk = crate::context::now();
+        debug!("{} channel={} value={}", self.name, channel, v);
+        if let Some(ref mut file) = self.file {
+            if let Err(e) = writeln!(file, "{},{},{}", clk, channel, v) {
+                warn!("{} failed to write samples: {}", self.name, e);
+                self.file = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::{HashMap, HashSet};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_yaml::Value;
+
+// Devices can be given an id, and be connected in a `wiring:` section instead
+// of with their own `peripheral`, `cs` and `address` fields. This keeps the
+// topology of a board in one place, with several devices on a bus:
+//
+//  devices:
+//    spi_flash:
+//      - id: ext_flash
+//        size: 0x800000
+//    spi_sd_card:
+//      - id: sd
+//        file: sd.img
+//    i2c_eeprom:
+//      - id: eeprom
+//        size: 256
+//    modbus_slave:
+//      - id: sensor
+//        address: 1
+//
+//  wiring:
+//    - device: ext_flash
+//      bus: SPI1             # any peripheral, like a software SPI
+//      cs: PA4
+//    - device: sd
+//      bus: SPI1
+//      cs: PC11
+//    - device: eeprom
+//      bus: I2C1
+//      address: 0x50
+//    - device: sensor
+//      bus: USART2
+//
+// The wiring is applied when the config is loaded, by filling the fields of
+// the devices, so a device is wired either way, not both.
+
+#[derive(Debug, Deserialize)]
+struct WireConfig {
+    device: String,
+    bus: String,
+    /// Chip select of SPI devices, a pin or `nss`
+    cs: Option<String>,
+    /// Address of I2C devices
+    address: Option<u8>,
+}
+
+/// Fills the `devices` of the config with the `wiring` section
+pub fn apply(devices: Option<&mut Value>, wiring: Value) -> Result<()> {
+    let wiring: Vec<WireConfig> = serde_yaml::from_value(wiring)
+        .context("Failed to parse the wiring section")?;
+
+    // Device id -> (kind, config)
+    let mut by_id: HashMap<String, (String, &mut serde_yaml::Mapping)> = HashMap::new();
+    for (kind, list) in devices.and_then(|d| d.as_mapping_mut()).into_iter().flatten() {
+        let kind = kind.as_str().unwrap_or_default().to_string();
+        for device in list.as_sequence_mut().into_iter().flatten().filter_map(|d| d.as_mapping_mut()) {
+            let id = match device.get(&Value::from("id")) {
+                None => continue,
+                Some(Value::String(id)) => id.clone(),
+                Some(id) => bail!("Invalid {} device id {:?}", kind, id),
+            };
+            if let Some((other, _)) = by_id.get(&id) {
+                bail!("Duplicate device id={} on a {} and a {}", id, other, kind);
+            }
+            by_id.insert(id, (kind.clone(), device));
+        }
+    }
+
+    let mut wired = HashSet::new();
+    for wire in wiring {
+        if !wired.insert(wire.device.clone()) {
+            bail!("Device id={} is wired twice", wire.device);
+        }
+        let (kind, device) = by_id.get_mut(&wire.device)
+            .with_context(|| format!("Wiring of unknown device id={}", wire.device))?;
+        let mut set = |field: &str, value: Value| -> Result<()> {
+            if device.insert(Value::from(field), value).is_some() {
+                bail!("The {} id={} has both a wiring and its own {}", kind, wire.device, field);
+            }
+            Ok(())
+        };
+        set("peripheral", Value::from(wire.bus.as_str()))?;
+        if let Some(cs) = wire.cs {
+            set("cs", Value::from(cs))?;
+        }
+        if let Some(address) = wire.address {
+            set("address", Value::from(address))?;
+        }
+        debug!("Wired {} id={} to {}", kind, wire.device, wire.bus);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,128 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::{Context, Result};
+
+use crate::config::Region;
+
+// Memory layout of the STM32 families, keyed by the device name found in the
+// SVD file. Sizes are the largest of the family, mapping more memory than the
+// real part has is harmless.
+
+/// The register layout of the USART, SPI, I2C, and GPIO peripherals changed
+/// over the generations. Each variant is named after the first family using it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Layout {
+    /// F1: GPIO configured with CRL/CRH. The rest is like the F4.
+    F1,
+    /// F2, F4, L1: USART with SR/DR, SPI without FIFO, I2C with SR1/SR2
+    #[default]
+    F4,
+    /// F0, F3, F7, L0, L4, G0, G4: USART with ISR/ICR/RDR/TDR, SPI with a
+    /// FIFO and DS in CR2, I2C with ISR/ICR/RXDR/TXDR
+    F7,
+    /// H7: Like the F7, with the SPI redesigned around CFG1/CFG2 and TXDR/RXDR
+    H7,
+}
+
+impl Layout {
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name.to_uppercase().as_str() {
+            "F1" => Self::F1,
+            "F4" => Self::F4,
+            "F7" => Self::F7,
+            "H7" => Self::H7,
+            _ => return None,
+        })
+    }
+}
+
+struct Family {
+    prefixes: &'static [&'static str],
+    // name, start, size
+    regions: &'static [(&'static str, u32, u32)],
+    layout: Layout,
+}
+
+const FLASH: u32 = 0x0800_0000;
+const SRAM: u32 = 0x2000_0000;
+const CCM: u32 = 0x1000_0000;
+
+const FAMILIES: &[Family] = &[
+    Family { prefixes: &["STM32F0"], regions: &[("FLASH", FLASH, 256 << 10), ("SRAM", SRAM, 32 << 10)], layout: Layout::F7 },
+    Family { prefixes: &["STM32F1"], regions: &[("FLASH", FLASH, 1 << 20), ("SRAM", SRAM, 96 << 10)], layout: Layout::F1 },
+    Family { prefixes: &["STM32F2"], regions: &[("FLASH", FLASH, 1 << 20), ("SRAM", SRAM, 128 << 10)], layout: Layout::F4 },
+    Family { prefixes: &["STM32F3"], regions: &[("FLASH", FLASH, 512 << 10), ("SRAM", SRAM, 80 << 10), ("CCM", CCM, 16 << 10)], layout: Layout::F7 },
+    Family { prefixes: &["STM32F401", "STM32F410", "STM32F411", "STM32F446"],
+        regions: &[("FLASH", FLASH, 512 << 10), ("SRAM", SRAM, 128 << 10)], layout: Layout::F4 },
+    Family { prefixes: &["STM32F412", "STM32F413", "STM32F423"],
+        regions: &[("FLASH", FLASH, 1536 << 10), ("SRAM", SRAM, 320 << 10)], layout: Layout::F4 },
+    Family { prefixes: &["STM32F405", "STM32F407", "STM32F415", "STM32F417"],
+        regions: &[("FLASH", FLASH, 1 << 20), ("SRAM", SRAM, 128 << 10), ("CCM", CCM, 64 << 10)], layout: Layout::F4 },
+    Family { prefixes: &["STM32F4"],
+        regions: &[("FLASH", FLASH, 2 << 20), ("SRAM", SRAM, 384 << 10), ("CCM", CCM, 64 << 10)], layout: Layout::F4 },
+    Family { prefixes: &["STM32F7"], regions: &[("FLASH", FLASH, 2 << 20), ("SRAM", SRAM, 512 << 10)], layout: Layout::F7 },
+    Family { prefixes: &["STM32G0"], regions: &[("FLASH", FLASH, 512 << 10), ("SRAM", SRAM, 144 << 10)], layout: Layout::F7 },
+    Family { prefixes: &["STM32G4"], regions: &[("FLASH", FLASH, 512 << 10), ("SRAM", SRAM, 128 << 10), ("CCM", CCM, 32 << 10)], layout: Layout::F7 },
+    Family { prefixes: &["STM32L0"], regions: &[("FLASH", FLASH, 192 << 10), ("SRAM", SRAM, 20 << 10)], layout: Layout::F7 },
+    Family { prefixes: &["STM32L1"], regions: &[("FLASH", FLASH, 512 << 10), ("SRAM", SRAM, 80 << 10)], layout: Layout::F4 },
+    Family { prefixes: &["STM32L4"], regions: &[("FLASH", FLASH, 2 << 20), ("SRAM", SRAM, 640 << 10), ("SRAM2", CCM, 64 << 10)], layout: Layout::F7 },
+    Family { prefixes: &["STM32H7"], regions: &[
+        ("FLASH", FLASH, 2 << 20),
+        ("DTCM", SRAM, 128 << 10),
+        ("AXI-SRAM", 0x2400_0000, 1 << 20),
+        ("SRAM1-3", 0x3000_0000, 288 << 10),
+        ("SRAM4", 0x3800_0000, 64 << 10),
+    ], layout: Layout::H7 },
+];
+
+fn find_family(device_name: &str) -> Option<&'static Family> {
+    let device_name = device_name.to_uppercase();
+    FAMILIES.iter()
+        .find(|f| f.prefixes.iter().any(|p| device_name.starts_with(p)))
+}
+
+/// Returns the standard memory regions of the device
+pub fn standard_regions(device_name: &str) -> Option<Vec<Region>> {
+    let family = find_family(device_name)?;
+
+    Some(family.regions.iter().map(|(name, start, size)| Region {
+        name: name.to_string(),
+        start: *start,
+        size: *size,
+        load: None,
+        cycles_per_instruction: None,
+        mirror_of: None,
+    }).collect())
+}
+
+/// The peripherals register layout of the device. `family` is a family name
+/// like STM32G4, or a layout name like F7. Defaults to the F4 layout.
+pub fn peripherals_layout(family: Option<&str>, device_name: &str) -> Result<Layout> {
+    match family {
+        Some(family) => Layout::parse(family)
+            .or_else(|| find_family(family).map(|f| f.layout))
+            .with_context(|| format!("Unknown family={}. Use a family like STM32G4, or a layout like F1, F4, F7, H7", family)),
+        None => Ok(find_family(device_name).map(|f| f.layout).unwrap_or_default()),
+    }
+}
+
+/// Adds the standard regions that don't overlap with the configured ones
+pub fn complete_regions(regions: &mut Vec<Region>, device_name: &str) -> bool {
+    let standard = match standard_regions(device_name) {
+        Some(standard) => standard,
+        None => return false,
+    };
+
+    for region in standard {
+        let end = region.start as u64 + region.size as u64;
+        let overlaps = regions.iter().any(|r|
+            (r.start as u64) < end && (region.start as u64) < r.start as u64 + r.size as u64
+        );
+        if !overlaps {
+            debug!("Adding standard region name={} start=0x{:08x} size=0x{:x}", region.name, region.start, region.size);
+            regions.push(region);
+        }
+    }
+
+    true
+}
@@ -0,0 +1,628 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::HashSet, fs, path::Path};
+
+use anyhow::{bail, Context, Result};
+
+// FAT images for the storage devices, like the SPI SD card. `mkfs` builds an
+// image from a host directory, and `extract` gets the files back out, e.g.
+// after an emulation with `write_back`:
+//
+//  $ stm32-emulator mkfs --dir assets/ --out sd.img --size 64M
+//  $ stm32-emulator extract --image sd.img --out sd/
+//
+// The image has no partition table, the volume starts at sector 0, which
+// FatFs accepts. Images below 512MB are FAT16, FAT32 above. Long file names
+// are supported.
+
+const SECTOR_LEN: usize = 512;
+const DIR_ENTRY_LEN: usize = 32;
+const NUM_FATS: usize = 2;
+const FAT16_ROOT_ENTRIES: usize = 512;
+const FAT32_MIN_SIZE: u64 = 512*1024*1024;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const LFN_CHARS: usize = 13;
+const LFN_LAST: u8 = 0x40;
+const DELETED: u8 = 0xE5;
+// Lowercase base name and extension flags of the short entries
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+// Deeper directories are taken as a loop in a corrupted image
+const MAX_DIR_DEPTH: usize = 64;
+
+// 2000-01-01 00:00, the files are timestamped the same for reproducible images
+const DATE: u16 = (20 << 9) | (1 << 5) | 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatType {
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    fn entry_len(self) -> usize {
+        match self { FatType::Fat16 => 2, FatType::Fat32 => 4 }
+    }
+
+    fn end_of_chain(self) -> u32 {
+        match self { FatType::Fat16 => 0xFFF8, FatType::Fat32 => 0x0FFF_FFF8 }
+    }
+}
+
+#[derive(Debug)]
+struct Layout {
+    fat_type: FatType,
+    total_sectors: usize,
+    sectors_per_cluster: usize,
+    reserved_sectors: usize,
+    fat_sectors: usize,
+    root_dir_sectors: usize,
+    num_clusters: usize,
+}
+
+impl Layout {
+    fn new(size: u64) -> Result<Self> {
+        let total_sectors = (size / SECTOR_LEN as u64) as usize;
+        if total_sectors > u32::MAX as usize {
+            bail!("Image size too large");
+        }
+
+        let (fat_type, reserved_sectors, root_dir_sectors, min_spc, clusters) = if size < FAT32_MIN_SIZE {
+            (FatType::Fat16, 1, FAT16_ROOT_ENTRIES * DIR_ENTRY_LEN / SECTOR_LEN, 1, 4085..65525)
+        } else {
+            // 4KB clusters at least
+            (FatType::Fat32, 32, 0, 8, 65525..0x0FFF_FFF5)
+        };
+
+        for sectors_per_cluster in (0..8).map(|i| 1 << i).filter(|spc| *spc >= min_spc) {
+            let data_sectors = total_sectors.saturating_sub(reserved_sectors + root_dir_sectors);
+            let fat_len = (data_sectors / sectors_per_cluster + 2) * fat_type.entry_len();
+            let fat_sectors = fat_len.div_ceil(SECTOR_LEN);
+            let data_start = reserved_sectors + NUM_FATS * fat_sectors + root_dir_sectors;
+            let num_clusters = total_sectors.saturating_sub(data_start) / sectors_per_cluster;
+            if clusters.contains(&num_clusters) {
+                return Ok(Self { fat_type, total_sectors, sectors_per_cluster, reserved_sectors,
+                    fat_sectors, root_dir_sectors, num_clusters });
+            }
+        }
+
+        bail!("Unsupported image size {}, at least 4M is needed", size)
+    }
+
+    fn cluster_len(&self) -> usize {
+        self.sectors_per_cluster * SECTOR_LEN
+    }
+
+    fn fat_offset(&self, copy: usize) -> usize {
+        (self.reserved_sectors + copy * self.fat_sectors) * SECTOR_LEN
+    }
+
+    fn root_dir_offset(&self) -> usize {
+        self.fat_offset(NUM_FATS)
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> usize {
+        self.root_dir_offset() + self.root_dir_sectors * SECTOR_LEN + (cluster as usize - 2) * self.cluster_len()
+    }
+}
+
+fn put16(buf: &mut [u8], offset: usize, v: u16) {
+    buf[offset..offset+2].copy_from_slice(&v.to_le_bytes());
+}
+
+fn put32(buf: &mut [u8], offset: usize, v: u32) {
+    buf[offset..offset+4].copy_from_slice(&v.to_le_bytes());
+}
+
+fn get16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([buf[offset], buf[offset+1]])
+}
+
+fn get32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset+4].try_into().unwrap())
+}
+
+///////////////////////////////////////////////////////////////////////////////////////
+// Names
+
+fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// Names that fit in an 8.3 entry as they are don't need long name entries
+fn needs_lfn(name: &str) -> bool {
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let valid = |s: &str, max| s.len() <= max && s.chars().all(is_short_name_char);
+    base.is_empty() || !valid(base, 8) || !valid(ext, 3) || name.ends_with('.')
+}
+
+fn num_lfn_entries(name: &str) -> usize {
+    if needs_lfn(name) {
+        name.encode_utf16().count().div_ceil(LFN_CHARS)
+    } else {
+        0
+    }
+}
+
+/// The 8.3 name of the entry, unique in its directory
+fn short_name(name: &str, taken: &mut HashSet<[u8; 11]>) -> [u8; 11] {
+    let pad = |s: &str, len| {
+        let mut v = s.as_bytes().to_vec();
+        v.resize(len, b' ');
+        v
+    };
+    let to_short = |s: &str| s.to_uppercase().chars()
+        .filter(|c| *c != ' ' && *c != '.')
+        .map(|c| if is_short_name_char(c) { c } else { '_' })
+        .collect::<String>();
+
+    let (base, ext) = name.rsplit_once('.').unwrap_or((name, ""));
+    let candidates: Box<dyn Iterator<Item=String>> = if needs_lfn(name) {
+        let base = to_short(base);
+        Box::new((1..).map(move |n| {
+            let tail = format!("~{}", n);
+            format!("{}{}", &base[..base.len().min(8 - tail.len())], tail)
+        }))
+    } else {
+        Box::new(std::iter::once(base.to_string()))
+    };
+    let ext = to_short(ext).chars().take(3).collect::<String>();
+
+    for base in candidates {
+        let mut short = [0; 11];
+        short[..8].copy_from_slice(&pad(&base, 8));
+        short[8..].copy_from_slice(&pad(&ext, 3));
+        if taken.insert(short) {
+            return short;
+        }
+    }
+    unreachable!()
+}
+
+fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, b| sum.rotate_right(1).wrapping_add(*b))
+}
+
+/// The long name entries, which come before the short entry, last part first
+fn lfn_entries(name: &str, short: &[u8; 11]) -> Vec<u8> {
+    let mut chars = name.encode_utf16().collect::<Vec<_>>();
+    let n = num_lfn_entries(name);
+    // Terminated with a 0 when there's room, and padded with 0xFFFF
+    if chars.len() < n * LFN_CHARS {
+        chars.push(0);
+    }
+    chars.resize(n * LFN_CHARS, 0xFFFF);
+
+    let checksum = lfn_checksum(short);
+    let mut entries = vec![];
+    for seq in (1..=n).rev() {
+        let mut entry = [0; DIR_ENTRY_LEN];
+        entry[0] = seq as u8 | if seq == n { LFN_LAST } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let part = &chars[(seq-1)*LFN_CHARS..seq*LFN_CHARS];
+        for (i, c) in part.iter().enumerate() {
+            let offset = match i {
+                0..=4 => 1 + 2*i,
+                5..=10 => 14 + 2*(i-5),
+                _ => 28 + 2*(i-11),
+            };
+            put16(&mut entry, offset, *c);
+        }
+        entries.extend(entry);
+    }
+    entries
+}
+
+fn short_entry(short: &[u8; 11], attr: u8, cluster: u32, size: u32) -> [u8; DIR_ENTRY_LEN] {
+    let mut entry = [0; DIR_ENTRY_LEN];
+    entry[..11].copy_from_slice(short);
+    entry[11] = attr;
+    for offset in [16, 18, 24] {
+        put16(&mut entry, offset, DATE);
+    }
+    put16(&mut entry, 20, (cluster >> 16) as u16);
+    put16(&mut entry, 26, cluster as u16);
+    put32(&mut entry, 28, size);
+    entry
+}
+
+///////////////////////////////////////////////////////////////////////////////////////
+// mkfs
+
+struct Builder {
+    layout: Layout,
+    image: Vec<u8>,
+    next_cluster: u32,
+}
+
+impl Builder {
+    fn set_fat(&mut self, cluster: u32, v: u32) {
+        let entry_len = self.layout.fat_type.entry_len();
+        for copy in 0..NUM_FATS {
+            let offset = self.layout.fat_offset(copy) + cluster as usize * entry_len;
+            match self.layout.fat_type {
+                FatType::Fat16 => put16(&mut self.image, offset, v as u16),
+                FatType::Fat32 => put32(&mut self.image, offset, v),
+            }
+        }
+    }
+
+    /// Allocates contiguous clusters for len bytes, at least one
+    fn alloc(&mut self, len: usize) -> Result<u32> {
+        let n = len.div_ceil(self.layout.cluster_len()).max(1) as u32;
+        let first = self.next_cluster;
+        if (first + n) as usize > self.layout.num_clusters + 2 {
+            bail!("The files don't fit in the image");
+        }
+        for cluster in first..first+n-1 {
+            self.set_fat(cluster, cluster + 1);
+        }
+        self.set_fat(first + n - 1, self.layout.fat_type.end_of_chain() | 0xF);
+        self.next_cluster += n;
+        Ok(first)
+    }
+
+    fn dir_len(path: &Path, is_root: bool, label: Option<&str>) -> Result<usize> {
+        let mut n = if is_root { label.is_some() as usize } else { 2 };
+        for entry in fs::read_dir(path)? {
+            let name = entry?.file_name();
+            n += 1 + num_lfn_entries(&name.to_string_lossy());
+        }
+        Ok(n * DIR_ENTRY_LEN)
+    }
+
+    /// Writes the files of the directory, and the directory entries at the
+    /// given cluster. The FAT16 root directory has its own region instead.
+    /// The root directory has no parent, and may have the volume label.
+    fn write_dir(&mut self, path: &Path, cluster: Option<u32>, parent: Option<u32>, label: Option<&str>) -> Result<()> {
+        let mut entries = vec![];
+        let mut taken = HashSet::new();
+
+        if let Some(label) = label {
+            let mut short = [b' '; 11];
+            let label = label.to_uppercase();
+            let len = label.len().min(11);
+            short[..len].copy_from_slice(&label.as_bytes()[..len]);
+            entries.extend(short_entry(&short, ATTR_VOLUME_ID, 0, 0));
+        }
+        if let Some(parent) = parent {
+            let dot = |s: &[u8]| { let mut short = [b' '; 11]; short[..s.len()].copy_from_slice(s); short };
+            entries.extend(short_entry(&dot(b"."), ATTR_DIRECTORY, cluster.unwrap_or_default(), 0));
+            entries.extend(short_entry(&dot(b".."), ATTR_DIRECTORY, parent, 0));
+        }
+
+        let mut children = fs::read_dir(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+            .collect::<std::io::Result<Vec<_>>>()?;
+        children.sort_by_key(|e| e.file_name());
+
+        for child in children {
+            let name = child.file_name().to_string_lossy().into_owned();
+            let child_path = child.path();
+            let short = short_name(&name, &mut taken);
+
+            let (attr, first_cluster, size) = if child.file_type()?.is_dir() {
+                let len = Self::dir_len(&child_path, false, None)?;
+                let first_cluster = self.alloc(len)?;
+                // ".." is 0 in the first level directories, even on FAT32
+                let parent = if parent.is_some() { cluster.unwrap_or_default() } else { 0 };
+                self.write_dir(&child_path, Some(first_cluster), Some(parent), None)?;
+                (ATTR_DIRECTORY, first_cluster, 0)
+            } else {
+                let data = fs::read(&child_path)
+                    .with_context(|| format!("Failed to read {}", child_path.display()))?;
+                let first_cluster = if data.is_empty() { 0 } else { self.alloc(data.len())? };
+                if first_cluster != 0 {
+                    let offset = self.layout.cluster_offset(first_cluster);
+                    self.image[offset..offset+data.len()].copy_from_slice(&data);
+                }
+                debug!("FAT file path={} size={}", child_path.display(), data.len());
+                (ATTR_ARCHIVE, first_cluster, data.len() as u32)
+            };
+
+            if needs_lfn(&name) {
+                entries.extend(lfn_entries(&name, &short));
+            }
+            entries.extend(short_entry(&short, attr, first_cluster, size));
+        }
+
+        let offset = match cluster {
+            Some(cluster) => self.layout.cluster_offset(cluster),
+            None => {
+                if entries.len() > self.layout.root_dir_sectors * SECTOR_LEN {
+                    bail!("Too many files in the root directory of {}", path.display());
+                }
+                self.layout.root_dir_offset()
+            }
+        };
+        self.image[offset..offset+entries.len()].copy_from_slice(&entries);
+        Ok(())
+    }
+
+    fn write_boot_sector(&mut self, label: &str) {
+        let l = &self.layout;
+        let mut boot = [0; SECTOR_LEN];
+        boot[..3].copy_from_slice(&[0xEB, 0x3C, 0x90]);
+        boot[3..11].copy_from_slice(b"STM32EMU");
+        put16(&mut boot, 11, SECTOR_LEN as u16);
+        boot[13] = l.sectors_per_cluster as u8;
+        put16(&mut boot, 14, l.reserved_sectors as u16);
+        boot[16] = NUM_FATS as u8;
+        boot[21] = 0xF8; // Fixed disk
+        put16(&mut boot, 24, 63);
+        put16(&mut boot, 26, 255);
+        if l.fat_type == FatType::Fat16 && l.total_sectors < 0x10000 {
+            put16(&mut boot, 19, l.total_sectors as u16);
+        } else {
+            put32(&mut boot, 32, l.total_sectors as u32);
+        }
+
+        let mut label_field = [b' '; 11];
+        let len = label.len().min(11);
+        label_field[..len].copy_from_slice(&label.to_uppercase().as_bytes()[..len]);
+
+        let ext = match l.fat_type {
+            FatType::Fat16 => {
+                put16(&mut boot, 17, FAT16_ROOT_ENTRIES as u16);
+                put16(&mut boot, 22, l.fat_sectors as u16);
+                36
+            }
+            FatType::Fat32 => {
+                boot[1] = 0x58;
+                put32(&mut boot, 36, l.fat_sectors as u32);
+                put32(&mut boot, 44, 2); // Root directory cluster
+                put16(&mut boot, 48, 1); // FSInfo sector
+                put16(&mut boot, 50, 6); // Backup boot sector
+                64
+            }
+        };
+        boot[ext] = 0x80; // Drive number
+        boot[ext+2] = 0x29; // Extended boot signature
+        put32(&mut boot, ext+3, 0x5354_4D32); // Volume ID
+        boot[ext+7..ext+18].copy_from_slice(&label_field);
+        boot[ext+18..ext+26].copy_from_slice(match l.fat_type {
+            FatType::Fat16 => b"FAT16   ",
+            FatType::Fat32 => b"FAT32   ",
+        });
+        boot[510] = 0x55;
+        boot[511] = 0xAA;
+
+        self.image[..SECTOR_LEN].copy_from_slice(&boot);
+        if l.fat_type == FatType::Fat32 {
+            self.image[6*SECTOR_LEN..7*SECTOR_LEN].copy_from_slice(&boot);
+        }
+    }
+
+    fn write_fsinfo(&mut self) {
+        let free = (self.layout.num_clusters + 2) as u32 - self.next_cluster;
+        let mut fsinfo = [0; SECTOR_LEN];
+        put32(&mut fsinfo, 0, 0x4161_5252);
+        put32(&mut fsinfo, 484, 0x6141_7272);
+        put32(&mut fsinfo, 488, free);
+        put32(&mut fsinfo, 492, self.next_cluster);
+        put32(&mut fsinfo, 508, 0xAA55_0000);
+        self.image[SECTOR_LEN..2*SECTOR_LEN].copy_from_slice(&fsinfo);
+        self.image[7*SECTOR_LEN..8*SECTOR_LEN].copy_from_slice(&fsinfo);
+    }
+}
+
+/// Builds a FAT image of the given size with the content of the directory
+pub fn mkfs(dir: &Path, size: u64, label: Option<&str>) -> Result<Vec<u8>> {
+    let layout = Layout::new(size)?;
+    debug!("FAT layout={:?}", layout);
+    let fat_type = layout.fat_type;
+    let mut b = Builder { image: vec![0; layout.total_sectors * SECTOR_LEN], layout, next_cluster: 2 };
+
+    b.write_boot_sector(label.unwrap_or("NO NAME"));
+    // Media type, and the clean shutdown bits
+    b.set_fat(0, fat_type.end_of_chain());
+    b.set_fat(1, fat_type.end_of_chain() | 0xF);
+
+    match fat_type {
+        FatType::Fat16 => b.write_dir(dir, None, None, label)?,
+        FatType::Fat32 => {
+            let len = Builder::dir_len(dir, true, label)?;
+            let root = b.alloc(len)?;
+            b.write_dir(dir, Some(root), None, label)?;
+            b.write_fsinfo();
+        }
+    }
+
+    info!("FAT image type={:?} size={} used_clusters={} num_clusters={}",
+        fat_type, size, b.next_cluster - 2, b.layout.num_clusters);
+    Ok(b.image)
+}
+
+///////////////////////////////////////////////////////////////////////////////////////
+// extract
+
+struct Volume<'a> {
+    image: &'a [u8],
+    fat_type: FatType,
+    sector_len: usize,
+    cluster_len: usize,
+    fat_offset: usize,
+    root_dir: (usize, usize), // FAT16 root directory region, offset and length
+    root_cluster: u32,
+    data_offset: usize,
+    num_clusters: usize,
+}
+
+impl<'a> Volume<'a> {
+    fn new(image: &'a [u8]) -> Result<Self> {
+        if image.len() < SECTOR_LEN || image[510..512] != [0x55, 0xAA] {
+            bail!("Not a FAT volume, the boot sector signature is missing");
+        }
+        let sector_len = get16(image, 11) as usize;
+        let sectors_per_cluster = image[13] as usize;
+        let reserved_sectors = get16(image, 14) as usize;
+        let num_fats = image[16] as usize;
+        let root_entries = get16(image, 17) as usize;
+        let total_sectors = match get16(image, 19) {
+            0 => get32(image, 32) as usize,
+            n => n as usize,
+        };
+        let fat_sectors = match get16(image, 22) {
+            0 => get32(image, 36) as usize,
+            n => n as usize,
+        };
+        if sector_len == 0 || sectors_per_cluster == 0 {
+            bail!("Invalid FAT boot sector");
+        }
+
+        let root_dir_sectors = (root_entries * DIR_ENTRY_LEN).div_ceil(sector_len);
+        let root_dir_start = reserved_sectors + num_fats * fat_sectors;
+        let data_start = root_dir_start + root_dir_sectors;
+        let num_clusters = total_sectors.saturating_sub(data_start) / sectors_per_cluster;
+        let fat_type = match num_clusters {
+            0..=4084 => bail!("FAT12 volumes are not supported"),
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        if total_sectors * sector_len > image.len() {
+            bail!("The image is smaller than the FAT volume");
+        }
+
+        Ok(Self {
+            image,
+            fat_type,
+            sector_len,
+            cluster_len: sectors_per_cluster * sector_len,
+            fat_offset: reserved_sectors * sector_len,
+            root_dir: (root_dir_start * sector_len, root_dir_sectors * sector_len),
+            root_cluster: if fat_type == FatType::Fat32 { get32(image, 44) } else { 0 },
+            data_offset: data_start * sector_len,
+            num_clusters,
+        })
+    }
+
+    fn next_cluster(&self, cluster: u32) -> Option<u32> {
+        let offset = self.fat_offset + cluster as usize * self.fat_type.entry_len();
+        if offset + self.fat_type.entry_len() > self.image.len() {
+            return None;
+        }
+        let next = match self.fat_type {
+            FatType::Fat16 => get16(self.image, offset) as u32,
+            FatType::Fat32 => get32(self.image, offset) & 0x0FFF_FFFF,
+        };
+        (next >= 2 && next < self.fat_type.end_of_chain() && (next as usize) < self.num_clusters + 2).then_some(next)
+    }
+
+    /// Reads the clusters of a chain, up to max_len bytes. Cluster 0 is an empty file.
+    fn read_chain(&self, first: u32, max_len: usize) -> Result<Vec<u8>> {
+        if first == 0 {
+            return Ok(vec![]);
+        }
+        if !(2..self.num_clusters as u32 + 2).contains(&first) {
+            bail!("Invalid FAT cluster={}, the volume has {} clusters", first, self.num_clusters);
+        }
+
+        let mut data = vec![];
+        let mut visited = HashSet::new();
+        let mut cluster = Some(first);
+        while let Some(c) = cluster.filter(|_| data.len() < max_len) {
+            if !visited.insert(c) {
+                bail!("The FAT chain starting at cluster={} loops at cluster={}", first, c);
+            }
+            let offset = self.data_offset + (c as usize - 2) * self.cluster_len;
+            data.extend(&self.image[offset..offset+self.cluster_len]);
+            cluster = self.next_cluster(c);
+        }
+        Ok(data)
+    }
+
+    fn extract_dir(&self, entries: &[u8], out: &Path, depth: usize) -> Result<usize> {
+        if depth > MAX_DIR_DEPTH {
+            bail!("The FAT directories are nested more than {} levels deep at {}", MAX_DIR_DEPTH, out.display());
+        }
+
+        fs::create_dir_all(out).with_context(|| format!("Failed to create {}", out.display()))?;
+
+        let mut num_files = 0;
+        let mut lfn: Option<(u8, Vec<u16>)> = None;
+        for entry in entries.chunks_exact(DIR_ENTRY_LEN) {
+            match entry[0] {
+                0 => break,
+                DELETED => { lfn = None; continue }
+                _ => {}
+            }
+
+            let attr = entry[11];
+            if attr & 0x3F == ATTR_LONG_NAME {
+                let seq = (entry[0] & 0x1F) as usize;
+                if entry[0] & LFN_LAST != 0 {
+                    lfn = Some((entry[13], vec![0xFFFF; seq * LFN_CHARS]));
+                }
+                if let Some((_, ref mut chars)) = lfn {
+                    let offsets = (0..5).map(|i| 1 + 2*i).chain((0..6).map(|i| 14 + 2*i)).chain([28, 30]);
+                    for (i, offset) in offsets.enumerate() {
+                        if let Some(c) = chars.get_mut((seq.max(1) - 1) * LFN_CHARS + i) {
+                            *c = get16(entry, offset);
+                        }
+                    }
+                }
+                continue;
+            }
+
+            let long_name = lfn.take().filter(|(checksum, _)| *checksum == lfn_checksum(entry[..11].try_into().unwrap()))
+                .map(|(_, chars)| {
+                    let len = chars.iter().position(|c| *c == 0 || *c == 0xFFFF).unwrap_or(chars.len());
+                    String::from_utf16_lossy(&chars[..len])
+                });
+            if attr & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+
+            let name = long_name.unwrap_or_else(|| {
+                let case = |s: &[u8], lower: bool| {
+                    let s = String::from_utf8_lossy(s).trim_end().to_string();
+                    if lower { s.to_lowercase() } else { s }
+                };
+                let base = case(&entry[..8], entry[12] & NT_LOWER_BASE != 0);
+                let ext = case(&entry[8..11], entry[12] & NT_LOWER_EXT != 0);
+                if ext.is_empty() { base } else { format!("{}.{}", base, ext) }
+            });
+            if name == "." || name == ".." {
+                continue;
+            }
+            if name.contains(['/', '\\']) || name.is_empty() {
+                warn!("FAT entry with an invalid name skipped name={:?}", name);
+                continue;
+            }
+
+            let cluster = (get16(entry, 20) as u32) << 16 | get16(entry, 26) as u32;
+            let path = out.join(&name);
+            if attr & ATTR_DIRECTORY != 0 {
+                num_files += self.extract_dir(&self.read_chain(cluster, usize::MAX)?, &path, depth + 1)?;
+            } else {
+                let size = get32(entry, 28) as usize;
+                let mut data = self.read_chain(cluster, size)?;
+                data.truncate(size);
+                debug!("FAT file path={} size={}", path.display(), data.len());
+                fs::write(&path, data).with_context(|| format!("Failed to write {}", path.display()))?;
+                num_files += 1;
+            }
+        }
+        Ok(num_files)
+    }
+}
+
+/// Writes the files of a FAT image to a directory. Returns the number of files.
+pub fn extract(image: &[u8], out: &Path) -> Result<usize> {
+    let volume = Volume::new(image)?;
+    debug!("FAT volume type={:?} sector_len={} cluster_len={}", volume.fat_type, volume.sector_len, volume.cluster_len);
+    let root = match volume.fat_type {
+        FatType::Fat16 => {
+            let (offset, len) = volume.root_dir;
+            image[offset..offset+len].to_vec()
+        }
+        FatType::Fat32 => volume.read_chain(volume.root_cluster, usize::MAX)?,
+    };
+    volume.extract_dir(&root, out, 0)
+}
@@ -0,0 +1,141 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{io::{prelude::*, BufReader}, net::TcpListener};
+
+use anyhow::{Context as _, Result, bail};
+use unicorn_engine::Unicorn;
+
+use crate::{control::EmulatorHandle, util::{self, UniErr}};
+
+// A server to program the emulated memory at runtime, like a flash loader
+// with a debug probe. The protocol is line based, so a tool like netcat is
+// a good enough client:
+//
+//   load <addr> <file>     Writes the content of a file (on the emulator host)
+//   write <addr> <hex>     Writes bytes, e.g. "write 0x08000000 0011aabb"
+//   erase <addr> <len>     Fills with 0xFF
+//   read <addr> <len>      Replies with the bytes in hex
+//   reset                  Requests a system reset
+//
+// Each command is replied with "OK", "OK <data>", or "ERR <reason>".
+// Commands are executed with the emulation paused.
+
+pub struct FlashServer;
+
+impl FlashServer {
+    /// The commands go through the control channel of the emulation
+    pub fn spawn(addr: &str, handle: EmulatorHandle) -> Result<()> {
+        let listener = TcpListener::bind(addr)
+            .with_context(|| format!("Failed to listen on {}", addr))?;
+        info!("Flash server listening on {}", addr);
+        std::thread::Builder::new()
+            .name("flash-server".to_string())
+            .spawn(move || Self::serve(listener, handle))
+            .context("Failed to spawn thread for flash-server")?;
+        Ok(())
+    }
+
+    fn serve(listener: TcpListener, handle: EmulatorHandle) {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Flash server accept failed: {}", e);
+                    return;
+                }
+            };
+
+            let reader = match stream.try_clone() {
+                Ok(s) => BufReader::new(s),
+                Err(_) => continue,
+            };
+
+            for line in reader.lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if line.trim().is_empty() {
+                    continue;
+                }
+
+                let reply = match handle.flash_server_command(line) {
+                    Ok(reply) => reply,
+                    // The emulation is over
+                    Err(_) => return,
+                };
+                if writeln!(stream, "{}", reply).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Executes a command. Returns the reply, and whether a reset is requested.
+    pub fn process(uc: &mut Unicorn<()>, line: &str) -> (String, bool) {
+        let (reply, reset) = match Self::execute(uc, line) {
+            Ok(Command::Reset) => ("OK".to_string(), true),
+            Ok(Command::Done(None)) => ("OK".to_string(), false),
+            Ok(Command::Done(Some(data))) => (format!("OK {}", data), false),
+            Err(e) => (format!("ERR {:#}", e), false),
+        };
+        info!("Flash server cmd='{}' reply={}", line.trim(), reply.split(' ').next().unwrap());
+        (reply, reset)
+    }
+
+    fn execute(uc: &mut Unicorn<()>, line: &str) -> Result<Command> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let parse_num = |s: Option<&&str>| -> Result<u32> {
+            let s = s.context("Missing argument")?;
+            clap_num::maybe_hex::<u32>(s).map_err(|e| anyhow::anyhow!(e))
+        };
+
+        let data = match args[0] {
+            "load" => {
+                let addr = parse_num(args.get(1))?;
+                let file = args.get(2).context("Missing file")?;
+                let content = util::read_file(file)?;
+                uc.mem_write(addr as u64, &content).map_err(UniErr)?;
+                None
+            }
+            "write" => {
+                let addr = parse_num(args.get(1))?;
+                let hex = args.get(2).context("Missing data")?;
+                let content = parse_hex(hex)?;
+                uc.mem_write(addr as u64, &content).map_err(UniErr)?;
+                None
+            }
+            "erase" => {
+                let addr = parse_num(args.get(1))?;
+                let len = parse_num(args.get(2))?;
+                uc.mem_write(addr as u64, &vec![0xFF; len as usize]).map_err(UniErr)?;
+                None
+            }
+            "read" => {
+                let addr = parse_num(args.get(1))?;
+                let len = parse_num(args.get(2))?;
+                let mut content = vec![0; len as usize];
+                uc.mem_read(addr as u64, &mut content).map_err(UniErr)?;
+                Some(content.iter().map(|b| format!("{:02x}", b)).collect())
+            }
+            "reset" => return Ok(Command::Reset),
+            cmd => bail!("Unknown command {}", cmd),
+        };
+
+        Ok(Command::Done(data))
+    }
+}
+
+enum Command {
+    Done(Option<String>),
+    Reset,
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.len() & 1 != 0 {
+        bail!("Odd number of hex digits");
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i+2], 16).context("Invalid hex"))
+        .collect()
+}
@@ -0,0 +1,112 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::VecDeque;
+
+use crate::{symbols::symbols, peripherals::Peripherals, json_string};
+
+// With --flight-recorder N, the last N executed instructions, and the last N
+// peripheral accesses and interrupt entries and exits, are kept in memory.
+// They are printed when the emulation aborts on a fault, like a lockup or an
+// unrecoverable memory access, as text or as JSON lines with --log-format json.
+
+enum Kind {
+    Instruction,
+    Read { addr: u32, value: u32 },
+    Write { addr: u32, value: u32 },
+    IrqEnter(i32),
+    IrqExit(i32),
+}
+
+struct Event {
+    clk: u64,
+    pc: u32,
+    kind: Kind,
+}
+
+pub struct FlightRecorder {
+    capacity: usize,
+    // The instructions would push everything else out of a single buffer
+    instructions: VecDeque<(u64, Event)>,
+    events: VecDeque<(u64, Event)>,
+    seq: u64,
+}
+
+impl FlightRecorder {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            instructions: VecDeque::with_capacity(capacity),
+            events: VecDeque::with_capacity(capacity),
+            seq: 0,
+        }
+    }
+
+    fn push(&mut self, kind: Kind, pc: u32) {
+        let clk = crate::context::now();
+        let buffer = match kind {
+            Kind::Instruction => &mut self.instructions,
+            _ => &mut self.events,
+        };
+        if buffer.len() == self.capacity {
+            buffer.pop_front();
+        }
+        if self.capacity > 0 {
+            buffer.push_back((self.seq, Event { clk, pc, kind }));
+        }
+        self.seq += 1;
+    }
+
+    pub fn instruction(&mut self, pc: u32) {
+        self.push(Kind::Instruction, pc);
+    }
+
+    pub fn access(&mut self, addr: u32, write: bool, value: u32) {
+        let pc = crate::context::last_pc();
+        self.push(if write { Kind::Write { addr, value } } else { Kind::Read { addr, value } }, pc);
+    }
+
+    pub fn irq_enter(&mut self, irq: i32) {
+        let pc = crate::context::last_pc();
+        self.push(Kind::IrqEnter(irq), pc);
+    }
+
+    pub fn irq_exit(&mut self, irq: i32) {
+        let pc = crate::context::last_pc();
+        self.push(Kind::IrqExit(irq), pc);
+    }
+
+    /// The recorded events, oldest first
+    fn events(&self) -> Vec<&Event> {
+        let mut events = self.instructions.iter().chain(self.events.iter()).collect::<Vec<_>>();
+        events.sort_by_key(|(seq, _)| *seq);
+        events.into_iter().map(|(_, e)| e).collect()
+    }
+
+    pub fn dump(&self, p: &Peripherals, json: bool) {
+        if !json {
+            info!("Flight recorder, last events:");
+        }
+        for e in self.events() {
+            let func = symbols().describe(e.pc);
+            if json {
+                let (type_, details) = match e.kind {
+                    Kind::Instruction => ("instruction", String::new()),
+                    Kind::Read { addr, value } => ("read", format!(r#","addr":{},"desc":{},"value":{}"#, addr, json_string(&p.addr_desc(addr)), value)),
+                    Kind::Write { addr, value } => ("write", format!(r#","addr":{},"desc":{},"value":{}"#, addr, json_string(&p.addr_desc(addr)), value)),
+                    Kind::IrqEnter(irq) => ("irq_enter", format!(r#","irq":{}"#, irq)),
+                    Kind::IrqExit(irq) => ("irq_exit", format!(r#","irq":{}"#, irq)),
+                };
+                println!(r#"{{"flight_recorder":"{}","clk":{},"pc":{},"func":{}{}}}"#, type_, e.clk, e.pc, json_string(&func), details);
+            } else {
+                let details = match e.kind {
+                    Kind::Instruction => String::new(),
+                    Kind::Read { addr, value } => format!(" {} read=0x{:08x}", p.addr_desc(addr), value),
+                    Kind::Write { addr, value } => format!(" {} write=0x{:08x}", p.addr_desc(addr), value),
+                    Kind::IrqEnter(irq) => format!(" irq={} enter", irq),
+                    Kind::IrqExit(irq) => format!(" irq={} exit", irq),
+                };
+                info!("  clk={} pc=0x{:08x} {}{}", e.clk, e.pc, func, details);
+            }
+        }
+    }
+}
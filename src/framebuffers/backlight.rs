@@ -0,0 +1,66 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{rc::Rc, cell::RefCell};
+
+use anyhow::{Context, Result};
+
+use crate::peripherals::{Peripherals, gpio::{GpioPorts, Pin, PinDuty}};
+use super::BacklightConfig;
+
+// The backlight of a framebuffer dims its SDL window, so fade-ins and a
+// backlight left off are visible:
+//
+//  framebuffers:
+//    - name: Display
+//      ...
+//      backlight:
+//        pin: PB1          # a GPIO, on/off or toggled as a software PWM
+//        pwm: TIM3.CH4     # or a timer PWM output
+//        active_low: false
+
+enum Source {
+    Pin(Rc<RefCell<PinDuty>>),
+    Pwm { timer: String, channel: u8 },
+}
+
+pub struct Backlight {
+    source: Source,
+    active_low: bool,
+}
+
+impl Backlight {
+    pub fn new(config: &BacklightConfig, gpio: &mut GpioPorts) -> Result<Self> {
+        let source = match (&config.pin, &config.pwm) {
+            (Some(pin), None) => {
+                let pin = Pin::parse(pin).with_context(|| format!("Invalid backlight pin {}", pin))?;
+                let duty = Rc::new(RefCell::new(PinDuty::default()));
+                let d = duty.clone();
+                gpio.add_write_callback(pin, move |_sys, v| d.borrow_mut().set_level(v));
+                Source::Pin(duty)
+            }
+            (None, Some(pwm)) => {
+                let (timer, channel) = pwm.split_once(".CH")
+                    .and_then(|(t, c)| Some((t.to_string(), c.parse().ok()?)))
+                    .with_context(|| format!("Invalid backlight pwm {}, expected like TIM3.CH4", pwm))?;
+                Source::Pwm { timer, channel }
+            }
+            _ => anyhow::bail!("The backlight needs either a pin or a pwm"),
+        };
+
+        Ok(Self { source, active_low: config.active_low.unwrap_or_default() })
+    }
+
+    /// From 0 to 1
+    pub fn brightness(&mut self, p: &Peripherals) -> f32 {
+        let duty = match self.source {
+            Source::Pin(ref duty) => duty.borrow_mut().measure(),
+            Source::Pwm { ref timer, channel } => {
+                // An unconfigured timer leaves the pin low
+                p.timers.borrow().get(timer)
+                    .and_then(|t| t.pwm_duty(timer, channel))
+                    .unwrap_or_default()
+            }
+        };
+        if self.active_low { 1.0 - duty } else { duty }
+    }
+}
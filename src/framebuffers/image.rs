@@ -1,6 +1,5 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::{io::BufWriter, fs::File};
 use super::{FramebufferConfig, Framebuffer, RGB565};
 use anyhow::Result;
 
@@ -20,14 +19,7 @@ impl Image {
         let mut v = Vec::with_capacity((self.config.width * self.config.height * 3).into());
 
         for c in self.framebuffer.iter().cloned() {
-            // RGB565
-            let r = (c >> 11) * 0xFF / 0b11111;
-            let g = ((c >> 5) & 0b111111) * 0xFF / 0b111111;
-            let b = (c & 0b11111) * 0xFF / 0b11111;
-
-            v.push(r as u8);
-            v.push(g as u8);
-            v.push(b as u8);
+            v.extend(super::rgb565_to_rgb(c));
         }
 
         v
@@ -35,19 +27,8 @@ impl Image {
 
     pub fn write_to_disk(&self) -> Result<()> {
         let path = &self.config.image.as_ref().unwrap().file;
-        let file = File::create(path).unwrap();
-        let ref mut w = BufWriter::new(file);
-
-        let mut encoder = png::Encoder::new(w, self.config.width.into(), self.config.height.into());
-        encoder.set_color(png::ColorType::Rgb);
-        encoder.set_depth(png::BitDepth::Eight);
-
-        let mut writer = encoder.write_header().unwrap();
-
-        writer.write_image_data(&self.get_framebuffer_as_rgb()).unwrap();
-
+        super::write_png(path, self.config.width, self.config.height, &self.get_framebuffer_as_rgb())?;
         info!("Wrote framebuffer to {}", path);
-
         Ok(())
     }
 }
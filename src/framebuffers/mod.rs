@@ -1,15 +1,27 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
 pub mod image;
+pub mod vnc;
+#[cfg(feature = "sdl")]
 pub mod sdl;
+#[cfg(feature = "sdl")]
 pub mod sdl_engine;
+#[cfg(feature = "sdl")]
+pub mod backlight;
 
 use std::{rc::Rc, cell::RefCell};
 use serde::Deserialize;
-use self::{image::Image, sdl::Sdl};
-use anyhow::Result;
+use self::{image::Image, vnc::Vnc};
+#[cfg(feature = "sdl")]
+use self::{sdl::Sdl, backlight::Backlight};
+use std::{io::BufWriter, fs::File, time::SystemTime};
+use anyhow::{Context, Result};
+
+#[cfg(feature = "sdl")]
+use crate::peripherals::gpio::GpioPorts;
 
 #[derive(Debug, Deserialize)]
+#[cfg_attr(not(feature = "sdl"), allow(dead_code))]
 pub struct FramebufferConfig {
     pub name: String,
     pub width: u16,
@@ -17,7 +29,14 @@ pub struct FramebufferConfig {
     pub mode: String,
     pub image: Option<ImageBackendConfig>,
     pub sdl: Option<bool>,
+    pub vnc: Option<VncBackendConfig>,
     pub downscale: Option<u32>,
+    /// Title of the SDL window. Defaults to the framebuffer name.
+    pub title: Option<String>,
+    /// Position of the SDL window on the screen, as [x, y]
+    pub position: Option<[i32; 2]>,
+    /// Dims the SDL window
+    pub backlight: Option<BacklightConfig>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -25,10 +44,77 @@ pub struct ImageBackendConfig {
     pub file: String,
 }
 
+/// See vnc.rs
+#[derive(Debug, Deserialize)]
+pub struct VncBackendConfig {
+    /// Address to listen on, like 127.0.0.1:5900
+    pub listen: String,
+}
+
+/// See backlight.rs
+#[derive(Debug, Deserialize, Default)]
+#[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+pub struct BacklightConfig {
+    pub pin: Option<String>,
+    pub pwm: Option<String>,
+    pub active_low: Option<bool>,
+}
+
+/// How often should we call pump_events() in terms of number of instructions emulated
+pub const PUMP_EVENT_INST_INTERVAL: u64 = 100_000; // ~1-10ms, depending on the speed of the host
+
+/// Minimum time between two redraws of a live framebuffer
+pub const REFRESH_DURATION_MILLIS: u64 = 20;
+
 pub type RGB565 = u16;
 pub type RGB888 = u32;
 //pub type Gray8 = u8;
 
+/// Expands the 5 and 6-bit components to 8 bits
+pub fn rgb565_to_rgb(c: RGB565) -> [u8; 3] {
+    let r = (c >> 11) * 0xFF / 0b11111;
+    let g = ((c >> 5) & 0b111111) * 0xFF / 0b111111;
+    let b = (c & 0b11111) * 0xFF / 0b11111;
+    [r as u8, g as u8, b as u8]
+}
+
+pub fn write_png(path: &str, width: u16, height: u16, rgb: &[u8]) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+    let w = BufWriter::new(file);
+
+    let mut encoder = png::Encoder::new(w, width.into(), height.into());
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+
+    encoder.write_header()
+        .and_then(|mut writer| writer.write_image_data(rgb))
+        .with_context(|| format!("Failed to write {}", path))
+}
+
+/// A file name like Display-20240131-235959.123.png, in UTC
+fn screenshot_path(name: &str) -> String {
+    let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs();
+    let (days, time) = (secs / 86400, secs % 86400);
+
+    // Days since the epoch to a civil date, from Howard Hinnant's algorithm
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2) / 153;
+    let day = doy - (153*mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    let name: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' })
+        .collect();
+    format!("{}-{:04}{:02}{:02}-{:02}{:02}{:02}.{:03}.png", name, year, month, day,
+        time / 3600, time / 60 % 60, time % 60, now.subsec_millis())
+}
+
 pub trait Framebuffer<Color> {
     fn get_config(&self) -> &FramebufferConfig;
 
@@ -41,30 +127,100 @@ pub trait Framebuffer<Color> {
 
 pub struct Framebuffers {
     pub images: Vec<Rc<RefCell<Image>>>,
+    pub vncs: Vec<Rc<RefCell<Vnc>>>,
+    #[cfg(feature = "sdl")]
     pub sdls: Vec<Rc<RefCell<Sdl>>>,
 }
 
 impl Framebuffers {
-    pub fn from_config(mut config: Vec<FramebufferConfig>) -> Self {
+    pub fn from_config(mut config: Vec<FramebufferConfig>) -> Result<Self> {
         let mut images = vec![];
+        let mut vncs = vec![];
+        #[cfg(feature = "sdl")]
         let mut sdls = vec![];
 
         for c in config.drain(..) {
-            match (c.image.is_some(), c.sdl == Some(true)) {
-                (true, false) => images.push(Rc::new(RefCell::new(Image::new(c)))),
-                (false, true) => sdls.push(Rc::new(RefCell::new(Sdl::new(c)))),
-                (false, false) => panic!("no framebuffer backend specified. Use image or sdl"),
+            match (c.image.is_some(), c.sdl == Some(true), c.vnc.is_some()) {
+                (true, false, false) => {
+                    if c.title.is_some() || c.position.is_some() || c.backlight.is_some() {
+                        warn!("Framebuffer {} is an image, its window title, position and backlight are ignored", c.name);
+                    }
+                    images.push(Rc::new(RefCell::new(Image::new(c))))
+                }
+                #[cfg(feature = "sdl")]
+                (false, true, false) => sdls.push(Rc::new(RefCell::new(Sdl::new(c)))),
+                #[cfg(not(feature = "sdl"))]
+                (false, true, false) => panic!("Framebuffer {} uses sdl, but this build has no SDL support. Use image or vnc", c.name),
+                (false, false, true) => {
+                    if c.title.is_some() || c.position.is_some() || c.backlight.is_some() {
+                        warn!("Framebuffer {} is served with VNC, its window title, position and backlight are ignored", c.name);
+                    }
+                    vncs.push(Rc::new(RefCell::new(Vnc::new(c)?)))
+                }
+                (false, false, false) => panic!("no framebuffer backend specified. Use image, sdl or vnc"),
                 _ => panic!("Multiple backend specified"),
             }
         }
 
-        Self { images, sdls }
+        Ok(Self {
+            images,
+            vncs,
+            #[cfg(feature = "sdl")]
+            sdls,
+        })
+    }
+
+    /// Writes the current content of the framebuffers, or of the named one,
+    /// to timestamped PNG files. Returns the file names.
+    pub fn screenshot(&self, name: Option<&str>) -> Result<Vec<String>> {
+        let mut files = vec![];
+        for fb in &self.images {
+            let fb = fb.borrow();
+            if name.is_none_or(|n| n == fb.config.name) {
+                let path = screenshot_path(&fb.config.name);
+                write_png(&path, fb.config.width, fb.config.height, &fb.get_framebuffer_as_rgb())?;
+                files.push(path);
+            }
+        }
+        for fb in &self.vncs {
+            let fb = fb.borrow();
+            if name.is_none_or(|n| n == fb.config.name) {
+                let path = screenshot_path(&fb.config.name);
+                write_png(&path, fb.config.width, fb.config.height, &fb.get_framebuffer_as_rgb())?;
+                files.push(path);
+            }
+        }
+        #[cfg(feature = "sdl")]
+        for fb in &self.sdls {
+            let fb = fb.borrow();
+            if name.is_none_or(|n| n == fb.config.name) {
+                files.push(fb.screenshot()?);
+            }
+        }
+        if let (Some(name), true) = (name, files.is_empty()) {
+            anyhow::bail!("Cannot find framebuffer {}", name);
+        }
+        Ok(files)
+    }
+
+    /// Hooks the backlight pins of the SDL windows
+    #[cfg(feature = "sdl")]
+    pub fn connect_backlights(&self, gpio: &mut GpioPorts) -> Result<()> {
+        for fb in &self.sdls {
+            let mut fb = fb.borrow_mut();
+            if let Some(ref config) = fb.config.backlight {
+                fb.backlight = Some(Backlight::new(config, gpio)?);
+            }
+        }
+        Ok(())
     }
 
     pub fn get<C>(&self, name: &str) -> Result<Rc<RefCell<dyn Framebuffer<C>>>> {
-        let images = self.images.iter().map(|fb| fb.clone() as Rc<RefCell<dyn Framebuffer<C>>>);
-        let sdls = self.sdls.iter().map(|fb| fb.clone() as Rc<RefCell<dyn Framebuffer<C>>>);
-        let fb = images.chain(sdls).find(|fb| fb.borrow().get_config().name == name);
+        let images = self.images.iter().map(|fb| fb.clone() as Rc<RefCell<dyn Framebuffer<C>>>)
+            .chain(self.vncs.iter().map(|fb| fb.clone() as Rc<RefCell<dyn Framebuffer<C>>>));
+        #[cfg(feature = "sdl")]
+        let images = images.chain(self.sdls.iter().map(|fb| fb.clone() as Rc<RefCell<dyn Framebuffer<C>>>));
+        let fb = images.into_iter().find(|fb| fb.borrow().get_config().name == name);
         fb.ok_or(anyhow::anyhow!("Cannot find framebuffer {}", name))
     }
 }
@@ -3,14 +3,16 @@
 use std::time::{Instant, Duration};
 
 use sdl2::mouse::MouseButton;
-use sdl2::{pixels::PixelFormatEnum, surface::Surface, render::Canvas, video::Window};
+use sdl2::{pixels::PixelFormatEnum, surface::Surface, render::Canvas, video::{Window, WindowPos}};
 use sdl2::{
     event::Event,
 };
 
-use super::{FramebufferConfig, Framebuffer, sdl_engine::SDL};
+use crate::peripherals::Peripherals;
 
-pub const REFRESH_DURATION_MILLIS: u64 = 20;
+use anyhow::Result;
+
+use super::{FramebufferConfig, Framebuffer, REFRESH_DURATION_MILLIS, backlight::Backlight, sdl_engine::SDL};
 
 pub struct Sdl {
     pub config: FramebufferConfig,
@@ -20,6 +22,8 @@ pub struct Sdl {
     last_redraw: Instant,
     pub window_id: u32,
     touch_position: Option<(u16, u16)>,
+    pub backlight: Option<Backlight>,
+    brightness: u8,
 }
 
 impl Sdl {
@@ -32,7 +36,7 @@ impl Sdl {
             _ => unimplemented!(),
         };
         let mut canvas = SDL.lock().unwrap().new_canvas(
-            &config.name,
+            config.title.as_deref().unwrap_or(&config.name),
             config.width.into(),
             config.height.into()
         );
@@ -56,6 +60,10 @@ impl Sdl {
             ).unwrap();
         }
 
+        if let Some([x, y]) = config.position {
+            canvas.window_mut().set_position(WindowPos::Positioned(x), WindowPos::Positioned(y));
+        }
+
         canvas.window_mut().raise();
 
         let last_redraw = Instant::now();
@@ -64,7 +72,7 @@ impl Sdl {
 
         let touch_position = None;
 
-        Self { config, canvas, framebuffer, need_redraw, last_redraw, window_id, touch_position }
+        Self { config, canvas, framebuffer, need_redraw, last_redraw, window_id, touch_position, backlight: None, brightness: 0xFF }
     }
 
     fn should_redraw(&mut self) -> bool {
@@ -82,27 +90,78 @@ impl Sdl {
         }
     }
 
-    pub fn maybe_redraw(&mut self) {
+    pub fn maybe_redraw(&mut self, p: &Peripherals) {
+        if let Some(ref mut backlight) = self.backlight {
+            let brightness = (backlight.brightness(p) * 255.0).round() as u8;
+            if brightness != self.brightness {
+                trace!("{} backlight brightness={}", self.config.name, brightness);
+                self.brightness = brightness;
+                self.need_redraw = true;
+            }
+        }
+
         if !self.should_redraw() {
             return;
         }
 
         let tc = self.canvas.texture_creator();
-        let texture = self.framebuffer.as_texture(&tc).unwrap();
+        let mut texture = self.framebuffer.as_texture(&tc).unwrap();
+        texture.set_color_mod(self.brightness, self.brightness, self.brightness);
         self.canvas.copy(&texture, None, None).unwrap();
 
         self.canvas.present();
     }
 
+    /// The content of the framebuffer, without the backlight dimming
+    pub fn get_framebuffer_as_rgb(&self) -> Vec<u8> {
+        let (width, height) = (self.config.width as usize, self.config.height as usize);
+        let pitch = self.framebuffer.pitch() as usize;
+        let pixels = self.framebuffer.without_lock().unwrap();
+        let rgb565 = self.framebuffer.pixel_format_enum() == PixelFormatEnum::RGB565;
+
+        let mut v = Vec::with_capacity(width * height * 3);
+        for row in pixels.chunks(pitch).take(height) {
+            if rgb565 {
+                for c in row.chunks_exact(2).take(width) {
+                    v.extend(super::rgb565_to_rgb(u16::from_ne_bytes([c[0], c[1]])));
+                }
+            } else {
+                for c in row.chunks_exact(4).take(width) {
+                    let [b, g, r, _] = u32::from_ne_bytes([c[0], c[1], c[2], c[3]]).to_le_bytes();
+                    v.extend([r, g, b]);
+                }
+            }
+        }
+        v
+    }
+
+    /// Writes the framebuffer to a timestamped PNG file, and returns its name
+    pub fn screenshot(&self) -> Result<String> {
+        let path = super::screenshot_path(&self.config.name);
+        super::write_png(&path, self.config.width, self.config.height, &self.get_framebuffer_as_rgb())?;
+        info!("Wrote screenshot of {} to {}", self.config.name, path);
+        Ok(path)
+    }
+
+    /// Window coordinates to framebuffer coordinates, as the window can be
+    /// downscaled or resized
+    fn to_framebuffer_position(&self, x: i32, y: i32) -> (u16, u16) {
+        let (w, h) = self.canvas.window().size();
+        let scale = |v: i32, window: u32, fb: u16| {
+            (v.max(0) as u64 * fb as u64 / window.max(1) as u64).min(fb.saturating_sub(1) as u64) as u16
+        };
+        (scale(x, w, self.config.width), scale(y, h, self.config.height))
+    }
+
     pub fn process_event(&mut self, event: Event) {
         match event {
             Event::MouseMotion { x, y, .. } => {
                 if self.touch_position.is_some() {
-                    self.touch_position = Some((x as u16, y as u16));
+                    self.touch_position = Some(self.to_framebuffer_position(x, y));
                 }
             }
             Event::MouseButtonDown { mouse_btn: MouseButton::Left, x, y, .. } => {
-                self.touch_position = Some((x as u16, y as u16));
+                self.touch_position = Some(self.to_framebuffer_position(x, y));
             }
             Event::MouseButtonUp { mouse_btn:MouseButton::Left, .. } => {
                 self.touch_position = None;
@@ -2,6 +2,8 @@
 
 use std::{sync::Mutex, rc::Rc, cell::RefCell};
 
+use crate::peripherals::Peripherals;
+
 use sdl2::{
     event::Event,
     keyboard::Keycode,
@@ -17,9 +19,6 @@ pub struct SdlEngine {
     video_subsystem: VideoSubsystem,
 }
 
-/// How often should we call pump_events() in terms of number of instructions emulated
-pub const PUMP_EVENT_INST_INTERVAL: u64 = 100_000; // ~1-10ms, depending on the speed of the host
-
 unsafe impl Send for SdlEngine {}
 unsafe impl Sync for SdlEngine {}
 
@@ -49,7 +48,7 @@ impl SdlEngine {
     }
 
     /// Returns false if we need to quit
-    pub fn pump_events(&mut self, framebuffers: &[Rc<RefCell<super::Sdl>>]) -> bool {
+    pub fn pump_events(&mut self, framebuffers: &[Rc<RefCell<super::Sdl>>], p: &Peripherals) -> bool {
         for event in self.event_pump.poll_iter() {
             match event {
                 Event::Quit {..} |
@@ -57,6 +56,21 @@ impl SdlEngine {
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     return false;
                 },
+                Event::KeyDown { keycode: Some(Keycode::R), .. } => {
+                    crate::context::current().reload_requested.store(true, std::sync::atomic::Ordering::Release);
+                }
+                Event::KeyDown { keycode: Some(Keycode::F12), window_id, .. } => {
+                    if let Some(fb) = framebuffers.iter().find(|fb| fb.borrow().window_id == window_id) {
+                        if let Err(e) = fb.borrow().screenshot() {
+                            warn!("Screenshot failed: {:#}", e);
+                        }
+                    }
+                }
+                // The keys turning the virtual encoders
+                Event::KeyDown { keycode: Some(keycode), .. } => {
+                    let hclk = p.rcc.borrow().hclk();
+                    p.timers.borrow_mut().key_pressed(&keycode.name(), hclk);
+                }
                 Event::MouseMotion { ref window_id, .. } |
                 Event::MouseButtonDown { ref window_id, .. } |
                 Event::MouseButtonUp { ref window_id, .. } => {
@@ -0,0 +1,344 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{io::{prelude::*, BufReader, BufWriter}, net::{TcpListener, TcpStream}, sync::{Arc, Mutex, Condvar}, time::{Duration, Instant}};
+
+use anyhow::{Context, Result};
+
+use super::{FramebufferConfig, Framebuffer, RGB565, REFRESH_DURATION_MILLIS};
+
+// A framebuffer served over VNC (RFB 3.3 to 3.8, no authentication), for
+// machines without a display:
+//
+//  framebuffers:
+//    - name: Display
+//      width: 320
+//      height: 240
+//      mode: rgb565
+//      vnc:
+//        listen: 127.0.0.1:5900
+//
+// Any VNC client can connect, and browsers with noVNC through websockify.
+// One client is served at a time. The left mouse button touches the screen.
+// Updates are sent whole, with the raw encoding, in the pixel format of the
+// client.
+
+#[derive(Debug, Clone, Copy)]
+struct PixelFormat {
+    bits_per_pixel: u8,
+    depth: u8,
+    big_endian: bool,
+    true_color: bool,
+    max: [u16; 3],
+    shift: [u8; 3],
+}
+
+impl Default for PixelFormat {
+    /// 32-bit little endian xRGB
+    fn default() -> Self {
+        Self { bits_per_pixel: 32, depth: 24, big_endian: false, true_color: true, max: [255; 3], shift: [16, 8, 0] }
+    }
+}
+
+impl PixelFormat {
+    fn parse(b: &[u8; 16]) -> Self {
+        Self {
+            bits_per_pixel: b[0],
+            depth: b[1],
+            big_endian: b[2] != 0,
+            true_color: b[3] != 0,
+            max: [
+                u16::from_be_bytes([b[4], b[5]]),
+                u16::from_be_bytes([b[6], b[7]]),
+                u16::from_be_bytes([b[8], b[9]]),
+            ],
+            shift: [b[10], b[11], b[12]],
+        }
+    }
+
+    fn to_bytes(self) -> [u8; 16] {
+        let [r, g, b] = self.max.map(u16::to_be_bytes);
+        [
+            self.bits_per_pixel, self.depth, self.big_endian as u8, self.true_color as u8,
+            r[0], r[1], g[0], g[1], b[0], b[1],
+            self.shift[0], self.shift[1], self.shift[2], 0, 0, 0,
+        ]
+    }
+
+    fn encode(&self, c: RGB565, out: &mut Vec<u8>) {
+        let mut v = 0u32;
+        for ((c, max), shift) in super::rgb565_to_rgb(c).into_iter().zip(self.max).zip(self.shift) {
+            v |= (c as u32 * max as u32 / 255) << shift;
+        }
+        let n = (self.bits_per_pixel / 8) as usize;
+        if self.big_endian {
+            out.extend(&v.to_be_bytes()[4-n..]);
+        } else {
+            out.extend(&v.to_le_bytes()[..n]);
+        }
+    }
+}
+
+/// Shared between the emulation and the VNC threads
+#[derive(Default)]
+struct State {
+    pixels: Vec<RGB565>,
+    /// Incremented on each change of the pixels
+    generation: u64,
+    touch_position: Option<(u16, u16)>,
+
+    // The connected client
+    format: PixelFormat,
+    update_requested: bool,
+    sent_generation: Option<u64>,
+    closed: bool,
+}
+
+type Shared = Arc<(Mutex<State>, Condvar)>;
+
+pub struct Vnc {
+    pub config: FramebufferConfig,
+    framebuffer: Vec<RGB565>,
+    need_update: bool,
+    last_update: Instant,
+    shared: Shared,
+}
+
+impl Vnc {
+    pub fn new(config: FramebufferConfig) -> Result<Self> {
+        let listen = config.vnc.as_ref().unwrap().listen.clone();
+        let framebuffer = vec![0; config.width as usize * config.height as usize];
+
+        let shared: Shared = Default::default();
+        shared.0.lock().unwrap().pixels = framebuffer.clone();
+
+        let listener = TcpListener::bind(&listen)
+            .with_context(|| format!("Failed to listen on {}", listen))?;
+        info!("Framebuffer {} served with VNC on {}", config.name, listen);
+
+        let (name, width, height) = (config.name.clone(), config.width, config.height);
+        let s = shared.clone();
+        std::thread::Builder::new()
+            .name(format!("vnc {}", name))
+            .spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            warn!("VNC {} accept failed: {}", name, e);
+                            return;
+                        }
+                    };
+                    let peer = stream.peer_addr().map(|a| a.to_string()).unwrap_or_default();
+                    info!("VNC {} client connected addr={}", name, peer);
+                    if let Err(e) = Self::serve(stream, &s, &name, width, height) {
+                        debug!("VNC {} {:#}", name, e);
+                    }
+                    info!("VNC {} client disconnected addr={}", name, peer);
+                }
+            })
+            .context("Failed to spawn the VNC thread")?;
+
+        Ok(Self { config, framebuffer, need_update: false, last_update: Instant::now(), shared })
+    }
+
+    /// Publishes the framebuffer to the VNC client, at most every REFRESH_DURATION_MILLIS
+    pub fn maybe_update(&mut self) {
+        if !self.need_update {
+            return;
+        }
+
+        let now = Instant::now();
+        if now.duration_since(self.last_update) > Duration::from_millis(REFRESH_DURATION_MILLIS) {
+            self.last_update = now;
+            self.need_update = false;
+            let (ref state, ref cvar) = *self.shared;
+            let mut state = state.lock().unwrap();
+            state.pixels.copy_from_slice(&self.framebuffer);
+            state.generation += 1;
+            cvar.notify_all();
+        }
+    }
+
+    pub fn get_framebuffer_as_rgb(&self) -> Vec<u8> {
+        self.framebuffer.iter().flat_map(|c| super::rgb565_to_rgb(*c)).collect()
+    }
+
+    fn serve(stream: TcpStream, shared: &Shared, name: &str, width: u16, height: u16) -> Result<()> {
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream.try_clone()?;
+
+        // Handshake: version, no security, then the client and server init
+        writer.write_all(b"RFB 003.008\n")?;
+        let mut version = [0; 12];
+        reader.read_exact(&mut version)?;
+        let minor = std::str::from_utf8(&version[8..11]).ok()
+            .and_then(|v| v.parse::<u32>().ok())
+            .context("Invalid protocol version")?;
+        if minor < 7 {
+            writer.write_all(&1u32.to_be_bytes())?;
+        } else {
+            writer.write_all(&[1, 1])?;
+            let mut security = [0];
+            reader.read_exact(&mut security)?;
+            if minor >= 8 {
+                writer.write_all(&0u32.to_be_bytes())?;
+            }
+        }
+        let mut shared_flag = [0];
+        reader.read_exact(&mut shared_flag)?;
+
+        let mut init = vec![];
+        init.extend(width.to_be_bytes());
+        init.extend(height.to_be_bytes());
+        init.extend(PixelFormat::default().to_bytes());
+        init.extend((name.len() as u32).to_be_bytes());
+        init.extend(name.as_bytes());
+        writer.write_all(&init)?;
+
+        {
+            let mut state = shared.0.lock().unwrap();
+            state.format = PixelFormat::default();
+            state.update_requested = false;
+            state.sent_generation = None;
+            state.closed = false;
+        }
+
+        let s = shared.clone();
+        let sender = std::thread::Builder::new()
+            .name(format!("vnc {} updates", name))
+            .spawn(move || Self::send_updates(writer, &s, width, height))
+            .context("Failed to spawn the VNC thread")?;
+
+        let result = Self::receive_messages(&mut reader, shared, width, height);
+
+        {
+            let (ref state, ref cvar) = **shared;
+            let mut state = state.lock().unwrap();
+            state.closed = true;
+            state.touch_position = None;
+            cvar.notify_all();
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+        let _ = sender.join();
+        result
+    }
+
+    fn receive_messages(reader: &mut impl Read, shared: &Shared, width: u16, height: u16) -> Result<()> {
+        let (ref state, ref cvar) = **shared;
+        loop {
+            let mut msg_type = [0];
+            reader.read_exact(&mut msg_type)?;
+            match msg_type[0] {
+                // SetPixelFormat
+                0 => {
+                    let mut b = [0; 19];
+                    reader.read_exact(&mut b)?;
+                    let format = PixelFormat::parse(b[3..].try_into().unwrap());
+                    if !format.true_color || ![8, 16, 32].contains(&format.bits_per_pixel) {
+                        anyhow::bail!("Unsupported pixel format {:?}", format);
+                    }
+                    trace!("VNC pixel format {:?}", format);
+                    state.lock().unwrap().format = format;
+                }
+                // SetEncodings. Raw is always supported.
+                2 => {
+                    let mut b = [0; 3];
+                    reader.read_exact(&mut b)?;
+                    let n = u16::from_be_bytes([b[1], b[2]]) as usize;
+                    let mut encodings = vec![0; 4*n];
+                    reader.read_exact(&mut encodings)?;
+                }
+                // FramebufferUpdateRequest
+                3 => {
+                    let mut b = [0; 9];
+                    reader.read_exact(&mut b)?;
+                    let incremental = b[0] != 0;
+                    let mut state = state.lock().unwrap();
+                    state.update_requested = true;
+                    if !incremental {
+                        state.sent_generation = None;
+                    }
+                    cvar.notify_all();
+                }
+                // KeyEvent
+                4 => {
+                    let mut b = [0; 7];
+                    reader.read_exact(&mut b)?;
+                }
+                // PointerEvent
+                5 => {
+                    let mut b = [0; 5];
+                    reader.read_exact(&mut b)?;
+                    let x = u16::from_be_bytes([b[1], b[2]]).min(width.saturating_sub(1));
+                    let y = u16::from_be_bytes([b[3], b[4]]).min(height.saturating_sub(1));
+                    let left_button = b[0] & 1 != 0;
+                    state.lock().unwrap().touch_position = left_button.then_some((x, y));
+                }
+                // ClientCutText
+                6 => {
+                    let mut b = [0; 7];
+                    reader.read_exact(&mut b)?;
+                    let len = u32::from_be_bytes([b[3], b[4], b[5], b[6]]) as u64;
+                    std::io::copy(&mut reader.take(len), &mut std::io::sink())?;
+                }
+                t => anyhow::bail!("Unsupported message type={}", t),
+            }
+        }
+    }
+
+    /// Sends the whole framebuffer when the client asked for it, and it changed
+    fn send_updates(writer: TcpStream, shared: &Shared, width: u16, height: u16) -> Result<()> {
+        let mut writer = BufWriter::new(writer);
+        let (ref state, ref cvar) = **shared;
+        let mut out = vec![];
+        loop {
+            let (format, pixels) = {
+                let mut state = cvar.wait_while(state.lock().unwrap(), |s| {
+                    // Waiting for a request, and for new pixels
+                    !s.closed && (!s.update_requested || s.sent_generation == Some(s.generation))
+                }).unwrap();
+                if state.closed {
+                    return Ok(());
+                }
+                state.update_requested = false;
+                state.sent_generation = Some(state.generation);
+                (state.format, state.pixels.clone())
+            };
+
+            out.clear();
+            // One rectangle covering the framebuffer, raw encoded
+            out.extend([0, 0, 0, 1]);
+            for v in [0, 0, width, height] {
+                out.extend(v.to_be_bytes());
+            }
+            out.extend(0i32.to_be_bytes());
+            for c in pixels {
+                format.encode(c, &mut out);
+            }
+            writer.write_all(&out)?;
+            writer.flush()?;
+        }
+    }
+}
+
+impl<Color> Framebuffer<Color> for Vnc {
+    fn get_config(&self) -> &FramebufferConfig {
+        &self.config
+    }
+
+    fn get_pixels(&mut self) -> &mut [Color] {
+        self.need_update = true;
+
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                self.framebuffer.as_mut_ptr() as *mut Color,
+                self.framebuffer.len() * std::mem::size_of::<RGB565>() / std::mem::size_of::<Color>(),
+            )
+        }
+    }
+
+    fn get_touch_position(&self) -> Option<(u16, u16)> {
+        self.shared.0.lock().unwrap().touch_position
+    }
+}
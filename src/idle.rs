@@ -0,0 +1,53 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use unicorn_engine::{Unicorn, RegisterARM};
+
+// An idle loop is a loop that doesn't make progress on its own, like waiting
+// for a variable that the SysTick handler increments. We recognize it when
+// the CPU state is identical on each iteration of the loop. Only an interrupt
+// can get us out of it, so we can fast-forward the time to the next one.
+
+// Number of identical iterations before we call it idle
+const NUM_ITERATIONS: u32 = 8;
+
+const REGISTERS: [RegisterARM; 16] = [
+    RegisterARM::R0, RegisterARM::R1, RegisterARM::R2, RegisterARM::R3,
+    RegisterARM::R4, RegisterARM::R5, RegisterARM::R6, RegisterARM::R7,
+    RegisterARM::R8, RegisterARM::R9, RegisterARM::R10, RegisterARM::R11,
+    RegisterARM::R12, RegisterARM::SP, RegisterARM::LR, RegisterARM::XPSR,
+];
+
+#[derive(Default)]
+pub struct IdleLoopDetector {
+    last_pc: u32,
+    // Loop head, which is the target of a backward branch
+    head: u32,
+    regs: [u64; REGISTERS.len()],
+    count: u32,
+}
+
+impl IdleLoopDetector {
+    /// Called on each instruction. Returns true when the CPU is in an idle loop.
+    pub fn on_instruction(&mut self, uc: &Unicorn<()>, pc: u32) -> bool {
+        let is_backward_branch = pc <= self.last_pc;
+        self.last_pc = pc;
+        if !is_backward_branch {
+            return false;
+        }
+
+        let mut regs = [0; REGISTERS.len()];
+        for (v, reg) in regs.iter_mut().zip(REGISTERS) {
+            *v = uc.reg_read(reg).unwrap();
+        }
+
+        if pc == self.head && regs == self.regs {
+            self.count += 1;
+        } else {
+            self.head = pc;
+            self.regs = regs;
+            self.count = 0;
+        }
+
+        self.count >= NUM_ITERATIONS
+    }
+}
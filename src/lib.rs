@@ -0,0 +1,70 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+// The emulator as a library, to run firmwares from Rust test harnesses
+// instead of shelling out to the binary:
+//
+//   let config = Config::load("firmware.yaml", &[])?;
+//   let mut emulator = Emulator::new(config, Args::from_options(["--fast-forward-idle"])?)?;
+//   emulator.run_for(1_000_000)?;
+//   let mut counter = [0; 4];
+//   emulator.read_mem(emulator.symbol("g_counter")?, &mut counter)?;
+//
+// Each emulator has its own emulated time, stop conditions and symbols, so
+// the tests of a harness can create as many as they need.
+
+// The peripherals' new() take the name of a SVD peripheral, and return the
+// boxed Peripheral that emulates it, or None when they don't.
+#![allow(clippy::new_ret_no_self)]
+
+mod config;
+mod emulator;
+mod context;
+mod util;
+mod peripherals;
+mod ext_devices;
+mod system;
+mod framebuffers;
+mod symbols;
+mod rtos;
+mod memcheck;
+mod stubs;
+mod profile;
+mod idle;
+mod flash_server;
+mod family;
+mod check;
+mod cpu;
+mod crypto;
+mod timing;
+mod console;
+mod boards;
+mod exit;
+mod fat;
+mod warn_context;
+mod flight_recorder;
+mod trace;
+mod log_filter;
+mod stats;
+mod watch;
+mod calls;
+mod expect;
+mod cosim;
+mod batch;
+mod control;
+mod catch;
+mod system_memory;
+mod bootloader;
+pub mod cli;
+
+pub use config::Config;
+pub use cli::Args;
+pub use emulator::Emulator;
+pub use control::{EmulatorHandle, EmulatorState};
+pub use ext_devices::{ExtDevice, I2cDevice, CustomDevices};
+pub use system::System;
+pub use unicorn_engine::RegisterARM;
+
+use cli::{verbose, json_string};
+
+#[macro_use]
+extern crate log;
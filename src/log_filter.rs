@@ -0,0 +1,120 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::HashMap, sync::{RwLock, atomic::{AtomicBool, Ordering}}};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde::Deserialize;
+
+// Log levels per peripheral or device, on top of the -v level:
+//
+//  log:
+//    SPI2: trace           # includes the register accesses of SPI2
+//    RCC: warn
+//    usart-probe: info
+//
+// A record belongs to a component when its message has the component name as
+// a word, like `SPI2 enabled ...`, `USART1 usart-probe ...`, or `peri=SPI2`.
+// When several components match, the most verbose level wins. The other
+// records follow the -v level.
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(l: LogLevel) -> Self {
+        match l {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref COMPONENTS: RwLock<Vec<(String, LevelFilter)>> = RwLock::new(vec![]);
+}
+
+/// Filters the records of the env_logger logger by component
+struct ComponentLogger {
+    inner: env_logger::Logger,
+    default: LevelFilter,
+}
+
+impl ComponentLogger {
+    fn level(&self, message: &str) -> LevelFilter {
+        let components = COMPONENTS.read().unwrap();
+        message.split_whitespace()
+            .flat_map(|word| [word, word.split_once('=').map_or(word, |(_, v)| v)])
+            .filter_map(|word| level_of(&components, word))
+            .max()
+            .unwrap_or(self.default)
+    }
+}
+
+fn level_of(components: &[(String, LevelFilter)], name: &str) -> Option<LevelFilter> {
+    components.iter().find(|(c, _)| c.eq_ignore_ascii_case(name)).map(|(_, l)| *l)
+}
+
+impl Log for ComponentLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        // Components can also be quieter than the default
+        let level = if ENABLED.load(Ordering::Relaxed) {
+            self.level(&record.args().to_string())
+        } else {
+            self.default
+        };
+        if record.level() <= level {
+            self.inner.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+/// Installs the logger. `inner` must let all the records through, the level is
+/// `default` until components are configured.
+pub fn install(inner: env_logger::Logger, default: LevelFilter) {
+    log::set_max_level(default);
+    log::set_boxed_logger(Box::new(ComponentLogger { inner, default }))
+        .expect("logger already installed");
+}
+
+/// Sets the levels of the components, from the `log` section of the config
+pub fn init(components: &HashMap<String, LogLevel>) {
+    if components.is_empty() {
+        return;
+    }
+    let default = log::max_level();
+    let components = components.iter()
+        .map(|(name, level)| (name.clone(), LevelFilter::from(*level)))
+        .collect::<Vec<_>>();
+    let max = components.iter().map(|(_, l)| *l).fold(default, Ord::max);
+    *COMPONENTS.write().unwrap() = components;
+    ENABLED.store(true, Ordering::Relaxed);
+    log::set_max_level(max);
+}
+
+/// Whether the register accesses of the peripheral are traced, like with -vvv
+pub fn trace_accesses(peripheral: &str) -> bool {
+    ENABLED.load(Ordering::Relaxed) &&
+        level_of(&COMPONENTS.read().unwrap(), peripheral) == Some(LevelFilter::Trace)
+}
@@ -0,0 +1,107 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{rc::Rc, cell::RefCell};
+use serde::Deserialize;
+use unicorn_engine::{Unicorn, RegisterARM};
+use anyhow::{Result, bail};
+
+use crate::{symbols::symbols, util::UniErr};
+
+#[derive(Debug, Deserialize, Default)]
+pub struct MemCheckConfig {
+    /// Lowest address the main stack is allowed to reach. Going below is
+    /// reported as a stack overflow.
+    pub stack_limit: Option<u32>,
+    /// Track the heap size by hooking _sbrk(). Needs the symbol.
+    pub heap: Option<bool>,
+}
+
+// Tracks the main stack and the heap usage. The task stacks of an RTOS are
+// covered by the RTOS awareness module.
+#[derive(Default)]
+pub struct MemCheck {
+    stack_limit: Option<u32>,
+    initial_sp: Option<u32>,
+    min_sp: u32,
+    // True while the stack is below the limit, so we warn once per overflow
+    overflowing: bool,
+    num_overflows: u32,
+
+    // The heap starts at the `end` symbol of the linker script, when present
+    heap_start: Option<u32>,
+    heap_size: i64,
+    heap_peak: i64,
+}
+
+impl MemCheck {
+    pub fn install(config: MemCheckConfig, uc: &mut Unicorn<()>) -> Result<Rc<RefCell<Self>>> {
+        let symbols = symbols();
+        let self_ = Rc::new(RefCell::new(Self {
+            stack_limit: config.stack_limit,
+            min_sp: u32::MAX,
+            heap_start: symbols.get("end").or_else(|| symbols.get("_end")).map(|s| s.addr),
+            ..Default::default()
+        }));
+
+        {
+            let self_ = self_.clone();
+            uc.add_code_hook(0, u64::MAX, move |uc, pc, _size| {
+                self_.borrow_mut().check_stack(uc, pc as u32);
+            }).expect("add_code_hook failed");
+        }
+
+        if config.heap.unwrap_or_default() {
+            let sbrk = match symbols.get("_sbrk") {
+                Some(s) => s.addr,
+                None => bail!("Heap tracking needs the _sbrk symbol"),
+            };
+
+            let self_ = self_.clone();
+            uc.add_code_hook(sbrk as u64, sbrk as u64, move |uc, _pc, _size| {
+                // void *_sbrk(ptrdiff_t incr)
+                let incr = uc.reg_read(RegisterARM::R0).unwrap() as u32 as i32;
+                self_.borrow_mut().on_sbrk(incr);
+            }).map_err(UniErr)?;
+        }
+
+        Ok(self_)
+    }
+
+    fn check_stack(&mut self, uc: &Unicorn<()>, pc: u32) {
+        let sp = uc.reg_read(RegisterARM::MSP).unwrap() as u32;
+        self.initial_sp.get_or_insert(sp);
+        self.min_sp = self.min_sp.min(sp);
+
+        let overflowing = self.stack_limit.map(|limit| sp < limit).unwrap_or_default();
+        if overflowing && !self.overflowing {
+            self.num_overflows += 1;
+            warn!("Stack overflow sp=0x{:08x} limit=0x{:08x} pc=0x{:08x}",
+                sp, self.stack_limit.unwrap(), pc);
+        }
+        self.overflowing = overflowing;
+    }
+
+    fn on_sbrk(&mut self, incr: i32) {
+        self.heap_size += incr as i64;
+        self.heap_peak = self.heap_peak.max(self.heap_size);
+        trace!("sbrk incr={} heap_size={}", incr, self.heap_size);
+
+        if let Some(heap_start) = self.heap_start {
+            let heap_end = heap_start as i64 + self.heap_size;
+            if self.min_sp != u32::MAX && heap_end > self.min_sp as i64 {
+                warn!("Heap collides with the stack heap_end=0x{:08x} min_sp=0x{:08x}",
+                    heap_end, self.min_sp);
+            }
+        }
+    }
+
+    pub fn report(&self) {
+        if let Some(initial_sp) = self.initial_sp {
+            info!("Main stack peak_usage={} min_sp=0x{:08x} num_overflows={}",
+                initial_sp.saturating_sub(self.min_sp), self.min_sp, self.num_overflows);
+        }
+        if self.heap_peak > 0 {
+            info!("Heap size={} peak_size={}", self.heap_size, self.heap_peak);
+        }
+    }
+}
@@ -0,0 +1,220 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::{system::System, util::{self, PersistPolicy}};
+use super::Peripheral;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct BackupDomainConfig {
+    /// When set, the backup registers and the backup SRAM are loaded from this
+    /// file, and saved back according to `persist`.
+    pub file: Option<String>,
+    /// always, on_exit (default), or never
+    pub persist: Option<PersistPolicy>,
+    /// Backup SRAM start address. Defaults to 0x40024000 as on the STM32F4.
+    pub sram_start: Option<u32>,
+    /// Backup SRAM size. The backup SRAM is only emulated when this is set.
+    pub sram_size: Option<u32>,
+}
+
+// Enough for all families. The F1 has the most with 42 registers.
+const NUM_REGISTERS: usize = 64;
+
+/// Everything that is battery-backed: the RTC/BKP backup registers, and the backup SRAM.
+pub struct BackupDomain {
+    file: Option<String>,
+    persist: PersistPolicy,
+    pub registers: [u32; NUM_REGISTERS],
+    sram_start: u32,
+    sram: Vec<u8>,
+}
+
+impl Default for BackupDomain {
+    fn default() -> Self {
+        Self { file: None, persist: PersistPolicy::default(), registers: [0; NUM_REGISTERS], sram_start: 0, sram: vec![] }
+    }
+}
+
+impl BackupDomain {
+    pub fn from_config(config: BackupDomainConfig) -> Result<Self> {
+        let sram_start = config.sram_start.unwrap_or(0x4002_4000);
+        let sram = vec![0; config.sram_size.unwrap_or(0) as usize];
+
+        let persist = config.persist.unwrap_or_default();
+        let mut self_ = Self { file: config.file, persist, sram_start, sram, ..Default::default() };
+
+        if let Some(ref file) = self_.file {
+            // The file doesn't exist on the first run
+            if std::path::Path::new(file).exists() {
+                let content = crate::util::read_file(file)?;
+                let (regs, sram) = content.split_at((4*NUM_REGISTERS).min(content.len()));
+                for (r, v) in self_.registers.iter_mut().zip(regs.chunks_exact(4)) {
+                    *r = u32::from_le_bytes(v.try_into().unwrap());
+                }
+                let len = sram.len().min(self_.sram.len());
+                self_.sram[..len].copy_from_slice(&sram[..len]);
+                info!("Loaded backup domain from {}", file);
+            }
+        }
+
+        Ok(self_)
+    }
+
+    fn write_back(&self, file: &str) -> Result<()> {
+        let mut content = Vec::with_capacity(4*NUM_REGISTERS + self.sram.len());
+        for r in &self.registers {
+            content.extend_from_slice(&r.to_le_bytes());
+        }
+        content.extend_from_slice(&self.sram);
+        util::write_file_atomic(file, &content)
+    }
+
+    /// Called when the emulation stops
+    pub fn save(&self, clean_exit: bool) -> Result<()> {
+        if let Some(ref file) = self.file {
+            if self.persist.on_exit(clean_exit) {
+                self.write_back(file)?;
+                info!("Saved backup domain to {}", file);
+            }
+        }
+        Ok(())
+    }
+
+    /// With the `always` policy, the file is rewritten on every write
+    fn written(&self) {
+        if let (Some(file), PersistPolicy::Always) = (&self.file, self.persist) {
+            if let Err(e) = self.write_back(file) {
+                warn!("Failed to save the backup domain: {:#}", e);
+            }
+        }
+    }
+
+    pub fn write_register(&mut self, i: usize, value: u32) {
+        if let Some(r) = self.registers.get_mut(i) {
+            *r = value;
+            self.written();
+        }
+    }
+
+    /// Returns the offset in the backup SRAM if the address falls in it
+    pub fn sram_offset(&self, addr: u32) -> Option<usize> {
+        let offset = addr.wrapping_sub(self.sram_start) as usize;
+        (offset < self.sram.len()).then_some(offset)
+    }
+
+    pub fn read_sram(&self, offset: usize, size: u8) -> u32 {
+        let mut v = [0; 4];
+        for (i, b) in v.iter_mut().enumerate().take(size as usize) {
+            *b = self.sram.get(offset + i).cloned().unwrap_or_default();
+        }
+        u32::from_le_bytes(v)
+    }
+
+    pub fn write_sram(&mut self, offset: usize, size: u8, value: u32) {
+        for (i, b) in value.to_le_bytes().iter().enumerate().take(size as usize) {
+            if let Some(m) = self.sram.get_mut(offset + i) {
+                *m = *b;
+            }
+        }
+        self.written();
+    }
+}
+
+/// RTC of the F2/F4/F7/L4 and others, where the backup registers live.
+#[derive(Default)]
+pub struct Rtc {
+    // Everything except the backup registers. We store written values.
+    regs: [u32; 0x50/4],
+}
+
+impl Rtc {
+    const BKP_START: u32 = 0x50;
+
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "RTC" {
+            Some(Box::new(Self::default()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for Rtc {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        if offset >= Self::BKP_START {
+            let i = ((offset - Self::BKP_START) / 4) as usize;
+            return sys.p.backup.borrow().registers.get(i).cloned().unwrap_or_default();
+        }
+
+        let v = self.regs[(offset/4) as usize];
+        match offset {
+            0x000C => {
+                // ISR register
+                // Bit 7 INIT: initialization mode
+                // Bit 6 INITF: initialization flag. Follows INIT.
+                // Bit 5 RSF: registers synchronization flag
+                let initf = if v & (1 << 7) != 0 { 1 << 6 } else { 0 };
+                v | initf | (1 << 5)
+            }
+            _ => v
+        }
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        if offset >= Self::BKP_START {
+            let i = ((offset - Self::BKP_START) / 4) as usize;
+            trace!("RTC BKP{}R write=0x{:08x}", i, value);
+            sys.p.backup.borrow_mut().write_register(i, value);
+            return;
+        }
+
+        self.regs[(offset/4) as usize] = value;
+    }
+}
+
+/// BKP peripheral of the F1, holding 16-bit data registers
+#[derive(Default)]
+pub struct Bkp {
+    regs: [u32; 0x40/4],
+}
+
+impl Bkp {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "BKP" {
+            Some(Box::new(Self::default()))
+        } else {
+            None
+        }
+    }
+
+    /// Maps the register offset to the backup register index.
+    /// DR1-DR10 are at 0x04-0x28, DR11-DR42 at 0x40-0xBC.
+    fn data_register(offset: u32) -> Option<usize> {
+        match offset {
+            0x04..=0x28 => Some((offset - 0x04) as usize / 4),
+            0x40..=0xBC => Some((offset - 0x40) as usize / 4 + 10),
+            _ => None,
+        }
+    }
+}
+
+impl Peripheral for Bkp {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        if let Some(i) = Self::data_register(offset) {
+            sys.p.backup.borrow().registers[i] & 0xFFFF
+        } else {
+            self.regs.get((offset/4) as usize).cloned().unwrap_or_default()
+        }
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        if let Some(i) = Self::data_register(offset) {
+            trace!("BKP DR{} write=0x{:04x}", i+1, value as u16);
+            sys.p.backup.borrow_mut().write_register(i, value & 0xFFFF);
+        } else if let Some(r) = self.regs.get_mut((offset/4) as usize) {
+            *r = value;
+        }
+    }
+}
@@ -0,0 +1,227 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::HashMap};
+
+use serde::Deserialize;
+
+use crate::system::System;
+use super::{Peripheral, Peripherals};
+
+// Comparators and op-amps, F3/L4/G4 layout. There's no analog input, so the
+// comparator outputs come from the config:
+//
+//  comp:
+//    - name: COMP1
+//      output: false       # initial output
+//      exti_line: 21       # raised on output changes
+//      irq: 64             # when the SVD has no EXTI21 interrupt
+//      events:             # output changes, at a given instruction count
+//        - at: 1000000
+//          output: true
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CompConfig {
+    pub name: String,
+    pub output: Option<bool>,
+    pub exti_line: Option<u32>,
+    pub irq: Option<i32>,
+    pub events: Option<Vec<CompEventConfig>>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct CompEventConfig {
+    pub at: u64,
+    pub output: bool,
+}
+
+// CSR register bits
+const CSR_EN: u32 = 1 << 0;
+const CSR_POL: u32 = 1 << 15;
+const CSR_VALUE: u32 = 1 << 30;
+
+#[derive(Default)]
+struct Comparator {
+    config: CompConfig,
+    csr: u32,
+    // Output of the analog comparison, before the polarity is applied
+    input: bool,
+    // Index in the sorted events
+    next_event: usize,
+}
+
+impl Comparator {
+    fn new(config: CompConfig) -> Self {
+        let mut config = config;
+        if let Some(ref mut events) = config.events {
+            events.sort_by_key(|e| e.at);
+        }
+        let input = config.output.unwrap_or_default();
+        Self { config, input, ..Self::default() }
+    }
+
+    fn output(&self) -> bool {
+        self.csr & CSR_EN != 0 && (self.input ^ (self.csr & CSR_POL != 0))
+    }
+
+    fn next_event_at(&self) -> Option<u64> {
+        self.config.events.as_ref()
+            .and_then(|events| events.get(self.next_event))
+            .map(|e| e.at)
+    }
+}
+
+#[derive(Default)]
+pub struct Comparators {
+    // By name, e.g. COMP1
+    comparators: HashMap<String, Comparator>,
+    next_event_at: Option<u64>,
+}
+
+impl Comparators {
+    pub fn from_config(config: Vec<CompConfig>) -> Self {
+        let comparators = config.into_iter()
+            .map(|c| (c.name.clone(), Comparator::new(c)))
+            .collect();
+        let mut comparators = Self { comparators, next_event_at: None };
+        comparators.update_next_event_at();
+        comparators
+    }
+
+    fn update_next_event_at(&mut self) {
+        self.next_event_at = self.comparators.values().filter_map(|c| c.next_event_at()).min();
+    }
+
+    /// The instruction count of the next scripted output change
+    pub fn next_event_at(&self) -> Option<u64> {
+        self.next_event_at
+    }
+
+    /// The CSR bits and the scripted outputs are reset, but the events
+    /// that already happened are not replayed.
+    pub fn reset(&mut self) {
+        for c in self.comparators.values_mut() {
+            c.csr = 0;
+        }
+    }
+
+    /// Applies the scripted events that are due
+    pub fn poll(p: &Peripherals) {
+        let now = crate::context::now();
+        if p.comp.borrow().next_event_at.is_none_or(|at| at > now) {
+            return;
+        }
+
+        let mut comps = p.comp.borrow_mut();
+        for (name, c) in &mut comps.comparators {
+            while let Some(event) = c.config.events.as_ref().and_then(|events| events.get(c.next_event)) {
+                if event.at > now {
+                    break;
+                }
+                let input = event.output;
+                c.next_event += 1;
+
+                let before = c.output();
+                c.input = input;
+                let after = c.output();
+                if before != after {
+                    debug!("{} output={}", name, after);
+                    if let Some(line) = c.config.exti_line {
+                        p.exti.borrow_mut().trigger(&mut p.nvic.borrow_mut(), line, after, c.config.irq);
+                    }
+                }
+            }
+        }
+        comps.update_next_event_at();
+    }
+
+    fn comparator(&mut self, name: &str) -> &mut Comparator {
+        self.comparators.entry(name.to_string()).or_insert_with(|| {
+            debug!("{} not configured, its output is low", name);
+            Comparator::new(CompConfig { name: name.to_string(), ..CompConfig::default() })
+        })
+    }
+}
+
+/// Comparator control and status registers. The SVD files either have a
+/// single COMP peripheral with one CSR per comparator, or one peripheral per
+/// comparator (COMP1, COMP2, ...).
+pub struct Comp {
+    name: String,
+}
+
+impl Comp {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name.starts_with("COMP") {
+            Some(Box::new(Self { name: name.to_string() }))
+        } else {
+            None
+        }
+    }
+
+    fn comparator_name(&self, offset: u32) -> String {
+        let has_index = self.name.chars().last().is_some_and(|c| c.is_ascii_digit());
+        if has_index {
+            self.name.clone()
+        } else {
+            format!("COMP{}", offset/4 + 1)
+        }
+    }
+}
+
+impl Peripheral for Comp {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        let mut comps = sys.p.comp.borrow_mut();
+        let c = comps.comparator(&self.comparator_name(offset));
+        let mut v = c.csr & !CSR_VALUE;
+        if c.output() {
+            v |= CSR_VALUE;
+        }
+        v
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        let name = self.comparator_name(offset);
+        let mut comps = sys.p.comp.borrow_mut();
+        let c = comps.comparator(&name);
+
+        let before = c.output();
+        if value & CSR_EN != c.csr & CSR_EN {
+            debug!("{} enabled={}", name, value & CSR_EN != 0);
+        }
+        c.csr = value & !CSR_VALUE;
+        let after = c.output();
+
+        // Enabling the comparator or flipping the polarity can change the output
+        if before != after {
+            if let Some(line) = c.config.exti_line {
+                sys.p.exti.borrow_mut().trigger(&mut sys.p.nvic.borrow_mut(), line, after, c.config.irq);
+            }
+        }
+    }
+}
+
+/// Op-amps only have configuration registers. The calibration output stays low.
+#[derive(Default)]
+pub struct Opamp {
+    regs: HashMap<u32, u32>,
+}
+
+impl Opamp {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name.starts_with("OPAMP") {
+            Some(Box::new(Self::default()))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for Opamp {
+    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        self.regs.get(&offset).cloned().unwrap_or_default()
+    }
+
+    fn write(&mut self, _sys: &System, offset: u32, value: u32) {
+        self.regs.insert(offset, value);
+    }
+}
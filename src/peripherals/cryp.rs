@@ -0,0 +1,256 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::VecDeque;
+
+use crate::crypto::{self, Aes};
+use crate::system::System;
+use super::Peripheral;
+
+// CR register bits
+const CR_ALGODIR: u32 = 1 << 2;
+const CR_ALGOMODE_SHIFT: u32 = 3;
+const CR_ALGOMODE3: u32 = 1 << 19;
+const CR_DATATYPE_SHIFT: u32 = 6;
+const CR_KEYSIZE_SHIFT: u32 = 8;
+const CR_FFLUSH: u32 = 1 << 14;
+const CR_CRYPEN: u32 = 1 << 15;
+
+// SR register bits
+const SR_IFEM: u32 = 1 << 0;
+const SR_IFNF: u32 = 1 << 1;
+const SR_OFNE: u32 = 1 << 2;
+const SR_OFFU: u32 = 1 << 3;
+
+const FIFO_SIZE: usize = 8;
+
+// AES chaining modes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Ecb,
+    Cbc,
+    Ctr,
+    KeyPrep,
+}
+
+/// Cryptographic processor, F2/F4/F7 layout. AES ECB, CBC and CTR are
+/// supported, DES, TDES, GCM and CCM are not. Blocks are processed as soon
+/// as four words are in the input FIFO, there's no busy time.
+#[derive(Default)]
+pub struct Cryp {
+    cr: u32,
+    // K0LR to K3RR
+    key: [u32; 8],
+    // IV0LR to IV1RR
+    iv: [u32; 4],
+    dmacr: u32,
+    imscr: u32,
+    input: Vec<u32>,
+    output: VecDeque<u32>,
+    aes: Option<Aes>,
+}
+
+impl Cryp {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "CRYP" {
+            Some(Box::new(Self::default()))
+        } else {
+            None
+        }
+    }
+
+    fn mode(&self) -> Option<Mode> {
+        if self.cr & CR_ALGOMODE3 != 0 {
+            return None;
+        }
+        match (self.cr >> CR_ALGOMODE_SHIFT) & 0b111 {
+            0b100 => Some(Mode::Ecb),
+            0b101 => Some(Mode::Cbc),
+            0b110 => Some(Mode::Ctr),
+            0b111 => Some(Mode::KeyPrep),
+            _ => None,
+        }
+    }
+
+    fn datatype(&self) -> u32 {
+        self.cr >> CR_DATATYPE_SHIFT
+    }
+
+    fn is_decrypt(&self) -> bool {
+        self.cr & CR_ALGODIR != 0
+    }
+
+    /// The key is right aligned in the key registers
+    fn key_bytes(&self) -> Vec<u8> {
+        let num_words = match (self.cr >> CR_KEYSIZE_SHIFT) & 0b11 {
+            0b00 => 4,
+            0b01 => 6,
+            _ => 8,
+        };
+        self.key[8-num_words..].iter()
+            .flat_map(|w| w.to_be_bytes())
+            .collect()
+    }
+
+    fn iv_bytes(&self) -> [u8; 16] {
+        let mut iv = [0u8; 16];
+        for (i, w) in self.iv.iter().enumerate() {
+            iv[4*i..4*i+4].copy_from_slice(&w.to_be_bytes());
+        }
+        iv
+    }
+
+    fn set_iv_bytes(&mut self, iv: &[u8; 16]) {
+        for (i, w) in self.iv.iter_mut().enumerate() {
+            *w = u32::from_be_bytes(iv[4*i..4*i+4].try_into().unwrap());
+        }
+    }
+
+    fn enable(&mut self) {
+        match self.mode() {
+            Some(Mode::KeyPrep) => {
+                // Our AES doesn't need the decryption key schedule to be
+                // prepared. CRYPEN is cleared when the preparation is done.
+                self.cr &= !CR_CRYPEN;
+            }
+            Some(mode) => {
+                debug!("CRYP enabled mode={:?} decrypt={} key_bits={}", mode, self.is_decrypt(), self.key_bytes().len()*8);
+            }
+            None => warn!("CRYP algomode=0x{:x} is not supported", (self.cr >> CR_ALGOMODE_SHIFT) & 0b111),
+        }
+    }
+
+    fn process_block(&mut self) {
+        let mut block = [0u8; 16];
+        for (i, w) in self.input.drain(..).enumerate() {
+            block[4*i..4*i+4].copy_from_slice(&w.to_be_bytes());
+        }
+
+        if self.mode().is_none_or(|mode| mode == Mode::KeyPrep) {
+            return;
+        }
+        if self.aes.is_none() {
+            self.aes = Some(Aes::new(&self.key_bytes()));
+        }
+        let aes = self.aes.as_ref().unwrap();
+
+        let mut iv = self.iv_bytes();
+        match self.mode() {
+            Some(Mode::Ecb) if self.is_decrypt() => aes.decrypt_block(&mut block),
+            Some(Mode::Ecb) => aes.encrypt_block(&mut block),
+            Some(Mode::Cbc) if self.is_decrypt() => {
+                let ciphertext = block;
+                aes.decrypt_block(&mut block);
+                for (b, v) in block.iter_mut().zip(iv) {
+                    *b ^= v;
+                }
+                iv = ciphertext;
+            }
+            Some(Mode::Cbc) => {
+                for (b, v) in block.iter_mut().zip(iv) {
+                    *b ^= v;
+                }
+                aes.encrypt_block(&mut block);
+                iv = block;
+            }
+            Some(Mode::Ctr) => {
+                let mut keystream = iv;
+                aes.encrypt_block(&mut keystream);
+                for (b, k) in block.iter_mut().zip(keystream) {
+                    *b ^= k;
+                }
+                // The counter is the last 32-bit word of the IV
+                let counter = u32::from_be_bytes(iv[12..16].try_into().unwrap()).wrapping_add(1);
+                iv[12..16].copy_from_slice(&counter.to_be_bytes());
+            }
+            _ => unreachable!(),
+        }
+        self.set_iv_bytes(&iv);
+
+        let datatype = self.datatype();
+        for w in block.chunks(4) {
+            let w = u32::from_be_bytes(w.try_into().unwrap());
+            if self.output.len() < FIFO_SIZE {
+                self.output.push_back(crypto::swap_datatype(w, datatype));
+            } else {
+                warn!("CRYP output FIFO overflow");
+            }
+        }
+    }
+
+    fn sr(&self) -> u32 {
+        let mut sr = 0;
+        if self.input.is_empty() {
+            sr |= SR_IFEM;
+        }
+        if self.input.len() < FIFO_SIZE {
+            sr |= SR_IFNF;
+        }
+        if !self.output.is_empty() {
+            sr |= SR_OFNE;
+        }
+        if self.output.len() >= FIFO_SIZE {
+            sr |= SR_OFFU;
+        }
+        sr
+    }
+}
+
+impl Peripheral for Cryp {
+    fn has_side_effects(&self, offset: u32) -> bool {
+        // DIN, DOUT
+        matches!(offset, 0x0008 | 0x000C)
+    }
+
+    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x0000 => self.cr & !CR_FFLUSH,
+            0x0004 => self.sr(),
+            0x000C => self.output.pop_front().unwrap_or_default(),
+            0x0010 => self.dmacr,
+            0x0014 => self.imscr,
+            // RISR. The input FIFO is always ready, the output is ready when not empty.
+            0x0018 | 0x001C => {
+                let ris = 1 | (!self.output.is_empty() as u32) << 1;
+                if offset == 0x001C { ris & self.imscr } else { ris }
+            }
+            0x0040..=0x004C => self.iv[((offset - 0x0040) / 4) as usize],
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, _sys: &System, offset: u32, value: u32) {
+        match offset {
+            0x0000 => {
+                let enabled = value & CR_CRYPEN != 0 && self.cr & CR_CRYPEN == 0;
+                self.cr = value & !CR_FFLUSH;
+                // The key size may have changed
+                self.aes = None;
+                if value & CR_FFLUSH != 0 {
+                    self.input.clear();
+                    self.output.clear();
+                }
+                if enabled {
+                    self.enable();
+                }
+            }
+            0x0008 => {
+                if self.cr & CR_CRYPEN == 0 {
+                    return;
+                }
+                self.input.push(crypto::swap_datatype(value, self.datatype()));
+                if self.input.len() == 4 {
+                    self.process_block();
+                }
+            }
+            0x0010 => self.dmacr = value,
+            0x0014 => self.imscr = value,
+            0x0020..=0x003C => {
+                self.key[((offset - 0x0020) / 4) as usize] = value;
+                // The key schedule is recomputed on the next block
+                self.aes = None;
+            }
+            0x0040..=0x004C => self.iv[((offset - 0x0040) / 4) as usize] = value,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::ext_devices::{ExtDevices, ExtDevice};
+use crate::system::System;
+use super::Peripheral;
+
+// CR bits, shifted by 16 for channel 2
+const CR_EN: u32 = 1 << 0;
+const CR_TEN: u32 = 1 << 2;
+const CR_TSEL_SHIFT: u32 = 3;
+const TSEL_SOFTWARE: u32 = 0b111;
+
+/// DAC with two 12-bit channels, F1/F2/F4/F7 layout. The data holding
+/// registers are transferred to the outputs immediately, or on a software
+/// trigger. Hardware triggers (timers, EXTI) are approximated with an
+/// immediate transfer.
+#[derive(Default)]
+pub struct Dac {
+    name: String,
+    ext_device: Option<Rc<RefCell<dyn ExtDevice<u8, u16>>>>,
+    cr: u32,
+    dhr: [u16; 2],
+    dor: [u16; 2],
+}
+
+impl Dac {
+    pub fn new(name: &str, ext_devices: &ExtDevices) -> Option<Box<dyn Peripheral>> {
+        if name.starts_with("DAC") {
+            let ext_device = ext_devices.find_analog_device(name);
+            let name = ext_device.as_ref()
+                .map(|d| d.borrow_mut().connect_peripheral(name))
+                .unwrap_or_else(|| name.to_string());
+            Some(Box::new(Self { name, ext_device, ..Default::default() }))
+        } else {
+            None
+        }
+    }
+
+    fn channel_cr(&self, channel: usize) -> u32 {
+        self.cr >> (16 * channel)
+    }
+
+    fn is_software_triggered(&self, channel: usize) -> bool {
+        let cr = self.channel_cr(channel);
+        cr & CR_TEN != 0 && (cr >> CR_TSEL_SHIFT) & 0b111 == TSEL_SOFTWARE
+    }
+
+    fn transfer(&mut self, sys: &System, channel: usize) {
+        if self.channel_cr(channel) & CR_EN == 0 {
+            return;
+        }
+
+        let value = self.dhr[channel];
+        if self.dor[channel] != value {
+            trace!("{} channel={} output={}", self.name, channel+1, value);
+        }
+        self.dor[channel] = value;
+
+        if let Some(ref d) = self.ext_device {
+            d.borrow_mut().write(sys, channel as u8 + 1, value);
+        }
+    }
+
+    fn write_dhr(&mut self, sys: &System, channel: usize, value: u16) {
+        self.dhr[channel] = value & 0xFFF;
+        if !self.is_software_triggered(channel) {
+            self.transfer(sys, channel);
+        }
+    }
+}
+
+impl Peripheral for Dac {
+    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x0000 => self.cr,
+            0x0008 => self.dhr[0] as u32,
+            0x000C => (self.dhr[0] as u32) << 4,
+            0x0010 => (self.dhr[0] >> 4) as u32,
+            0x0014 => self.dhr[1] as u32,
+            0x0018 => (self.dhr[1] as u32) << 4,
+            0x001C => (self.dhr[1] >> 4) as u32,
+            0x0020 => self.dhr[0] as u32 | (self.dhr[1] as u32) << 16,
+            0x0024 => (self.dhr[0] as u32) << 4 | (self.dhr[1] as u32) << 20,
+            0x0028 => (self.dhr[0] >> 4) as u32 | ((self.dhr[1] >> 4) as u32) << 8,
+            0x002C => self.dor[0] as u32,
+            0x0030 => self.dor[1] as u32,
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        match offset {
+            0x0000 => {
+                let enabled = value & !self.cr;
+                self.cr = value;
+                // Enabling a channel outputs what's in the data holding register
+                for channel in 0..2 {
+                    if (enabled >> (16 * channel)) & CR_EN != 0 {
+                        debug!("{} channel={} enabled", self.name, channel+1);
+                        self.transfer(sys, channel);
+                    }
+                }
+            }
+            0x0004 => {
+                // SWTRIGR
+                for channel in 0..2 {
+                    if value & (1 << channel) != 0 && self.is_software_triggered(channel) {
+                        self.transfer(sys, channel);
+                    }
+                }
+            }
+            // 12-bit right aligned, 12-bit left aligned, 8-bit right aligned
+            0x0008 => self.write_dhr(sys, 0, value as u16),
+            0x000C => self.write_dhr(sys, 0, (value >> 4) as u16),
+            0x0010 => self.write_dhr(sys, 0, ((value & 0xFF) << 4) as u16),
+            0x0014 => self.write_dhr(sys, 1, value as u16),
+            0x0018 => self.write_dhr(sys, 1, (value >> 4) as u16),
+            0x001C => self.write_dhr(sys, 1, ((value & 0xFF) << 4) as u16),
+            // Dual channel
+            0x0020 => {
+                self.write_dhr(sys, 0, value as u16);
+                self.write_dhr(sys, 1, (value >> 16) as u16);
+            }
+            0x0024 => {
+                self.write_dhr(sys, 0, (value >> 4) as u16);
+                self.write_dhr(sys, 1, (value >> 20) as u16);
+            }
+            0x0028 => {
+                self.write_dhr(sys, 0, ((value & 0xFF) << 4) as u16);
+                self.write_dhr(sys, 1, (((value >> 8) & 0xFF) << 4) as u16);
+            }
+            _ => {}
+        }
+    }
+}
@@ -23,6 +23,11 @@ impl Dma {
 }
 
 impl Peripheral for Dma {
+    fn has_side_effects(&self, offset: u32) -> bool {
+        // Reading CR applies the pending configuration
+        matches!(Access::from_offset(offset), Access::StreamReg(_, 0x0000))
+    }
+
     fn read(&mut self, sys: &System, offset: u32) -> u32 {
         match Access::from_offset(offset) {
             Access::StreamReg(i, offset) => self.streams[i].read(&self.name, sys, offset),
@@ -36,6 +41,12 @@ impl Peripheral for Dma {
             _ => {}
         }
     }
+
+    fn tick(&mut self, sys: &System) {
+        for stream in &mut self.streams {
+            stream.service(&self.name, sys);
+        }
+    }
 }
 
 #[derive(Default)]
@@ -47,6 +58,8 @@ struct Stream {
     pub m0ar: u32,
     pub m1ar: u32,
     pub fcr: u32,
+    // When the peripheral paces the transfer, the memory address of the next byte
+    pub pending_addr: Option<u32>,
 }
 
 impl Stream {
@@ -85,6 +98,47 @@ impl Stream {
         }
     }
 
+    /// Peripherals like the SPI only have data to read once it's received.
+    /// The transfer is then done as the data comes.
+    fn is_paced(&self, sys: &System) -> bool {
+        self.dir() == Dir::Read &&
+            Peripherals::get_peripheral(&sys.p.peripherals, self.par)
+                .is_some_and(|p| p.peripheral.borrow_mut().dma_read_available(sys, self.par - p.start).is_some())
+    }
+
+    /// Moves the data the peripheral has ready for a paced transfer. The
+    /// stream is disabled once NDTR reaches 0.
+    fn service(&mut self, name: &str, sys: &System) {
+        let addr = match self.pending_addr {
+            Some(addr) => addr,
+            None => return,
+        };
+        let p = match Peripherals::get_peripheral(&sys.p.peripherals, self.par) {
+            Some(p) => p,
+            None => return,
+        };
+        let offset = self.par - p.start;
+
+        let mut peri = p.peripheral.borrow_mut();
+        let available = peri.dma_read_available(sys, offset).unwrap_or(usize::MAX);
+        let size = available.min(self.data_size()) / self.word_size() * self.word_size();
+        if size > 0 {
+            let mut buf = peri.read_dma(sys, offset, size);
+            trace!("{} xfer buf={:x?}", name, buf);
+            if let Err(e) = sys.uc.borrow_mut().mem_write(addr.into(), buf.make_contiguous()) {
+                warn!("DMA read failed addr=0x{:08x} size={} e={}", addr, size, UniErr(e));
+            }
+            self.pending_addr = Some(addr + size as u32);
+            self.ndtr -= (size / self.word_size()) as u32;
+        }
+
+        if self.ndtr == 0 {
+            debug!("{} xfer done channel={} peri_{}", name, self.channel(), sys.p.addr_desc(self.par));
+            self.pending_addr = None;
+            self.cr &= !1;
+        }
+    }
+
     fn do_xfer(&self, name: &str, sys: &System) {
         let dir = self.dir();
         let data_addr = self.data_addr();
@@ -127,6 +181,10 @@ impl Stream {
 
         trace!("{} xfer buf={:x?}", name, buf);
 
+        if matches!(dir, Dir::Write | Dir::MemCopy) && Self::write_block(sys, dst, self.word_size(), buf.make_contiguous()) {
+            return;
+        }
+
         match dir {
             Dir::Write => {
                 peri.map(|p| p.peripheral.borrow_mut().write_dma(sys, peri_addr-p.start, buf));
@@ -140,6 +198,25 @@ impl Stream {
         }
     }
 
+    /// Halfword transfers to memory regions like the FSMC banks are written in
+    /// one call. A full frame blit to a display doesn't go through the
+    /// memory hooks for every pixel. Returns false when not applicable.
+    fn write_block(sys: &System, dst: u32, word_size: usize, buf: &[u8]) -> bool {
+        if word_size != 2 || Peripherals::is_register(dst) {
+            return false;
+        }
+        let p = match Peripherals::get_peripheral(&sys.p.peripherals, dst) {
+            Some(p) => p,
+            None => return false,
+        };
+        let values = buf.chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .collect::<Vec<_>>();
+        p.accesses.set(p.accesses.get() + values.len() as u64);
+        p.peripheral.borrow_mut().write_block(sys, dst - p.start, &values);
+        true
+    }
+
     pub fn read(&mut self, _name: &str, _sys: &System, offset: u32) -> u32 {
         match offset {
             0x0000 => {
@@ -174,7 +251,14 @@ impl Stream {
                 self.cr = value;
 
                 // CRx register
-                if value & 1 != 0 {
+                if value & 1 == 0 {
+                    self.pending_addr = None;
+                } else if self.is_paced(sys) {
+                    debug!("{} xfer initiated channel={} peri_{} dir={:?} addr=0x{:08x} size={} paced",
+                        name, self.channel(), sys.p.addr_desc(self.par), self.dir(), self.data_addr(), self.data_size());
+                    self.pending_addr = Some(self.data_addr());
+                    self.service(name, sys);
+                } else {
                     // Enable is on. do the transfer.
                     self.do_xfer(name, sys);
 
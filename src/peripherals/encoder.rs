@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+
+use anyhow::{Result, bail};
+use serde::Deserialize;
+
+// Virtual quadrature encoders, read by the timers in encoder mode, like the
+// knob of a user interface or the encoder of a motor:
+//
+//  encoders:
+//    - timer: TIM4
+//      position: 0           # initial position
+//      keys: [Left, Right]   # SDL keys turning it down and up
+//      step: 4               # counts per key press, 4 by default
+//      events:               # at a given instruction count
+//        - at: 1000000
+//          position: 400
+//        - at: 2000000
+//          velocity: -1000   # counts per second from there
+//
+// The position is in counts of the x4 decoding, which is what the encoder
+// mode 3 (SMS=011) counts. The modes 1 and 2 count half of them. The console
+// turns them too, with `encoder <timer> ...`.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct EncoderConfig {
+    pub timer: String,
+    pub position: Option<i64>,
+    pub keys: Option<[String; 2]>,
+    pub step: Option<i64>,
+    pub events: Option<Vec<EncoderEventConfig>>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct EncoderEventConfig {
+    pub at: u64,
+    pub position: Option<i64>,
+    pub velocity: Option<f64>,
+}
+
+// Counts per key press, a detent of the usual knobs
+const DEFAULT_STEP: i64 = 4;
+
+pub struct Encoder {
+    pub timer: String,
+    keys: Option<[String; 2]>,
+    step: i64,
+    events: Vec<EncoderEventConfig>,
+    next_event: usize,
+    // The position at the emulated time `since`, moving at `velocity` counts per second
+    position: f64,
+    since: u64,
+    velocity: f64,
+    // The direction of the last move, for the DIR bit
+    pub down: bool,
+}
+
+impl Encoder {
+    pub fn new(config: EncoderConfig) -> Result<Self> {
+        let mut events = config.events.unwrap_or_default();
+        if events.iter().any(|e| e.position.is_none() && e.velocity.is_none()) {
+            bail!("{} encoder: events need a position or a velocity", config.timer);
+        }
+        events.sort_by_key(|e| e.at);
+        Ok(Self {
+            timer: config.timer,
+            keys: config.keys,
+            step: config.step.unwrap_or(DEFAULT_STEP),
+            events,
+            next_event: 0,
+            position: config.position.unwrap_or_default() as f64,
+            since: 0,
+            velocity: 0.0,
+            down: false,
+        })
+    }
+
+    fn position_at(&self, at: u64, hclk: u64) -> f64 {
+        self.position + self.velocity * at.saturating_sub(self.since) as f64 / hclk.max(1) as f64
+    }
+
+    /// Moves the encoder, and sets its velocity, from the emulated time `at`
+    fn set(&mut self, position: Option<f64>, velocity: Option<f64>, at: u64, hclk: u64) {
+        let current = self.position_at(at, hclk);
+        let position = position.unwrap_or(current);
+        if position != current {
+            self.down = position < current;
+        }
+        self.position = position;
+        self.since = at;
+        if let Some(velocity) = velocity {
+            if velocity != 0.0 {
+                self.down = velocity < 0.0;
+            }
+            self.velocity = velocity;
+        }
+        trace!("{} encoder position={:.0} velocity={}", self.timer, self.position, self.velocity);
+    }
+
+    /// The current position, once the scripted events that are due are applied
+    pub fn position(&mut self, hclk: u64) -> i64 {
+        let now = crate::context::now();
+        while let Some(e) = self.events.get(self.next_event).filter(|e| e.at <= now) {
+            let (at, position, velocity) = (e.at, e.position.map(|p| p as f64), e.velocity);
+            self.set(position, velocity, at, hclk);
+            self.next_event += 1;
+        }
+        self.position_at(now, hclk).floor() as i64
+    }
+
+    pub fn set_position(&mut self, position: i64, hclk: u64) {
+        let now = crate::context::now();
+        self.set(Some(position as f64), None, now, hclk);
+    }
+
+    pub fn turn(&mut self, counts: i64, hclk: u64) {
+        let position = self.position(hclk);
+        self.set_position(position + counts, hclk);
+    }
+
+    pub fn set_velocity(&mut self, velocity: f64, hclk: u64) {
+        let now = crate::context::now();
+        self.set(None, Some(velocity), now, hclk);
+    }
+
+    /// Turns the encoder when the key is one of its keys. Returns whether it is.
+    pub fn key_pressed(&mut self, key: &str, hclk: u64) -> bool {
+        let direction = match &self.keys {
+            Some([down, _]) if down.eq_ignore_ascii_case(key) => -1,
+            Some([_, up]) if up.eq_ignore_ascii_case(key) => 1,
+            _ => return false,
+        };
+        self.turn(direction * self.step, hclk);
+        let position = self.position(hclk);
+        debug!("{} encoder position={}", self.timer, position);
+        true
+    }
+}
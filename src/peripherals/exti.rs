@@ -0,0 +1,160 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::HashMap;
+
+use svd_parser::svd::Interrupt;
+
+use crate::system::System;
+use super::{Peripheral, nvic::Nvic};
+
+// Extended interrupt controller, F1/F2/F4/F7/L1/L4/G4 layout. Lines 32 and
+// above are in a second bank of registers at 0x20 (L4/G4).
+
+const NUM_BANKS: usize = 2;
+
+#[derive(Default)]
+struct Bank {
+    imr: u32,
+    emr: u32,
+    rtsr: u32,
+    ftsr: u32,
+    pr: u32,
+}
+
+#[derive(Default)]
+pub struct Exti {
+    banks: [Bank; NUM_BANKS],
+    // line -> irq, from the SVD interrupt names, e.g. EXTI0, EXTI9_5, EXTI15_10.
+    irqs: HashMap<u32, i32>,
+}
+
+impl Exti {
+    pub fn from_svd(interrupts: &[Interrupt]) -> Self {
+        let mut irqs = HashMap::new();
+        for interrupt in interrupts {
+            let lines = match interrupt.name.strip_prefix("EXTI") {
+                Some(lines) => lines,
+                None => continue,
+            };
+            let range = match lines.split_once('_') {
+                // The names are like EXTI9_5, highest line first
+                Some((high, low)) => low.parse::<u32>().ok().zip(high.parse::<u32>().ok()),
+                None => lines.parse::<u32>().ok().map(|line| (line, line)),
+            };
+            if let Some((low, high)) = range {
+                for line in low.min(high)..=low.max(high) {
+                    irqs.insert(line, interrupt.value as i32);
+                }
+            }
+        }
+        Self { irqs, ..Self::default() }
+    }
+
+    /// The line to interrupt mapping comes from the SVD file, and is kept
+    pub fn reset(&mut self) {
+        self.banks = Default::default();
+    }
+
+    fn bank(&mut self, line: u32) -> Option<(&mut Bank, u32)> {
+        self.banks.get_mut(line as usize / 32).map(|bank| (bank, 1 << (line % 32)))
+    }
+
+    /// Signals an edge on a line. The interrupt is raised when the edge
+    /// detection and the interrupt of the line are enabled. Lines without a
+    /// dedicated interrupt in the SVD file use `irq`.
+    pub fn trigger(&mut self, nvic: &mut Nvic, line: u32, rising: bool, irq: Option<i32>) {
+        let irq = self.irqs.get(&line).cloned().or(irq);
+        let (bank, mask) = match self.bank(line) {
+            Some(bank) => bank,
+            None => return,
+        };
+
+        let edges = if rising { bank.rtsr } else { bank.ftsr };
+        if edges & mask == 0 {
+            return;
+        }
+
+        if bank.emr & mask != 0 {
+            trace!("EXTI event line={}", line);
+        }
+
+        if bank.imr & mask != 0 {
+            bank.pr |= mask;
+            match irq {
+                Some(irq) => nvic.set_intr_pending(irq),
+                None => warn!("EXTI line={} has no interrupt, specify the irq", line),
+            }
+        }
+    }
+
+    pub fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        let bank = match self.banks.get((offset / 0x20) as usize) {
+            Some(bank) => bank,
+            None => return 0,
+        };
+        match offset % 0x20 {
+            0x00 => bank.imr,
+            0x04 => bank.emr,
+            0x08 => bank.rtsr,
+            0x0C => bank.ftsr,
+            0x14 => bank.pr,
+            _ => 0
+        }
+    }
+
+    pub fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        let bank_index = (offset / 0x20) as usize;
+        let bank = match self.banks.get_mut(bank_index) {
+            Some(bank) => bank,
+            None => return,
+        };
+        match offset % 0x20 {
+            0x00 => bank.imr = value,
+            0x04 => bank.emr = value,
+            0x08 => bank.rtsr = value,
+            0x0C => bank.ftsr = value,
+            0x10 => {
+                // SWIER. Raises the interrupt of enabled lines, regardless of the edge configuration.
+                let pending = value & bank.imr;
+                bank.pr |= pending;
+                let mut nvic = sys.p.nvic.borrow_mut();
+                for bit in (0..32).filter(|bit| pending & (1 << bit) != 0) {
+                    let line = bank_index as u32 * 32 + bit;
+                    if let Some(irq) = self.irqs.get(&line) {
+                        nvic.set_intr_pending(*irq);
+                    }
+                }
+            }
+            // Write 1 to clear
+            0x14 => bank.pr &= !value,
+            _ => {}
+        }
+    }
+}
+
+pub struct ExtiWrapper;
+
+impl ExtiWrapper {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "EXTI" {
+            Some(Box::new(Self))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for ExtiWrapper {
+    fn has_side_effects(&self, offset: u32) -> bool {
+        // PR is write-1-to-clear
+        offset % 0x20 == 0x14
+    }
+
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        sys.p.exti.borrow_mut().read(sys, offset)
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        sys.p.exti.borrow_mut().write(sys, offset, value)
+    }
+}
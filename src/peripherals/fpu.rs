@@ -0,0 +1,110 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::system::System;
+use super::Peripheral;
+
+// Unicorn executes FP instructions regardless of CPACR, and sets CONTROL.FPCA
+// on the first one of a context. We keep the FP control registers here, and
+// the NVIC uses them to stack the FP state on exceptions.
+//
+// Lazy stacking defers saving S0-S15 and FPSCR until the handler executes its
+// first FP instruction. The registers can't change before that, so we save
+// them eagerly at exception entry, and the stack content is the same. Only
+// FPCCR.LSPACT differs, it always reads 0.
+
+// FPCCR bits
+const ASPEN: u32 = 1 << 31;
+const LSPEN: u32 = 1 << 30;
+// FPDSCR holds the AHP, DN, FZ, and RMode bits
+const FPDSCR_MASK: u32 = 0b1_1111 << 22;
+
+pub struct FpState {
+    pub cpacr: u32,
+    pub fpccr: u32,
+    pub fpcar: u32,
+    pub fpdscr: u32,
+    warned_disabled: bool,
+}
+
+impl Default for FpState {
+    fn default() -> Self {
+        Self { cpacr: 0, fpccr: ASPEN | LSPEN, fpcar: 0, fpdscr: 0, warned_disabled: false }
+    }
+}
+
+impl FpState {
+    /// CP10 and CP11 are the FPU, they are configured identically
+    pub fn is_enabled(&self) -> bool {
+        Self::cp10_access(self.cpacr) != 0
+    }
+
+    fn cp10_access(cpacr: u32) -> u32 {
+        (cpacr >> 20) & 0b11
+    }
+
+    /// FPSCR of a new FP context, when the FP state is preserved automatically
+    pub fn new_context_fpscr(&self) -> Option<u32> {
+        (self.fpccr & ASPEN != 0).then_some(self.fpdscr)
+    }
+
+    /// Returns whether the FP context should be stacked. CONTROL.FPCA is set
+    /// when the context has used the FPU.
+    pub fn should_stack(&mut self, fpca: bool) -> bool {
+        if !fpca {
+            return false;
+        }
+
+        if !self.is_enabled() && !self.warned_disabled {
+            warn!("The firmware uses the FPU, but it is not enabled in CPACR cpacr=0x{:08x}", self.cpacr);
+            self.warned_disabled = true;
+        }
+
+        // Without ASPEN, the firmware saves the FP registers itself
+        self.fpccr & ASPEN != 0
+    }
+}
+
+#[derive(Default)]
+pub struct Fpu {
+    cpacr_only: bool,
+}
+
+impl Fpu {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        match name {
+            "FPU" => Some(Box::new(Self { cpacr_only: false })),
+            "FPU_CPACR" => Some(Box::new(Self { cpacr_only: true })),
+            _ => None,
+        }
+    }
+}
+
+impl Peripheral for Fpu {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        let nvic = sys.p.nvic.borrow();
+        let fpu = &nvic.fpu;
+        match (self.cpacr_only, offset) {
+            (true, 0x0000) => fpu.cpacr,
+            (false, 0x0000) => fpu.fpccr,
+            (false, 0x0004) => fpu.fpcar,
+            (false, 0x0008) => fpu.fpdscr,
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        let mut nvic = sys.p.nvic.borrow_mut();
+        let fpu = &mut nvic.fpu;
+        match (self.cpacr_only, offset) {
+            (true, 0x0000) => {
+                debug!("FPU {}", if FpState::cp10_access(value) != 0 { "enabled" } else { "disabled" });
+                fpu.cpacr = value;
+            }
+            // LSPACT and the other status bits are not writable
+            (false, 0x0000) => fpu.fpccr = value & (ASPEN | LSPEN),
+            (false, 0x0004) => fpu.fpcar = value & !0b111,
+            (false, 0x0008) => fpu.fpdscr = value & FPDSCR_MASK,
+            _ => {}
+        }
+    }
+}
@@ -31,7 +31,7 @@ impl Fsmc {
             0x2000_0000..=0x2fff_ffff => Access::Data(2, offset - 0x2000_0000),
             0x3000_0000..=0x3fff_ffff => Access::Data(3, offset - 0x3000_0000),
             0x4000_0000..=0x4fff_ffff => {
-                match offset - 0x4000_0000 {
+                match (offset - 0x4000_0000) & !3 {
                     0x0000 => Access::Register(0, Reg::BCR),
                     0x0004 => Access::Register(0, Reg::BTR),
                     0x0008 => Access::Register(1, Reg::BCR),
@@ -76,8 +76,27 @@ impl Peripheral for Fsmc {
     }
 
     fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        self.write_sized(sys, offset, 4, value)
+    }
+
+    fn read_sized(&mut self, sys: &System, offset: u32, _size: u8) -> u32 {
+        self.read(sys, offset)
+    }
+
+    fn write_block(&mut self, sys: &System, offset: u32, values: &[u16]) {
         match Self::access(offset) {
-            Access::Data(bank, offset) => self.banks[bank].write_data(sys, offset, value),
+            Access::Data(bank, offset) => self.banks[bank].write_block(sys, offset, values),
+            Access::Register(..) => {
+                for v in values {
+                    self.write_sized(sys, offset, 2, *v as u32);
+                }
+            }
+        }
+    }
+
+    fn write_sized(&mut self, sys: &System, offset: u32, size: u8, value: u32) {
+        match Self::access(offset) {
+            Access::Data(bank, offset) => self.banks[bank].write_data(sys, offset, size, value),
             Access::Register(bank, reg) => self.banks[bank].write_reg(sys, reg, value),
         }
     }
@@ -89,39 +108,78 @@ pub trait FsmcDevice {
     fn write_data(&mut self, bank: &mut Bank, offset: u32, value: u32);
 }
 
+// The NOR/SRAM bank is split in 4 regions of 64MB, selected by NE1-NE4
+const SUB_BANK_SIZE: u32 = 0x0400_0000;
+
+type MemDevice = Rc<RefCell<dyn ExtDevice<u32, u32>>>;
+
 pub struct Bank {
     pub name: String,
-    ext_device: Option<Rc<RefCell<dyn ExtDevice<u32, u32>>>>,
+    ext_device: Option<MemDevice>,
+    /// Devices connected to FSMC.BANK1.NE1-NE4, with the name they log with.
+    /// The offsets they see are relative to their region.
+    sub_devices: [Option<(String, MemDevice)>; 4],
 }
 
 impl Bank {
     pub fn new(bank: usize, ext_devices: &ExtDevices) -> Self {
         let name = format!("FSMC.BANK{}", bank+1);
 
+        let sub_devices = std::array::from_fn(|i| {
+            if bank != 0 {
+                return None;
+            }
+            let name = format!("{}.NE{}", name, i+1);
+            ext_devices.find_mem_device(&name).map(|d| {
+                let name = d.borrow_mut().connect_peripheral(&name);
+                (name, d)
+            })
+        });
+
         let ext_device = ext_devices.find_mem_device(&name);
         let name = ext_device.as_ref()
             .map(|d| d.borrow_mut().connect_peripheral(&name))
             .unwrap_or(name);
 
-        Self { name, ext_device }
+        Self { name, ext_device, sub_devices }
+    }
+
+    /// The device at this offset, with its name and the offset in its region
+    fn device_at(&self, offset: u32) -> (&str, Option<&MemDevice>, u32) {
+        let i = (offset / SUB_BANK_SIZE) as usize;
+        match self.sub_devices.get(i) {
+            Some(Some((name, d))) => (name, Some(d), offset % SUB_BANK_SIZE),
+            _ => (&self.name, self.ext_device.as_ref(), offset),
+        }
     }
 
     fn read_data(&mut self, sys: &System, offset: u32) -> u32 {
-        let v = self.ext_device.as_ref().map(|d|
+        let (name, device, offset) = self.device_at(offset);
+        let v = device.map(|d|
             d.borrow_mut().read(sys, offset)
         ).unwrap_or_default();
 
-        trace!("{} data read at offset=0x{:08x} value=0x{:08x}", self.name, offset, v);
+        trace!("{} data read at offset=0x{:08x} value=0x{:08x}", name, offset, v);
 
         v
     }
 
-    fn write_data(&mut self, sys: &System, offset: u32, value: u32) {
-        self.ext_device.as_ref().map(|d|
-            d.borrow_mut().write(sys, offset, value)
-        );
+    fn write_data(&mut self, sys: &System, offset: u32, size: u8, value: u32) {
+        let (name, device, offset) = self.device_at(offset);
+        if let Some(d) = device {
+            d.borrow_mut().write_sized(sys, offset, value, size);
+        }
+
+        trace!("{} data write at offset=0x{:08x} size={} value=0x{:08x}", name, offset, size, value);
+    }
+
+    fn write_block(&mut self, sys: &System, offset: u32, values: &[u16]) {
+        let (name, device, offset) = self.device_at(offset);
+        if let Some(d) = device {
+            d.borrow_mut().write_block(sys, offset, values);
+        }
 
-        trace!("{} data write at offset=0x{:08x} value=0x{:08x}", self.name, offset, value);
+        trace!("{} data block write at offset=0x{:08x} len={}", name, offset, values.len());
     }
 
     fn read_reg(&mut self, _sys: &System, reg: Reg) -> u32 {
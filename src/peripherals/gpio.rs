@@ -1,9 +1,36 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use crate::system::System;
-use super::Peripheral;
+
+use crate::{system::System, family::Layout};
+use super::{Peripheral, size_mask};
 
 use regex::Regex;
+use serde::Deserialize;
+
+// Input levels of the pins that no device drives, like board ID straps and
+// mode jumpers. Levels can be static, or follow events:
+//
+//  gpio:
+//    - pin: PB2
+//      level: true         # initial level
+//      events:             # level changes, at a given instruction count
+//        - at: 1000000
+//          level: false
+//      period: 2000000     # the events repeat with this period
+
+#[derive(Debug, Deserialize, Default)]
+pub struct GpioInputConfig {
+    pub pin: String,
+    pub level: Option<bool>,
+    pub events: Option<Vec<GpioInputEventConfig>>,
+    pub period: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct GpioInputEventConfig {
+    pub at: u64,
+    pub level: bool,
+}
 
 const NUM_PORTS: usize = 11;
 
@@ -15,21 +42,97 @@ pub struct Pin {
 
 impl Pin {
     pub fn from_str(name: &str) -> Self {
+        Self::parse(name).expect("Pin name invalid")
+    }
+
+    /// Parses a pin name like PB5. Returns None when it's not a valid pin.
+    pub fn parse(name: &str) -> Option<Self> {
         let name = name.to_uppercase();
-        let re = Regex::new(r"^P?([A-Z])(\d+)$").unwrap();
-        let captures = re.captures(&name).expect("Pin name invalid");
+        let re = Regex::new(r"^P?([A-K])(\d+)$").unwrap();
+        let captures = re.captures(&name)?;
         let port = captures.get(1).unwrap().as_str().chars().next().unwrap();
         let port = GpioPorts::port_index(port);
-        let pin = captures.get(2).unwrap().as_str().parse().unwrap();
-        assert!(pin < 16);
-        Self { port, pin }
+        let pin = captures.get(2).unwrap().as_str().parse().ok().filter(|pin| *pin < 16)?;
+        Some(Self { port, pin })
+    }
+}
+
+/// How a device drives a pin
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Drive {
+    // Drives both levels. The line follows the device.
+    PushPull,
+    // Pulls the line low, or releases it. The line has an external pull-up,
+    // and is shared with the other drivers, like on I2C or 1-Wire buses.
+    OpenDrain,
+}
+
+type ReadCallback = Box<dyn FnMut(&System) -> bool>;
+
+/// Electrical configuration of a port, set by the firmware in the GPIO registers
+#[derive(Default, Clone, Copy)]
+struct PortConfig {
+    // Output pins, in push-pull and open-drain mode
+    push_pull: u16,
+    open_drain: u16,
+    // Pins with the internal pull-up enabled
+    pull_up: u16,
+    // Output data register
+    odr: u16,
+}
+
+/// Time spent high by a GPIO pin since the last measure
+#[derive(Default)]
+pub struct PinDuty {
+    level: bool,
+    last_change: u64,
+    window_start: u64,
+    high_time: u64,
+    toggled: bool,
+}
+
+impl PinDuty {
+    pub fn set_level(&mut self, level: bool) {
+        if level == self.level {
+            return;
+        }
+        let now = crate::context::now();
+        if self.level {
+            self.high_time += now - self.last_change;
+        }
+        self.level = level;
+        self.last_change = now;
+        self.toggled = true;
+    }
+
+    /// The duty cycle since the last call, or the level when the pin didn't move
+    pub fn measure(&mut self) -> f32 {
+        let now = crate::context::now();
+        let elapsed = now - self.window_start;
+        if self.level {
+            self.high_time += now - self.last_change;
+        }
+
+        let duty = if self.toggled && elapsed > 0 {
+            self.high_time as f32 / elapsed as f32
+        } else if self.level {
+            1.0
+        } else {
+            0.0
+        };
+
+        *self = Self { level: self.level, last_change: now, window_start: now, ..Self::default() };
+        duty
     }
 }
 
 #[derive(Default)]
 pub struct GpioPorts {
-    read_callbacks: [Vec<(u8, Box<dyn FnMut(&System) -> bool>)>; NUM_PORTS],
+    read_callbacks: [Vec<(u8, Drive, ReadCallback)>; NUM_PORTS],
     write_callbacks: [Vec<(u8, Box<dyn FnMut(&System, bool)>)>; NUM_PORTS],
+    // Input levels forced from the console, they take precedence: (mask, levels)
+    forced_inputs: [(u16, u16); NUM_PORTS],
+    configs: [PortConfig; NUM_PORTS],
 }
 
 impl GpioPorts {
@@ -40,25 +143,121 @@ impl GpioPorts {
         }
     }
 
+    /// The device drives the pin level
     pub fn add_read_callback(&mut self, pin: Pin, cb: impl FnMut(&System) -> bool + 'static) {
-        self.read_callbacks[pin.port as usize].push((pin.pin, Box::new(cb)));
+        self.read_callbacks[pin.port as usize].push((pin.pin, Drive::PushPull, Box::new(cb)));
+    }
+
+    /// The device pulls the line low when the callback returns false, and
+    /// releases it otherwise. The line is pulled up externally.
+    pub fn add_open_drain_read_callback(&mut self, pin: Pin, cb: impl FnMut(&System) -> bool + 'static) {
+        self.read_callbacks[pin.port as usize].push((pin.pin, Drive::OpenDrain, Box::new(cb)));
     }
 
     pub fn add_write_callback(&mut self, pin: Pin, cb: impl FnMut(&System, bool) + 'static) {
         self.write_callbacks[pin.port as usize].push((pin.pin, Box::new(cb)));
     }
 
+    /// Resolves the line levels of a port. Push-pull devices set the level of
+    /// their pins, then push-pull outputs read back their ODR value. The other
+    /// lines are wired-AND: low when a device or an open-drain output pulls
+    /// them low, high with a pull-up, and low when floating or pulled down.
     pub fn read_port(&mut self, sys: &System, port: u8) -> u16 {
-        let mut v = 0;
-        for (pin, cb) in &mut self.read_callbacks[port as usize] {
-            if cb(sys) {
-                v |= 1 << *pin;
+        let config = self.configs[port as usize];
+        let mut driven = 0;
+        let mut levels = 0;
+        let mut pulled_low = config.open_drain & !config.odr;
+        let mut pull_up = config.pull_up;
+
+        for (pin, drive, cb) in &mut self.read_callbacks[port as usize] {
+            let v = cb(sys);
+            match drive {
+                Drive::PushPull => {
+                    driven |= 1 << *pin;
+                    if v {
+                        levels |= 1 << *pin;
+                    }
+                }
+                Drive::OpenDrain => {
+                    pull_up |= 1 << *pin;
+                    if !v {
+                        pulled_low |= 1 << *pin;
+                    }
+                }
             }
         }
-        v
+
+        let wired = pull_up & !pulled_low;
+        let outputs = config.push_pull & !driven;
+        driven |= outputs;
+        let v = (levels & !outputs) | (config.odr & outputs) | (wired & !driven);
+        let (mask, levels) = self.forced_inputs[port as usize];
+        (v & !mask) | (levels & mask)
+    }
+
+    /// Called by the GPIO peripheral when the port configuration changes
+    pub fn configure_port(&mut self, port: u8, push_pull: u16, open_drain: u16, pull_up: u16) {
+        let config = &mut self.configs[port as usize];
+        config.push_pull = push_pull;
+        config.open_drain = open_drain;
+        config.pull_up = pull_up;
+    }
+
+    /// The port configurations go back to their reset state. The devices and
+    /// the forced inputs stay.
+    pub fn reset(&mut self) {
+        self.configs = Default::default();
+    }
+
+    /// Drives the pin from the config, through a read callback
+    pub fn add_input(&mut self, config: GpioInputConfig) {
+        let pin = Pin::from_str(&config.pin);
+        let level = config.level.unwrap_or_default();
+        let mut events = config.events.unwrap_or_default();
+        events.sort_by_key(|e| e.at);
+        let period = config.period.filter(|p| *p > 0);
+
+        debug!("GPIO input pin={} level={} num_events={}", config.pin.to_uppercase(), level as u8, events.len());
+
+        self.add_read_callback(pin, move |_sys| {
+            let now = crate::context::now();
+            let now = period.map(|p| now % p).unwrap_or(now);
+            events.iter()
+                .take_while(|e| e.at <= now)
+                .last()
+                .map(|e| e.level)
+                .unwrap_or(level)
+        });
+    }
+
+    pub fn force_input(&mut self, pin: Pin, value: bool) {
+        let (mask, levels) = &mut self.forced_inputs[pin.port as usize];
+        *mask |= 1 << pin.pin;
+        if value {
+            *levels |= 1 << pin.pin;
+        } else {
+            *levels &= !(1 << pin.pin);
+        }
+    }
+
+    /// Gives the pin back to its read callbacks
+    pub fn release_input(&mut self, pin: Pin) {
+        self.forced_inputs[pin.port as usize].0 &= !(1 << pin.pin);
+    }
+
+    /// Level of a pin, as seen by the firmware in IDR
+    pub fn read_pin(&mut self, sys: &System, pin: Pin) -> bool {
+        self.read_port(sys, pin.port) & (1 << pin.pin) != 0
     }
 
     pub fn write_port(&mut self, sys: &System, port: u8, pin: u8, value: bool) {
+        let odr = &mut self.configs[port as usize].odr;
+        if value {
+            *odr |= 1 << pin;
+        } else {
+            *odr &= !(1 << pin);
+        }
+
         for (pin_cb, cb) in &mut self.write_callbacks[port as usize] {
             if *pin_cb == pin {
                 cb(sys, value);
@@ -67,11 +266,16 @@ impl GpioPorts {
     }
 }
 
+// The F1 ports are configured with CRL/CRH, followed by IDR, ODR, BSRR, BRR,
+// and LCKR. The other families use MODER, OTYPER, etc.
 #[derive(Default)]
 pub struct Gpio {
     port_letter: char,
     port: u8,
+    layout: Layout,
 
+    // CRL, CRH on the F1
+    cr: [u32; 2],
     mode: u32,
     otype: u32,
     ospeed: u32,
@@ -83,11 +287,13 @@ pub struct Gpio {
 }
 
 impl Gpio {
-    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+    pub fn new(name: &str, layout: Layout) -> Option<Box<dyn Peripheral>> {
         if let Some(block) = name.strip_prefix("GPIO") {
             let port_letter = block.chars().next().unwrap();
             let port = GpioPorts::port_index(port_letter);
-            Some(Box::new(Self { port_letter, port, ..Self::default() }))
+            // All pins are floating inputs after reset on the F1
+            let cr = [0x4444_4444; 2];
+            Some(Box::new(Self { port_letter, port, layout, cr, ..Self::default() }))
         } else {
             None
         }
@@ -111,25 +317,139 @@ impl Gpio {
     fn port_str(&self, pin: u8) -> String {
         format!("GPIO{} P{}{}", self.port_letter, self.port_letter, pin)
     }
+
+    /// Tells GpioPorts which pins are outputs, and which are pulled up
+    fn update_port_config(&self, sys: &System) {
+        let mut push_pull = 0;
+        let mut open_drain = 0;
+        let mut pull_up = 0;
+        for pin in 0..16 {
+            let (is_output, is_open_drain, is_pull_up) = if self.layout == Layout::F1 {
+                let v = (self.cr[pin / 8] >> (4 * (pin % 8))) & 0xF;
+                let (mode, cnf) = (v & 0b11, v >> 2);
+                // Inputs with pull are pulled up or down depending on ODR
+                (mode != 0 && cnf & 0b10 == 0, cnf == 0b01, mode == 0 && cnf == 0b10 && self.od & (1 << pin) != 0)
+            } else {
+                let mode = (self.mode >> (2 * pin)) & 0b11;
+                (mode == 0b01, self.otype & (1 << pin) != 0, (self.pupd >> (2 * pin)) & 0b11 == 0b01)
+            };
+            push_pull |= ((is_output && !is_open_drain) as u16) << pin;
+            open_drain |= ((is_output && is_open_drain) as u16) << pin;
+            pull_up |= (is_pull_up as u16) << pin;
+        }
+        sys.p.gpio.borrow_mut().configure_port(self.port, push_pull, open_drain, pull_up);
+    }
+
+    fn read_idr(&self, sys: &System) -> u32 {
+        let v = sys.p.gpio.borrow_mut().read_port(sys, self.port);
+        trace!("GPIO{} read v=0x{:04x}", self.port_letter, v);
+        v as u32
+    }
+
+    fn write_odr(&mut self, sys: &System, value: u32) {
+        let mut gpio = sys.p.gpio.borrow_mut();
+        Self::iter_port_reg_changes(self.od, value, 1, |pin, v| {
+            gpio.write_port(sys, self.port, pin, v != 0);
+            trace!("{} output={}", self.port_str(pin), v);
+        });
+        self.od = value;
+    }
+
+    fn write_bsrr(&mut self, sys: &System, value: u32) {
+        let reset = value >> 16;
+        let set = value & 0xFFFF;
+        let mut gpio = sys.p.gpio.borrow_mut();
+
+        Self::iter_port_reg_changes(0, set, 1, |pin, _| {
+            gpio.write_port(sys, self.port, pin, true);
+            trace!("{} output=1", self.port_str(pin));
+        });
+
+        Self::iter_port_reg_changes(0, reset & !set, 1, |pin, _| {
+            gpio.write_port(sys, self.port, pin, false);
+            trace!("{} output=0", self.port_str(pin));
+        });
+
+        self.od &= !reset;
+        self.od |= set;
+    }
+
+    /// CRL/CRH on the F1, 4 bits per pin: CNF[1:0] MODE[1:0]
+    fn write_cr(&mut self, index: usize, value: u32) {
+        Self::iter_port_reg_changes(self.cr[index], value, 4, |pin, v| {
+            let (mode, cnf) = (v & 0b11, v >> 2);
+            let config = match (mode, cnf) {
+                (0, 0b00) => "analog",
+                (0, 0b01) => "input",
+                (0, 0b10) => "input-pull",
+                (0, _) => "reserved",
+                (_, 0b00) => "output",
+                (_, 0b01) => "output-open-drain",
+                (_, 0b10) => "alternate",
+                (_, _) => "alternate-open-drain",
+            };
+            trace!("{} mode={}", self.port_str(pin + 8*index as u8), config);
+        });
+        self.cr[index] = value;
+    }
+
+    fn read_f1(&mut self, sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x0000 => self.cr[0],
+            0x0004 => self.cr[1],
+            0x0008 => self.read_idr(sys),
+            0x000C => self.od,
+            0x0010 | 0x0014 => 0, // bsrr, brr
+            0x0018 => self.lck,
+            _ => {
+                warn!("GPIO invalid offset=0x{:08x}", offset);
+                0
+            }
+        }
+    }
+
+    fn write_f1(&mut self, sys: &System, offset: u32, value: u32) {
+        match offset {
+            0x0000 => self.write_cr(0, value),
+            0x0004 => self.write_cr(1, value),
+            0x0008 => {
+                // input data register. read-only
+            }
+            0x000C => self.write_odr(sys, value),
+            0x0010 => self.write_bsrr(sys, value),
+            0x0014 => self.write_bsrr(sys, (value & 0xFFFF) << 16),
+            0x0018 => {
+                trace!("GPIO{} port locked", self.port_letter);
+                self.lck = value;
+            }
+            _ => {
+                warn!("GPIO invalid offset=0x{:08x}", offset);
+            }
+        }
+        // The pull direction of the inputs comes from ODR
+        self.update_port_config(sys);
+    }
 }
 
 impl Peripheral for Gpio {
     fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        if self.layout == Layout::F1 {
+            return self.read_f1(sys, offset);
+        }
+
         match offset {
             0x0000 => self.mode,
             0x0004 => self.otype,
             0x0008 => self.ospeed,
             0x000C => self.pupd,
-            0x0010 => {
-                let v = sys.p.gpio.borrow_mut().read_port(sys, self.port);
-                trace!("GPIO{} read v=0x{:04x}", self.port_letter, v);
-                v as u32
-            }
+            0x0010 => self.read_idr(sys),
             0x0014 => self.od,
             0x0018 => 0, // bsr
             0x001C => self.lck,
             0x0020 => self.afrl,
             0x0024 => self.afrh,
+            // brr, on the F0/F3/G0/G4/L4
+            0x0028 if self.layout == Layout::F7 => 0,
             _ => {
                 warn!("GPIO invalid offset=0x{:08x}", offset);
                 0
@@ -138,6 +458,10 @@ impl Peripheral for Gpio {
     }
 
     fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        if self.layout == Layout::F1 {
+            return self.write_f1(sys, offset, value);
+        }
+
         match offset {
             0x0000 => {
                 Self::iter_port_reg_changes(self.mode, value, 2, |pin, v| {
@@ -192,32 +516,8 @@ impl Peripheral for Gpio {
             0x0010 => {
                 // input data register. read-only
             }
-            0x0014 => {
-                let mut gpio = sys.p.gpio.borrow_mut();
-                Self::iter_port_reg_changes(self.od, value, 1, |pin, v| {
-                    gpio.write_port(sys, self.port, pin, v != 0);
-                    trace!("{} output={}", self.port_str(pin), v);
-                });
-                self.od = value;
-            }
-            0x0018 => {
-                let reset = value >> 16;
-                let set = value & 0xFFFF;
-                let mut gpio = sys.p.gpio.borrow_mut();
-
-                Self::iter_port_reg_changes(0, set, 1, |pin, _| {
-                    gpio.write_port(sys, self.port, pin, true);
-                    trace!("{} output=1", self.port_str(pin));
-                });
-
-                Self::iter_port_reg_changes(0, reset, 1, |pin, _| {
-                    gpio.write_port(sys, self.port, pin, false);
-                    trace!("{} output=0", self.port_str(pin));
-                });
-
-                self.od &= !reset;
-                self.od |= set;
-            }
+            0x0014 => self.write_odr(sys, value),
+            0x0018 => self.write_bsrr(sys, value),
             0x001C => {
                 trace!("GPIO{} port locked", self.port_letter);
                 self.lck = value;
@@ -234,9 +534,30 @@ impl Peripheral for Gpio {
                 });
                 self.afrh = value;
             }
+            0x0028 if self.layout == Layout::F7 => self.write_bsrr(sys, (value & 0xFFFF) << 16),
             _ => {
                 warn!("GPIO invalid offset=0x{:08x}", offset);
             }
         }
+        // moder, otyper, pupdr
+        if matches!(offset, 0x0000 | 0x0004 | 0x000C) {
+            self.update_port_config(sys);
+        }
+    }
+
+    /// Byte and halfword writes, like `strb` to ODR or to the reset half of
+    /// BSRR. BSRR and BRR only act on the bits of the written bytes. The other
+    /// registers, ODR included, keep the pins of the other bytes.
+    fn write_sized(&mut self, sys: &System, offset: u32, size: u8, value: u32) {
+        let byte_offset = offset % 4;
+        let reg = offset - byte_offset;
+        let mask = size_mask(size) << (8*byte_offset);
+        let value = (value << (8*byte_offset)) & mask;
+        let is_bsrr = match self.layout {
+            Layout::F1 => matches!(reg, 0x0010 | 0x0014),
+            _ => reg == 0x0018 || (reg == 0x0028 && self.layout == Layout::F7),
+        };
+        let others = if is_bsrr { 0 } else { self.read(sys, reg) & !mask };
+        self.write(sys, reg, others | value)
     }
 }
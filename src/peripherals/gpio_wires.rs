@@ -0,0 +1,176 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{cell::RefCell, collections::HashMap, net::UdpSocket, os::unix::net::UnixDatagram, rc::Rc, sync::mpsc::{Sender, Receiver}};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+
+use crate::ext_devices::background::Background;
+use super::gpio::{GpioPorts, Pin};
+
+// Virtual wires between the GPIO pins of two emulator instances, like the
+// handshake lines between two MCUs of a board. Pin changes are published to
+// the peer, and the levels of the remote pins are received:
+//
+//  gpio_wires:
+//    listen: 127.0.0.1:7000        # or a unix socket path, like /tmp/mcu-a.sock
+//    peer: 127.0.0.1:7001
+//    pins:
+//      - pin: PA8
+//        wire: ready               # defaults to the pin name
+//        direction: out            # publishes what the firmware writes
+//      - pin: PA9
+//        wire: irq
+//        direction: in             # follows the remote pin
+//        level: true               # until the first change arrives
+//
+// The other instance has the same wires, with the opposite directions. A
+// change is a datagram `wire=0` or `wire=1`.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct GpioWiresConfig {
+    pub listen: String,
+    pub peer: String,
+    pub pins: Vec<GpioWireConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WireDirection {
+    In,
+    Out,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GpioWireConfig {
+    pub pin: String,
+    pub wire: Option<String>,
+    pub direction: WireDirection,
+    pub level: Option<bool>,
+}
+
+enum Socket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+impl Socket {
+    fn bind(addr: &str) -> Result<Self> {
+        let socket = if addr.contains('/') {
+            // A stale socket file from a previous run would fail the bind
+            let _ = std::fs::remove_file(addr);
+            Socket::Unix(UnixDatagram::bind(addr)?)
+        } else {
+            Socket::Udp(UdpSocket::bind(addr)?)
+        };
+        Ok(socket)
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(match self {
+            Socket::Udp(s) => Socket::Udp(s.try_clone()?),
+            Socket::Unix(s) => Socket::Unix(s.try_clone()?),
+        })
+    }
+
+    fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Socket::Udp(s) => s.recv(buf),
+            Socket::Unix(s) => s.recv(buf),
+        }
+    }
+
+    fn send_to(&self, buf: &[u8], peer: &str) -> std::io::Result<usize> {
+        match self {
+            Socket::Udp(s) => s.send_to(buf, peer),
+            Socket::Unix(s) => s.send_to(buf, peer),
+        }
+    }
+}
+
+struct GpioWires {
+    socket: Background<(String, bool), (String, bool)>,
+    // Levels of the remote pins
+    levels: HashMap<String, bool>,
+}
+
+impl GpioWires {
+    fn poll(&mut self) {
+        while let Some((wire, level)) = self.socket.try_recv() {
+            trace!("GPIO wire={} remote level={}", wire, level as u8);
+            self.levels.insert(wire, level);
+        }
+    }
+
+    /// The I/O happens on background threads. Changes from the peer are
+    /// received, and our changes are sent.
+    fn serve(socket: Socket, peer: String, rx: Receiver<(String, bool)>, tx: Sender<(String, bool)>) {
+        if let Ok(reader) = socket.try_clone() {
+            std::thread::spawn(move || {
+                let mut buf = [0; 256];
+                while let Ok(n) = reader.recv(&mut buf) {
+                    let msg = String::from_utf8_lossy(&buf[..n]);
+                    let change = match msg.trim().rsplit_once('=') {
+                        Some((wire, "0")) => (wire.to_string(), false),
+                        Some((wire, "1")) => (wire.to_string(), true),
+                        _ => {
+                            warn!("GPIO wires invalid message={:?}", msg);
+                            continue;
+                        }
+                    };
+                    if tx.send(change).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+
+        while let Ok((wire, level)) = rx.recv() {
+            let msg = format!("{}={}", wire, level as u8);
+            // The peer may not be running yet, the change is lost then
+            if let Err(e) = socket.send_to(msg.as_bytes(), &peer) {
+                debug!("GPIO wires failed to send to peer={}: {}", peer, e);
+            }
+        }
+    }
+}
+
+pub fn register(config: GpioWiresConfig, gpio: &mut GpioPorts) -> Result<()> {
+    let socket = Socket::bind(&config.listen)
+        .with_context(|| format!("Failed to bind GPIO wires to {}", config.listen))?;
+    info!("GPIO wires listening on {} peer={}", config.listen, config.peer);
+
+    let peer = config.peer.clone();
+    let socket = Background::spawn("gpio-wires", move |rx, tx| GpioWires::serve(socket, peer, rx, tx))?;
+    let wires = Rc::new(RefCell::new(GpioWires { socket, levels: HashMap::new() }));
+
+    for c in config.pins {
+        let pin = Pin::parse(&c.pin).with_context(|| format!("Invalid GPIO wire pin {}", c.pin))?;
+        let wire = c.wire.unwrap_or_else(|| c.pin.to_uppercase());
+        if wire.contains('=') {
+            bail!("GPIO wire names can't contain '=': {}", wire);
+        }
+        debug!("GPIO wire pin={} wire={} direction={:?}", c.pin.to_uppercase(), wire, c.direction);
+
+        let wires = wires.clone();
+        match c.direction {
+            WireDirection::In => {
+                let level = c.level.unwrap_or_default();
+                gpio.add_read_callback(pin, move |_sys| {
+                    let mut wires = wires.borrow_mut();
+                    wires.poll();
+                    wires.levels.get(&wire).copied().unwrap_or(level)
+                });
+            }
+            WireDirection::Out => {
+                let mut last = None;
+                gpio.add_write_callback(pin, move |_sys, level| {
+                    if last.replace(level) != Some(level) {
+                        wires.borrow().socket.send((wire.clone(), level));
+                    }
+                });
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,138 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use crate::crypto::{self, HashAlgo};
+use crate::system::System;
+use super::Peripheral;
+
+// CR register bits
+const CR_INIT: u32 = 1 << 2;
+const CR_DATATYPE_SHIFT: u32 = 4;
+const CR_MODE: u32 = 1 << 6;
+const CR_ALGO0: u32 = 1 << 7;
+const CR_ALGO1: u32 = 1 << 18;
+
+// STR register bits
+const STR_NBLW_MASK: u32 = 0x1F;
+const STR_DCAL: u32 = 1 << 8;
+
+// SR register bits
+const SR_DINIS: u32 = 1 << 0;
+const SR_DCIS: u32 = 1 << 1;
+
+/// Hash processor, F2/F4/F7 layout. The message is accumulated as it's
+/// written to DIN, and the digest is computed in one go when DCAL is set.
+/// There's no busy time. MD5 and HMAC are not supported.
+#[derive(Default)]
+pub struct Hash {
+    cr: u32,
+    str: u32,
+    imr: u32,
+    sr: u32,
+    // Big-endian words, after the DATATYPE swap
+    data: Vec<u32>,
+    digest: Vec<u8>,
+}
+
+impl Hash {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "HASH" {
+            Some(Box::new(Self { sr: SR_DINIS, ..Self::default() }))
+        } else {
+            None
+        }
+    }
+
+    fn algo(&self) -> Option<HashAlgo> {
+        match (self.cr & CR_ALGO1 != 0, self.cr & CR_ALGO0 != 0) {
+            (false, false) => Some(HashAlgo::Sha1),
+            (true, false) => Some(HashAlgo::Sha224),
+            (true, true) => Some(HashAlgo::Sha256),
+            (false, true) => None,
+        }
+    }
+
+    fn compute_digest(&mut self) {
+        let algo = match self.algo() {
+            Some(algo) => algo,
+            None => {
+                warn!("HASH MD5 is not supported");
+                return;
+            }
+        };
+        if self.cr & CR_MODE != 0 {
+            warn!("HASH HMAC mode is not supported");
+        }
+
+        let mut message = self.data.iter()
+            .flat_map(|w| w.to_be_bytes())
+            .collect::<Vec<_>>();
+
+        // NBLW is the number of valid bits of the last word, 0 meaning all of them
+        let nblw = self.str & STR_NBLW_MASK;
+        if nblw != 0 && !message.is_empty() {
+            message.truncate(message.len() - 4 + (nblw as usize).div_ceil(8));
+        }
+
+        self.digest = crypto::hash(algo, &message);
+        debug!("HASH algo={:?} len={} digest={}", algo, message.len(),
+            self.digest.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+
+        self.data.clear();
+        self.sr |= SR_DCIS | SR_DINIS;
+    }
+
+    fn digest_word(&self, index: u32) -> u32 {
+        let i = index as usize * 4;
+        self.digest.get(i..i+4)
+            .map(|w| u32::from_be_bytes(w.try_into().unwrap()))
+            .unwrap_or_default()
+    }
+}
+
+impl Peripheral for Hash {
+    fn has_side_effects(&self, offset: u32) -> bool {
+        // DIN
+        offset == 0x0004
+    }
+
+    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x0000 => self.cr & !CR_INIT,
+            0x0008 => self.str & !STR_DCAL,
+            // HR0-HR4
+            0x000C..=0x001C => self.digest_word((offset - 0x000C) / 4),
+            0x0020 => self.imr,
+            0x0024 => self.sr,
+            // HR0-HR7, for SHA-224/256
+            0x0310..=0x032C => self.digest_word((offset - 0x0310) / 4),
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, _sys: &System, offset: u32, value: u32) {
+        match offset {
+            0x0000 => {
+                self.cr = value;
+                if value & CR_INIT != 0 {
+                    self.data.clear();
+                    self.digest.clear();
+                    self.sr &= !SR_DCIS;
+                }
+            }
+            0x0004 => {
+                let datatype = self.cr >> CR_DATATYPE_SHIFT;
+                self.data.push(crypto::swap_datatype(value, datatype));
+            }
+            0x0008 => {
+                self.str = value;
+                if value & STR_DCAL != 0 {
+                    self.compute_digest();
+                }
+            }
+            0x0020 => self.imr = value,
+            // Write 0 to clear
+            0x0024 => self.sr &= value | !(SR_DINIS | SR_DCIS),
+            _ => {}
+        }
+    }
+}
@@ -1,27 +1,93 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use crate::system::System;
+use crate::{system::System, family::Layout};
 use super::Peripheral;
 
+// ISR register bits, F7 layout
+const ISR_TXE: u32 = 1 << 0;
+const ISR_TXIS: u32 = 1 << 1;
+const ISR_RXNE: u32 = 1 << 2;
+const ISR_STOPF: u32 = 1 << 5;
+const ISR_TC: u32 = 1 << 6;
+const ISR_TCR: u32 = 1 << 7;
+
+// No devices are connected to the I2C peripherals. The status flags are
+// faked so that the firmware goes through its transfers, which are logged.
+// On the F4 layout (SR1/SR2), the flags are toggled. On the F7 layout
+// (ISR/TXDR/RXDR), the peripheral is always ready and never busy.
 #[derive(Default)]
 pub struct I2c {
     name: String,
+    layout: Layout,
     toggle: u8,
+    cr1: u32,
+    cr2: u32,
+    timingr: u32,
 }
 
 impl I2c {
-    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+    pub fn new(name: &str, layout: Layout) -> Option<Box<dyn Peripheral>> {
         if name.starts_with("I2C") {
             let name = name.to_string();
-            Some(Box::new(Self { name, ..I2c::default() }))
+            Some(Box::new(Self { name, layout, ..I2c::default() }))
         } else {
             None
         }
     }
+
+    fn has_isr(&self) -> bool {
+        matches!(self.layout, Layout::F7 | Layout::H7)
+    }
+
+    fn read_isr_layout(&mut self, offset: u32) -> u32 {
+        match offset {
+            0x0000 => self.cr1,
+            0x0004 => self.cr2,
+            0x0010 => self.timingr,
+            // ISR
+            0x0018 => ISR_TXE | ISR_TXIS | ISR_RXNE | ISR_STOPF | ISR_TC | ISR_TCR,
+            0x0024 => {
+                // RXDR
+                debug!("{} READ", self.name);
+                0
+            }
+            _ => 0
+        }
+    }
+
+    fn write_isr_layout(&mut self, offset: u32, value: u32) {
+        match offset {
+            0x0000 => self.cr1 = value,
+            0x0004 => {
+                // START and STOP are cleared by the hardware
+                self.cr2 = value & !((1 << 13) | (1 << 14));
+            }
+            0x0010 => self.timingr = value,
+            0x0028 => {
+                // TXDR
+                debug!("{} WRITE value=0x{:08x}", self.name, value);
+            }
+            _ => {}
+        }
+    }
 }
 
 impl Peripheral for I2c {
+    fn has_side_effects(&self, offset: u32) -> bool {
+        if self.has_isr() {
+            // RXDR, TXDR
+            matches!(offset, 0x0024 | 0x0028)
+        } else {
+            // DR, SR1, SR2
+            matches!(offset, 0x0010..=0x0018)
+        }
+    }
+
     fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        if self.has_isr() {
+            return self.read_isr_layout(offset);
+        }
+
         match offset {
             0x0010 => {
                 // DR
@@ -43,6 +109,10 @@ impl Peripheral for I2c {
     }
 
     fn write(&mut self, _sys: &System, offset: u32, value: u32) {
+        if self.has_isr() {
+            return self.write_isr_layout(offset, value);
+        }
+
         match offset {
             0x0010 => {
                 debug!("{} WRITE value=0x{:08x}", self.name, value);
@@ -5,12 +5,29 @@ pub mod spi;
 pub mod usart;
 pub mod systick;
 pub mod gpio;
+pub mod gpio_wires;
 pub mod dma;
 pub mod fsmc;
 pub mod i2c;
 pub mod nvic;
 pub mod scb;
 pub mod sw_spi;
+pub mod sw_i2c;
+pub mod sw_uart;
+pub mod onewire;
+pub mod backup;
+pub mod mpu;
+pub mod pwr;
+pub mod fpu;
+pub mod syscfg;
+pub mod scripted;
+pub mod dac;
+pub mod exti;
+pub mod comp;
+pub mod hash;
+pub mod cryp;
+pub mod tim;
+pub mod encoder;
 
 use rcc::*;
 use serde::Deserialize;
@@ -18,35 +35,136 @@ use spi::*;
 use usart::*;
 use systick::*;
 use gpio::*;
+use gpio_wires::*;
 use dma::*;
 use fsmc::*;
 use i2c::*;
 use nvic::*;
 use scb::*;
 use sw_spi::*;
-
-use std::{collections::{BTreeMap, VecDeque, HashMap}, cell::RefCell};
-use svd_parser::svd::{RegisterInfo, Device as SvdDevice};
-
-use crate::{system::System, ext_devices::ExtDevices};
+use sw_i2c::*;
+use sw_uart::*;
+use onewire::*;
+use backup::*;
+use mpu::*;
+use pwr::*;
+use fpu::*;
+use syscfg::*;
+use scripted::*;
+use dac::*;
+use exti::*;
+use comp::*;
+use hash::*;
+use cryp::*;
+use tim::*;
+use encoder::*;
+
+use std::{collections::{BTreeMap, VecDeque, HashMap, HashSet}, cell::{Cell, RefCell}, rc::Rc};
+use svd_parser::svd::{RegisterInfo, Device as SvdDevice, Usage};
+
+use anyhow::Result;
+use unicorn_engine::{Unicorn, RegisterARM};
+
+use crate::{system::System, ext_devices::ExtDevices, cpu::CpuModel, family::Layout, flight_recorder::FlightRecorder, trace::TraceWriter, expect::Expectations};
 
 #[derive(Debug, Deserialize, Default)]
 pub struct PeripheralsConfig {
     pub software_spi: Option<Vec<SoftwareSpiConfig>>,
+    pub software_i2c: Option<Vec<SoftwareI2cConfig>>,
+    pub software_uart: Option<Vec<SoftwareUartConfig>>,
+    pub onewire: Option<Vec<OneWireConfig>>,
+    pub backup_domain: Option<BackupDomainConfig>,
+    pub rcc: Option<RccConfig>,
+    pub flash: Option<FlashConfig>,
+    pub scripted: Option<Vec<ScriptedPeripheralConfig>>,
+    pub comp: Option<Vec<CompConfig>>,
+    pub timer_inputs: Option<Vec<TimerInputConfig>>,
+    pub encoders: Option<Vec<EncoderConfig>>,
+    pub gpio: Option<Vec<GpioInputConfig>>,
+    pub gpio_wires: Option<GpioWiresConfig>,
 }
 
+type TickCallback = Box<dyn FnMut(&System)>;
+
 #[derive(Default)]
 pub struct Peripherals {
+    pub cpu: CpuModel,
+    pub layout: Layout,
     debug_peripherals: Vec<PeripheralSlot<GenericPeripheral>>,
     peripherals: Vec<PeripheralSlot<RefCell<Box<dyn Peripheral>>>>,
     pub nvic: RefCell<Nvic>,
+    pub mpu: RefCell<Mpu>,
+    pub rcc: RefCell<Rcc>,
+    pub pwr: RefCell<Pwr>,
+    pub syscfg: RefCell<Syscfg>,
+    pub exti: RefCell<Exti>,
+    pub comp: RefCell<Comparators>,
+    pub timers: RefCell<Timers>,
     pub gpio: RefCell<GpioPorts>,
+    pub backup: RefCell<BackupDomain>,
+    pub poll: RefCell<PollTracker>,
+    pub recorder: RefCell<Option<FlightRecorder>>,
+    pub trace: RefCell<Option<TraceWriter>>,
+    /// Interrupt entries per irq, for --stats
+    pub irq_counts: RefCell<BTreeMap<i32, u64>>,
+    /// The expect config section
+    pub expect: RefCell<Option<Expectations>>,
+    // Config defined peripherals, by name. They take precedence.
+    scripted: HashMap<String, Rc<ScriptedPeripheralConfig>>,
+    // Interrupt number of the peripherals, from the SVD file
+    interrupts: HashMap<String, i32>,
+    // All the interrupt numbers, by interrupt name
+    interrupts_by_name: HashMap<String, i32>,
+    // Emulated time at which the peripherals want to be ticked
+    next_tick: Cell<Option<u64>>,
+    // Ticks of the devices driven through the GPIOs, like the software UARTs
+    tick_callbacks: RefCell<Vec<TickCallback>>,
 }
 
 pub struct PeripheralSlot<T> {
+    pub name: String,
     pub start: u32,
     pub end: u32,
     pub peripheral: T,
+    /// Reads and writes, for --stats
+    pub accesses: Cell<u64>,
+}
+
+/// Detects the firmware spinning on a peripheral register, reading the same
+/// value from the same instruction over and over.
+#[derive(Default)]
+pub struct PollTracker {
+    // pc, addr, value
+    last_read: Option<(u32, u32, u32)>,
+    count: u32,
+    reported: HashSet<(u32, u32)>,
+    pub busy_loop_reached: bool,
+}
+
+impl PollTracker {
+    const NUM_READS_THRESHOLD: u32 = 10_000;
+
+    /// Returns true when we start spinning on the register
+    fn on_read(&mut self, pc: u32, addr: u32, value: u32) -> bool {
+        if self.last_read == Some((pc, addr, value)) {
+            self.count += 1;
+        } else {
+            self.last_read = Some((pc, addr, value));
+            self.count = 0;
+        }
+
+        if self.count == Self::NUM_READS_THRESHOLD {
+            self.busy_loop_reached = true;
+            // We only report once per polling site, the firmware may have a timeout
+            return self.reported.insert((pc, addr));
+        }
+
+        false
+    }
+
+    pub fn last_read(&self) -> Option<(u32, u32, u32)> {
+        self.last_read
+    }
 }
 
 impl Peripherals {
@@ -63,7 +181,7 @@ impl Peripherals {
 
         trace!("Peripheral start=0x{:08x} end=0x{:08x} name={}", start, end, p.name());
 
-        self.debug_peripherals.push(PeripheralSlot { start, end, peripheral: p });
+        self.debug_peripherals.push(PeripheralSlot { name: name.clone(), start, end, peripheral: p, accesses: Cell::new(0) });
 
         // The debug peripheral is just for to print registers right now. So we
         // change the (start, end) only for the real peripheral.
@@ -72,24 +190,101 @@ impl Peripherals {
             _ => (start, end),
         };
 
-        let p = None
-            .or_else(|| NvicWrapper::new(&name))
-            .or_else(||     SysTick::new(&name))
-            .or_else(||         Scb::new(&name))
-            .or_else(||        Gpio::new(&name))
-            .or_else(||       Usart::new(&name, ext_devices))
-            .or_else(||        Fsmc::new(&name, ext_devices))
-            .or_else(||         Rcc::new(&name))
-            .or_else(||         I2c::new(&name))
-            .or_else(||         Dma::new(&name))
-            .or_else(||         Spi::new(&name, ext_devices))
-        ;
+        if let Some(p) = self.new_peripheral(&name, ext_devices) {
+            self.peripherals.push(PeripheralSlot { name, start, end, peripheral: RefCell::new(p), accesses: Cell::new(0) });
+        }
+    }
+
+    fn new_peripheral(&self, name: &str, ext_devices: &ExtDevices) -> Option<Box<dyn Peripheral>> {
+        if let Some(config) = self.scripted.get(name) {
+            return Some(ScriptedPeripheral::new(config.clone()));
+        }
+
+        None
+            .or_else(|| NvicWrapper::new(name))
+            .or_else(||  MpuWrapper::new(name))
+            .or_else(||     SysTick::new(name))
+            .or_else(||         Scb::new(name))
+            .or_else(||         Fpu::new(name))
+            .or_else(||        Gpio::new(name, self.layout))
+            .or_else(||       Usart::new(name, self.layout, ext_devices, self.interrupts.get(name).copied()))
+            .or_else(||        Fsmc::new(name, ext_devices))
+            .or_else(||  RccWrapper::new(name))
+            .or_else(||  PwrWrapper::new(name))
+            .or_else(|| SyscfgWrapper::new(name))
+            .or_else(||  ExtiWrapper::new(name))
+            .or_else(||         I2c::new(name, self.layout))
+            .or_else(||         Dma::new(name))
+            .or_else(||         Spi::new(name, self.layout, ext_devices, self.interrupts.get(name).copied()))
+            .or_else(||         Dac::new(name, ext_devices))
+            .or_else(||        Comp::new(name))
+            .or_else(||       Opamp::new(name))
+            .or_else(||        Hash::new(name))
+            .or_else(||        Cryp::new(name))
+            .or_else(||         Rtc::new(name))
+            .or_else(||         Bkp::new(name))
+            .or_else(||         Tim::new(name, self.timer_irq(name)))
+    }
+
+    /// The capture/compare interrupt of the advanced timers, e.g. TIM1_CC
+    fn timer_irq(&self, name: &str) -> Option<i32> {
+        self.interrupts_by_name.get(&format!("{}_CC", name))
+            .or_else(|| self.interrupts.get(name))
+            .copied()
+    }
+
+    /// Puts all the peripherals back in their power-on state, as done on a
+    /// system reset. The backup domain and the external devices are left untouched.
+    pub fn reset(&self, uc: &mut Unicorn<()>, ext_devices: &ExtDevices) {
+        self.nvic.borrow_mut().reset();
+        *self.mpu.borrow_mut() = Mpu::default();
+        self.rcc.borrow_mut().reset();
+        *self.pwr.borrow_mut() = Pwr::default();
+        self.syscfg.borrow_mut().reset(uc);
+        self.exti.borrow_mut().reset();
+        self.comp.borrow_mut().reset();
+        self.timers.borrow_mut().reset();
+        self.gpio.borrow_mut().reset();
+        self.next_tick.set(None);
+
+        for slot in &self.peripherals {
+            if let Some(p) = self.new_peripheral(&slot.name, ext_devices) {
+                *slot.peripheral.borrow_mut() = p;
+            }
+        }
+    }
+
+    /// Asks for the peripherals to be ticked once the emulated time reaches `at`
+    pub fn schedule_tick(&self, at: u64) {
+        let at = self.next_tick.get().map_or(at, |t| t.min(at));
+        self.next_tick.set(Some(at));
+    }
 
-        if let Some(p) = p {
-            self.peripherals.push(PeripheralSlot { start, end, peripheral: RefCell::new(p) });
+    pub fn next_tick(&self) -> Option<u64> {
+        self.next_tick.get()
+    }
+
+    /// Ticks all the peripherals when the scheduled time is reached. They
+    /// schedule their next tick as they need.
+    pub fn tick(&self, sys: &System) {
+        let now = crate::context::now();
+        if self.next_tick.get().is_some_and(|t| t <= now) {
+            self.next_tick.set(None);
+            for slot in &self.peripherals {
+                slot.peripheral.borrow_mut().tick(sys);
+            }
+            for cb in self.tick_callbacks.borrow_mut().iter_mut() {
+                cb(sys);
+            }
         }
     }
 
+    /// Ticks a device that isn't a peripheral, with the others. It schedules
+    /// its ticks with schedule_tick().
+    pub fn add_tick_callback(&self, cb: impl FnMut(&System) + 'static) {
+        self.tick_callbacks.borrow_mut().push(Box::new(cb));
+    }
+
     pub fn finish_registration(&mut self) {
         // We sort because we do binary searches to find peripherals
         self.debug_peripherals.sort_by_key(|p| p.start);
@@ -108,10 +303,22 @@ impl Peripherals {
         }
     }
 
-    pub fn from_svd(mut svd_device: SvdDevice, config: PeripheralsConfig, gpio: GpioPorts, ext_devices: &ExtDevices) -> Self {
-        let mut peripherals = Self { gpio: RefCell::new(gpio), .. Peripherals::default() };
+    pub fn from_svd(mut svd_device: SvdDevice, mut config: PeripheralsConfig, layout: Layout, gpio: GpioPorts, ext_devices: &ExtDevices) -> Result<Self> {
+        let backup = BackupDomain::from_config(config.backup_domain.unwrap_or_default())?;
+        let syscfg = Syscfg::from_config(config.flash.take().unwrap_or_default(), &svd_device.name);
+        let comp = Comparators::from_config(config.comp.take().unwrap_or_default());
+        let timers = Timers::from_config(config.timer_inputs.take().unwrap_or_default(), config.encoders.take().unwrap_or_default())?;
+        let mut peripherals = Self { gpio: RefCell::new(gpio), backup: RefCell::new(backup), syscfg: RefCell::new(syscfg), comp: RefCell::new(comp), timers: RefCell::new(timers), layout, .. Peripherals::default() };
+
+        let mut scripted = config.scripted.take().unwrap_or_default().into_iter()
+            .map(|c| (c.name.clone(), c))
+            .collect::<HashMap<_,_>>();
 
         svd_device.peripherals.sort_by_key(|f| f.base_address);
+        let interrupts = svd_device.peripherals.iter().flat_map(|p| p.interrupt.iter().cloned()).collect::<Vec<_>>();
+        peripherals.exti = RefCell::new(Exti::from_svd(&interrupts));
+        peripherals.interrupts_by_name = interrupts.iter().map(|i| (i.name.clone(), i.value as i32)).collect();
+
         let svd_peripherals = svd_device.peripherals.iter()
             .map(|d| (d.name.to_string(), d))
             .collect::<HashMap<_,_>>();
@@ -120,6 +327,10 @@ impl Peripherals {
             let name = &p.name;
             let base = p.base_address;
 
+            if let Some(interrupt) = p.interrupt.first() {
+                peripherals.interrupts.insert(name.to_string(), interrupt.value as i32);
+            }
+
             let p = if let Some(derived_from) = p.derived_from.as_ref() {
                 svd_peripherals.get(derived_from)
                     .as_ref()
@@ -130,6 +341,15 @@ impl Peripherals {
 
             let regs = crate::util::extract_svd_registers(p);
 
+            if let Some(mut scripted_config) = scripted.remove(name) {
+                scripted_config.resolve_offsets(&regs)?;
+                peripherals.scripted.insert(name.to_string(), Rc::new(scripted_config));
+            }
+
+            if name == "RCC" {
+                peripherals.rcc = RefCell::new(Rcc::from_svd(config.rcc.take().unwrap_or_default(), &regs, &svd_device.name));
+            }
+
             peripherals.register_peripheral(name.to_string(), base as u32, &regs, ext_devices);
 
             if crate::verbose() >= 3 {
@@ -139,12 +359,36 @@ impl Peripherals {
             }
         }
 
+        for name in scripted.keys() {
+            warn!("Scripted peripheral {} not found in the SVD file", name);
+        }
+
+        for gpio_config in config.gpio.unwrap_or_default() {
+            peripherals.gpio.borrow_mut().add_input(gpio_config);
+        }
+
+        if let Some(wires_config) = config.gpio_wires {
+            gpio_wires::register(wires_config, &mut peripherals.gpio.borrow_mut())?;
+        }
+
         for sw_spi_config in config.software_spi.unwrap_or_default() {
             SoftwareSpi::register(sw_spi_config, &mut peripherals.gpio.borrow_mut(), ext_devices);
         }
 
+        for sw_i2c_config in config.software_i2c.unwrap_or_default() {
+            SoftwareI2c::register(sw_i2c_config, &mut peripherals.gpio.borrow_mut(), ext_devices);
+        }
+
+        for sw_uart_config in config.software_uart.unwrap_or_default() {
+            SoftwareUart::register(sw_uart_config, &peripherals, ext_devices)?;
+        }
+
+        for onewire_config in config.onewire.unwrap_or_default() {
+            OneWire::register(onewire_config, &mut peripherals.gpio.borrow_mut(), ext_devices)?;
+        }
+
         peripherals.finish_registration();
-        peripherals
+        Ok(peripherals)
     }
 
     /////////////////////////////////////////////////////////////////////////////////////////////////////////////
@@ -164,11 +408,39 @@ impl Peripherals {
         }
     }
 
-    fn bitbanding(addr: u32) -> Option<(u32, u8)> {
-        if (0x4200_0000..0x4400_0000).contains(&addr) {
+    /// Reads and writes per peripheral
+    pub fn access_counts(&self) -> Vec<(&str, u64)> {
+        self.peripherals.iter().map(|p| (p.name.as_str(), p.accesses.get())).collect()
+    }
+
+    /// With -vvv, or a trace level for the peripheral in the log config
+    fn trace_accesses(&self, addr: u32) -> bool {
+        crate::verbose() >= 3 || Self::get_peripheral(&self.debug_peripherals, addr)
+            .is_some_and(|p| crate::log_filter::trace_accesses(&p.peripheral.name))
+    }
+
+    /// The register value decoded with the SVD fields, e.g. " SW=PLL HPRE=DIV1"
+    pub fn fields_desc(&self, addr: u32, value: u32, usage: Usage) -> String {
+        Self::get_peripheral(&self.debug_peripherals, addr)
+            .map(|p| p.peripheral.decode_fields(addr - p.start, value, usage))
+            .unwrap_or_default()
+    }
+
+    fn warn_context_begin(&self, sys: &System, addr: u32, written: Option<u32>) {
+        let pc = crate::context::last_pc();
+        let lr = sys.uc.try_borrow().ok()
+            .and_then(|uc| uc.reg_read(RegisterARM::LR).ok())
+            .unwrap_or_default() as u32;
+        crate::warn_context::begin(pc, lr, self.addr_desc(addr), written);
+    }
+
+    fn bitbanding(&self, addr: u32) -> Option<(u32, u8)> {
+        if self.cpu.has_bitbanding() && (0x4200_0000..0x4400_0000).contains(&addr) {
+            // Each alias word maps a bit of the registers. We return the
+            // register, and the bit number in it.
             //let old_addr = addr;
-            let bit_number = (addr % 32) / 4;
-            let addr = 0x4000_0000 + (addr - 0x4200_0000)/32;
+            let bit_number = (addr % 128) / 4;
+            let addr = 0x4000_0000 + (addr - 0x4200_0000)/128*4;
             //trace!("bitbanding: 0x{:08x} -> addr=0x{:08x} bit={}", old_addr, addr, bit_number);
             return Some((addr, bit_number as u8));
         } else {
@@ -182,19 +454,62 @@ impl Peripherals {
         (0x6000_0000..0xA000_0000).contains(&addr)
     }
 
+    /// Returns false when the peripheral clock is disabled and the access should be dropped
+    fn check_clock(&self, addr: u32) -> bool {
+        if !self.rcc.borrow().has_clock_gates() {
+            return true;
+        }
+
+        match Self::get_peripheral(&self.debug_peripherals, addr) {
+            Some(p) => self.rcc.borrow_mut().check_access(&p.name),
+            None => true,
+        }
+    }
+
     fn align_addr_4(addr: u32) -> (u32, u8) {
         let byte_offset = (addr % 4) as u8;
         let addr = addr - byte_offset as u32;
         (addr, byte_offset)
     }
 
+    /// For --flight-recorder and --record-trace
+    fn record_access(&self, addr: u32, write: bool, value: u32) {
+        if let Some(ref mut recorder) = *self.recorder.borrow_mut() {
+            recorder.access(addr, write, value);
+        }
+        if let Some(ref mut trace) = *self.trace.borrow_mut() {
+            trace.access(addr, write, value);
+        }
+    }
+
+    /// For --flight-recorder and --record-trace
+    pub fn record_irq(&self, irq: i32, enter: bool) {
+        if enter {
+            *self.irq_counts.borrow_mut().entry(irq).or_default() += 1;
+        }
+        if let Some(ref mut recorder) = *self.recorder.borrow_mut() {
+            if enter { recorder.irq_enter(irq) } else { recorder.irq_exit(irq) }
+        }
+        if let Some(ref mut trace) = *self.trace.borrow_mut() {
+            if enter { trace.irq_enter(irq) } else { trace.irq_exit(irq) }
+        }
+    }
+
     pub fn read(&self, sys: &System, addr: u32, size: u8) -> u32 {
-        if let Some((addr, bit_number)) = Self::bitbanding(addr) {
-            return (self.read(sys, addr, 1) >> bit_number) & 1;
+        if let Some((addr, bit_number)) = self.bitbanding(addr) {
+            return (self.read(sys, addr, 4) >> bit_number) & 1;
         }
 
-        let (addr, byte_offset) = if Self::is_register(addr) {
-            // Reduce the access to 4 byte alignements to make things easier when dealing with registers
+        if let Some(offset) = self.backup.borrow().sram_offset(addr) {
+            return self.backup.borrow().read_sram(offset, size);
+        }
+
+        if !self.check_clock(addr) {
+            return 0;
+        }
+
+        // Registers are described at their 4 byte aligned address
+        let (reg_addr, byte_offset) = if Self::is_register(addr) {
             Self::align_addr_4(addr)
         } else {
             (addr, 0)
@@ -203,28 +518,51 @@ impl Peripherals {
         assert!(byte_offset + size <= 4);
 
         let value = if let Some(p) = Self::get_peripheral(&self.peripherals, addr) {
-            p.peripheral.borrow_mut().read(sys, addr - p.start) << (8*byte_offset)
+            let warn_context = crate::warn_context::enabled();
+            if warn_context {
+                self.warn_context_begin(sys, reg_addr, None);
+            }
+            p.accesses.set(p.accesses.get() + 1);
+            let v = p.peripheral.borrow_mut().read_sized(sys, addr - p.start, size);
+            if warn_context {
+                crate::warn_context::end(v);
+            }
+            self.record_access(addr, false, v);
+            v
         } else {
             0
         };
 
-        if crate::verbose() >= 3 {
-            trace!("read:  {} read=0x{:08x}", self.addr_desc(addr), value);
+        if self.trace_accesses(reg_addr) {
+            trace!("read:  {}{} read=0x{:08x}{}", self.addr_desc(reg_addr), Self::size_desc(byte_offset, size),
+                value, self.fields_desc(reg_addr, value << (8*byte_offset), Usage::Read));
+        }
+
+        let pc = crate::context::last_pc();
+        if self.poll.borrow_mut().on_read(pc, addr, value) {
+            info!("Busy loop pc=0x{:08x} polling {} value=0x{:08x}", pc, self.addr_desc(reg_addr), value);
         }
 
         value
     }
 
-    pub fn write(&self, sys: &System, addr: u32, size: u8, mut value: u32) {
-        if let Some((addr, bit_number)) = Self::bitbanding(addr) {
-            let mut v = self.read(sys, addr, 1);
-            v &= 1 << bit_number;
+    pub fn write(&self, sys: &System, addr: u32, size: u8, value: u32) {
+        if let Some((addr, bit_number)) = self.bitbanding(addr) {
+            let mut v = self.read(sys, addr, 4);
+            v &= !(1 << bit_number);
             v |= (value & 1) << bit_number;
-            return self.write(sys, addr, 1, v);
+            return self.write(sys, addr, 4, v);
+        }
+
+        if let Some(offset) = self.backup.borrow().sram_offset(addr) {
+            return self.backup.borrow_mut().write_sram(offset, size, value);
+        }
+
+        if !self.check_clock(addr) {
+            return;
         }
 
-        let (addr, byte_offset) = if Self::is_register(addr) {
-            // Reduce the access to 4 byte alignements to make things easier when dealing with registers
+        let (reg_addr, byte_offset) = if Self::is_register(addr) {
             Self::align_addr_4(addr)
         } else {
             (addr, 0)
@@ -232,25 +570,80 @@ impl Peripherals {
 
         assert!(byte_offset + size <= 4);
 
-        if byte_offset != 0 {
-            let v = self.read(sys, addr, 4);
-            value = (value << 8*byte_offset) | (v & (0xFFFF_FFFF >> (32-8*byte_offset)));
+        if let Some(p) = Self::get_peripheral(&self.peripherals, addr) {
+            let warn_context = crate::warn_context::enabled();
+            if warn_context {
+                self.warn_context_begin(sys, reg_addr, Some(value));
+            }
+            p.accesses.set(p.accesses.get() + 1);
+            p.peripheral.borrow_mut().write_sized(sys, addr - p.start, size, value);
+            if warn_context {
+                crate::warn_context::end(value);
+            }
+            self.record_access(addr, true, value);
         }
 
-        if let Some(p) = Self::get_peripheral(&self.peripherals, addr) {
-            p.peripheral.borrow_mut().write(sys, addr - p.start, value)
+        if self.trace_accesses(reg_addr) {
+            trace!("write: {}{} write=0x{:08x}{}", self.addr_desc(reg_addr), Self::size_desc(byte_offset, size),
+                value, self.fields_desc(reg_addr, value << (8*byte_offset), Usage::Write));
         }
+    }
 
-        if crate::verbose() >= 3 {
-            trace!("write: {} write=0x{:08x}", self.addr_desc(addr), value);
+    /// Describes the sub-word accesses of registers
+    fn size_desc(byte_offset: u8, size: u8) -> String {
+        if byte_offset == 0 && size == 4 {
+            String::new()
+        } else {
+            format!(" byte_offset={} size={}", byte_offset, size)
         }
     }
 }
 
+/// The bits of an access of `size` bytes
+pub fn size_mask(size: u8) -> u32 {
+    u32::MAX >> (32 - 8*size as u32)
+}
+
 pub trait Peripheral {
     fn read(&mut self, sys: &System, offset: u32) -> u32;
     fn write(&mut self, sys: &System, offset: u32, value: u32);
 
+    /// Reads of `size` bytes at `offset`, which is not aligned for byte and
+    /// halfword accesses. By default, the register is read, and the accessed
+    /// bytes are returned.
+    fn read_sized(&mut self, sys: &System, offset: u32, size: u8) -> u32 {
+        let byte_offset = offset % 4;
+        (self.read(sys, offset - byte_offset) >> (8*byte_offset)) & size_mask(size)
+    }
+
+    /// Whether reading the register at `offset` has side effects, like data
+    /// registers and clear-on-read flags, or writing back the value read
+    /// changes its state, like write-1-to-clear or set/clear registers.
+    fn has_side_effects(&self, _offset: u32) -> bool {
+        false
+    }
+
+    /// Writes of `size` bytes at `offset`. By default, the register is written
+    /// with the other bytes taken from its current value. Registers with side
+    /// effects are not read, and their other bytes are zero instead.
+    /// Memory regions, like the FSMC banks, override this.
+    fn write_sized(&mut self, sys: &System, offset: u32, size: u8, value: u32) {
+        let byte_offset = offset % 4;
+        let reg = offset - byte_offset;
+        let mask = size_mask(size) << (8*byte_offset);
+        let value = (value << (8*byte_offset)) & mask;
+        let others = if self.has_side_effects(reg) { 0 } else { self.read(sys, reg) & !mask };
+        self.write(sys, reg, others | value)
+    }
+
+    /// Halfwords written in a row at `offset`, for DMA transfers to memory
+    /// regions like the FSMC banks
+    fn write_block(&mut self, sys: &System, offset: u32, values: &[u16]) {
+        for v in values {
+            self.write_sized(sys, offset, 2, *v as u32);
+        }
+    }
+
     fn read_dma(&mut self, sys: &System, offset: u32, size: usize) -> VecDeque<u8> {
         let mut v = VecDeque::with_capacity(size);
         for _ in 0..size {
@@ -263,6 +656,13 @@ pub trait Peripheral {
             self.write(sys, offset, v.into());
         }
     }
+
+    /// Number of bytes ready for a DMA read at the given offset. None when the
+    /// data is always there, and the DMA transfer completes right away.
+    fn dma_read_available(&mut self, _sys: &System, _offset: u32) -> Option<usize> { None }
+
+    /// Called once the time given to Peripherals::schedule_tick() is reached
+    fn tick(&mut self, _sys: &System) {}
 }
 
 struct GenericPeripheral {
@@ -288,6 +688,36 @@ impl GenericPeripheral {
             .unwrap_or_else(|| format!("offset=0x{:04x} reg=????", offset))
     }
 
+    pub fn decode_fields(&self, offset: u32, value: u32, usage: Usage) -> String {
+        let reg = match self.registers.get(&offset) {
+            Some(reg) => reg,
+            None => return String::new(),
+        };
+
+        let mut fields = reg.fields().collect::<Vec<_>>();
+        fields.sort_by_key(|f| f.bit_range.offset);
+
+        let mut desc = String::new();
+        for f in fields {
+            let mask = (1u64 << f.bit_range.width) - 1;
+            let v = (value as u64 >> f.bit_range.offset) & mask;
+
+            // Enumerated values can differ between reads and writes
+            let enum_name = f.enumerated_values.iter()
+                .filter(|e| e.usage.is_none_or(|u| u == Usage::ReadWrite || u == usage))
+                .flat_map(|e| &e.values)
+                .find(|e| e.value == Some(v))
+                .map(|e| &e.name);
+
+            match enum_name {
+                Some(name) => desc += &format!(" {}={}", f.name, name),
+                None if f.bit_range.width == 1 => desc += &format!(" {}={}", f.name, v),
+                None => desc += &format!(" {}=0x{:x}", f.name, v),
+            }
+        }
+        desc
+    }
+
     fn name(&self) -> &str {
         &self.name
     }
@@ -300,3 +730,139 @@ impl GenericPeripheral {
             .unwrap_or(0) + 4
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unicorn_engine::unicorn_const::{Arch, Mode};
+    use crate::{ext_devices::ExtDevicesConfig, framebuffers::Framebuffers};
+
+    /// Four registers, counting the reads of the register interface.
+    /// The first one is a data register, reading it has side effects.
+    #[derive(Default)]
+    struct Registers {
+        regs: [u32; 4],
+        num_reads: Rc<Cell<u32>>,
+    }
+
+    impl Peripheral for Registers {
+        fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+            self.num_reads.set(self.num_reads.get() + 1);
+            self.regs[offset as usize / 4]
+        }
+
+        fn write(&mut self, _sys: &System, offset: u32, value: u32) {
+            self.regs[offset as usize / 4] = value;
+        }
+
+        fn has_side_effects(&self, offset: u32) -> bool {
+            offset == 0
+        }
+    }
+
+    const BASE: u32 = 0x4000_0000;
+
+    /// Runs f with the peripheral mapped at BASE on a Cortex-M4
+    fn with_peripheral(peripheral: Box<dyn Peripheral>, f: impl FnOnce(&System)) {
+        let peripherals = Peripherals {
+            cpu: CpuModel::CortexM4,
+            peripherals: vec![PeripheralSlot {
+                name: "TEST".to_string(),
+                start: BASE,
+                end: BASE + 0x3FF,
+                peripheral: RefCell::new(peripheral),
+                accesses: Cell::new(0),
+            }],
+            ..Default::default()
+        };
+        let framebuffers = Framebuffers::from_config(vec![]).unwrap();
+        let ext_devices = ExtDevicesConfig::default().into_ext_devices(&mut GpioPorts::default(), &framebuffers).unwrap();
+        let mut uc = Unicorn::new(Arch::ARM, Mode::LITTLE_ENDIAN).unwrap();
+        let sys = System { uc: RefCell::new(&mut uc), p: Rc::new(peripherals), d: Rc::new(ext_devices) };
+        f(&sys);
+    }
+
+    fn with_system(regs: [u32; 4], f: impl FnOnce(&System, Rc<Cell<u32>>)) {
+        let num_reads = Rc::new(Cell::new(0));
+        let peripheral = Box::new(Registers { regs, num_reads: num_reads.clone() });
+        with_peripheral(peripheral, |sys| f(sys, num_reads));
+    }
+
+    #[test]
+    fn sub_word_reads() {
+        with_system([0x4433_2211, 0, 0, 0], |sys, _| {
+            for offset in 0..4 {
+                assert_eq!(sys.p.read(sys, BASE + offset, 1), 0x11 * (offset + 1));
+            }
+            assert_eq!(sys.p.read(sys, BASE, 2), 0x2211);
+            assert_eq!(sys.p.read(sys, BASE + 1, 2), 0x3322);
+            assert_eq!(sys.p.read(sys, BASE + 2, 2), 0x4433);
+            assert_eq!(sys.p.read(sys, BASE + 3, 1), 0x44);
+        });
+    }
+
+    #[test]
+    fn sub_word_writes_keep_the_other_bytes() {
+        with_system([0, 0x4433_2211, 0, 0], |sys, _| {
+            sys.p.write(sys, BASE + 4, 1, 0x1FF);
+            assert_eq!(sys.p.read(sys, BASE + 4, 4), 0x4433_22FF);
+            sys.p.write(sys, BASE + 5, 1, 0xAA);
+            assert_eq!(sys.p.read(sys, BASE + 4, 4), 0x4433_AAFF);
+            sys.p.write(sys, BASE + 6, 2, 0xCCBB);
+            assert_eq!(sys.p.read(sys, BASE + 4, 4), 0xCCBB_AAFF);
+            sys.p.write(sys, BASE + 7, 1, 0x1DD);
+            assert_eq!(sys.p.read(sys, BASE + 4, 4), 0xDDBB_AAFF);
+        });
+    }
+
+    #[test]
+    fn sub_word_writes_dont_read_registers_with_side_effects() {
+        with_system([0xFFFF_FFFF, 0, 0, 0], |sys, num_reads| {
+            sys.p.write(sys, BASE, 1, 0x1AB);
+            assert_eq!(num_reads.get(), 0);
+            sys.p.write(sys, BASE + 2, 2, 0x1_2345);
+            assert_eq!(num_reads.get(), 0);
+            assert_eq!(sys.p.read(sys, BASE, 4), 0x2345_0000);
+        });
+    }
+
+    #[test]
+    fn gpio_byte_writes() {
+        let gpio = Gpio::new("GPIOA", Layout::F4).unwrap();
+        with_peripheral(gpio, |sys| {
+            const ODR: u32 = BASE + 0x14;
+            const BSRR: u32 = BASE + 0x18;
+            sys.p.write(sys, ODR, 4, 0xFF00);
+            // The pins 8-15 keep their output
+            sys.p.write(sys, ODR, 1, 0x0F);
+            assert_eq!(sys.p.read(sys, ODR, 4), 0xFF0F);
+            sys.p.write(sys, ODR + 1, 1, 0xF0);
+            assert_eq!(sys.p.read(sys, ODR, 4), 0xF00F);
+            // Resets pin 0 and 15, without touching the others
+            sys.p.write(sys, BSRR + 2, 2, 0x8001);
+            assert_eq!(sys.p.read(sys, ODR, 4), 0x700E);
+            // Sets pin 8
+            sys.p.write(sys, BSRR + 1, 1, 0x01);
+            assert_eq!(sys.p.read(sys, ODR, 4), 0x710E);
+        });
+    }
+
+    #[test]
+    fn bitbanding() {
+        // The alias of bit n of the register at BASE + 4
+        let alias = |n: u32| 0x4200_0000 + 4 * 32 + n * 4;
+        with_system([0, 0xF0F0_F0F0, 0, 0], |sys, _| {
+            assert_eq!(sys.p.read(sys, alias(4), 4), 1);
+            assert_eq!(sys.p.read(sys, alias(3), 4), 0);
+
+            sys.p.write(sys, alias(0), 4, 1);
+            assert_eq!(sys.p.read(sys, BASE + 4, 4), 0xF0F0_F0F1);
+            sys.p.write(sys, alias(31), 4, 0);
+            assert_eq!(sys.p.read(sys, BASE + 4, 4), 0x70F0_F0F1);
+            // Only bit 0 of the written value counts
+            sys.p.write(sys, alias(12), 4, 0xFFFF_FFFE);
+            assert_eq!(sys.p.read(sys, BASE + 4, 4), 0x70F0_E0F1);
+            assert_eq!(sys.p.read(sys, BASE, 4), 0);
+        });
+    }
+}
@@ -0,0 +1,222 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use unicorn_engine::{RegisterARM, Unicorn};
+
+use crate::system::System;
+use super::{Peripheral, nvic::Fault};
+
+const NUM_REGIONS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Region {
+    rbar: u32,
+    rasr: u32,
+}
+
+impl Region {
+    fn is_enabled(&self) -> bool {
+        self.rasr & 1 != 0
+    }
+
+    fn contains(&self, addr: u32) -> bool {
+        // RASR bits 5:1 SIZE. The region is 2^(SIZE+1) bytes, aligned on its size.
+        let size = 2u64 << ((self.rasr >> 1) & 0x1F);
+        let base = self.rbar as u64 & !(size - 1);
+        let offset = (addr as u64).wrapping_sub(base);
+        if offset >= size {
+            return false;
+        }
+
+        // RASR bits 15:8 SRD. Regions of 256 bytes and more are split in 8
+        // sub-regions that can be individually disabled.
+        if size >= 256 {
+            let subregion = offset / (size / 8);
+            if (self.rasr >> 8) & (1 << subregion) != 0 {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    fn allows(&self, access: Access, privileged: bool) -> bool {
+        // RASR bit 28 XN
+        if access == Access::Execute && self.rasr & (1 << 28) != 0 {
+            return false;
+        }
+
+        // RASR bits 26:24 AP
+        let write = access == Access::Write;
+        match (self.rasr >> 24) & 0b111 {
+            0b001 => privileged,
+            0b010 => privileged || !write,
+            0b011 => true,
+            0b101 => privileged && !write,
+            0b110 | 0b111 => !write,
+            _ => false,
+        }
+    }
+}
+
+/// A data access violation. It is detected in the middle of the instruction,
+/// and delivered before the next one.
+pub struct Violation {
+    pub pc: u32,
+    pub addr: u32,
+    // Memory content before a faulting write, so we can undo it.
+    pub old_data: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+pub struct Mpu {
+    ctrl: u32,
+    rnr: u32,
+    regions: [Region; NUM_REGIONS],
+    pub pending_violation: Option<Violation>,
+}
+
+impl Mpu {
+    pub fn is_enabled(&self) -> bool {
+        // CTRL bit 0 ENABLE
+        self.ctrl & 1 != 0
+    }
+
+    /// Handler mode is always privileged. Otherwise CONTROL bit 0 nPRIV tells.
+    pub fn is_privileged(uc: &Unicorn<()>) -> bool {
+        let ipsr = uc.reg_read(RegisterARM::IPSR).unwrap() & 0x1FF;
+        let control = uc.reg_read(RegisterARM::CONTROL).unwrap();
+        ipsr != 0 || control & 1 == 0
+    }
+
+    pub fn check(&self, uc: &Unicorn<()>, addr: u32, access: Access) -> bool {
+        if !self.is_enabled() {
+            return true;
+        }
+
+        // The system region is not subject to the MPU
+        if addr >= 0xE000_0000 {
+            return true;
+        }
+
+        // CTRL bit 1 HFNMIENA. Without it, the MPU is off in the HardFault and NMI handlers.
+        let ipsr = uc.reg_read(RegisterARM::IPSR).unwrap() & 0x1FF;
+        if (ipsr == 2 || ipsr == 3) && self.ctrl & (1 << 1) == 0 {
+            return true;
+        }
+
+        let privileged = Self::is_privileged(uc);
+
+        // Higher region numbers take priority
+        if let Some(region) = self.regions.iter().rev().find(|r| r.is_enabled() && r.contains(addr)) {
+            return region.allows(access, privileged);
+        }
+
+        // CTRL bit 2 PRIVDEFENA: the default memory map applies to privileged accesses
+        privileged && self.ctrl & (1 << 2) != 0
+    }
+
+    /// Called before executing each instruction. Delivers the MemManage fault
+    /// of a data access violation of the previous instruction, or of fetching
+    /// the current one. Returns false when the CPU locks up.
+    pub fn enforce(sys: &System, pc: u32, vector_table_addr: u32) -> bool {
+        let violation = sys.p.mpu.borrow_mut().pending_violation.take();
+
+        let fault = if let Some(v) = violation {
+            let mut uc = sys.uc.borrow_mut();
+            if let Some(data) = v.old_data {
+                uc.mem_write(v.addr as u64, &data).unwrap();
+            }
+            // The stacked PC is the faulting instruction
+            uc.reg_write(RegisterARM::PC, v.pc as u64).unwrap();
+            Some(Fault::DataAccessViolation(v.addr))
+        } else if !sys.p.mpu.borrow().check(&sys.uc.borrow(), pc, Access::Execute) {
+            Some(Fault::InstructionAccessViolation)
+        } else {
+            None
+        };
+
+        if let Some(fault) = fault {
+            sys.p.nvic.borrow_mut().raise_fault(sys, vector_table_addr, fault)
+        } else {
+            true
+        }
+    }
+}
+
+impl Peripheral for Mpu {
+    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        match offset {
+            // TYPER register. DREGION in bits 15:8.
+            0x0000 => (NUM_REGIONS as u32) << 8,
+            0x0004 => self.ctrl,
+            0x0008 => self.rnr,
+            // RBAR and RASR, and their aliases
+            0x000C..=0x0020 => {
+                let region = &self.regions[self.rnr as usize];
+                if (offset - 0x0C) & 4 == 0 {
+                    region.rbar | self.rnr
+                } else {
+                    region.rasr
+                }
+            }
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, _sys: &System, offset: u32, value: u32) {
+        match offset {
+            0x0004 => {
+                if (self.ctrl ^ value) & 1 != 0 {
+                    debug!("MPU enabled={}", value & 1 != 0);
+                }
+                self.ctrl = value;
+            }
+            0x0008 => self.rnr = value % NUM_REGIONS as u32,
+            0x000C..=0x0020 => {
+                if (offset - 0x0C) & 4 == 0 {
+                    // RBAR bit 4 VALID: the region number comes from bits 3:0
+                    if value & (1 << 4) != 0 {
+                        self.rnr = (value & 0xF) % NUM_REGIONS as u32;
+                    }
+                    self.regions[self.rnr as usize].rbar = value & !0x1F;
+                } else {
+                    self.regions[self.rnr as usize].rasr = value;
+                }
+                let region = &self.regions[self.rnr as usize];
+                trace!("MPU region={} rbar=0x{:08x} rasr=0x{:08x}", self.rnr, region.rbar, region.rasr);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Glue, similarly to the NVIC. The MPU state must be reachable from the memory hooks.
+
+pub struct MpuWrapper;
+
+impl MpuWrapper {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "MPU" {
+            Some(Box::new(Self))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for MpuWrapper {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        sys.p.mpu.borrow_mut().read(sys, offset)
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        sys.p.mpu.borrow_mut().write(sys, offset, value)
+    }
+}
@@ -1,78 +1,316 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::sync::atomic::Ordering;
 
 use unicorn_engine::{RegisterARM, Unicorn};
 
-use crate::system::System;
-use super::Peripheral;
+use crate::{system::System, util::Rng};
+use super::{Peripheral, fpu::FpState, systick::SysTickTimer};
+
+// Exceptions are indexed by their exception number. External interrupts start at 16.
+// 128 different exceptions. Good enough for now.
+const NUM_EXCEPTIONS: usize = 128;
 
 #[derive(Default)]
 pub struct Nvic {
-    pub systick_period: Option<u32>,
-    pub last_systick_trigger: u64,
+    pub systick: SysTickTimer,
 
-    // 128 different interrupts. Good enough for now
     pending: u128,
-    in_interrupt: bool,
+    // Only meaningful for external interrupts. System exceptions are always enabled.
+    enabled: u128,
+    active: u128,
+    priorities: Vec<u8>,
+    // Number of implemented priority bits, the upper bits of each priority
+    // byte. The low bits read as zero. All 8 bits when unknown.
+    pub priority_bits: Option<u32>,
+    // AIRCR PRIGROUP. Splits priorities into group priority and sub-priority.
+    pub prigroup: u32,
+
+    // Fault registers of the SCB. They live here because faults are raised
+    // from the interrupt hook, where we only have the NVIC at hand.
+    pub shcsr: u32,
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+    // SCB SCR. SLEEPDEEP selects the low power mode entered with WFI.
+    pub scr: u32,
+    // FPU control registers, used for stacking the FP state
+    pub fpu: FpState,
+    /// With --irq-jitter
+    pub jitter: Option<IrqJitter>,
+    /// Exceptions stopping the emulation instead of being entered, with --catch-exceptions
+    catch: u128,
+}
+
+/// Delays the delivery of the interrupts by a random number of cycles, to
+/// shake out the races of RTOS firmwares. The seed makes a run reproducible.
+pub struct IrqJitter {
+    max: u64,
+    rng: Rng,
+    /// The pending interrupts are held until then
+    hold_until: Option<u64>,
+}
+
+impl IrqJitter {
+    pub fn new(max: u64, seed: u64) -> Self {
+        Self { max, rng: Rng::new(seed), hold_until: None }
+    }
+
+    /// Called when an interrupt could be taken. Returns true to hold it.
+    fn hold(&mut self) -> bool {
+        let now = crate::context::now();
+        let until = match self.hold_until {
+            Some(until) => until,
+            None => {
+                let until = now + self.rng.next_u64() % (self.max + 1);
+                self.hold_until = Some(until);
+                until
+            }
+        };
+        if now < until {
+            return true;
+        }
+        self.hold_until = None;
+        false
+    }
+}
+
+/// CPU faults reported by Unicorn
+#[derive(Debug, Clone, Copy)]
+pub enum Fault {
+    UndefinedInstruction,
+    InvalidState,
+    NoCoprocessor,
+    Unaligned,
+    InstructionBusError,
+    /// With the faulting address when known
+    DataBusError(Option<u32>),
+    Breakpoint,
+    InstructionAccessViolation,
+    DataAccessViolation(u32),
 }
 
 const IRQ_OFFSET: i32 = 16;
 
+// Exceptions below 16 are the system exceptions
+const SYSTEM_EXCEPTIONS: u128 = 0xFFFF;
+
 pub mod irq {
+    pub const NMI: i32 = -14;
+    pub const HARDFAULT: i32 = -13;
+    pub const MEMMANAGE: i32 = -12;
+    pub const BUSFAULT: i32 = -11;
+    pub const USAGEFAULT: i32 = -10;
+    pub const SVCALL: i32 = -5;
     pub const PENDSV: i32 = -2;
     pub const SYSTICK: i32 = -1;
 }
 
-// This is all poorly implemented. If this is not making much sense, it might be
-// best to re-implement everything correctly. Right now, I'm just trying to get
-// the saturn firmware to work just well enough.
+// Priority of the thread mode, lower than any exception
+const THREAD_PRIORITY: i32 = 256;
 
 impl Nvic {
+    fn exception(irq: i32) -> usize {
+        let exception = IRQ_OFFSET + irq;
+        assert!(exception > 0);
+        exception as usize
+    }
+
+    /// The number of priority bits is a property of the chip, and is kept
+    pub fn reset(&mut self) {
+        *self = Self { priority_bits: self.priority_bits, jitter: self.jitter.take(), catch: self.catch, ..Self::default() };
+    }
+
+    pub fn catch_exception(&mut self, irq: i32) {
+        self.catch |= 1 << Self::exception(irq);
+    }
+
+    /// Stops the emulation when the exception is caught, before its handler runs
+    fn is_caught(&self, uc: &mut Unicorn<()>, irq: i32) -> bool {
+        if self.catch & (1 << Self::exception(irq)) == 0 {
+            return false;
+        }
+        crate::catch::dump(uc, irq, self);
+        uc.emu_stop().unwrap();
+        true
+    }
+
     pub fn set_intr_pending(&mut self, irq: i32) {
         trace!("Set irq pending irq={}", irq);
-        let bit = IRQ_OFFSET + irq;
-        assert!(bit > 0);
-        self.pending |= 1 << (IRQ_OFFSET + irq);
+        self.pending |= 1 << Self::exception(irq);
+    }
+
+    pub fn clear_intr_pending(&mut self, irq: i32) {
+        self.pending &= !(1 << Self::exception(irq));
     }
 
-    pub fn get_and_clear_next_intr_pending(&mut self) -> Option<i32> {
-        if self.pending != 0 {
-            let bit = self.pending.trailing_zeros();
-            self.pending &= !(1 << bit);
-            let irq = (bit as i32) - IRQ_OFFSET;
-            Some(irq)
+    pub fn is_intr_pending(&self, irq: i32) -> bool {
+        self.pending & (1 << Self::exception(irq)) != 0
+    }
+
+    /// Priority of an exception, as configured. Lower is more urgent.
+    /// Reset, NMI and HardFault have fixed negative priorities.
+    fn priority(&self, exception: usize) -> i32 {
+        match exception {
+            1 => -3,
+            2 => -2,
+            3 => -1,
+            _ => self.priorities.get(exception).cloned().unwrap_or_default() as i32,
+        }
+    }
+
+    /// Only the group priority decides preemption. The sub-priority only
+    /// orders pending exceptions of the same group priority.
+    fn group_priority(&self, exception: usize) -> i32 {
+        let priority = self.priority(exception);
+        if priority < 0 {
+            priority
         } else {
-            None
+            priority & self.group_priority_mask()
         }
     }
 
-    pub fn maybe_set_systick_intr_pending(&mut self) {
-        if let Some(systick_period) = self.systick_period {
-            let n = crate::emulator::NUM_INSTRUCTIONS.load(Ordering::Relaxed);
-            let delta_num_instructions = n - self.last_systick_trigger;
-            if delta_num_instructions > (systick_period as u64) {
-                self.last_systick_trigger = n;
-                self.set_intr_pending(irq::SYSTICK);
+    fn group_priority_mask(&self) -> i32 {
+        (0xFF << (self.prigroup + 1)) & 0xFF
+    }
+
+    /// The priority of the running code. Exceptions need a higher priority
+    /// (lower value) to preempt it.
+    fn execution_priority(&self, sys: &System) -> i32 {
+        let mut priority = THREAD_PRIORITY;
+
+        let mut active = self.active;
+        while active != 0 {
+            let exception = active.trailing_zeros() as usize;
+            active &= !(1 << exception);
+            priority = priority.min(self.group_priority(exception));
+        }
+
+        let uc = sys.uc.borrow();
+        let basepri = uc.reg_read(RegisterARM::BASEPRI).unwrap() as i32 & 0xFF;
+        if basepri != 0 {
+            priority = priority.min(basepri & self.group_priority_mask());
+        }
+        if uc.reg_read(RegisterARM::PRIMASK).unwrap() & 1 != 0 {
+            priority = priority.min(0);
+        }
+        if uc.reg_read(RegisterARM::FAULTMASK).unwrap() & 1 != 0 {
+            priority = priority.min(-1);
+        }
+
+        priority
+    }
+
+    /// The pending exception to take next, regardless of the execution priority.
+    /// Ties are broken by sub-priority, then by exception number. With the
+    /// same priority, PendSV (14) goes before SysTick (15).
+    pub fn next_pending_exception(&self) -> Option<usize> {
+        let candidates = self.pending & (self.enabled | SYSTEM_EXCEPTIONS);
+        if candidates == 0 {
+            return None;
+        }
+        (0..NUM_EXCEPTIONS)
+            .filter(|e| candidates & (1 << e) != 0)
+            .min_by_key(|e| (self.priority(*e), *e))
+    }
+
+    /// Returns a pending exception that can preempt the running code, and
+    /// clears its pending state.
+    fn take_preempting_exception(&mut self, sys: &System) -> Option<usize> {
+        let exception = self.next_pending_exception()?;
+        if self.group_priority(exception) < self.execution_priority(sys) {
+            if self.jitter.as_mut().is_some_and(|j| j.hold()) {
+                return None;
             }
+            self.pending &= !(1 << exception);
+            Some(exception)
+        } else {
+            None
         }
     }
 
-   fn are_interrupts_disabled(sys: &System) -> bool {
-        let primask = sys.uc.borrow().reg_read(RegisterARM::PRIMASK).unwrap();
-        primask != 0
+    /// Returns the instruction count at which the CPU sleeping on WFI wakes
+    /// up. Pending interrupts wake it up even when masked by PRIMASK.
+    pub fn next_wakeup(&self) -> Option<u64> {
+        if self.next_pending_exception().is_some() {
+            return Some(crate::context::now());
+        }
+        self.systick.next_interrupt()
+    }
+
+    pub fn maybe_set_systick_intr_pending(&mut self) {
+        if self.systick.update() {
+            self.set_intr_pending(irq::SYSTICK);
+        }
     }
 
     pub fn run_pending_interrupts(&mut self, sys: &System, vector_table_addr: u32) {
         self.maybe_set_systick_intr_pending();
 
-        if Self::are_interrupts_disabled(sys) || self.in_interrupt {
-            return;
+        if let Some(exception) = self.take_preempting_exception(sys) {
+            self.run_interrupt(sys, vector_table_addr, exception as i32 - IRQ_OFFSET);
+        }
+    }
+
+    /// Vectors into the fault handler. Faults that are not enabled in SHCSR,
+    /// or that can't preempt the running code escalate to a HardFault. ARMv6-M
+    /// only has the HardFault.
+    /// Returns false when the CPU locks up, which happens when the HardFault
+    /// can't preempt either, or without a handler.
+    pub fn raise_fault(&mut self, sys: &System, vector_table_addr: u32, fault: Fault) -> bool {
+        // CFSR bits: UsageFault in 31:16, BusFault in 15:8, MemManage in 7:0.
+        // SHCSR bits: 16 MEMFAULTENA, 17 BUSFAULTENA, 18 USGFAULTENA.
+        let (irq, cfsr_bit, enable_bit) = match fault {
+            Fault::UndefinedInstruction => (irq::USAGEFAULT, 1 << 16, 1 << 18),
+            Fault::InvalidState =>         (irq::USAGEFAULT, 1 << 17, 1 << 18),
+            Fault::NoCoprocessor =>        (irq::USAGEFAULT, 1 << 19, 1 << 18),
+            Fault::Unaligned =>            (irq::USAGEFAULT, 1 << 24, 1 << 18),
+            Fault::InstructionBusError =>  (irq::BUSFAULT,   1 << 8,  1 << 17),
+            Fault::DataBusError(None) =>   (irq::BUSFAULT,   1 << 9,  1 << 17),
+            // BFARVALID is bit 15
+            Fault::DataBusError(Some(_)) => (irq::BUSFAULT,  (1 << 9) | (1 << 15), 1 << 17),
+            Fault::Breakpoint =>           (irq::HARDFAULT,  0,       0),
+            Fault::InstructionAccessViolation => (irq::MEMMANAGE, 1 << 0, 1 << 16),
+            // MMARVALID is bit 7
+            Fault::DataAccessViolation(_) => (irq::MEMMANAGE, (1 << 1) | (1 << 7), 1 << 16),
+        };
+
+        self.cfsr |= cfsr_bit;
+        match fault {
+            Fault::DataAccessViolation(addr) => self.mmfar = addr,
+            Fault::DataBusError(Some(addr)) => self.bfar = addr,
+            _ => {}
         }
 
-        if let Some(irq) = self.get_and_clear_next_intr_pending() {
-            self.run_interrupt(sys, vector_table_addr, irq);
+        let execution_priority = self.execution_priority(sys);
+
+        let irq = if irq == irq::HARDFAULT {
+            // HFSR bit 31 DEBUGEVT. Without a debugger, BKPT escalates to a HardFault.
+            self.hfsr |= 1 << 31;
+            irq
+        } else if !sys.p.cpu.is_v6m() && self.shcsr & enable_bit != 0 &&
+                  self.group_priority(Self::exception(irq)) < execution_priority {
+            irq
+        } else {
+            // HFSR bit 30 FORCED
+            self.hfsr |= 1 << 30;
+            irq::HARDFAULT
+        };
+
+        let pc = sys.uc.borrow().reg_read(RegisterARM::PC).unwrap();
+        let vector = Self::read_vector_addr(sys, vector_table_addr, irq);
+
+        if execution_priority <= self.priority(Self::exception(irq::HARDFAULT)) || vector == 0 {
+            error!("Lockup fault={:?} pc=0x{:08x} priority={} cfsr=0x{:08x} hfsr=0x{:08x}",
+                fault, pc, execution_priority, self.cfsr, self.hfsr);
+            return false;
         }
+
+        warn!("Fault fault={:?} irq={} pc=0x{:08x} cfsr=0x{:08x} hfsr=0x{:08x}",
+            fault, irq, pc, self.cfsr, self.hfsr);
+
+        self.run_interrupt(sys, vector_table_addr, irq);
+        true
     }
 
     fn read_vector_addr(sys: &System, vector_table_addr: u32, irq: i32) -> u32 {
@@ -84,26 +322,42 @@ impl Nvic {
         u32::from_le_bytes(vector)
     }
 
-    // SPSEL, bit[1], 0 means we use MSP, 1 means we use PSP.
-    // FPCA, bit[2], if the processor includes the FP extension.
+    // CONTROL register:
+    // SPSEL, bit[1], 0 means we use MSP, 1 means we use PSP. Always MSP in handler mode.
+    // FPCA, bit[2], if the current context uses the FPU.
 
-    fn run_interrupt(&mut self, sys: &System, vector_table_addr: u32, irq: i32) {
+    pub fn run_interrupt(&mut self, sys: &System, vector_table_addr: u32, irq: i32) {
+        let exception = Self::exception(irq);
         let vector = Self::read_vector_addr(sys, vector_table_addr, irq);
 
         let mut uc = sys.uc.borrow_mut();
+        if self.is_caught(&mut uc, irq) {
+            return;
+        }
 
-        // SPSEL, bit[1], 0 means we use MSP, 1 means we use PSP.
-        // FPCA, bit[2], if the processor includes the FP extension.
+        let handler_mode = uc.reg_read(RegisterARM::IPSR).unwrap() & 0x1FF != 0;
         let control_reg = uc.reg_read(RegisterARM::CONTROL).unwrap();
-        let spsel = control_reg & (1 << 1) != 0;
-        let fpca = control_reg & (2 << 1) != 0;
-
-        trace!("Running interrupt irq={} spsel={} fpca={} vector={:#08x}",
-            irq, spsel, fpca, vector);
-
-        Self::push_regs(&mut uc, spsel, fpca);
+        let spsel = !handler_mode && control_reg & (1 << 1) != 0;
+        // The extended frame is pushed when the model has an FPU, the interrupted
+        // context used it (CONTROL.FPCA), and FPCCR.ASPEN enables the stacking
+        let has_fpu = sys.p.cpu.has_fpu();
+        let fpca = has_fpu && self.fpu.should_stack(control_reg & (1 << 2) != 0);
+
+        trace!("Running interrupt irq={} nested={} spsel={} fpca={} vector={:#08x}",
+            irq, handler_mode, spsel, fpca, vector);
+        sys.p.record_irq(irq, true);
+
+        // The frame goes on the stack in use
+        let sp = Self::push_regs(&mut uc, fpca);
+        if fpca {
+            // Where S0 is stacked
+            self.fpu.fpcar = sp + 4*Self::CONTEXT_REGS.len() as u32;
+        }
+        if let Some(fpscr) = self.fpu.new_context_fpscr().filter(|_| has_fpu) {
+            uc.reg_write(RegisterARM::FPSCR, fpscr.into()).unwrap();
+        }
 
-        // LR meaning:
+        // EXC_RETURN meaning:
         //   EXC_RETURN    Return to      Return stack Frame type
         //   0xFFFF_FFE1   Handler mode   Main         Extended
         //   0xFFFF_FFE9   Thread mode    Main         Extended
@@ -111,159 +365,280 @@ impl Nvic {
         //   0xFFFF_FFF1   Handler mode   Main         Basic
         //   0xFFFF_FFF9   Thread mode    Main         Basic
         //   0xFFFF_FFFD   Thread mode    Process      Basic
-
-        // Right now, we don't supposed nested interrupts.
-        let mut lr: u32 = 0xFFFF_FFE9;
+        // ARMv8-M clears bit 0 (ES) and bit 6 (S) as we run in the non-secure
+        // state, e.g. 0xFFFF_FFBC instead of 0xFFFF_FFFD.
+        let mut lr: u32 = if sys.p.cpu.is_v8m() { 0xFFFF_FFA0 } else { 0xFFFF_FFE1 };
+        if !handler_mode { lr |= 0b0000_1000; }
         if spsel { lr |= 0b0000_0100; }
         if !fpca { lr |= 0b0001_0000; } // Yes, no fpca means the bit is set
-        uc.reg_write(RegisterARM::LR, lr.into()).unwrap();
 
-        uc.reg_write(RegisterARM::IPSR, irq as u64).unwrap();
+        // Entering handler mode switches to the main stack
+        uc.reg_write(RegisterARM::IPSR, exception as u64).unwrap();
+        uc.reg_write(RegisterARM::CONTROL, control_reg & !0b110).unwrap();
+        uc.reg_write(RegisterARM::LR, lr.into()).unwrap();
         uc.reg_write(RegisterARM::PC, vector as u64).unwrap();
 
-        self.in_interrupt = true;
+        self.active |= 1 << exception;
     }
 
-    pub fn return_from_interrupt(&mut self, sys: &System) {
-        let mut uc = sys.uc.borrow_mut();
+    pub fn return_from_interrupt(&mut self, sys: &System, vector_table_addr: u32) {
+        let (exc_return, exception) = {
+            let uc = sys.uc.borrow();
+            // The PC holds EXC_RETURN, regardless of the return instruction used.
+            let pc = uc.reg_read(RegisterARM::PC).unwrap() as u32 | 1;
+            let ipsr = uc.reg_read(RegisterARM::IPSR).unwrap() as usize & 0x1FF;
+            (pc, ipsr)
+        };
 
-        let lr = uc.reg_read(RegisterARM::LR).unwrap();
-        if lr & 0xFFFF_FF00 == 0xFFFF_FF00 {
-            let spsel = lr & 0b0000_0100 != 0;
-            let fpca = lr & 0b0001_0000 == 0; // 0 means yes here
+        self.active &= !(1 << exception);
+        sys.p.record_irq(exception as i32 - IRQ_OFFSET, false);
 
-            Self::pop_regs(&mut uc, spsel, fpca);
+        // Tail-chaining: the next exception reuses the stacked frame.
+        if let Some(next) = self.take_preempting_exception(sys) {
+            let irq = next as i32 - IRQ_OFFSET;
+            if self.is_caught(&mut sys.uc.borrow_mut(), irq) {
+                return;
+            }
+            let vector = Self::read_vector_addr(sys, vector_table_addr, irq);
+            trace!("Tail-chaining irq={} vector={:#08x}", irq, vector);
+            sys.p.record_irq(irq, true);
+
+            let mut uc = sys.uc.borrow_mut();
+            uc.reg_write(RegisterARM::IPSR, next as u64).unwrap();
+            uc.reg_write(RegisterARM::LR, exc_return.into()).unwrap();
+            uc.reg_write(RegisterARM::PC, vector as u64).unwrap();
+            self.active |= 1 << next;
+            return;
+        }
 
-            trace!("Return from interrupt spsel={} fpca={} pc=0x{:08x}",
-                spsel, fpca, uc.reg_read(RegisterARM::PC).unwrap());
+        let spsel = exc_return & 0b0000_0100 != 0;
+        let fpca = sys.p.cpu.has_fpu() && exc_return & 0b0001_0000 == 0; // 0 means yes here
 
-            // SPSEL, bit[1], 0 means we use MSP, 1 means we use PSP.
-            // FPCA, bit[2], if the processor includes the FP extension.
-            let mut control_reg = 0;
-            if spsel { control_reg |= 1 << 1; }
-            if fpca { control_reg |= 2 << 1; }
-            uc.reg_write(RegisterARM::CONTROL, control_reg).unwrap();
-        } else {
-            let control_reg = uc.reg_read(RegisterARM::CONTROL).unwrap();
-            let spsel = control_reg & (1 << 1) != 0;
-            let fpca = control_reg & (2 << 1) != 0;
-            Self::pop_regs(&mut uc, spsel, fpca);
+        let mut uc = sys.uc.borrow_mut();
 
-            trace!("Return from interrupt spsel={} fpca={} pc=0x{:08x} -- LR was not right",
-                spsel, fpca, uc.reg_read(RegisterARM::PC).unwrap());
-        }
+        // We are still in handler mode, so this doesn't switch stacks yet.
+        // Restoring the IPSR with the stacked xPSR does.
+        let mut control_reg = uc.reg_read(RegisterARM::CONTROL).unwrap() & !0b110;
+        if spsel { control_reg |= 1 << 1; }
+        if fpca { control_reg |= 1 << 2; }
+        uc.reg_write(RegisterARM::CONTROL, control_reg).unwrap();
+
+        Self::pop_regs(&mut uc, spsel, fpca);
 
-        self.in_interrupt = false;
+        trace!("Return from interrupt irq={} spsel={} fpca={} pc=0x{:08x}",
+            exception as i32 - IRQ_OFFSET, spsel, fpca, uc.reg_read(RegisterARM::PC).unwrap());
     }
 
+    // Order of the registers in the stack frame, from the lowest address.
+    // The extended frame adds the FP registers, and a reserved word.
+    const CONTEXT_REGS: [RegisterARM; 8] = [
+        RegisterARM::R0,
+        RegisterARM::R1,
+        RegisterARM::R2,
+        RegisterARM::R3,
+        RegisterARM::R12,
+        RegisterARM::LR,
+        RegisterARM::PC,
+        RegisterARM::XPSR,
+    ];
+
     const CONTEXT_REGS_EXTENDED: [RegisterARM; 17] = [
-        RegisterARM::FPSCR,
-        RegisterARM::S15,
-        RegisterARM::S14,
-        RegisterARM::S13,
-        RegisterARM::S12,
-        RegisterARM::S11,
-        RegisterARM::S10,
-        RegisterARM::S9,
-        RegisterARM::S8,
-        RegisterARM::S7,
-        RegisterARM::S6,
-        RegisterARM::S5,
-        RegisterARM::S4,
-        RegisterARM::S3,
-        RegisterARM::S2,
-        RegisterARM::S1,
         RegisterARM::S0,
+        RegisterARM::S1,
+        RegisterARM::S2,
+        RegisterARM::S3,
+        RegisterARM::S4,
+        RegisterARM::S5,
+        RegisterARM::S6,
+        RegisterARM::S7,
+        RegisterARM::S8,
+        RegisterARM::S9,
+        RegisterARM::S10,
+        RegisterARM::S11,
+        RegisterARM::S12,
+        RegisterARM::S13,
+        RegisterARM::S14,
+        RegisterARM::S15,
+        RegisterARM::FPSCR,
     ];
 
-    const CONTEXT_REGS: [RegisterARM; 8] = [
-        RegisterARM::XPSR,
-        RegisterARM::PC,
-        RegisterARM::LR,
-        RegisterARM::R12,
-        RegisterARM::R3,
-        RegisterARM::R2,
-        RegisterARM::R1,
-        RegisterARM::R0,
-    ];
+    fn frame_size(fpca: bool) -> u32 {
+        if fpca { 4*26 } else { 4*8 }
+    }
 
-    fn push_regs(uc: &mut Unicorn<()>, spsel: bool, fpca: bool) {
-        let sp_reg = if spsel { RegisterARM::PSP } else { RegisterARM::MSP };
-        let mut sp = uc.reg_read(sp_reg).unwrap();
+    // xPSR bit 9: the stack was realigned to 8 bytes by adding a padding word
+    const XPSR_REALIGNED: u32 = 1 << 9;
 
-        let mut push_reg = |reg| {
-            let v = uc.reg_read(reg).unwrap() as u32;
-            //trace!("push sp=0x{:08x} {:5?}=0x{:08x}", sp, reg, v);
-            sp -= 4;
-            uc.mem_write(sp, &v.to_le_bytes()).expect("Invalid SP pointer during interrupt");
-        };
+    /// Returns the new SP
+    fn push_regs(uc: &mut Unicorn<()>, fpca: bool) -> u32 {
+        let sp = uc.reg_read(RegisterARM::SP).unwrap() as u32;
+        let frame_size = Self::frame_size(fpca);
 
+        let mut regs = Self::CONTEXT_REGS.to_vec();
         if fpca {
-            for reg in Self::CONTEXT_REGS_EXTENDED {
-                push_reg(reg);
-            }
+            regs.extend(Self::CONTEXT_REGS_EXTENDED);
         }
-        for reg in Self::CONTEXT_REGS {
-            push_reg(reg);
+
+        let mut frame = Vec::with_capacity(frame_size as usize);
+        for reg in regs {
+            let mut v = uc.reg_read(reg).unwrap() as u32;
+            if reg == RegisterARM::XPSR && sp & 4 != 0 {
+                v |= Self::XPSR_REALIGNED;
+            }
+            frame.extend_from_slice(&v.to_le_bytes());
         }
-        uc.reg_write(RegisterARM::SP, sp).unwrap();
+        frame.resize(frame_size as usize, 0);
+
+        let sp = (sp - frame_size) & !7;
+        uc.mem_write(sp as u64, &frame).expect("Invalid SP pointer during interrupt");
+        uc.reg_write(RegisterARM::SP, sp as u64).unwrap();
+        sp
     }
 
     fn pop_regs(uc: &mut Unicorn<()>, spsel: bool, fpca: bool) {
         let sp_reg = if spsel { RegisterARM::PSP } else { RegisterARM::MSP };
-        let mut sp = uc.reg_read(sp_reg).unwrap();
+        let sp = uc.reg_read(sp_reg).unwrap() as u32;
 
-        let mut pop_reg = |reg| {
-            let mut v = [0,0,0,0];
-            uc.mem_read(sp, &mut v).expect("Invalid SP pointer during interrupt return");
-            let v = u32::from_le_bytes(v);
-            //trace!("pop sp=0x{:08x} {:5?}=0x{:08x}", sp, reg, v);
+        let mut frame = vec![0; Self::frame_size(fpca) as usize];
+        uc.mem_read(sp as u64, &mut frame).expect("Invalid SP pointer during interrupt return");
+        let mut frame = frame.chunks_exact(4).map(|v| u32::from_le_bytes(v.try_into().unwrap()));
+
+        let mut values: Vec<_> = Self::CONTEXT_REGS.iter().zip(&mut frame).collect();
+        if fpca {
+            // The FP registers go first, as writing the xPSR must come last.
+            let fp_values = Self::CONTEXT_REGS_EXTENDED.iter().zip(&mut frame);
+            values.splice(0..0, fp_values);
+        }
+
+        let xpsr = values.last().unwrap().1;
+        let mut sp = sp + Self::frame_size(fpca);
+        if xpsr & Self::XPSR_REALIGNED != 0 {
             sp += 4;
-            uc.reg_write(reg, v as u64).unwrap();
-        };
+        }
+        uc.reg_write(sp_reg, sp as u64).unwrap();
 
-        for reg in Self::CONTEXT_REGS.iter().rev() {
-            pop_reg(*reg);
+        // Restoring the IPSR with the xPSR switches back to thread mode when
+        // returning from the last exception, and the stack pointer with it.
+        for (reg, v) in values {
+            let v = if *reg == RegisterARM::XPSR { v & !Self::XPSR_REALIGNED } else { v };
+            uc.reg_write(*reg, v as u64).unwrap();
         }
-        if fpca {
-            for reg in Self::CONTEXT_REGS_EXTENDED.iter().rev() {
-                pop_reg(*reg);
+    }
+
+    /// Reads 4 consecutive priorities
+    pub fn read_priorities(&self, exception: usize) -> u32 {
+        let mut v = [0; 4];
+        for (i, p) in v.iter_mut().enumerate() {
+            *p = self.priorities.get(exception + i).cloned().unwrap_or_default();
+        }
+        u32::from_le_bytes(v)
+    }
+
+    /// Writes 4 consecutive priorities. The firmware (FreeRTOS for one)
+    /// finds out the number of priority bits by writing 0xFF and reading back.
+    pub fn write_priorities(&mut self, exception: usize, value: u32) {
+        let mask = (0xFF00u32 >> self.priority_bits.unwrap_or(8).min(8)) as u8;
+        self.priorities.resize(NUM_EXCEPTIONS, 0);
+        for (i, p) in value.to_le_bytes().iter().enumerate() {
+            if let Some(priority) = self.priorities.get_mut(exception + i) {
+                if *priority != *p & mask && exception + i < IRQ_OFFSET as usize {
+                    trace!("System exception priority irq={} priority={}", (exception + i) as i32 - IRQ_OFFSET, *p & mask);
+                }
+                *priority = *p & mask;
             }
         }
-        uc.reg_write(RegisterARM::SP, sp).unwrap();
+    }
+
+    // The NVIC registers hold 32 external interrupts per word.
+    fn irq_word(bits: u128, offset: u32) -> u32 {
+        let shift = IRQ_OFFSET as u32 + 32*((offset & 0x1F) / 4);
+        bits.checked_shr(shift).unwrap_or_default() as u32
+    }
+
+    fn irq_word_mask(offset: u32, value: u32) -> u128 {
+        let shift = IRQ_OFFSET as u32 + 32*((offset & 0x1F) / 4);
+        (value as u128).checked_shl(shift).unwrap_or_default()
+    }
+
+    /// ISER, ICER, ISPR, ICPR set and clear the bits written
+    fn is_set_clear_reg(offset: u32) -> bool {
+        matches!(offset, 0x000..=0x01C | 0x080..=0x09C | 0x100..=0x11C | 0x180..=0x19C)
     }
 }
 
 impl Peripheral for Nvic {
-    fn read(&mut self, _sys: &System, _offset: u32) -> u32 {
-        0
+    fn has_side_effects(&self, offset: u32) -> bool {
+        Self::is_set_clear_reg(offset)
     }
 
-    fn write(&mut self, _sys: &System, _offset: u32, _value: u32) {
+    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x000..=0x01C | 0x080..=0x09C => Self::irq_word(self.enabled, offset),
+            0x100..=0x11C | 0x180..=0x19C => Self::irq_word(self.pending, offset),
+            0x200..=0x21C => Self::irq_word(self.active, offset),
+            0x300..=0x3EC => self.read_priorities(IRQ_OFFSET as usize + (offset - 0x300) as usize),
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, _sys: &System, offset: u32, value: u32) {
+        match offset {
+            // ISER
+            0x000..=0x01C => {
+                let mask = Self::irq_word_mask(offset, value);
+                if mask & !self.enabled != 0 {
+                    trace!("NVIC enable irqs mask={:#x}", mask >> IRQ_OFFSET);
+                }
+                self.enabled |= mask;
+            }
+            // ICER
+            0x080..=0x09C => self.enabled &= !Self::irq_word_mask(offset, value),
+            // ISPR
+            0x100..=0x11C => self.pending |= Self::irq_word_mask(offset, value),
+            // ICPR
+            0x180..=0x19C => self.pending &= !Self::irq_word_mask(offset, value),
+            // IPR
+            0x300..=0x3EC => self.write_priorities(IRQ_OFFSET as usize + (offset - 0x300) as usize, value),
+            // STIR, from the NVIC_STIR block
+            0xE00 => {
+                let irq = (value & 0x1FF) as i32;
+                if ((IRQ_OFFSET + irq) as usize) < NUM_EXCEPTIONS {
+                    self.set_intr_pending(irq);
+                }
+            }
+            _ => {}
+        }
     }
 }
 
 /// The next part is glue. Maybe we could have a better architecture.
 
-pub struct NvicWrapper;
+pub struct NvicWrapper {
+    // The NVIC_STIR block is described separately from the NVIC in the SVD
+    offset: u32,
+}
 
 impl NvicWrapper {
     pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
-        if name == "NVIC" {
-            Some(Box::new(Self))
-        } else {
-            None
+        match name {
+            "NVIC" => Some(Box::new(Self { offset: 0 })),
+            "NVIC_STIR" => Some(Box::new(Self { offset: 0xE00 })),
+            _ => None,
         }
     }
 }
 
 impl Peripheral for NvicWrapper {
+    fn has_side_effects(&self, offset: u32) -> bool {
+        Nvic::is_set_clear_reg(self.offset + offset)
+    }
+
     fn read(&mut self, sys: &System, offset: u32) -> u32 {
-        sys.p.nvic.borrow_mut().read(sys, offset)
+        sys.p.nvic.borrow_mut().read(sys, self.offset + offset)
     }
 
     fn write(&mut self, sys: &System, offset: u32, value: u32) {
-        sys.p.nvic.borrow_mut().write(sys, offset, value)
+        sys.p.nvic.borrow_mut().write(sys, self.offset + offset, value)
     }
 }
 
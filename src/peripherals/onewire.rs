@@ -0,0 +1,135 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use anyhow::{Result, bail};
+use serde::Deserialize;
+
+use crate::ext_devices::{ExtDevices, OneWireDevice};
+use crate::peripherals::gpio::{Pin, GpioPorts};
+use crate::system::System;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct OneWireConfig {
+    pub name: String,
+    pub pin: String,
+    /// Number of emulated cycles in a microsecond. The protocol is all about pulse durations.
+    pub cycles_per_us: u64,
+}
+
+// Durations in us
+const RESET_MIN_DURATION: u64 = 480;
+const WRITE_ONE_MAX_DURATION: u64 = 15;
+const PRESENCE_DELAY: u64 = 15;
+const PRESENCE_DURATION: u64 = 60;
+const READ_ZERO_DURATION: u64 = 45;
+
+#[derive(Default)]
+pub struct OneWire {
+    name: String,
+    cycles_per_us: u64,
+
+    // Level driven by the master (the firmware)
+    master_level: bool,
+    fall_time: u64,
+    // What the devices drive during the current time slot
+    slave_bit: bool,
+    presence: Option<(u64, u64)>,
+
+    ext_devices: Vec<Rc<RefCell<dyn OneWireDevice>>>,
+}
+
+fn now() -> u64 {
+    crate::context::now()
+}
+
+impl OneWire {
+    pub fn register(config: OneWireConfig, gpio: &mut GpioPorts, ext_devices: &ExtDevices) -> Result<()> {
+        if config.cycles_per_us == 0 {
+            bail!("1-Wire bus {}: cycles_per_us must be positive", config.name);
+        }
+
+        let pin = Pin::from_str(&config.pin);
+
+        let ext_devices = ext_devices.find_onewire_devices(&config.name);
+        for d in &ext_devices {
+            d.borrow_mut().connect_peripheral(&config.name);
+        }
+
+        let self_ = Rc::new(RefCell::new(Self {
+            name: config.name,
+            cycles_per_us: config.cycles_per_us,
+            master_level: true,
+            slave_bit: true,
+            ext_devices,
+            ..Default::default()
+        }));
+
+        let s = self_.clone();
+        gpio.add_write_callback(pin, move |sys, v| { s.borrow_mut().write(sys, v) });
+
+        let s = self_.clone();
+        gpio.add_open_drain_read_callback(pin, move |sys| { s.borrow_mut().read(sys) });
+
+        Ok(())
+    }
+
+    fn us(&self, us: u64) -> u64 {
+        us * self.cycles_per_us
+    }
+
+    pub fn write(&mut self, sys: &System, value: bool) {
+        let now = now();
+
+        if self.master_level && !value {
+            // Start of a time slot. Devices decide what they drive on the line.
+            self.fall_time = now;
+            self.presence = None;
+            // All devices must be asked, they track the time slots.
+            self.slave_bit = true;
+            for d in &self.ext_devices {
+                self.slave_bit &= d.borrow_mut().read(sys, ());
+            }
+        } else if !self.master_level && value {
+            let duration = now - self.fall_time;
+            if duration >= self.us(RESET_MIN_DURATION) {
+                let mut present = false;
+                for d in &self.ext_devices {
+                    present |= d.borrow_mut().reset(sys);
+                }
+                trace!("{} reset presence={}", self.name, present);
+                if present {
+                    let start = now + self.us(PRESENCE_DELAY);
+                    self.presence = Some((start, start + self.us(PRESENCE_DURATION)));
+                }
+            } else {
+                let master_bit = duration < self.us(WRITE_ONE_MAX_DURATION);
+                // The line is wired-AND
+                let bit = master_bit && self.slave_bit;
+                for d in &self.ext_devices {
+                    d.borrow_mut().write(sys, (), bit);
+                }
+            }
+        }
+
+        self.master_level = value;
+    }
+
+    pub fn read(&mut self, _sys: &System) -> bool {
+        let now = now();
+
+        if !self.master_level {
+            return false;
+        }
+
+        if let Some((start, end)) = self.presence {
+            if (start..end).contains(&now) {
+                return false;
+            }
+        }
+
+        // A device sending a 0 holds the line low for a little while
+        self.slave_bit || now >= self.fall_time + self.us(READ_ZERO_DURATION)
+    }
+}
@@ -0,0 +1,159 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::sync::atomic::Ordering;
+
+use crate::system::System;
+use super::Peripheral;
+
+// CR register bits
+const CR_PDDS: u32 = 1 << 1;
+const CR_CWUF: u32 = 1 << 2;
+const CR_CSBF: u32 = 1 << 3;
+const CR_ODEN: u32 = 1 << 16;
+const CR_ODSWEN: u32 = 1 << 17;
+
+// CSR register bits
+const CSR_WUF: u32 = 1 << 0;
+const CSR_SBF: u32 = 1 << 1;
+const CSR_BRR: u32 = 1 << 3;
+const CSR_EWUP: u32 = 1 << 8;
+const CSR_BRE: u32 = 1 << 9;
+const CSR_VOSRDY: u32 = 1 << 14;
+const CSR_ODRDY: u32 = 1 << 16;
+const CSR_ODSWRDY: u32 = 1 << 17;
+
+// SCB SCR bit 2
+const SCR_SLEEPDEEP: u32 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SleepMode {
+    Sleep,
+    Stop,
+    Standby,
+}
+
+/// Power controller, F1/F2/F4 layout. The regulators are always ready.
+#[derive(Default)]
+pub struct Pwr {
+    cr: u32,
+    csr: u32,
+}
+
+impl Pwr {
+    /// Called when the CPU halts on a WFI instruction. Instead of spinning, we
+    /// fast-forward the time to the next wakeup event. Returns false when
+    /// nothing can wake up the CPU.
+    pub fn wait_for_interrupt(sys: &System) -> bool {
+        let p = &sys.p;
+        let mode = if p.nvic.borrow().scr & SCR_SLEEPDEEP == 0 {
+            SleepMode::Sleep
+        } else if p.pwr.borrow().cr & CR_PDDS == 0 {
+            SleepMode::Stop
+        } else {
+            SleepMode::Standby
+        };
+
+        if mode == SleepMode::Standby {
+            // Only the wakeup pin and the RTC can get us out of standby. We don't emulate these.
+            info!("Entered standby mode, there's no wakeup source");
+            return false;
+        }
+
+        let ctx = crate::context::current();
+        let now = ctx.num_instructions.load(Ordering::Relaxed);
+        // The scripted comparator outputs and the peripheral ticks can raise interrupts as well
+        // The end of a co-simulation step too, the simulator may change the inputs
+        let wakeup = [p.nvic.borrow().next_wakeup(), p.comp.borrow().next_event_at(), p.next_tick(), crate::cosim::step_end(&ctx)];
+        let wakeup = match wakeup.into_iter().flatten().min() {
+            Some(wakeup) => wakeup.max(now),
+            None => {
+                info!("Entered mode={:?} with no pending interrupt and no SysTick, nothing can wake up the CPU", mode);
+                return false;
+            }
+        };
+
+        debug!("Entered mode={:?} skipping num_instructions={}", mode, wakeup - now);
+        ctx.num_instructions.store(wakeup, Ordering::Relaxed);
+
+        if mode == SleepMode::Stop {
+            // The PLLs and the HSE are stopped, and we wake up on the HSI
+            p.rcc.borrow_mut().enter_stop_mode();
+        }
+
+        p.tick(sys);
+        super::comp::Comparators::poll(p);
+        let mut nvic = p.nvic.borrow_mut();
+        nvic.maybe_set_systick_intr_pending();
+        if let Some(exception) = nvic.next_pending_exception() {
+            debug!("Wakeup mode={:?} exception={}", mode, exception);
+        }
+        p.pwr.borrow_mut().csr |= CSR_WUF;
+
+        true
+    }
+}
+
+impl Peripheral for Pwr {
+    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x0000 => self.cr & !(CR_CWUF | CR_CSBF),
+            0x0004 => {
+                let mut v = self.csr | CSR_VOSRDY;
+                if self.csr & CSR_BRE != 0 {
+                    v |= CSR_BRR;
+                }
+                if self.cr & CR_ODEN != 0 {
+                    v |= CSR_ODRDY;
+                }
+                if self.cr & CR_ODSWEN != 0 {
+                    v |= CSR_ODSWRDY;
+                }
+                v
+            }
+            _ => 0
+        }
+    }
+
+    fn write(&mut self, _sys: &System, offset: u32, value: u32) {
+        match offset {
+            0x0000 => {
+                if value & CR_CWUF != 0 {
+                    self.csr &= !CSR_WUF;
+                }
+                if value & CR_CSBF != 0 {
+                    self.csr &= !CSR_SBF;
+                }
+                self.cr = value;
+            }
+            0x0004 => {
+                let mask = CSR_EWUP | CSR_BRE;
+                self.csr = (self.csr & !mask) | (value & mask);
+            }
+            _ => {}
+        }
+    }
+}
+
+// Glue, similarly to the NVIC. The PWR state is needed when executing WFI.
+
+pub struct PwrWrapper;
+
+impl PwrWrapper {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "PWR" {
+            Some(Box::new(Self))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for PwrWrapper {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        sys.p.pwr.borrow_mut().read(sys, offset)
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        sys.p.pwr.borrow_mut().write(sys, offset, value)
+    }
+}
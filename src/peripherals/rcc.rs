@@ -1,39 +1,386 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+use svd_parser::svd::RegisterInfo;
+
 use crate::system::System;
 use super::Peripheral;
 
+#[derive(Debug, Deserialize, Default)]
+pub struct RccConfig {
+    /// External oscillator frequency in Hz. Defaults to 8MHz.
+    pub hse_freq: Option<u32>,
+    /// Internal oscillator frequency in Hz. Defaults to 16MHz, or 8MHz on the F0, F1 and F3.
+    pub hsi_freq: Option<u32>,
+    /// Accesses to a peripheral with its clock disabled in the xxxENR registers
+    /// are reported. When set, they also read as zero and writes are ignored, as on silicon.
+    pub gate_unclocked: Option<bool>,
+}
+
+/// A register field, located from the SVD file
+#[derive(Debug, Clone, Copy)]
+pub struct Field {
+    pub offset: u32,
+    pub shift: u32,
+    pub width: u32,
+}
+
+impl Field {
+    fn mask(&self) -> u32 {
+        (((1u64 << self.width) - 1) as u32) << self.shift
+    }
+
+    pub fn get(&self, value: u32) -> u32 {
+        (value & self.mask()) >> self.shift
+    }
+
+    fn set(&self, value: u32, field_value: u32) -> u32 {
+        (value & !self.mask()) | ((field_value << self.shift) & self.mask())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Clocks {
+    pub sysclk: u32,
+    pub hclk: u32,
+    pub pclk1: u32,
+    pub pclk2: u32,
+}
+
+/// How the system clock and the PLL are configured, which differs between families
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+enum ClockTree {
+    /// F0, F1, F3: PLLMUL in CFGR. SW: 0=HSI, 1=HSE, 2=PLL.
+    F1,
+    /// F2, F4, F7: PLLCFGR with PLLM, PLLN, PLLP. SW: 0=HSI, 1=HSE, 2=PLL.
+    F4,
+    /// L4, G4: PLLCFGR with PLLM+1, PLLN, PLLR. SW: 0=MSI, 1=HSI16, 2=HSE, 3=PLL.
+    L4,
+    /// G0: like the L4, with PLLR+1. SW: 0=HSISYS, 1=HSE, 2=PLL, 3=LSI, 4=LSE.
+    G0,
+    /// The others, like the H7 with its D1CFGR, or the L0 and L1
+    #[default]
+    Unsupported,
+}
+
+impl ClockTree {
+    fn from_device_name(name: &str) -> Self {
+        let name = name.to_uppercase();
+        let family = name.get(..7).unwrap_or_default();
+        match family {
+            "STM32F0" | "STM32F1" | "STM32F3" => Self::F1,
+            "STM32F2" | "STM32F4" | "STM32F7" => Self::F4,
+            "STM32L4" | "STM32G4" => Self::L4,
+            "STM32G0" => Self::G0,
+            _ => Self::Unsupported,
+        }
+    }
+}
+
+// The register layout differs between families, so we locate the fields we
+// care about from the SVD file. Writes are stored, and read back with the
+// ready flags of the enabled oscillators and PLLs set, and with the system
+// clock switch status reflecting the selected clock.
+#[derive(Default)]
 pub struct Rcc {
+    hse_freq: u32,
+    hsi_freq: u32,
+    clock_tree: ClockTree,
+    reset_values: HashMap<u32, u32>,
+    values: HashMap<u32, u32>,
+    // register name -> field name -> field
+    fields: HashMap<String, HashMap<String, Field>>,
+    // (XXXRDY, XXXON) pairs
+    ready_flags: Vec<(Field, Field)>,
+    clocks: Option<Clocks>,
+    // peripheral name -> clock enable field
+    clock_gates: HashMap<String, Field>,
+    gate_unclocked: bool,
+    reported_unclocked: HashSet<String>,
 }
 
 impl Rcc {
-    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
-        if name == "RCC" {
-            Some(Box::new(Rcc {}))
-        } else {
-            None
+    pub fn from_svd(config: RccConfig, registers: &[RegisterInfo], device_name: &str) -> Self {
+        let mut fields: HashMap<String, HashMap<String, Field>> = HashMap::new();
+        let mut reset_values = HashMap::new();
+
+        for r in registers {
+            reset_values.insert(r.address_offset, r.properties.reset_value.unwrap_or(0) as u32);
+            let reg_fields = fields.entry(r.name.clone()).or_default();
+            for f in r.fields() {
+                reg_fields.insert(f.name.clone(), Field {
+                    offset: r.address_offset,
+                    shift: f.bit_range.offset,
+                    width: f.bit_range.width,
+                });
+            }
+        }
+
+        // PLLRDY goes with PLLON, HSERDY with HSEON, etc.
+        let ready_flags = fields.values()
+            .flat_map(|reg_fields| reg_fields.iter()
+                .filter_map(|(name, rdy)| {
+                    let prefix = name.strip_suffix("RDY")?;
+                    reg_fields.get(&format!("{}ON", prefix)).map(|on| (*rdy, *on))
+                })
+            ).collect();
+
+        // GPIOAEN in AHB1ENR gates GPIOA, etc. The low power variants (LPENR, SMENR) don't count.
+        // The F1 names its GPIO bits IOPAEN, IOPBEN, etc.
+        let clock_gates = fields.iter()
+            .filter(|(reg, _)| reg.ends_with("ENR") && !reg.ends_with("LPENR") && !reg.ends_with("SMENR"))
+            .flat_map(|(_, reg_fields)| reg_fields.iter()
+                .filter_map(|(name, field)| {
+                    let peripheral = name.strip_suffix("EN").filter(|p| !p.is_empty())?;
+                    let peripheral = match peripheral.strip_prefix("IOP") {
+                        Some(port) if port.len() == 1 => format!("GPIO{}", port),
+                        _ => peripheral.to_string(),
+                    };
+                    Some((peripheral, *field))
+                })
+            ).collect();
+
+        let clock_tree = ClockTree::from_device_name(device_name);
+        let hsi_freq = config.hsi_freq.unwrap_or(if clock_tree == ClockTree::F1 { 8_000_000 } else { 16_000_000 });
+        if clock_tree == ClockTree::Unsupported {
+            warn!("The clock tree of device={} isn't supported, the timings follow the HSI at {}Hz", device_name, hsi_freq);
         }
+
+        let mut self_ = Self {
+            hse_freq: config.hse_freq.unwrap_or(8_000_000),
+            hsi_freq,
+            clock_tree,
+            reset_values,
+            fields,
+            ready_flags,
+            clock_gates,
+            gate_unclocked: config.gate_unclocked.unwrap_or_default(),
+            ..Default::default()
+        };
+        self_.reset();
+        self_
     }
-}
 
+    pub fn reset(&mut self) {
+        self.values = self.reset_values.clone();
+        self.clocks = None;
+        self.reported_unclocked.clear();
+    }
+
+    pub fn field(&self, reg: &str, field: &str) -> Option<Field> {
+        self.fields.get(reg).and_then(|f| f.get(field)).copied()
+    }
+
+    fn read_field(&self, reg: &str, field: &str) -> Option<u32> {
+        self.field(reg, field).map(|f| f.get(self.reg_value(f.offset)))
+    }
+
+    fn reg_value(&self, offset: u32) -> u32 {
+        self.values.get(&offset).copied().unwrap_or(0)
+    }
+
+    fn pll_freq(&self) -> Option<u32> {
+        let hse_freq = self.hse_freq as u64;
+        let hsi_freq = self.hsi_freq as u64;
+
+        let freq = match self.clock_tree {
+            ClockTree::F1 => {
+                // SYSCLK = src * PLLMUL
+                let mul = (self.read_field("CFGR", "PLLMUL")? as u64 + 2).min(16);
+                let src = if self.read_field("CFGR", "PLLSRC")? != 0 {
+                    hse_freq >> self.read_field("CFGR", "PLLXTPRE").unwrap_or(0)
+                } else {
+                    hsi_freq / 2
+                };
+                src * mul
+            }
+            ClockTree::F4 => {
+                // VCO = src / PLLM * PLLN, SYSCLK = VCO / PLLP
+                let src = if self.read_field("PLLCFGR", "PLLSRC")? != 0 { hse_freq } else { hsi_freq };
+                let pllm = self.read_field("PLLCFGR", "PLLM")? as u64;
+                let plln = self.read_field("PLLCFGR", "PLLN")? as u64;
+                let pllp = 2 * (self.read_field("PLLCFGR", "PLLP")? as u64 + 1);
+                src / pllm.max(1) * plln / pllp
+            }
+            ClockTree::L4 | ClockTree::G0 => {
+                // VCO = src / (PLLM + 1) * PLLN, SYSCLK = VCO / PLLR
+                let src = match self.read_field("PLLCFGR", "PLLSRC")? {
+                    1 if self.clock_tree == ClockTree::L4 => self.msi_freq()? as u64,
+                    2 => hsi_freq,
+                    3 => hse_freq,
+                    _ => return None,
+                };
+                let pllm = self.read_field("PLLCFGR", "PLLM")? as u64 + 1;
+                let plln = self.read_field("PLLCFGR", "PLLN")? as u64;
+                let pllr = self.read_field("PLLCFGR", "PLLR")? as u64;
+                let pllr = if self.clock_tree == ClockTree::L4 { 2 * (pllr + 1) } else { pllr + 1 };
+                src / pllm * plln / pllr
+            }
+            ClockTree::Unsupported => return None,
+        };
+
+        Some(freq as u32)
+    }
+
+    /// The L4 MSI, following MSIRANGE once selected with MSIRGSEL. 4MHz out of reset.
+    fn msi_freq(&self) -> Option<u32> {
+        const RANGES: [u32; 12] = [
+            100_000, 200_000, 400_000, 800_000, 1_000_000, 2_000_000,
+            4_000_000, 8_000_000, 16_000_000, 24_000_000, 32_000_000, 48_000_000,
+        ];
+        let range = match self.read_field("CR", "MSIRGSEL") {
+            Some(1) => self.read_field("CR", "MSIRANGE")?,
+            _ => 6,
+        };
+        RANGES.get(range as usize).copied()
+    }
+
+    /// The CPU clock frequency. The HSI when the clock tree is unknown.
+    pub fn hclk(&self) -> u32 {
+        self.clocks().map(|c| c.hclk).filter(|f| *f != 0).unwrap_or(self.hsi_freq)
+    }
+
+    pub fn clocks(&self) -> Option<Clocks> {
+        let sw = self.read_field("CFGR", "SW")?;
+        let sysclk = match (self.clock_tree, sw) {
+            (ClockTree::F1 | ClockTree::F4, 0) => self.hsi_freq,
+            (ClockTree::F1 | ClockTree::F4, 1) => self.hse_freq,
+            (ClockTree::F1 | ClockTree::F4, 2) => self.pll_freq()?,
+            (ClockTree::L4, 0) => self.msi_freq()?,
+            (ClockTree::L4, 1) => self.hsi_freq,
+            (ClockTree::L4, 2) => self.hse_freq,
+            (ClockTree::L4, 3) => self.pll_freq()?,
+            // HSISYS is the HSI16 divided by HSIDIV
+            (ClockTree::G0, 0) => self.hsi_freq >> self.read_field("CR", "HSIDIV").unwrap_or(0),
+            (ClockTree::G0, 1) => self.hse_freq,
+            (ClockTree::G0, 2) => self.pll_freq()?,
+            (ClockTree::G0, 3) => 32_000,
+            (ClockTree::G0, 4) => 32_768,
+            _ => return None,
+        };
+
+        let hpre = self.read_field("CFGR", "HPRE").unwrap_or(0);
+        let hclk = match hpre {
+            0..=7 => sysclk,
+            // 2, 4, 8, 16, then 64, 128, 256, 512. There's no divide by 32.
+            8..=11 => sysclk >> (hpre - 7),
+            _ => sysclk >> (hpre - 6),
+        };
+
+        let apb = |ppre: Option<u32>| match ppre.unwrap_or(0) {
+            ppre @ 4..=7 => hclk >> (ppre - 3),
+            _ => hclk,
+        };
+
+        // The G0 has a single APB, with PPRE
+        let ppre = self.read_field("CFGR", "PPRE");
+        Some(Clocks {
+            sysclk,
+            hclk,
+            pclk1: apb(self.read_field("CFGR", "PPRE1").or(ppre)),
+            pclk2: apb(self.read_field("CFGR", "PPRE2").or(ppre)),
+        })
+    }
+
+    /// Entering stop mode turns off the PLLs and the HSE. The HSI becomes the
+    /// system clock, or the MSI on the L4.
+    pub fn enter_stop_mode(&mut self) {
+        for (reg, field) in [("CR", "HSEON"), ("CR", "PLLON"), ("CFGR", "SW")] {
+            if let Some(f) = self.field(reg, field) {
+                let v = f.set(self.reg_value(f.offset), 0);
+                self.values.insert(f.offset, v);
+            }
+        }
+        self.update_clocks();
+    }
+
+    pub fn has_clock_gates(&self) -> bool {
+        !self.clock_gates.is_empty()
+    }
+
+    /// Returns true when the access to the peripheral should go through
+    pub fn check_access(&mut self, peripheral: &str) -> bool {
+        let enabled = match self.clock_gates.get(peripheral) {
+            Some(field) => field.get(self.reg_value(field.offset)) != 0,
+            None => return true,
+        };
+
+        if !enabled && self.reported_unclocked.insert(peripheral.to_string()) {
+            warn!("Access to peripheral={} with its clock disabled", peripheral);
+        }
+
+        enabled || !self.gate_unclocked
+    }
+
+    fn update_clocks(&mut self) {
+        let clocks = self.clocks();
+        if clocks != self.clocks {
+            if let Some(c) = clocks {
+                info!("Clock tree sysclk={}Hz hclk={}Hz pclk1={}Hz pclk2={}Hz",
+                    c.sysclk, c.hclk, c.pclk1, c.pclk2);
+            }
+            self.clocks = clocks;
+        }
+    }
+}
 
 impl Peripheral for Rcc {
     fn read(&mut self, _sys: &System, offset: u32) -> u32 {
-        match offset {
-            0x0000 => {
-                // CR register
-                // Return all the r to true. This is where the PLL ready flags are.
-                //0b0010_0000_0010_0000_0000_0000_0010
-                0xFFFF_FFFF
+        if self.fields.is_empty() {
+            // The SVD file has no fields. Return all the ready flags to true,
+            // and the PLL as the system clock.
+            return match offset {
+                0x0000 => 0xFFFF_FFFF,
+                0x0008 => 0b1000,
+                _ => 0,
+            };
+        }
+
+        let mut value = self.reg_value(offset);
+
+        for (rdy, on) in &self.ready_flags {
+            if rdy.offset == offset {
+                value = rdy.set(value, on.get(self.reg_value(on.offset)));
             }
-            0x0008 => {
-                // CFGR register
-                0b1000
+        }
+
+        if let (Some(sws), Some(sw)) = (self.field("CFGR", "SWS"), self.field("CFGR", "SW")) {
+            if sws.offset == offset {
+                value = sws.set(value, sw.get(self.reg_value(sw.offset)));
             }
-            _ => 0
         }
+
+        value
+    }
+
+    fn write(&mut self, _sys: &System, offset: u32, value: u32) {
+        self.values.insert(offset, value);
+        self.update_clocks();
+    }
+}
+
+// Glue, similarly to the NVIC. Other peripherals need to know the clock configuration.
+
+pub struct RccWrapper;
+
+impl RccWrapper {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "RCC" {
+            Some(Box::new(Self))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for RccWrapper {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        sys.p.rcc.borrow_mut().read(sys, offset)
     }
 
-    fn write(&mut self, _sys: &System, _offset: u32, _value: u32) {
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        sys.p.rcc.borrow_mut().write(sys, offset, value)
     }
 }
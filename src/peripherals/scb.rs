@@ -1,5 +1,9 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::sync::atomic::Ordering;
+
+use unicorn_engine::RegisterARM;
+
 use crate::system::System;
 use super::{Peripheral, nvic::irq};
 
@@ -18,23 +22,111 @@ impl Scb {
 }
 
 impl Peripheral for Scb {
-    fn read(&mut self, _sys: &System, _offset: u32) -> u32 {
-        0
+    fn has_side_effects(&self, offset: u32) -> bool {
+        // ICSR sets and clears pending bits, CFSR and HFSR are write-1-to-clear
+        matches!(offset, 0x0004 | 0x0028 | 0x002C)
+    }
+
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        let nvic = sys.p.nvic.borrow();
+        match offset {
+            0x0004 => {
+                // ICSR register
+                // bits 8:0: VECTACTIVE
+                // bits 20:12: VECTPENDING
+                // bit 22: ISRPENDING
+                // bit 26: systick pending
+                // bit 28: PendSV pending
+                let mut v = sys.uc.borrow().reg_read(RegisterARM::IPSR).unwrap() as u32 & 0x1FF;
+                if let Some(exception) = nvic.next_pending_exception() {
+                    v |= (exception as u32) << 12;
+                    if exception >= 16 {
+                        v |= 1 << 22;
+                    }
+                }
+                if nvic.is_intr_pending(irq::SYSTICK) {
+                    v |= 1 << 26;
+                }
+                if nvic.is_intr_pending(irq::PENDSV) {
+                    v |= 1 << 28;
+                }
+                v
+            }
+            // AIRCR register
+            0x000C => (0xFA05 << 16) | (nvic.prigroup << 8),
+            // SCR register
+            0x0010 => nvic.scr,
+            // SHPR1-3 registers, priorities of the system exceptions 4 to 15
+            0x0018..=0x0020 => nvic.read_priorities(4 + (offset - 0x18) as usize),
+            0x0024 => nvic.shcsr,
+            0x0028 => nvic.cfsr,
+            0x002C => nvic.hfsr,
+            0x0034 => nvic.mmfar,
+            0x0038 => nvic.bfar,
+            // CPACR register, when the SVD file doesn't have it in a FPU_CPACR block
+            0x0088 => nvic.fpu.cpacr,
+            _ => 0
+        }
     }
 
     fn write(&mut self, sys: &System, offset: u32, value: u32) {
         match offset {
             0x0004 => {
                 // ICSR register
+                // bit 25: clear systick pending
                 // bit 26: set systick pending
+                // bit 27: clear PendSV pending
                 // bit 28: set PendSV pending
+                let mut nvic = sys.p.nvic.borrow_mut();
+                if value & (1 << 25) != 0 {
+                    nvic.clear_intr_pending(irq::SYSTICK);
+                }
                 if value & (1 << 26) != 0 {
-                    sys.p.nvic.borrow_mut().set_intr_pending(irq::SYSTICK);
+                    nvic.set_intr_pending(irq::SYSTICK);
+                }
+                if value & (1 << 27) != 0 {
+                    nvic.clear_intr_pending(irq::PENDSV);
                 }
                 if value & (1 << 28) != 0 {
-                    sys.p.nvic.borrow_mut().set_intr_pending(irq::PENDSV);
+                    nvic.set_intr_pending(irq::PENDSV);
                 }
             }
+            // AIRCR register
+            // bits 31:16: VECTKEY. Writes are ignored unless it's 0x05FA
+            // bits 10:8: PRIGROUP
+            // bit 2: SYSRESETREQ
+            0x000C if value >> 16 == 0x05FA => {
+                sys.p.nvic.borrow_mut().prigroup = (value >> 8) & 0b111;
+                if value & (1 << 2) != 0 {
+                    info!("System reset requested");
+                    crate::context::current().reset_requested.store(true, Ordering::Release);
+                    sys.uc.borrow_mut().emu_stop().unwrap();
+                }
+            }
+            0x0010 => {
+                // SCR register
+                sys.p.nvic.borrow_mut().scr = value;
+            }
+            0x0018..=0x0020 => {
+                // SHPR1-3 registers
+                sys.p.nvic.borrow_mut().write_priorities(4 + (offset - 0x18) as usize, value);
+            }
+            0x0024 => {
+                // SHCSR register
+                sys.p.nvic.borrow_mut().shcsr = value;
+            }
+            0x0028 => {
+                // CFSR register. Write 1 to clear.
+                sys.p.nvic.borrow_mut().cfsr &= !value;
+            }
+            0x002C => {
+                // HFSR register. Write 1 to clear.
+                sys.p.nvic.borrow_mut().hfsr &= !value;
+            }
+            0x0088 => {
+                // CPACR register
+                sys.p.nvic.borrow_mut().fpu.cpacr = value;
+            }
             _ => {}
         }
     }
@@ -0,0 +1,126 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::HashMap, rc::Rc};
+
+use anyhow::{Context, Result, bail};
+use serde::Deserialize;
+use svd_parser::svd::RegisterInfo;
+
+use crate::system::System;
+use super::Peripheral;
+
+// A peripheral defined in the config, to stub peripherals that are not
+// implemented. Registers that are not listed keep what is written to them.
+//
+//  scripted:
+//    - name: HASH
+//      registers:
+//        - reg: SR         # or offset: 0x24
+//          value: 0x1      # constant, unless storage is set
+//        - reg: DOUT
+//          increment: 1    # the value increments on each read
+//        - offset: 0x04
+//          toggle: 0x2     # the bits of the mask flip every N reads
+//          every: 10
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ScriptedPeripheralConfig {
+    pub name: String,
+    pub registers: Vec<ScriptedRegisterConfig>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct ScriptedRegisterConfig {
+    pub offset: Option<u32>,
+    /// Alternative to offset, a register name of the SVD file
+    pub reg: Option<String>,
+    /// Initial value. 0 by default.
+    pub value: Option<u32>,
+    /// Added to the value after each read
+    pub increment: Option<u32>,
+    /// Bits to flip every `every` reads
+    pub toggle: Option<u32>,
+    pub every: Option<u32>,
+    /// Writes are stored, and read back
+    pub storage: Option<bool>,
+}
+
+impl ScriptedPeripheralConfig {
+    /// Turns the register names into offsets
+    pub fn resolve_offsets(&mut self, registers: &[RegisterInfo]) -> Result<()> {
+        for r in &mut self.registers {
+            match (r.offset, r.reg.as_ref()) {
+                (Some(_), None) => {}
+                (None, Some(reg)) => {
+                    let offset = registers.iter()
+                        .find(|info| &info.name == reg)
+                        .map(|info| info.address_offset)
+                        .with_context(|| format!("Register {} not found in peripheral {}", reg, self.name))?;
+                    r.offset = Some(offset);
+                }
+                _ => bail!("Scripted registers of {} must have exactly one of offset or reg", self.name),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct RegisterState {
+    value: u32,
+    num_reads: u32,
+}
+
+pub struct ScriptedPeripheral {
+    config: Rc<ScriptedPeripheralConfig>,
+    registers: HashMap<u32, RegisterState>,
+}
+
+impl ScriptedPeripheral {
+    pub fn new(config: Rc<ScriptedPeripheralConfig>) -> Box<dyn Peripheral> {
+        let registers = config.registers.iter()
+            .map(|r| (r.offset.unwrap(), RegisterState { value: r.value.unwrap_or_default(), num_reads: 0 }))
+            .collect();
+        Box::new(Self { config, registers })
+    }
+
+    fn register_config(&self, offset: u32) -> Option<&ScriptedRegisterConfig> {
+        self.config.registers.iter().find(|r| r.offset == Some(offset))
+    }
+}
+
+impl Peripheral for ScriptedPeripheral {
+    fn has_side_effects(&self, offset: u32) -> bool {
+        self.register_config(offset).is_some_and(|r| r.increment.is_some() || r.toggle.is_some())
+    }
+
+    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        let config = self.config.clone();
+        let state = self.registers.entry(offset).or_default();
+        let value = state.value;
+
+        if let Some(r) = config.registers.iter().find(|r| r.offset == Some(offset)) {
+            if let Some(increment) = r.increment {
+                state.value = state.value.wrapping_add(increment);
+            }
+            if let Some(toggle) = r.toggle {
+                state.num_reads += 1;
+                if state.num_reads >= r.every.unwrap_or(1) {
+                    state.num_reads = 0;
+                    state.value ^= toggle;
+                }
+            }
+        }
+
+        value
+    }
+
+    fn write(&mut self, _sys: &System, offset: u32, value: u32) {
+        let storage = self.register_config(offset)
+            .map(|r| r.storage.unwrap_or_default())
+            .unwrap_or(true);
+        if storage {
+            self.registers.entry(offset).or_default().value = value;
+        }
+    }
+}
@@ -1,55 +1,558 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use crate::{system::System, ext_devices::ExtDevice};
+use crate::{system::System, ext_devices::{ChipSelect, SpiBusDevice, SpiMaster}, family::Layout};
 use super::Peripheral;
 
 use crate::ext_devices::ExtDevices;
 
-use std::{rc::Rc, cell::RefCell};
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
 
+// CR1 register bits
+const CR1_CPHA: u32 = 1 << 0;
+const CR1_CPOL: u32 = 1 << 1;
+const CR1_MSTR: u32 = 1 << 2;
+const CR1_BR_SHIFT: u32 = 3;
+const CR1_SPE: u32 = 1 << 6;
+const CR1_LSBFIRST: u32 = 1 << 7;
+const CR1_SSM: u32 = 1 << 9;
+// Only in the F4 layout
+const CR1_DFF: u32 = 1 << 11;
+
+// CR2 register bits
+const CR2_RXDMAEN: u32 = 1 << 0;
+const CR2_SSOE: u32 = 1 << 2;
+const CR2_ERRIE: u32 = 1 << 5;
+const CR2_RXNEIE: u32 = 1 << 6;
+const CR2_TXEIE: u32 = 1 << 7;
+// Only in the F7 layout
+const CR2_DS_SHIFT: u32 = 8;
+const CR2_FRXTH: u32 = 1 << 12;
+
+// SR register bits
+const SR_RXNE: u32 = 1 << 0;
+const SR_TXE: u32 = 1 << 1;
+const SR_OVR: u32 = 1 << 6;
+const SR_BSY: u32 = 1 << 7;
+// Only in the F7 layout
+const SR_FRLVL_SHIFT: u32 = 9;
+const SR_FTLVL_SHIFT: u32 = 11;
+
+// H7 register bits
+const H7_CR1_SPE: u32 = 1 << 0;
+const H7_CR1_CSTART: u32 = 1 << 9;
+const H7_CFG1_RXDMAEN: u32 = 1 << 14;
+const H7_CFG1_MBR_SHIFT: u32 = 28;
+const H7_CFG2_MASTER: u32 = 1 << 22;
+const H7_CFG2_LSBFRST: u32 = 1 << 23;
+const H7_CFG2_CPHA: u32 = 1 << 24;
+const H7_CFG2_CPOL: u32 = 1 << 25;
+const H7_CFG2_SSM: u32 = 1 << 26;
+const H7_CFG2_SSOE: u32 = 1 << 29;
+// Same bits in IER, SR, and IFCR
+const H7_SR_RXP: u32 = 1 << 0;
+const H7_SR_TXP: u32 = 1 << 1;
+const H7_SR_DXP: u32 = 1 << 2;
+const H7_SR_EOT: u32 = 1 << 3;
+const H7_SR_TXTF: u32 = 1 << 4;
+const H7_SR_OVR: u32 = 1 << 6;
+const H7_SR_TXC: u32 = 1 << 12;
+const H7_SR_RXPLVL_SHIFT: u32 = 13;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reg {
+    Cr1,
+    Cr2,
+    Sr,
+    Dr,
+    // Only in the H7 layout
+    Cfg1,
+    Cfg2,
+    Ier,
+    Ifcr,
+    Txdr,
+    Rxdr,
+}
+
+// Devices are byte oriented and MSB first. Frames are bit reversed for
+// LSBFIRST, and 16-bit frames are sent as two bytes. The clock polarity and
+// phase don't change the bytes exchanged, we just log them.
+// The bytes are exchanged with the devices as soon as DR is written, but the
+// frames take their time on the bus: the data size times the baud rate
+// prescaler of the APB clock. A frame written while another one is shifted
+// out waits in the TX buffer, clearing TXE, and BSY stays set until the last
+// one is out. The received frames show up when their transfer ends, and wait
+// in the RX buffer until read (one frame on the F4, a FIFO on the F7 and H7),
+// a frame received while it's full is lost, and OVR is set. With RXDMAEN,
+// the frames are queued for the DMA instead, which pulls them as they come.
+// A TX DMA transfer completes right away, its frames are then shifted out.
+// On the H7, EOT is set once the TSIZE frames of the transfer are received.
+// In slave mode, an spi_master device clocks the frames instead. The written
+// frames wait until clocked out, and TXE is set once they are all gone.
 #[derive(Default)]
 pub struct Spi {
     pub name: String,
+    layout: Layout,
     pub cr1: u32,
+    pub cr2: u32,
+    cfg1: u32,
+    cfg2: u32,
+    ier: u32,
+    // The last frame read
     pub rx_buffer: u32,
-    pub ready_toggle: bool,
-    pub ext_device: Option<Rc<RefCell<dyn ExtDevice<(), u8>>>>,
+    rx: VecDeque<u32>,
+    ovr: bool,
+    // OVR is cleared by reading DR, then SR
+    ovr_clear_armed: bool,
+    // Frames exchanged since CSTART, and end of transfer flags, on the H7
+    num_frames: u32,
+    eot: bool,
+    irq: Option<i32>,
+    pub devices: Vec<SpiBusDevice>,
+    master: Option<Rc<RefCell<SpiMaster>>>,
+    // Frames waiting for the master to clock them, in slave mode
+    tx: VecDeque<u32>,
+    // The frames on the bus in master mode, as (start, end) emulated times.
+    // Those not started yet are in the TX buffer.
+    shifting: VecDeque<(u64, u64)>,
+    // The received frames, with the end of their transfer
+    rx_pending: VecDeque<(u64, u32)>,
+    // On APB2 instead of APB1
+    is_apb2: bool,
 }
 
 impl Spi {
-    pub fn new(name: &str, ext_devices: &ExtDevices) -> Option<Box<dyn Peripheral>> {
+    pub fn new(name: &str, layout: Layout, ext_devices: &ExtDevices, irq: Option<i32>) -> Option<Box<dyn Peripheral>> {
         if name.starts_with("SPI") {
-            let ext_device = ext_devices.find_serial_device(name);
-            let name = ext_device.as_ref()
-                .map(|d| d.borrow_mut().connect_peripheral(name))
-                .unwrap_or_else(|| name.to_string());
-            Some(Box::new(Self { name, ext_device, ..Default::default() }))
+            let devices = ext_devices.find_spi_devices(name);
+            let master = ext_devices.find_spi_master(name);
+            let names = devices.iter()
+                .map(|d| d.device.borrow_mut().connect_peripheral(name))
+                .chain(master.iter().map(|m| m.borrow_mut().connect_peripheral(name)))
+                .collect::<Vec<_>>();
+            let is_apb2 = matches!(name, "SPI1" | "SPI4" | "SPI5" | "SPI6");
+            let name = match names.len() {
+                0 => name.to_string(),
+                1 => names[0].clone(),
+                _ => format!("{} ({})", name, names.join(", ")),
+            };
+            // Reset values, with 8-bit frames
+            let (cr2, cfg1) = match layout {
+                Layout::F7 => (0x0000_0700, 0),
+                Layout::H7 => (0, 0x0007_0007),
+                _ => (0, 0),
+            };
+            Some(Box::new(Self { name, layout, cr2, cfg1, devices, master, irq, is_apb2, ..Default::default() }))
         } else {
             None
         }
     }
 
+    fn now() -> u64 {
+        crate::context::now()
+    }
+
+    fn reg(&self, offset: u32) -> Option<Reg> {
+        let reg = if self.layout == Layout::H7 {
+            match offset {
+                0x0000 => Reg::Cr1,
+                0x0004 => Reg::Cr2,
+                0x0008 => Reg::Cfg1,
+                0x000C => Reg::Cfg2,
+                0x0010 => Reg::Ier,
+                0x0014 => Reg::Sr,
+                0x0018 => Reg::Ifcr,
+                0x0020 => Reg::Txdr,
+                0x0030 => Reg::Rxdr,
+                _ => return None,
+            }
+        } else {
+            match offset {
+                0x0000 => Reg::Cr1,
+                0x0004 => Reg::Cr2,
+                0x0008 => Reg::Sr,
+                0x000C => Reg::Dr,
+                _ => return None,
+            }
+        };
+        Some(reg)
+    }
+
+    /// Frame size, in bits
+    fn data_size(&self) -> u32 {
+        match self.layout {
+            Layout::F1 | Layout::F4 => if self.cr1 & CR1_DFF != 0 { 16 } else { 8 },
+            Layout::F7 => ((self.cr2 >> CR2_DS_SHIFT) & 0xF) + 1,
+            Layout::H7 => (self.cfg1 & 0x1F) + 1,
+        }
+    }
+
     pub fn is_16bits(&self) -> bool {
-        self.cr1 & (1 << 11) != 0
+        self.data_size() > 8
+    }
+
+    fn is_enabled(&self) -> bool {
+        match self.layout {
+            Layout::H7 => self.cr1 & H7_CR1_SPE != 0,
+            _ => self.cr1 & CR1_SPE != 0,
+        }
+    }
+
+    fn is_lsb_first(&self) -> bool {
+        match self.layout {
+            Layout::H7 => self.cfg2 & H7_CFG2_LSBFRST != 0,
+            _ => self.cr1 & CR1_LSBFIRST != 0,
+        }
+    }
+
+    /// CPOL*2 + CPHA
+    fn mode(&self) -> u8 {
+        let (cpol, cpha) = match self.layout {
+            Layout::H7 => (self.cfg2 & H7_CFG2_CPOL != 0, self.cfg2 & H7_CFG2_CPHA != 0),
+            _ => (self.cr1 & CR1_CPOL != 0, self.cr1 & CR1_CPHA != 0),
+        };
+        cpol as u8 * 2 + cpha as u8
+    }
+
+    fn is_rx_dma(&self) -> bool {
+        match self.layout {
+            Layout::H7 => self.cfg1 & H7_CFG1_RXDMAEN != 0,
+            _ => self.cr2 & CR2_RXDMAEN != 0,
+        }
+    }
+
+    fn is_master(&self) -> bool {
+        match self.layout {
+            Layout::H7 => self.cfg2 & H7_CFG2_MASTER != 0,
+            _ => self.cr1 & CR1_MSTR != 0,
+        }
+    }
+
+    /// In slave mode, with an spi_master device
+    fn is_clocked_by_master(&self) -> bool {
+        self.master.is_some() && self.is_enabled() && !self.is_master()
+    }
+
+    /// The NSS pin is driven low by a master with SSOE set, while enabled
+    fn is_nss_active(&self) -> bool {
+        let (ssm, ssoe) = match self.layout {
+            Layout::H7 => (self.cfg2 & H7_CFG2_SSM != 0, self.cfg2 & H7_CFG2_SSOE != 0),
+            _ => (self.cr1 & CR1_SSM != 0, self.cr2 & CR2_SSOE != 0),
+        };
+        self.is_enabled() && self.is_master() && !ssm && ssoe
+    }
+
+    fn is_selected(&self, d: &SpiBusDevice) -> bool {
+        match &d.cs {
+            ChipSelect::Always => true,
+            ChipSelect::Pin(high) => !high.get(),
+            ChipSelect::Nss => self.is_nss_active(),
+        }
+    }
+
+    /// Writes a configuration register, and notifies the devices selected with NSS
+    fn write_config(&mut self, sys: &System, reg: Reg, value: u32) {
+        let was_enabled = self.is_enabled();
+        let nss_active = self.is_nss_active();
+        match reg {
+            Reg::Cr1 => {
+                if self.layout == Layout::H7 && value & H7_CR1_CSTART != 0 && self.cr1 & H7_CR1_CSTART == 0 {
+                    self.num_frames = 0;
+                    self.eot = false;
+                }
+                self.cr1 = value;
+            }
+            Reg::Cr2 => self.cr2 = value,
+            Reg::Cfg1 => self.cfg1 = value,
+            Reg::Cfg2 => self.cfg2 = value,
+            _ => unreachable!(),
+        }
+
+        if self.is_enabled() && !was_enabled {
+            debug!("{} enabled mode={} lsb_first={} data_size={}", self.name,
+                self.mode(), self.is_lsb_first(), self.data_size());
+            if self.data_size() != 8 && self.data_size() != 16 {
+                warn!("{} data_size={} is not supported", self.name, self.data_size());
+            }
+            if self.is_clocked_by_master() {
+                debug!("{} slave clocked by the spi-master", self.name);
+                self.schedule_master(sys);
+            }
+        }
+
+        if nss_active != self.is_nss_active() {
+            for d in self.devices.iter().filter(|d| matches!(d.cs, ChipSelect::Nss)) {
+                d.device.borrow_mut().select(sys, !nss_active);
+            }
+        }
+    }
+
+    /// Exchanges a byte with the selected devices. Devices that aren't
+    /// driving MISO return 0.
+    fn transfer_byte(&self, sys: &System, v: u8) -> u8 {
+        if let Some(ref mut expect) = *sys.p.expect.borrow_mut() {
+            expect.on_spi(&self.name, v);
+        }
+        let mut rx = 0;
+        for d in self.devices.iter().filter(|d| self.is_selected(d)) {
+            let mut d = d.device.borrow_mut();
+            rx |= d.read(sys, ());
+            d.write(sys, (), v);
+        }
+        rx
+    }
+
+    fn frame_bytes(&self) -> usize {
+        if self.is_16bits() { 2 } else { 1 }
+    }
+
+    /// Number of frames the RX buffer holds
+    fn rx_capacity(&self) -> usize {
+        match self.layout {
+            Layout::F1 | Layout::F4 => 1,
+            // 32-bit FIFO
+            Layout::F7 => 4 / self.frame_bytes(),
+            // At least 8 bytes, depending on the instance
+            Layout::H7 => 8 / self.frame_bytes(),
+        }
+    }
+
+    /// Duration of a frame on the bus, in CPU cycles
+    fn frame_time(&self, sys: &System) -> u64 {
+        let br = match self.layout {
+            Layout::H7 => (self.cfg1 >> H7_CFG1_MBR_SHIFT) & 0b111,
+            _ => (self.cr1 >> CR1_BR_SHIFT) & 0b111,
+        };
+        let divider = 2u64 << br;
+
+        // The emulated time runs at the CPU clock
+        let (hclk, pclk) = sys.p.rcc.borrow().clocks()
+            .map(|c| (c.hclk, if self.is_apb2 { c.pclk2 } else { c.pclk1 }))
+            .filter(|(_, pclk)| *pclk != 0)
+            .unwrap_or((1, 1));
+
+        (self.data_size() as u64 * divider * hclk as u64 / pclk as u64).max(1)
+    }
+
+    /// Frames waiting in the TX buffer for the bus
+    fn tx_waiting(&self, now: u64) -> usize {
+        self.shifting.iter().filter(|(start, _)| *start > now).count()
+    }
+
+    fn txe(&self, now: u64) -> bool {
+        self.tx.is_empty() && self.tx_waiting(now) < self.rx_capacity()
+    }
+
+    /// Retires the frames whose transfer is over, and receives their data
+    fn update_frames(&mut self, sys: &System) {
+        let now = Self::now();
+        while self.shifting.front().is_some_and(|(_, end)| *end <= now) {
+            self.shifting.pop_front();
+        }
+        while let Some((_, rx)) = self.rx_pending.pop_front_if(|(end, _)| *end <= now) {
+            self.receive(sys, rx);
+        }
+    }
+
+    fn sr(&mut self) -> u32 {
+        if std::mem::take(&mut self.ovr_clear_armed) {
+            self.ovr = false;
+        }
+
+        let now = Self::now();
+        let rx_bytes = (self.rx.len() * self.frame_bytes()) as u32;
+        match self.layout {
+            Layout::F1 | Layout::F4 | Layout::F7 => {
+                let mut sr = 0;
+                if self.txe(now) {
+                    sr |= SR_TXE;
+                }
+                if !self.shifting.is_empty() {
+                    sr |= SR_BSY;
+                }
+                // With FRXTH cleared, RXNE waits for 16 bits
+                let rx_threshold = if self.layout == Layout::F7 && self.cr2 & CR2_FRXTH == 0 { 2 } else { 1 };
+                if rx_bytes >= rx_threshold || (rx_bytes > 0 && self.is_16bits()) {
+                    sr |= SR_RXNE;
+                }
+                if self.ovr {
+                    sr |= SR_OVR;
+                }
+                if self.layout == Layout::F7 {
+                    sr |= rx_bytes.min(3) << SR_FRLVL_SHIFT;
+                    let tx_bytes = (self.tx_waiting(now) * self.frame_bytes()) as u32;
+                    sr |= tx_bytes.min(3) << SR_FTLVL_SHIFT;
+                }
+                sr
+            }
+            Layout::H7 => {
+                let mut sr = 0;
+                if self.txe(now) {
+                    sr |= H7_SR_TXP;
+                }
+                if self.tx.is_empty() && self.shifting.is_empty() {
+                    sr |= H7_SR_TXC;
+                }
+                if !self.rx.is_empty() {
+                    sr |= H7_SR_RXP | (self.rx.len().min(3) as u32) << H7_SR_RXPLVL_SHIFT;
+                }
+                if sr & H7_SR_RXP != 0 {
+                    sr |= H7_SR_DXP;
+                }
+                if self.eot {
+                    sr |= H7_SR_EOT | H7_SR_TXTF;
+                }
+                if self.ovr {
+                    sr |= H7_SR_OVR;
+                }
+                sr
+            }
+        }
+    }
+
+    fn update_interrupt(&self, sys: &System) {
+        let now = Self::now();
+        let pending = match self.layout {
+            Layout::H7 => {
+                (self.ier & H7_SR_TXP != 0 && self.txe(now)) ||
+                (self.ier & H7_SR_RXP != 0 && !self.rx.is_empty()) ||
+                (self.ier & H7_SR_EOT != 0 && self.eot) ||
+                (self.ier & H7_SR_OVR != 0 && self.ovr)
+            }
+            _ => {
+                (self.cr2 & CR2_TXEIE != 0 && self.txe(now)) ||
+                (self.cr2 & CR2_RXNEIE != 0 && !self.rx.is_empty()) ||
+                (self.cr2 & CR2_ERRIE != 0 && self.ovr)
+            }
+        };
+
+        if let Some(irq) = self.irq.filter(|_| pending) {
+            sys.p.nvic.borrow_mut().set_intr_pending(irq);
+        }
+    }
+
+    fn read_dr(&mut self) -> u32 {
+        if let Some(v) = self.rx.pop_front() {
+            self.rx_buffer = v;
+        }
+        if self.ovr {
+            self.ovr_clear_armed = true;
+        }
+        self.rx_buffer
+    }
+
+    fn write_dr(&mut self, sys: &System, value: u32) {
+        if self.is_clocked_by_master() {
+            self.tx.push_back(value);
+            return;
+        }
+        let now = Self::now();
+        let start = self.shifting.back().map_or(now, |(_, end)| (*end).max(now));
+        let end = start + self.frame_time(sys);
+        self.shifting.push_back((start, end));
+        let rx = self.transfer(sys, value);
+        self.rx_pending.push_back((end, rx));
+
+        // TXE once the frame leaves the TX buffer, RXNE once it's received
+        if start > now {
+            sys.p.schedule_tick(start);
+        }
+        sys.p.schedule_tick(end);
+    }
+
+    /// Queues a received frame, once exchanged
+    fn receive(&mut self, sys: &System, rx: u32) {
+        if self.is_rx_dma() {
+            self.rx.push_back(rx);
+            // The DMA services its request on the next tick
+            sys.p.schedule_tick(Self::now());
+        } else if self.rx.len() < self.rx_capacity() {
+            self.rx.push_back(rx);
+        } else {
+            if !self.ovr {
+                trace!("{} overrun", self.name);
+            }
+            self.ovr = true;
+        }
+
+        if self.layout == Layout::H7 {
+            self.num_frames += 1;
+            let tsize = self.cr2 & 0xFFFF;
+            if tsize != 0 && self.num_frames >= tsize {
+                self.eot = true;
+                self.cr1 &= !H7_CR1_CSTART;
+            }
+        }
+    }
+
+    fn transfer(&self, sys: &System, value: u32) -> u32 {
+        self.exchange_frame(value, |v| self.transfer_byte(sys, v))
+    }
+
+    /// Exchanges a frame as bytes with `xfer`
+    fn exchange_frame(&self, value: u32, mut xfer: impl FnMut(u8) -> u8) -> u32 {
+        let lsb_first = self.is_lsb_first();
+        if self.is_16bits() {
+            let value = if lsb_first { (value as u16).reverse_bits() } else { value as u16 };
+            let [h, l] = value.to_be_bytes();
+            let h = xfer(h);
+            let l = xfer(l);
+            let rx = u16::from_be_bytes([h, l]);
+            (if lsb_first { rx.reverse_bits() } else { rx }) as u32
+        } else {
+            let value = if lsb_first { (value as u8).reverse_bits() } else { value as u8 };
+            let rx = xfer(value);
+            (if lsb_first { rx.reverse_bits() } else { rx }) as u32
+        }
+    }
+
+    /// Exchanges a frame with the spi_master device, when it's due. The
+    /// master gets 0 when no frame was written.
+    fn clock_slave(&mut self, sys: &System) {
+        let master = match self.master.clone() {
+            Some(master) if self.is_clocked_by_master() => master,
+            _ => return,
+        };
+        let now = Self::now();
+        let ready = master.borrow_mut().is_ready(now, self.frame_bytes());
+        if ready {
+            let tx = self.tx.pop_front().unwrap_or_default();
+            let rx = {
+                let mut master = master.borrow_mut();
+                let rx = self.exchange_frame(tx, |v| master.exchange(v));
+                master.frame_done(now);
+                rx
+            };
+            self.receive(sys, rx);
+            self.update_interrupt(sys);
+        }
+        self.schedule_master(sys);
+    }
+
+    fn schedule_master(&self, sys: &System) {
+        let now = Self::now();
+        if let Some(at) = self.master.as_ref().and_then(|m| m.borrow().next_frame_at()) {
+            sys.p.schedule_tick(at.max(now + 1));
+        }
     }
 }
 
 impl Peripheral for Spi {
-    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
-        match offset {
-            0x0000 => {
-                self.cr1
-            }
-            0x0008 => {
-                // SR register
-                // receive buffer not empty
-                // transmit buffer empty
-                self.ready_toggle = !self.ready_toggle;
-                if self.ready_toggle { 0b11 } else { 0 }
-            }
-            0x000C => {
-                // DR register
-                let v = self.rx_buffer;
+    fn has_side_effects(&self, offset: u32) -> bool {
+        matches!(self.reg(offset), Some(Reg::Dr | Reg::Txdr | Reg::Rxdr))
+    }
+
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        self.update_frames(sys);
+        match self.reg(offset) {
+            Some(Reg::Cr1) => self.cr1,
+            Some(Reg::Cr2) => self.cr2,
+            Some(Reg::Cfg1) => self.cfg1,
+            Some(Reg::Cfg2) => self.cfg2,
+            Some(Reg::Ier) => self.ier,
+            Some(Reg::Sr) => self.sr(),
+            Some(Reg::Dr | Reg::Rxdr) => {
+                let v = self.read_dr();
+                self.update_interrupt(sys);
                 if self.is_16bits() {
                     trace!("{} read={:04x?}", self.name, v as u16);
                 } else {
@@ -63,38 +566,69 @@ impl Peripheral for Spi {
     }
 
     fn write(&mut self, sys: &System, offset: u32, value: u32) {
-        match offset {
-            0x0000 => {
-                // CR1 register
-                self.cr1 = value;
+        self.update_frames(sys);
+        match self.reg(offset) {
+            Some(reg @ (Reg::Cr1 | Reg::Cr2 | Reg::Cfg1 | Reg::Cfg2)) => {
+                self.write_config(sys, reg, value);
+                self.update_interrupt(sys);
             }
-            0x000C => {
-                // DR register
-
-                self.rx_buffer = self.ext_device.as_ref().map(|d| d.borrow_mut()).map(|mut d| {
-                    if self.is_16bits() {
-                        let h = d.read(sys, ()) as u32;
-                        let l = d.read(sys, ()) as u32;
-                        (h << 8) | l
-                    } else {
-                        d.read(sys, ()) as u32
-                    }
-                }).unwrap_or(0);
+            Some(Reg::Ier) => {
+                self.ier = value;
+                self.update_interrupt(sys);
+            }
+            Some(Reg::Ifcr) => {
+                if value & (H7_SR_EOT | H7_SR_TXTF) != 0 {
+                    self.eot = false;
+                }
+                if value & H7_SR_OVR != 0 {
+                    self.ovr = false;
+                }
+            }
+            Some(Reg::Dr | Reg::Txdr) => {
+                self.write_dr(sys, value);
+                self.update_interrupt(sys);
 
                 if self.is_16bits() {
-                    self.ext_device.as_ref().map(|d| d.borrow_mut()).map(|mut d| {
-                        d.write(sys, (), (value >> 8) as u8);
-                        d.write(sys, (), value as u8);
-                    });
-
                     trace!("{} write={:04x?}", self.name, value as u16);
                 } else {
-                    let v = value as u8;
-                    self.ext_device.as_ref().map(|d| d.borrow_mut().write(sys, (), v));
-                    trace!("{} write={:02x?}", self.name, v);
+                    trace!("{} write={:02x?}", self.name, value as u8);
                 }
             }
             _ => {}
         }
     }
+
+    // 16-bit frames are moved as two bytes, little-endian like the memory
+
+    fn read_dma(&mut self, sys: &System, _offset: u32, size: usize) -> VecDeque<u8> {
+        self.update_frames(sys);
+        let mut v = VecDeque::with_capacity(size);
+        while v.len() < size {
+            let frame = self.read_dr().to_le_bytes();
+            v.extend(&frame[..self.frame_bytes()]);
+        }
+        v.truncate(size);
+        self.update_interrupt(sys);
+        v
+    }
+
+    fn write_dma(&mut self, sys: &System, _offset: u32, value: VecDeque<u8>) {
+        let frame_bytes = self.frame_bytes();
+        for frame in Vec::from(value).chunks(frame_bytes) {
+            let v = frame.iter().rev().fold(0, |v, b| (v << 8) | *b as u32);
+            self.write_dr(sys, v);
+        }
+        self.update_interrupt(sys);
+    }
+
+    fn dma_read_available(&mut self, sys: &System, _offset: u32) -> Option<usize> {
+        self.update_frames(sys);
+        Some(self.rx.len() * self.frame_bytes())
+    }
+
+    fn tick(&mut self, sys: &System) {
+        self.update_frames(sys);
+        self.update_interrupt(sys);
+        self.clock_slave(sys);
+    }
 }
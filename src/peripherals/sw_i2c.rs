@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use serde::Deserialize;
+
+use crate::ext_devices::{ExtDevices, I2cDevice};
+use crate::peripherals::gpio::{Pin, GpioPorts};
+use crate::system::System;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct SoftwareI2cConfig {
+    pub name: String,
+    pub scl: String,
+    pub sda: String,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+enum State {
+    #[default]
+    Idle,
+    Address,
+    Write,
+    Read,
+}
+
+#[derive(Default)]
+pub struct SoftwareI2c {
+    name: String,
+
+    state: State,
+    // Number of SCL rising edges seen in the current byte. 8 data bits, then the ack bit.
+    bit_index: u8,
+    data: u8,
+    read: bool,
+    master_nack: bool,
+
+    // Line levels driven by the master (the firmware)
+    scl: bool,
+    sda: bool,
+    // SDA level driven by the slave. The line is wired-AND.
+    slave_sda: bool,
+
+    ext_devices: Vec<Rc<RefCell<dyn I2cDevice>>>,
+    selected: Option<Rc<RefCell<dyn I2cDevice>>>,
+}
+
+impl SoftwareI2c {
+    pub fn register(config: SoftwareI2cConfig, gpio: &mut GpioPorts, ext_devices: &ExtDevices) {
+        let scl = Pin::from_str(&config.scl);
+        let sda = Pin::from_str(&config.sda);
+
+        let ext_devices = ext_devices.find_i2c_devices(&config.name);
+        for d in &ext_devices {
+            d.borrow_mut().connect_peripheral(&config.name);
+        }
+
+        let self_ = Rc::new(RefCell::new(Self {
+            name: config.name,
+            scl: true, sda: true, slave_sda: true,
+            ext_devices,
+            ..Default::default()
+        }));
+
+        let s = self_.clone();
+        gpio.add_write_callback(scl, move |sys, v| { s.borrow_mut().write_scl(sys, v) });
+
+        let s = self_.clone();
+        gpio.add_write_callback(sda, move |sys, v| { s.borrow_mut().write_sda(sys, v) });
+
+        let s = self_.clone();
+        gpio.add_open_drain_read_callback(sda, move |sys| { s.borrow_mut().read_sda(sys) });
+
+        let s = self_.clone();
+        gpio.add_open_drain_read_callback(scl, move |_sys| { s.borrow().scl });
+    }
+
+    pub fn write_sda(&mut self, sys: &System, value: bool) {
+        if self.scl {
+            // SDA changing while SCL is high are START/STOP conditions
+            if self.sda && !value {
+                trace!("{} START", self.name);
+                self.state = State::Address;
+                self.bit_index = 0;
+                self.data = 0;
+                self.slave_sda = true;
+            } else if !self.sda && value {
+                trace!("{} STOP", self.name);
+                if let Some(d) = self.selected.take() {
+                    d.borrow_mut().stop(sys);
+                }
+                self.state = State::Idle;
+                self.slave_sda = true;
+            }
+        }
+        self.sda = value;
+    }
+
+    pub fn write_scl(&mut self, sys: &System, value: bool) {
+        if !self.scl && value {
+            self.clock_rise();
+        } else if self.scl && !value {
+            self.clock_fall(sys);
+        }
+        self.scl = value;
+    }
+
+    pub fn read_sda(&mut self, _sys: &System) -> bool {
+        self.sda && self.slave_sda
+    }
+
+    fn clock_rise(&mut self) {
+        if self.state == State::Idle {
+            return;
+        }
+
+        if self.bit_index < 8 {
+            if self.state != State::Read {
+                self.data = (self.data << 1) | self.sda as u8;
+            }
+        } else if self.state == State::Read {
+            // Ack bit from the master. High means NACK, the master wants no more data.
+            self.master_nack = self.sda;
+        }
+
+        self.bit_index += 1;
+    }
+
+    fn clock_fall(&mut self, sys: &System) {
+        match (self.state, self.bit_index) {
+            (State::Idle, _) | (_, 0) => {}
+            (_, 8) => self.byte_done(sys),
+            (_, 9) => self.ack_done(sys),
+            (State::Read, i) => {
+                self.slave_sda = (self.data >> (7 - i)) & 1 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    /// The 8 data bits have been clocked. Drive the ack bit.
+    fn byte_done(&mut self, sys: &System) {
+        match self.state {
+            State::Address => {
+                let addr = self.data >> 1;
+                self.read = self.data & 1 != 0;
+
+                self.selected = self.ext_devices.iter()
+                    .find(|d| d.borrow().address() == addr)
+                    .cloned();
+
+                if let Some(ref d) = self.selected {
+                    d.borrow_mut().start(sys, self.read);
+                    trace!("{} addr=0x{:02x} read={} ACK", self.name, addr, self.read);
+                } else {
+                    debug!("{} addr=0x{:02x} read={} NACK", self.name, addr, self.read);
+                }
+
+                self.slave_sda = self.selected.is_none();
+            }
+            State::Write => {
+                trace!("{} write={:02x}", self.name, self.data);
+                if let Some(ref d) = self.selected {
+                    d.borrow_mut().write(sys, (), self.data);
+                }
+                self.slave_sda = self.selected.is_none();
+            }
+            State::Read => {
+                // Release the line so the master can ack
+                self.slave_sda = true;
+            }
+            State::Idle => {}
+        }
+    }
+
+    fn ack_done(&mut self, sys: &System) {
+        self.bit_index = 0;
+        self.slave_sda = true;
+        self.data = 0;
+
+        match self.state {
+            State::Address if self.selected.is_none() => {
+                self.state = State::Idle;
+            }
+            State::Address if !self.read => {
+                self.state = State::Write;
+            }
+            State::Address | State::Read => {
+                if self.state == State::Read && self.master_nack {
+                    self.state = State::Idle;
+                    return;
+                }
+
+                self.state = State::Read;
+                self.master_nack = false;
+                if let Some(ref d) = self.selected {
+                    self.data = d.borrow_mut().read(sys, ());
+                }
+                trace!("{} read={:02x}", self.name, self.data);
+                self.slave_sda = self.data & 0x80 != 0;
+            }
+            State::Write | State::Idle => {}
+        }
+    }
+}
@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::rc::Rc;
+use std::cell::RefCell;
+
+use anyhow::{Result, bail};
+use serde::Deserialize;
+
+use crate::ext_devices::{ExtDevice, ExtDevices};
+use crate::peripherals::{Peripherals, gpio::Pin};
+use crate::system::System;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct SoftwareUartConfig {
+    pub name: String,
+    pub tx: Option<String>,
+    pub rx: Option<String>,
+    /// Duration of a bit, in emulated cycles
+    pub bit_cycles: u64,
+}
+
+#[derive(Default)]
+pub struct SoftwareUart {
+    name: String,
+    bit_cycles: u64,
+
+    // TX decoding. The firmware drives the TX pin.
+    tx_level: bool,
+    // Time of the start bit falling edge
+    tx_frame_start: Option<u64>,
+    // Last time we sampled the line, in bit units from the frame start
+    tx_bit_index: u64,
+    tx_data: u8,
+
+    // RX generation. We drive the RX pin.
+    rx_frame: Option<(u64, u8)>,
+
+    ext_device: Option<Rc<RefCell<dyn ExtDevice<(), u8>>>>,
+}
+
+fn now() -> u64 {
+    crate::context::now()
+}
+
+impl SoftwareUart {
+    pub fn register(config: SoftwareUartConfig, p: &Peripherals, ext_devices: &ExtDevices) -> Result<()> {
+        if config.bit_cycles == 0 {
+            bail!("Software UART {}: bit_cycles must be positive", config.name);
+        }
+
+        let tx = config.tx.as_ref().map(|s| Pin::from_str(s));
+        let rx = config.rx.as_ref().map(|s| Pin::from_str(s));
+
+        let ext_device = ext_devices.find_serial_device(&config.name);
+        let name = ext_device.as_ref()
+            .map(|d| d.borrow_mut().connect_peripheral(&config.name))
+            .unwrap_or_else(|| config.name.to_string());
+
+        let self_ = Rc::new(RefCell::new(Self {
+            name,
+            bit_cycles: config.bit_cycles,
+            tx_level: true,
+            ext_device,
+            ..Default::default()
+        }));
+
+        let mut gpio = p.gpio.borrow_mut();
+        if let Some(tx) = tx {
+            let s = self_.clone();
+            gpio.add_write_callback(tx, move |sys, v| { s.borrow_mut().write_tx(sys, v) });
+            let s = self_.clone();
+            p.add_tick_callback(move |sys| { s.borrow_mut().sample_tx(sys, now()) });
+        }
+
+        if let Some(rx) = rx {
+            let s = self_.clone();
+            gpio.add_read_callback(rx, move |sys| { s.borrow_mut().read_rx(sys) });
+        }
+
+        Ok(())
+    }
+
+    /// Samples the TX line in the middle of each bit up to the current time.
+    /// The line has stayed at `tx_level` since the last write.
+    fn sample_tx(&mut self, sys: &System, now: u64) {
+        if let Some(start) = self.tx_frame_start {
+            // Bit 0 is the start bit, then 8 data bits LSB first.
+            while self.tx_bit_index < 8 {
+                let i = self.tx_bit_index + 1;
+                let sample_time = start + i * self.bit_cycles + self.bit_cycles / 2;
+                if sample_time > now {
+                    break;
+                }
+                self.tx_bit_index = i;
+                self.tx_data >>= 1;
+                if self.tx_level {
+                    self.tx_data |= 0x80;
+                }
+            }
+
+            if self.tx_bit_index == 8 {
+                self.tx_frame_start = None;
+                trace!("{} write={:02x}", self.name, self.tx_data);
+                if let Some(ref d) = self.ext_device {
+                    d.borrow_mut().write(sys, (), self.tx_data);
+                }
+            }
+        }
+    }
+
+    pub fn write_tx(&mut self, sys: &System, value: bool) {
+        let now = now();
+        self.sample_tx(sys, now);
+
+        if self.tx_frame_start.is_none() && self.tx_level && !value {
+            // Start bit
+            self.tx_frame_start = Some(now);
+            self.tx_bit_index = 0;
+            self.tx_data = 0;
+            // When bit 7 is high, there's no edge into the stop bit. The frame
+            // is sampled in the middle of the stop bit instead of waiting for
+            // the next start bit, which may never come.
+            sys.p.schedule_tick(now + 9 * self.bit_cycles + self.bit_cycles / 2);
+        }
+
+        self.tx_level = value;
+    }
+
+    pub fn read_rx(&mut self, sys: &System) -> bool {
+        let now = now();
+
+        if let Some((start, data)) = self.rx_frame {
+            // start bit, 8 data bits, stop bit, and one idle bit between frames.
+            let bit = (now - start) / self.bit_cycles;
+            match bit {
+                0 => return false,
+                1..=8 => return (data >> (bit - 1)) & 1 != 0,
+                9 | 10 => return true,
+                _ => self.rx_frame = None,
+            }
+        }
+
+        if let Some(ref d) = self.ext_device {
+            let mut d = d.borrow_mut();
+            if d.has_data(sys) {
+                let data = d.read(sys, ());
+                trace!("{} read={:02x}", self.name, data);
+                self.rx_frame = Some((now, data));
+                return false;
+            }
+        }
+
+        true
+    }
+}
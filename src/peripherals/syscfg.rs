@@ -0,0 +1,115 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use serde::Deserialize;
+use unicorn_engine::Unicorn;
+
+use crate::system::System;
+use super::Peripheral;
+
+#[derive(Debug, Deserialize, Default)]
+pub struct FlashConfig {
+    /// Flash start address. Defaults to 0x08000000.
+    pub start: Option<u32>,
+    /// Size of a bank on dual-bank devices. Enables the bank swap.
+    pub bank_size: Option<u32>,
+}
+
+// MEMRMP bit 8 swaps the flash banks on the F4 (FB_MODE), F7 (SWP_FB), L4 and
+// G4 (FB_MODE). Bank 2 then shows at the flash start address, and bank 1 after.
+// We swap the content of the two banks in memory, so all the accesses,
+// including the instruction fetches, see the swapped layout. The H7 swaps its
+// banks with the SWAP_BANK option byte instead, which isn't supported.
+const MEMRMP_SWP_FB: u32 = 1 << 8;
+
+const FLASH_START: u32 = 0x0800_0000;
+
+#[derive(Default)]
+pub struct Syscfg {
+    config: FlashConfig,
+    memrmp: u32,
+    banks_swapped: bool,
+}
+
+impl Syscfg {
+    pub fn from_config(config: FlashConfig, device_name: &str) -> Self {
+        if config.bank_size.is_some() && device_name.to_uppercase().starts_with("STM32H7") {
+            warn!("The flash bank swap of device={} (FLASH_OPTCR SWAP_BANK) isn't supported", device_name);
+        }
+        Self { config, ..Self::default() }
+    }
+
+    fn swap_banks(&mut self, uc: &mut Unicorn<()>) {
+        let bank_size = match self.config.bank_size {
+            Some(bank_size) => bank_size,
+            None => {
+                warn!("Flash bank swap requested, but flash bank_size is not configured");
+                return;
+            }
+        };
+
+        let bank1 = self.config.start.unwrap_or(FLASH_START) as u64;
+        let bank2 = bank1 + bank_size as u64;
+
+        let (content1, content2) = match (
+            uc.mem_read_as_vec(bank1, bank_size as usize),
+            uc.mem_read_as_vec(bank2, bank_size as usize),
+        ) {
+            (Ok(content1), Ok(content2)) => (content1, content2),
+            _ => {
+                warn!("Flash banks are not mapped bank1=0x{:08x} bank2=0x{:08x}", bank1, bank2);
+                return;
+            }
+        };
+        uc.mem_write(bank1, &content2).unwrap();
+        uc.mem_write(bank2, &content1).unwrap();
+
+        self.banks_swapped = !self.banks_swapped;
+        info!("Flash banks swapped, bank={} is at 0x{:08x}", if self.banks_swapped { 2 } else { 1 }, bank1);
+    }
+
+    /// The bank swap doesn't survive a reset
+    pub fn reset(&mut self, uc: &mut Unicorn<()>) {
+        if self.banks_swapped {
+            self.swap_banks(uc);
+        }
+        self.memrmp = 0;
+    }
+
+    pub fn read(&mut self, _sys: &System, offset: u32) -> u32 {
+        match offset {
+            0x0000 => self.memrmp,
+            _ => 0
+        }
+    }
+
+    pub fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        if offset == 0x0000 {
+            if (value ^ self.memrmp) & MEMRMP_SWP_FB != 0 {
+                self.swap_banks(&mut sys.uc.borrow_mut());
+            }
+            self.memrmp = value;
+        }
+    }
+}
+
+pub struct SyscfgWrapper;
+
+impl SyscfgWrapper {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "SYSCFG" {
+            Some(Box::new(Self))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for SyscfgWrapper {
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        sys.p.syscfg.borrow_mut().read(sys, offset)
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        sys.p.syscfg.borrow_mut().write(sys, offset, value)
+    }
+}
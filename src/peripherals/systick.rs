@@ -1,69 +1,197 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+
 use crate::system::System;
 use super::Peripheral;
 
+// CTRL register bits
+const CTRL_ENABLE: u32 = 1 << 0;
+const CTRL_TICKINT: u32 = 1 << 1;
+const CTRL_CLKSOURCE: u32 = 1 << 2;
+const CTRL_COUNTFLAG: u32 = 1 << 16;
+
+// The external reference clock is HCLK/8 on the STM32s
+const EXTERNAL_CLOCK_DIVIDER: u64 = 8;
+
+/// The SysTick down-counter, clocked by the emulated time (crate::context::now()).
+/// The counter isn't stepped. We remember where it was at a point in time,
+/// and derive its value, and the number of times it reached 0, from there.
+/// It lives in the NVIC, which raises its interrupt.
 #[derive(Default)]
-pub struct SysTick {
-    ctl: u32,
-    reload: u32,
-    val_toggle: bool,
+pub struct SysTickTimer {
+    ctrl: u32,
+    load: u32,
+    countflag: bool,
+    // Counter value at base_time
+    base_val: u32,
+    base_time: u64,
+    // Number of times the counter reached 0 since base_time, already accounted for
+    num_zeros_seen: u64,
 }
 
-impl SysTick {
-    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
-        if name == "STK" {
-            Some(Box::new(Self::default()))
+impl SysTickTimer {
+    fn now() -> u64 {
+        crate::context::now()
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.ctrl & CTRL_ENABLE != 0
+    }
+
+    fn divider(&self) -> u64 {
+        if self.ctrl & CTRL_CLKSOURCE != 0 { 1 } else { EXTERNAL_CLOCK_DIVIDER }
+    }
+
+    fn elapsed_ticks(&self, now: u64) -> u64 {
+        if self.is_enabled() {
+            now.saturating_sub(self.base_time) / self.divider()
         } else {
-            None
+            0
         }
     }
 
-    fn has_int_enabled(&self) -> bool {
-        (self.ctl & 0b11) == 0b11
+    fn period(&self) -> u64 {
+        self.load as u64 + 1
+    }
+
+    /// Number of ticks after base_time when the counter first reaches 0. When
+    /// the counter starts from 0, it first reloads.
+    fn first_zero(&self) -> u64 {
+        if self.base_val > 0 { self.base_val as u64 } else { self.period() }
     }
 
-    fn set_nvic_systick_period(&self, sys: &System) {
-        let nvic_systick_period = if self.has_int_enabled() {
-            Some(self.reload)
+    /// Number of times the counter went from 1 to 0 in the elapsed ticks.
+    /// With a LOAD of 0, the counter stops at 0.
+    fn num_zeros(&self, elapsed: u64) -> u64 {
+        let first = self.first_zero();
+        if elapsed < first || (self.load == 0 && self.base_val == 0) {
+            0
+        } else if self.load == 0 {
+            1
         } else {
-            None
-        };
+            1 + (elapsed - first) / self.period()
+        }
+    }
 
-        sys.p.nvic.borrow_mut().systick_period = nvic_systick_period;
+    fn val_at(&self, elapsed: u64) -> u32 {
+        if self.load == 0 {
+            return self.base_val.saturating_sub(elapsed.min(u32::MAX as u64) as u32);
+        }
+        if self.base_val > 0 && elapsed < self.base_val as u64 {
+            return self.base_val - elapsed as u32;
+        }
+        let since_zero = (elapsed - self.base_val as u64) % self.period();
+        ((self.period() - since_zero) % self.period()) as u32
     }
-}
 
-impl Peripheral for SysTick {
-    fn read(&mut self, _sys: &System, offset: u32) -> u32 {
-        match offset {
+    /// Accounts for the counter reaching 0 since the last update. Returns
+    /// true when the interrupt should be raised.
+    pub fn update(&mut self) -> bool {
+        let num_zeros = self.num_zeros(self.elapsed_ticks(Self::now()));
+        if num_zeros > self.num_zeros_seen {
+            self.num_zeros_seen = num_zeros;
+            self.countflag = true;
+            self.ctrl & CTRL_TICKINT != 0
+        } else {
+            false
+        }
+    }
+
+    /// Restarts the bookkeeping from the current counter value. Done before
+    /// changing the configuration.
+    fn rebase(&mut self) -> bool {
+        let pending = self.update();
+        let now = Self::now();
+        let elapsed = self.elapsed_ticks(now);
+        self.base_val = self.val_at(elapsed);
+        // Keep the partial tick, so the clock division stays exact
+        self.base_time = if self.is_enabled() { self.base_time + elapsed * self.divider() } else { now };
+        self.num_zeros_seen = 0;
+        pending
+    }
+
+    /// The emulated time of the next interrupt, if any
+    pub fn next_interrupt(&self) -> Option<u64> {
+        if !self.is_enabled() || self.ctrl & CTRL_TICKINT == 0 || (self.load == 0 && self.base_val == 0) {
+            return None;
+        }
+        if self.load == 0 && self.num_zeros_seen > 0 {
+            return None;
+        }
+        let ticks = self.first_zero() + self.num_zeros_seen * self.period();
+        Some(self.base_time + ticks * self.divider())
+    }
+
+    pub fn read(&mut self, offset: u32) -> (u32, bool) {
+        let pending = self.update();
+        let v = match offset {
             0x0000 => {
-                self.val_toggle = !self.val_toggle;
-                // toggle the count bit
-                self.ctl | if self.val_toggle { 0 } else { 1 << 16 }
-            }
-            0x0004 => self.reload,
-            0x0008 => {
-                self.val_toggle = !self.val_toggle;
-                if self.val_toggle { 0 } else { self.reload/2 }
+                // COUNTFLAG is cleared on read
+                let countflag = if std::mem::take(&mut self.countflag) { CTRL_COUNTFLAG } else { 0 };
+                self.ctrl | countflag
             }
+            0x0004 => self.load,
+            0x0008 => self.val_at(self.elapsed_ticks(Self::now())),
             _ => 0
-        }
+        };
+        (v, pending)
     }
 
-    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+    /// Returns true when the interrupt should be raised
+    pub fn write(&mut self, offset: u32, value: u32) -> bool {
+        let pending = self.rebase();
         match offset {
             0x0000 => {
-                // CTRL register
-                self.ctl = value;
-                self.set_nvic_systick_period(sys);
+                if (self.ctrl ^ value) & CTRL_ENABLE != 0 {
+                    debug!("SysTick enabled={} load={} tickint={}", value & CTRL_ENABLE != 0, self.load, value & CTRL_TICKINT != 0);
+                }
+                self.ctrl = value & (CTRL_ENABLE | CTRL_TICKINT | CTRL_CLKSOURCE);
             }
-            0x0004 => {
-                // LOAD register
-                self.reload = value;
-                self.set_nvic_systick_period(sys);
+            // The new LOAD is used on the next reload
+            0x0004 => self.load = value & 0x00FF_FFFF,
+            // Any write clears the counter and COUNTFLAG
+            0x0008 => {
+                self.base_val = 0;
+                self.countflag = false;
             }
             _ => {}
         }
+        pending
+    }
+}
+
+/// Register access to the timer that lives in the NVIC
+pub struct SysTick;
+
+impl SysTick {
+    pub fn new(name: &str) -> Option<Box<dyn Peripheral>> {
+        if name == "STK" {
+            Some(Box::new(Self))
+        } else {
+            None
+        }
+    }
+}
+
+impl Peripheral for SysTick {
+    fn has_side_effects(&self, offset: u32) -> bool {
+        // COUNTFLAG is cleared on read
+        offset == 0x0000
+    }
+
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        let mut nvic = sys.p.nvic.borrow_mut();
+        let (v, pending) = nvic.systick.read(offset);
+        if pending {
+            nvic.set_intr_pending(super::nvic::irq::SYSTICK);
+        }
+        v
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        let mut nvic = sys.p.nvic.borrow_mut();
+        if nvic.systick.write(offset, value) {
+            nvic.set_intr_pending(super::nvic::irq::SYSTICK);
+        }
     }
 }
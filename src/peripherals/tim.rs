@@ -0,0 +1,535 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::HashMap};
+
+use anyhow::{Result, bail};
+use serde::Deserialize;
+
+use crate::system::System;
+use super::{Peripheral, encoder::{Encoder, EncoderConfig}};
+
+// General purpose and advanced timers. The counter counts up at the timer
+// clock, without update or compare events. The PWM duty cycle of the output
+// channels is known from the configuration registers, the backlight of the
+// framebuffers follows it.
+//
+// The input channels capture square waves from the config, like the pulses of
+// an RC receiver or the tachometer of a fan:
+//
+//  timer_inputs:
+//    - timer: TIM3
+//      channel: 1            # TI1
+//      pulses:
+//        - period_us: 20000  # or frequency: 50
+//          high_us: 1500     # or duty: 0.075, 0.5 by default
+//    - timer: TIM2
+//      channel: 3
+//      repeat: true          # plays the pulses again, instead of holding the last one
+//      pulses:
+//        - frequency: 100
+//          count: 500        # periods before the next pulse, 1 by default
+//        - frequency: 200
+//          count: 500
+//
+// Each period starts with a rising edge. The edges are latched into CCRx by
+// the channels in input capture mode, with their polarity and prescaler, and
+// raise the CC interrupts. The reset slave mode on TI1FP1 or TI2FP2 restarts
+// the counter, which is how the PWM input mode measures the period and the
+// duty cycle.
+//
+// In encoder mode, the counter follows a virtual encoder, see encoder.rs.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct TimerInputConfig {
+    pub timer: String,
+    /// The TIx input, 1 to 4
+    pub channel: u8,
+    pub pulses: Vec<PulseConfig>,
+    pub repeat: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+pub struct PulseConfig {
+    pub period_us: Option<f64>,
+    /// Alternative to period_us, in Hz
+    pub frequency: Option<f64>,
+    pub high_us: Option<f64>,
+    /// Alternative to high_us, from 0 to 1
+    pub duty: Option<f64>,
+    pub count: Option<u32>,
+}
+
+const NUM_REGISTERS: usize = 0x60/4;
+
+// Register offsets
+const CR1: u32 = 0x00;
+const SMCR: u32 = 0x08;
+const DIER: u32 = 0x0C;
+const SR: u32 = 0x10;
+const EGR: u32 = 0x14;
+const CCMR1: u32 = 0x18;
+const CCER: u32 = 0x20;
+const CNT: u32 = 0x24;
+const PSC: u32 = 0x28;
+const ARR: u32 = 0x2C;
+const CCR1: u32 = 0x34;
+const CCR4: u32 = 0x40;
+const BDTR: u32 = 0x44;
+
+const CR1_CEN: u32 = 1 << 0;
+const CR1_DIR: u32 = 1 << 4;
+const EGR_UG: u32 = 1 << 0;
+const SR_TIF: u32 = 1 << 6;
+// CCxIF/CCxIE and TIF/TIE have the same bits in SR and DIER
+const CAPTURE_INTERRUPTS: u32 = 0b1_1110 | SR_TIF;
+const BDTR_MOE: u32 = 1 << 15;
+
+// SMCR slave mode and trigger selection
+const SMS_ENCODER_MODE_3: u32 = 0b011;
+const SMS_RESET: u32 = 0b100;
+const TS_TI1FP1: u32 = 0b101;
+const TS_TI2FP2: u32 = 0b110;
+
+struct Pulse {
+    period_us: f64,
+    high_us: f64,
+    count: u32,
+}
+
+impl Pulse {
+    fn new(config: &PulseConfig, name: &str) -> Result<Self> {
+        let period_us = match (config.period_us, config.frequency) {
+            (Some(period_us), None) => period_us,
+            (None, Some(frequency)) if frequency > 0.0 => 1e6 / frequency,
+            _ => bail!("{}: pulses need a period_us or a positive frequency", name),
+        };
+        let high_us = match (config.high_us, config.duty) {
+            (Some(high_us), None) => high_us,
+            (None, duty) => period_us * duty.unwrap_or(0.5),
+            _ => bail!("{}: pulses take either high_us or duty", name),
+        };
+        if !(high_us > 0.0 && high_us < period_us) {
+            bail!("{}: the high time of the pulses must be within their period", name);
+        }
+        let count = config.count.unwrap_or(1);
+        if count == 0 {
+            bail!("{}: the count of the pulses must be positive", name);
+        }
+        Ok(Self { period_us, high_us, count })
+    }
+}
+
+/// The square wave on a timer input
+struct TimerInput {
+    timer: String,
+    channel: u8,
+    pulses: Vec<Pulse>,
+    repeat: bool,
+    // The current pulse, and its periods left
+    index: usize,
+    remaining: u32,
+    level: bool,
+    // Emulated time of the next edge
+    next_edge: u64,
+}
+
+impl TimerInput {
+    fn new(config: TimerInputConfig) -> Result<Self> {
+        let name = format!("{} CH{}", config.timer, config.channel);
+        if !(1..=4).contains(&config.channel) {
+            bail!("{}: timer inputs are channels 1 to 4", name);
+        }
+        if config.pulses.is_empty() {
+            bail!("{}: no pulses", name);
+        }
+        let pulses = config.pulses.iter().map(|p| Pulse::new(p, &name)).collect::<Result<Vec<_>>>()?;
+        let remaining = pulses[0].count;
+        Ok(Self {
+            timer: config.timer, channel: config.channel, pulses, repeat: config.repeat.unwrap_or_default(),
+            index: 0, remaining, level: false, next_edge: 0,
+        })
+    }
+
+    /// Moves to the next edge. Returns the time and the direction of the current one.
+    fn take_edge(&mut self, hclk: u64) -> (u64, bool) {
+        let at = self.next_edge;
+        let pulse = &self.pulses[self.index];
+        let cycles = |us: f64| ((us * hclk as f64 / 1e6) as u64).max(1);
+
+        self.level = !self.level;
+        if self.level {
+            self.next_edge = at + cycles(pulse.high_us);
+        } else {
+            self.next_edge = at + cycles(pulse.period_us - pulse.high_us);
+            self.remaining -= 1;
+            if self.remaining == 0 {
+                if self.index + 1 < self.pulses.len() {
+                    self.index += 1;
+                } else if self.repeat {
+                    self.index = 0;
+                }
+                // Otherwise the last pulse goes on
+                self.remaining = self.pulses[self.index].count;
+            }
+        }
+        (at, self.level)
+    }
+}
+
+/// The timer clock, and the CPU clock at which the emulated time runs
+#[derive(Clone, Copy)]
+struct TimerClock {
+    hclk: u64,
+    timclk: u64,
+}
+
+#[derive(Default)]
+pub struct Timer {
+    regs: [u32; NUM_REGISTERS],
+    // The counter was at `base` at the emulated time `start`, or at the
+    // encoder position `encoder_base` in encoder mode
+    base: u32,
+    start: u64,
+    encoder_base: i64,
+    // Edges seen per capture channel, for the input prescalers
+    ic_events: [u32; 4],
+}
+
+impl Timer {
+    fn reg(&self, offset: u32) -> u32 {
+        self.regs[(offset/4) as usize]
+    }
+
+    fn reg_mut(&mut self, offset: u32) -> &mut u32 {
+        &mut self.regs[(offset/4) as usize]
+    }
+
+    fn is_encoder(&self) -> bool {
+        matches!(self.reg(SMCR) & 0b111, 0b001..=SMS_ENCODER_MODE_3)
+    }
+
+    /// Inverting one of the encoder inputs with CCxP reverses the direction
+    fn is_encoder_inverted(&self) -> bool {
+        let ccer = self.reg(CCER);
+        (ccer & (1 << 1) != 0) != (ccer & (1 << 5) != 0)
+    }
+
+    fn counter_at(&self, at: u64, clk: TimerClock, encoder: Option<i64>) -> u32 {
+        if self.reg(CR1) & CR1_CEN == 0 {
+            return self.base;
+        }
+        if self.is_encoder() {
+            let Some(position) = encoder else {
+                return self.base;
+            };
+            // The modes 1 and 2 count the edges of a single input
+            let div = if self.reg(SMCR) & 0b111 == SMS_ENCODER_MODE_3 { 1 } else { 2 };
+            let mut counts = position.div_euclid(div) - self.encoder_base.div_euclid(div);
+            if self.is_encoder_inverted() {
+                counts = -counts;
+            }
+            return (self.base as i64 + counts).rem_euclid(self.reg(ARR) as i64 + 1) as u32;
+        }
+        let cycles = at.saturating_sub(self.start) as u128;
+        let ticks = cycles * clk.timclk as u128 / (clk.hclk as u128 * (self.reg(PSC) as u128 + 1));
+        ((self.base as u128 + ticks) % (self.reg(ARR) as u128 + 1)) as u32
+    }
+
+    fn set_counter(&mut self, value: u32, now: u64, encoder: Option<i64>) {
+        self.base = value;
+        self.start = now;
+        self.encoder_base = encoder.unwrap_or_default();
+    }
+
+    /// The input (1 to 4) captured by a channel (0 to 3), None when the
+    /// channel is an output
+    fn capture_input(&self, ch: u32) -> Option<u8> {
+        let ccmr = self.reg(CCMR1 + 4*(ch/2));
+        match (ccmr >> (8*(ch%2))) & 0b11 {
+            0b01 => Some(ch as u8 + 1),
+            // The other input of the pair, e.g. TI2 for IC1
+            0b10 => Some((ch ^ 1) as u8 + 1),
+            _ => None,
+        }
+    }
+
+    /// Whether an edge matches the CCxP/CCxNP polarity of a channel (0 to 3)
+    fn edge_matches(&self, ch: u32, rising: bool) -> bool {
+        let ccer = self.reg(CCER) >> (4*ch);
+        match (ccer & 0b1000 != 0, ccer & 0b10 != 0) {
+            // Both edges
+            (true, true) => true,
+            (_, falling) => rising != falling,
+        }
+    }
+
+    fn is_capturing(&self) -> bool {
+        (0..4).any(|ch| self.reg(CCER) & (1 << (4*ch)) != 0 && self.capture_input(ch).is_some())
+    }
+
+    /// An edge on a TIx input (1 to 4) at the emulated time `at`. Returns
+    /// whether it raises an interrupt.
+    fn input_edge(&mut self, input: u8, rising: bool, at: u64, clk: TimerClock) -> bool {
+        let cnt = self.counter_at(at, clk, None);
+        let mut flags = 0;
+
+        for ch in 0..4 {
+            if self.reg(CCER) & (1 << (4*ch)) == 0 || self.capture_input(ch) != Some(input) || !self.edge_matches(ch, rising) {
+                continue;
+            }
+            // ICxPSC: captures once every 1, 2, 4 or 8 edges
+            let ccmr = self.reg(CCMR1 + 4*(ch/2));
+            let prescaler = 1 << ((ccmr >> (2 + 8*(ch%2))) & 0b11);
+            self.ic_events[ch as usize] += 1;
+            if !self.ic_events[ch as usize].is_multiple_of(prescaler) {
+                continue;
+            }
+
+            *self.reg_mut(CCR1 + 4*ch) = cnt;
+            let ccif = 1 << (ch + 1);
+            if self.reg(SR) & ccif != 0 {
+                // CCxOF: the previous capture wasn't read
+                *self.reg_mut(SR) |= 1 << (ch + 9);
+            }
+            flags |= ccif;
+        }
+
+        let smcr = self.reg(SMCR);
+        let trigger = match (smcr >> 4) & 0b111 {
+            TS_TI1FP1 => Some(1),
+            TS_TI2FP2 => Some(2),
+            _ => None,
+        };
+        if smcr & 0b111 == SMS_RESET && trigger == Some(input) && self.edge_matches(input as u32 - 1, rising) {
+            self.set_counter(0, at, None);
+            flags |= SR_TIF;
+        }
+
+        *self.reg_mut(SR) |= flags;
+        flags & self.reg(DIER) & CAPTURE_INTERRUPTS != 0
+    }
+
+    /// The duty cycle of an output channel (1 to 4), from 0 to 1, or None when
+    /// the channel isn't an enabled PWM output.
+    pub fn pwm_duty(&self, name: &str, channel: u8) -> Option<f32> {
+        if !(1..=4).contains(&channel) {
+            return None;
+        }
+        let ch = (channel - 1) as u32;
+
+        let ccer = self.reg(CCER);
+        if ccer & (1 << (4*ch)) == 0 {
+            return None;
+        }
+        // The outputs of the advanced timers are gated by the main output enable
+        if matches!(name, "TIM1" | "TIM8" | "TIM20") && self.reg(BDTR) & BDTR_MOE == 0 {
+            return None;
+        }
+
+        // OCxM of CCMR1 for channels 1 and 2, CCMR2 for 3 and 4
+        let ccmr = self.reg(CCMR1 + 4*(ch/2));
+        let oc_mode = (ccmr >> (4 + 8*(ch%2))) & 0b111;
+        let running = self.reg(CR1) & CR1_CEN != 0;
+        let ratio = (self.reg(CCR1 + 4*ch) as f32 / (self.reg(ARR) as f32 + 1.0)).min(1.0);
+        let duty = match oc_mode {
+            0b100 => 0.0,
+            0b101 => 1.0,
+            0b110 if running => ratio,
+            0b111 if running => 1.0 - ratio,
+            // The counter is stopped at 0, below the compare value in PWM mode 1
+            0b110 => if self.reg(CCR1 + 4*ch) > 0 { 1.0 } else { 0.0 },
+            0b111 => if self.reg(CCR1 + 4*ch) > 0 { 0.0 } else { 1.0 },
+            _ => return None,
+        };
+
+        // CCxP: active low output
+        Some(if ccer & (1 << (4*ch + 1)) != 0 { 1.0 - duty } else { duty })
+    }
+}
+
+#[derive(Default)]
+pub struct Timers {
+    // By name, e.g. TIM3
+    timers: HashMap<String, Timer>,
+    // The signals and the encoders go on across resets
+    inputs: Vec<TimerInput>,
+    encoders: Vec<Encoder>,
+}
+
+impl Timers {
+    pub fn from_config(inputs: Vec<TimerInputConfig>, encoders: Vec<EncoderConfig>) -> Result<Self> {
+        let inputs = inputs.into_iter().map(TimerInput::new).collect::<Result<_>>()?;
+        let encoders = encoders.into_iter().map(Encoder::new).collect::<Result<_>>()?;
+        Ok(Self { timers: HashMap::new(), inputs, encoders })
+    }
+
+    pub fn encoder(&mut self, timer: &str) -> Option<&mut Encoder> {
+        self.encoders.iter_mut().find(|e| e.timer.eq_ignore_ascii_case(timer))
+    }
+
+    /// Turns the encoders bound to the key. Returns whether there's one.
+    pub fn key_pressed(&mut self, key: &str, hclk: u32) -> bool {
+        let mut found = false;
+        for e in &mut self.encoders {
+            found |= e.key_pressed(key, hclk as u64);
+        }
+        found
+    }
+
+    fn encoder_position(&mut self, timer: &str, hclk: u64) -> Option<i64> {
+        self.encoder(timer).map(|e| e.position(hclk))
+    }
+
+    pub fn reset(&mut self) {
+        self.timers.clear();
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Timer> {
+        self.timers.get(name)
+    }
+}
+
+pub struct Tim {
+    name: String,
+    irq: Option<i32>,
+    // On APB2 instead of APB1
+    is_apb2: bool,
+}
+
+impl Tim {
+    pub fn new(name: &str, irq: Option<i32>) -> Option<Box<dyn Peripheral>> {
+        if name.starts_with("TIM") {
+            let is_apb2 = matches!(name, "TIM1" | "TIM8" | "TIM9" | "TIM10" | "TIM11" | "TIM15" | "TIM16" | "TIM17" | "TIM20");
+            Some(Box::new(Self { name: name.to_string(), irq, is_apb2 }))
+        } else {
+            None
+        }
+    }
+
+    fn clock(&self, sys: &System) -> TimerClock {
+        let rcc = sys.p.rcc.borrow();
+        let hclk = rcc.hclk() as u64;
+        let timclk = rcc.clocks()
+            .map(|c| (c.hclk as u64, if self.is_apb2 { c.pclk2 } else { c.pclk1 } as u64))
+            .filter(|(_, pclk)| *pclk != 0)
+            // The timers run twice as fast as a divided APB clock
+            .map(|(hclk, pclk)| if pclk < hclk { 2*pclk } else { pclk })
+            .unwrap_or(hclk);
+        TimerClock { hclk, timclk }
+    }
+
+    /// Plays the input edges up to now, and schedules the tick of the next one
+    fn update(&self, sys: &System) {
+        let now = crate::context::now();
+        let clk = self.clock(sys);
+        let mut timers = sys.p.timers.borrow_mut();
+        let Timers { timers, inputs, .. } = &mut *timers;
+        let t = timers.entry(self.name.clone()).or_default();
+
+        let mut interrupt = false;
+        while let Some(input) = inputs.iter_mut()
+            .filter(|i| i.timer == self.name && i.next_edge <= now)
+            .min_by_key(|i| i.next_edge)
+        {
+            let (at, rising) = input.take_edge(clk.hclk);
+            interrupt |= t.input_edge(input.channel, rising, at, clk);
+        }
+
+        if interrupt {
+            if let Some(irq) = self.irq {
+                sys.p.nvic.borrow_mut().set_intr_pending(irq);
+            }
+        }
+
+        // Without capture, the edges are played on the next access
+        if t.is_capturing() {
+            if let Some(at) = inputs.iter().filter(|i| i.timer == self.name).map(|i| i.next_edge).min() {
+                sys.p.schedule_tick(at);
+            }
+        }
+    }
+}
+
+impl Peripheral for Tim {
+    fn has_side_effects(&self, offset: u32) -> bool {
+        // Reading the capture clears CCxIF
+        matches!(offset, CCR1..=CCR4)
+    }
+
+    fn read(&mut self, sys: &System, offset: u32) -> u32 {
+        self.update(sys);
+        let now = crate::context::now();
+        let clk = self.clock(sys);
+        let mut timers = sys.p.timers.borrow_mut();
+        let encoder = timers.encoder_position(&self.name, clk.hclk);
+        let down = timers.encoder(&self.name).is_some_and(|e| e.down);
+        let t = timers.timers.entry(self.name.clone()).or_default();
+        match offset {
+            CR1 if t.is_encoder() && encoder.is_some() => {
+                let mut v = t.reg(CR1) & !CR1_DIR;
+                if down != t.is_encoder_inverted() {
+                    v |= CR1_DIR;
+                }
+                v
+            }
+            // No update events are generated
+            EGR => 0,
+            CNT => t.counter_at(now, clk, encoder),
+            CCR1..=CCR4 => {
+                // Reading the capture clears CCxIF
+                let ch = (offset - CCR1)/4;
+                if t.capture_input(ch).is_some() {
+                    *t.reg_mut(SR) &= !(1 << (ch + 1));
+                }
+                t.reg(offset)
+            }
+            _ => t.regs.get((offset/4) as usize).cloned().unwrap_or_default(),
+        }
+    }
+
+    fn write(&mut self, sys: &System, offset: u32, value: u32) {
+        self.update(sys);
+        let now = crate::context::now();
+        let clk = self.clock(sys);
+        {
+            let mut timers = sys.p.timers.borrow_mut();
+            let encoder = timers.encoder_position(&self.name, clk.hclk);
+            let t = timers.timers.entry(self.name.clone()).or_default();
+            let before = (1..=4).map(|ch| t.pwm_duty(&self.name, ch)).collect::<Vec<_>>();
+            match offset {
+                // The flags are cleared by writing 0
+                SR => *t.reg_mut(SR) &= value,
+                CNT => t.set_counter(value, now, encoder),
+                EGR => if value & EGR_UG != 0 {
+                    t.set_counter(0, now, encoder);
+                }
+                _ => {
+                    // The counter goes on from where it is with the new settings
+                    if matches!(offset, CR1 | SMCR | CCER | PSC | ARR) {
+                        let cnt = t.counter_at(now, clk, encoder);
+                        t.set_counter(cnt, now, encoder);
+                    }
+                    if let Some(r) = t.regs.get_mut((offset/4) as usize) {
+                        *r = value;
+                    }
+                }
+            }
+
+            for (ch, before) in (1..=4).zip(before) {
+                let duty = t.pwm_duty(&self.name, ch);
+                if duty != before {
+                    match duty {
+                        Some(duty) => debug!("{} CH{} pwm duty={:.3}", self.name, ch, duty),
+                        None => debug!("{} CH{} pwm disabled", self.name, ch),
+                    }
+                }
+            }
+        }
+        // An input capture may have been enabled
+        self.update(sys);
+    }
+
+    fn tick(&mut self, sys: &System) {
+        self.update(sys);
+    }
+}
@@ -4,65 +4,407 @@ use std::cell::RefCell;
 use std::rc::Rc;
 
 use crate::ext_devices::{ExtDevices, ExtDevice};
+use crate::family::Layout;
 use crate::system::System;
 use super::Peripheral;
 
+// SR register bits, same in ISR
+const SR_IDLE: u32 = 1 << 4;
+const SR_RXNE: u32 = 1 << 5;
+const SR_TC: u32 = 1 << 6;
+const SR_TXE: u32 = 1 << 7;
+// Only in ISR
+const ISR_RTOF: u32 = 1 << 11;
+const ISR_TEACK: u32 = 1 << 21;
+const ISR_REACK: u32 = 1 << 22;
+
+// ICR register bits
+const ICR_IDLECF: u32 = 1 << 4;
+const ICR_TCCF: u32 = 1 << 6;
+const ICR_RTOCF: u32 = 1 << 11;
+
+// CR1 register bits
+const CR1_RE: u32 = 1 << 2;
+const CR1_IDLEIE: u32 = 1 << 4;
+const CR1_TE: u32 = 1 << 3;
+const CR1_RXNEIE: u32 = 1 << 5;
+const CR1_TCIE: u32 = 1 << 6;
+const CR1_TXEIE: u32 = 1 << 7;
+const CR1_PS: u32 = 1 << 9;
+const CR1_PCE: u32 = 1 << 10;
+const CR1_M0: u32 = 1 << 12;
+const CR1_OVER8: u32 = 1 << 15;
+// Only on the newer USARTs, for 7-bit words
+const CR1_M1: u32 = 1 << 28;
+// Only in the F7 layout
+const CR1_RTOIE: u32 = 1 << 26;
+
+// CR2 register bits
+const CR2_STOP_SHIFT: u32 = 12;
+const CR2_RTOEN: u32 = 1 << 23;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reg {
+    Sr,
+    Dr,
+    Brr,
+    Cr1,
+    Cr2,
+    Cr3,
+    // Only in the F7 layout
+    Rtor,
+    Icr,
+    Rdr,
+    Tdr,
+}
+
+/// USARTs, UARTs, and LPUARTs, with the F4 (SR/DR) or F7 (ISR/ICR/RDR/TDR)
+/// register layout. Bytes are handed to the external device as soon as they
+/// are written, but TXE and TC follow the transmission time derived from
+/// BRR, against the emulated clock. Reception isn't timed: bytes are received
+/// when the firmware reads them. IDLE is set a frame after the last received
+/// byte, once the device has no more data, and RTOF (receiver timeout) after
+/// the RTOR number of bits. With parity enabled, the parity bit is stripped
+/// from the transmitted data, and computed on the received data.
 #[derive(Default)]
 pub struct Usart {
     pub name: String,
     pub ext_device: Option<Rc<RefCell<dyn ExtDevice<(), u8>>>>,
+    irq: Option<i32>,
+    // ISR/ICR/RDR/TDR registers
+    has_isr: bool,
+    // The LPUART baud rate divider has 8 fractional bits
+    is_lpuart: bool,
+    // On APB2 instead of APB1
+    is_apb2: bool,
+    brr: u32,
+    cr1: u32,
+    cr2: u32,
+    cr3: u32,
+    rtor: u32,
+    // Emulated time at which the last written byte is shifted out
+    tx_end: u64,
+    tx_busy: bool,
+    tc: bool,
+    // Emulated time at which the last byte was received. Cleared once the
+    // line is idle.
+    rx_last: Option<u64>,
+    idle: bool,
+    // Armed by each received byte, like IDLE, but only with RTOEN
+    rto_armed: bool,
+    rtof: bool,
 }
 
 impl Usart {
-    pub fn new(name: &str, ext_devices: &ExtDevices) -> Option<Box<dyn Peripheral>> {
-        if name.starts_with("USART") {
-            let ext_device = ext_devices.find_serial_device(&name);
+    pub fn new(name: &str, layout: Layout, ext_devices: &ExtDevices, irq: Option<i32>) -> Option<Box<dyn Peripheral>> {
+        if ["USART", "UART", "LPUART"].iter().any(|prefix| name.starts_with(prefix)) {
+            let ext_device = ext_devices.find_serial_device(name);
+            let is_lpuart = name.starts_with("LPUART");
+            // The LPUARTs came with the new layout
+            let has_isr = is_lpuart || matches!(layout, Layout::F7 | Layout::H7);
+            let is_apb2 = matches!(name, "USART1" | "USART6");
             let name = ext_device.as_ref()
                 .map(|d| d.borrow_mut().connect_peripheral(name))
                 .unwrap_or_else(|| name.to_string());
-            Some(Box::new(Self { name, ext_device, ..Default::default() }))
+            Some(Box::new(Self { name, ext_device, irq, has_isr, is_lpuart, is_apb2, tc: true, ..Default::default() }))
         } else {
             None
         }
     }
+
+    fn reg(&self, offset: u32) -> Option<Reg> {
+        let reg = if self.has_isr {
+            match offset {
+                0x0000 => Reg::Cr1,
+                0x0004 => Reg::Cr2,
+                0x0008 => Reg::Cr3,
+                0x000C => Reg::Brr,
+                0x0014 => Reg::Rtor,
+                0x001C => Reg::Sr,
+                0x0020 => Reg::Icr,
+                0x0024 => Reg::Rdr,
+                0x0028 => Reg::Tdr,
+                _ => return None,
+            }
+        } else {
+            match offset {
+                0x0000 => Reg::Sr,
+                0x0004 => Reg::Dr,
+                0x0008 => Reg::Brr,
+                0x000C => Reg::Cr1,
+                0x0010 => Reg::Cr2,
+                0x0014 => Reg::Cr3,
+                _ => return None,
+            }
+        };
+        Some(reg)
+    }
+
+    fn now() -> u64 {
+        crate::context::now()
+    }
+
+    /// Number of bits of a word, parity included. Set with M1:M0.
+    fn word_bits(&self) -> u32 {
+        match (self.cr1 & CR1_M1 != 0, self.cr1 & CR1_M0 != 0) {
+            (true, _) => 7,
+            (false, true) => 9,
+            (false, false) => 8,
+        }
+    }
+
+    /// Number of data bits of a word. The parity bit takes the MSB.
+    fn data_bits(&self) -> u32 {
+        self.word_bits() - (self.cr1 & CR1_PCE != 0) as u32
+    }
+
+    /// Duration of a frame, in CPU cycles. 0 when the baud rate isn't configured.
+    fn byte_time(&self, sys: &System) -> u64 {
+        // Start bit, word bits, and 1, 0.5, 2, or 1.5 stop bits. Counted in half bits.
+        let stop_half_bits = [2, 1, 4, 3][((self.cr2 >> CR2_STOP_SHIFT) & 0b11) as usize];
+        let frame_half_bits = 2*(1 + self.word_bits()) + stop_half_bits;
+        self.half_bits_time(sys, frame_half_bits as u64)
+    }
+
+    /// Duration of the receiver timeout, in CPU cycles. RTOR counts bits.
+    fn rto_time(&self, sys: &System) -> u64 {
+        self.half_bits_time(sys, 2 * (self.rtor & 0xFF_FFFF) as u64)
+    }
+
+    fn half_bits_time(&self, sys: &System, half_bits: u64) -> u64 {
+        // Bit duration, in peripheral clock cycles, over a divider
+        let (bit_time, divider) = if self.is_lpuart {
+            (self.brr & 0xF_FFFF, 256)
+        } else if self.cr1 & CR1_OVER8 != 0 {
+            ((self.brr >> 4) * 8 + (self.brr & 0x7), 1)
+        } else {
+            (self.brr & 0xFFFF, 1)
+        };
+
+        // The emulated time runs at the CPU clock
+        let (hclk, pclk) = sys.p.rcc.borrow().clocks()
+            .map(|c| (c.hclk, if self.is_apb2 { c.pclk2 } else { c.pclk1 }))
+            .filter(|(_, pclk)| *pclk != 0)
+            .unwrap_or((1, 1));
+
+        half_bits * bit_time as u64 * hclk as u64 / (2 * divider * pclk as u64)
+    }
+
+    /// The received word, as read from DR. The parity bit is set to match the configured parity.
+    fn rx_word(&self, v: u8) -> u32 {
+        let data_bits = self.data_bits();
+        let v = v as u32 & ((1 << data_bits.min(8)) - 1);
+        if self.cr1 & CR1_PCE != 0 {
+            let odd = self.cr1 & CR1_PS != 0;
+            let parity = (v.count_ones() % 2 == 1) != odd;
+            v | (parity as u32) << data_bits
+        } else {
+            v
+        }
+    }
+
+    fn update_tx(&mut self, now: u64) {
+        if self.tx_busy && now >= self.tx_end {
+            self.tx_busy = false;
+            self.tc = true;
+        }
+    }
+
+    /// Sets IDLE and RTOF once the line stays quiet after the last received byte
+    fn update_rx(&mut self, sys: &System, now: u64) {
+        let last = match self.rx_last {
+            Some(last) => last,
+            None => return,
+        };
+        if self.has_rx_data(sys) {
+            return;
+        }
+        if self.rto_armed && self.cr2 & CR2_RTOEN != 0 && now >= last + self.rto_time(sys) {
+            trace!("{} receiver timeout", self.name);
+            self.rto_armed = false;
+            self.rtof = true;
+        }
+        if now >= last + self.byte_time(sys) {
+            trace!("{} idle line", self.name);
+            self.rx_last = None;
+            self.idle = true;
+        }
+    }
+
+    /// The data register is free once the last byte moved to the shift register
+    fn txe(&self, sys: &System, now: u64) -> bool {
+        self.tx_end <= now + self.byte_time(sys)
+    }
+
+    fn sr(&mut self, sys: &System) -> u32 {
+        let now = Self::now();
+        self.update_tx(now);
+
+        self.update_rx(sys, now);
+
+        // We don't know when the external device has data, RXNE stays set
+        let mut sr = SR_RXNE;
+        if self.idle {
+            sr |= SR_IDLE;
+        }
+        if self.rtof && self.has_isr {
+            sr |= ISR_RTOF;
+        }
+        if self.txe(sys, now) {
+            sr |= SR_TXE;
+        }
+        if self.tc {
+            sr |= SR_TC;
+        }
+        if self.has_isr {
+            // The transmitter and receiver acknowledge being enabled right away
+            if self.cr1 & CR1_TE != 0 {
+                sr |= ISR_TEACK;
+            }
+            if self.cr1 & CR1_RE != 0 {
+                sr |= ISR_REACK;
+            }
+        }
+        sr
+    }
+
+    fn has_rx_data(&self, sys: &System) -> bool {
+        self.ext_device.as_ref()
+            .is_some_and(|d| d.borrow_mut().has_data(sys))
+    }
+
+    /// Raises the interrupt when an enabled event is active, and schedules
+    /// the next check for the events to come.
+    fn update_interrupt(&mut self, sys: &System) {
+        let now = Self::now();
+        let sr = self.sr(sys);
+
+        let pending = (sr & SR_TXE != 0 && self.cr1 & CR1_TXEIE != 0) ||
+                      (sr & SR_TC != 0 && self.cr1 & CR1_TCIE != 0) ||
+                      (sr & SR_IDLE != 0 && self.cr1 & CR1_IDLEIE != 0) ||
+                      (sr & ISR_RTOF != 0 && self.cr1 & CR1_RTOIE != 0) ||
+                      (self.cr1 & CR1_RXNEIE != 0 && self.has_rx_data(sys));
+
+        if let Some(irq) = self.irq.filter(|_| pending) {
+            sys.p.nvic.borrow_mut().set_intr_pending(irq);
+        }
+
+        let byte_time = self.byte_time(sys);
+        if self.tx_busy {
+            if self.cr1 & CR1_TXEIE != 0 && sr & SR_TXE == 0 {
+                sys.p.schedule_tick(self.tx_end.saturating_sub(byte_time));
+            }
+            if self.cr1 & CR1_TCIE != 0 {
+                sys.p.schedule_tick(self.tx_end);
+            }
+        }
+        if self.cr1 & CR1_RXNEIE != 0 {
+            // Bytes can't arrive faster than that
+            sys.p.schedule_tick(now + byte_time.max(1));
+        }
+        if let Some(last) = self.rx_last {
+            if self.cr1 & CR1_IDLEIE != 0 {
+                sys.p.schedule_tick((last + byte_time).max(now + 1));
+            }
+            if self.rto_armed && self.cr1 & CR1_RTOIE != 0 && self.cr2 & CR2_RTOEN != 0 {
+                sys.p.schedule_tick((last + self.rto_time(sys)).max(now + 1));
+            }
+        }
+    }
+
+    fn transmit(&mut self, sys: &System, value: u32) {
+        // The parity bit is generated by the hardware, the written one is ignored
+        let data_bits = self.data_bits();
+        let value = value & ((1 << data_bits) - 1);
+        if value > 0xFF {
+            trace!("{} 9th data bit dropped write=0x{:03x}", self.name, value);
+        }
+        let value = value as u8;
+
+        let now = Self::now();
+        self.update_tx(now);
+        self.tx_end = self.tx_end.max(now) + self.byte_time(sys);
+        self.tx_busy = true;
+        self.tc = false;
+
+        if let Some(ref d) = self.ext_device {
+            d.borrow_mut().write(sys, (), value);
+        }
+        if let Some(ref mut expect) = *sys.p.expect.borrow_mut() {
+            expect.on_usart(&self.name, value);
+        }
+
+        trace!("{} write={:02x}", self.name, value);
+    }
 }
 
 impl Peripheral for Usart {
+    fn has_side_effects(&self, offset: u32) -> bool {
+        matches!(self.reg(offset), Some(Reg::Dr | Reg::Rdr | Reg::Tdr))
+    }
+
     fn read(&mut self, sys: &System, offset: u32) -> u32 {
-        match offset {
-            0x0000 => {
-                // SR register
-                // Bit 7 TXE: Transmit data register empty
-                // Bit 6 TC: Transmission complete
-                // Bit 5 RXNE: Read data register not empty
-                // Bit 4 IDLE: IDLE line detected
-                // We could do something smarter to indicate that there's data to read
-                (1 << 7) | (1 << 6) | (1 << 5) | (1 << 4)
-            }
-            0x0004 => {
-                // DR register
+        match self.reg(offset) {
+            Some(Reg::Sr) => self.sr(sys),
+            Some(Reg::Dr | Reg::Rdr) => {
+                if self.has_rx_data(sys) {
+                    self.rx_last = Some(Self::now());
+                    self.rto_armed = true;
+                }
+                // On the F4 layout, IDLE is cleared by reading SR then DR
+                if !self.has_isr {
+                    self.idle = false;
+                }
                 let v = self.ext_device.as_ref().map(|d|
                     d.borrow_mut().read(sys, ())
-                ).unwrap_or_default() as u32;
+                ).unwrap_or_default();
+                let v = self.rx_word(v);
 
                 trace!("{} read={:02x}", self.name, v);
                 v
             }
+            Some(Reg::Brr) => self.brr,
+            Some(Reg::Rtor) => self.rtor,
+            Some(Reg::Cr1) => self.cr1,
+            Some(Reg::Cr2) => self.cr2,
+            Some(Reg::Cr3) => self.cr3,
             _ => 0
         }
     }
 
     fn write(&mut self, sys: &System, offset: u32, value: u32) {
-        match offset {
-            0x0004 => {
-                // DR register
-                self.ext_device.as_ref().map(|d|
-                    d.borrow_mut().write(sys, (), value as u8)
-                );
-
-                trace!("{} write={:02x}", self.name, value as u8);
+        match self.reg(offset) {
+            Some(Reg::Sr) => {
+                // TC can be cleared by writing 0. ISR is read-only.
+                self.update_tx(Self::now());
+                if value & SR_TC == 0 && !self.has_isr {
+                    self.tc = false;
+                }
+            }
+            Some(Reg::Icr) => {
+                self.update_tx(Self::now());
+                if value & ICR_TCCF != 0 {
+                    self.tc = false;
+                }
+                if value & ICR_IDLECF != 0 {
+                    self.idle = false;
+                }
+                if value & ICR_RTOCF != 0 {
+                    self.rtof = false;
+                }
             }
+            Some(Reg::Dr | Reg::Tdr) => self.transmit(sys, value),
+            Some(Reg::Brr) => self.brr = value,
+            Some(Reg::Rtor) => self.rtor = value,
+            Some(Reg::Cr1) => self.cr1 = value,
+            Some(Reg::Cr2) => self.cr2 = value,
+            Some(Reg::Cr3) => self.cr3 = value,
             _ => {}
         }
+        self.update_interrupt(sys);
+    }
+
+    fn tick(&mut self, sys: &System) {
+        self.update_interrupt(sys);
     }
 }
@@ -0,0 +1,96 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{rc::Rc, cell::RefCell, collections::HashMap, io::Write};
+use unicorn_engine::Unicorn;
+use anyhow::{Context, Result};
+
+use crate::symbols::symbols;
+
+// Number of functions shown in the table printed at exit
+const NUM_FUNCTIONS_SHOWN: usize = 30;
+
+/// Counts executed instructions per PC. They are attributed to functions at exit.
+#[derive(Default)]
+pub struct Profiler {
+    counts: HashMap<u32, u64>,
+}
+
+struct FunctionProfile {
+    name: String,
+    count: u64,
+    // Per instruction counts, for the callgrind output
+    counts: Vec<(u32, u64)>,
+}
+
+impl Profiler {
+    pub fn install(uc: &mut Unicorn<()>) -> Rc<RefCell<Self>> {
+        let self_ = Rc::new(RefCell::new(Self::default()));
+
+        {
+            let self_ = self_.clone();
+            uc.add_code_hook(0, u64::MAX, move |_uc, pc, _size| {
+                *self_.borrow_mut().counts.entry(pc as u32).or_default() += 1;
+            }).expect("add_code_hook failed");
+        }
+
+        self_
+    }
+
+    fn functions(&self) -> Vec<FunctionProfile> {
+        let mut functions: HashMap<String, FunctionProfile> = HashMap::new();
+
+        for (pc, count) in &self.counts {
+            let name = symbols().find_function(*pc)
+                .map(|s| s.name.clone())
+                .unwrap_or_else(|| "??".to_string());
+            let f = functions.entry(name.clone())
+                .or_insert_with(|| FunctionProfile { name, count: 0, counts: vec![] });
+            f.count += count;
+            f.counts.push((*pc, *count));
+        }
+
+        let mut functions: Vec<_> = functions.into_values().collect();
+        for f in &mut functions {
+            f.counts.sort_unstable();
+        }
+        functions.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.name.cmp(&b.name)));
+        functions
+    }
+
+    pub fn report(&self, callgrind_file: Option<&str>) -> Result<()> {
+        let functions = self.functions();
+        let total: u64 = functions.iter().map(|f| f.count).sum();
+
+        info!("Profile num_instructions={} num_functions={}", total, functions.len());
+        for f in functions.iter().take(NUM_FUNCTIONS_SHOWN) {
+            info!("{:>14} {:>5.1}% {}", f.count, 100.0 * f.count as f64 / total.max(1) as f64, f.name);
+        }
+
+        if let Some(path) = callgrind_file {
+            Self::write_callgrind(path, &functions)
+                .with_context(|| format!("Failed to write {}", path))?;
+            info!("Callgrind profile written to {}", path);
+        }
+
+        Ok(())
+    }
+
+    fn write_callgrind(path: &str, functions: &[FunctionProfile]) -> Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(out, "# callgrind format")?;
+        writeln!(out, "version: 1")?;
+        writeln!(out, "creator: stm32-emulator")?;
+        writeln!(out, "positions: instr")?;
+        writeln!(out, "events: Instructions")?;
+        writeln!(out)?;
+        writeln!(out, "fl=firmware")?;
+        for f in functions {
+            writeln!(out, "fn={}", f.name)?;
+            for (pc, count) in &f.counts {
+                writeln!(out, "0x{:08x} {}", pc, count)?;
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use serde::Deserialize;
+use unicorn_engine::{Unicorn, RegisterARM};
+use anyhow::{Result, bail};
+
+use crate::{symbols::symbols, util::UniErr};
+
+// RTOS awareness. We walk the task lists of the kernel to show what each task
+// is doing, which helps when the firmware deadlocks.
+// Only FreeRTOS is supported for now.
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub enum RtosKind {
+    FreeRTOS,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RtosConfig {
+    pub kind: RtosKind,
+    /// TCB field offsets. The defaults correspond to a FreeRTOS build without
+    /// the MPU wrappers and without the list integrity check bytes.
+    pub tcb_priority_offset: Option<u32>,
+    pub tcb_stack_offset: Option<u32>,
+    pub tcb_name_offset: Option<u32>,
+    /// configMAX_TASK_NAME_LEN
+    pub max_task_name_len: Option<u32>,
+    /// configMAX_PRIORITIES. Derived from the size of pxReadyTasksLists when absent.
+    pub max_priorities: Option<u32>,
+}
+
+// FreeRTOS fills the task stacks with this byte on creation
+const STACK_FILL_BYTE: u8 = 0xA5;
+// Guards against corrupted lists
+const MAX_LIST_ITEMS: usize = 256;
+// sizeof(List_t)
+const LIST_SIZE: u32 = 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TaskState {
+    Running,
+    Ready,
+    Blocked,
+    Suspended,
+    Deleted,
+}
+
+struct Task {
+    tcb: u32,
+    name: String,
+    state: TaskState,
+    priority: u32,
+    sp: u32,
+    stack_start: u32,
+}
+
+pub struct Rtos {
+    config: RtosConfig,
+}
+
+fn read_u32(uc: &Unicorn<()>, addr: u32) -> Result<u32> {
+    let mut v = [0; 4];
+    uc.mem_read(addr as u64, &mut v).map_err(UniErr)?;
+    Ok(u32::from_le_bytes(v))
+}
+
+impl Rtos {
+    pub fn new(config: RtosConfig) -> Self {
+        Self { config }
+    }
+
+    /// Returns the TCBs of a List_t
+    fn walk_list(uc: &Unicorn<()>, list: u32) -> Result<Vec<u32>> {
+        // List_t: uxNumberOfItems, pxIndex, xListEnd { xItemValue, pxNext, pxPrevious }
+        // ListItem_t: xItemValue, pxNext, pxPrevious, pvOwner, pvContainer
+        let list_end = list + 8;
+        let mut item = read_u32(uc, list_end + 4)?;
+        let mut tcbs = vec![];
+        while item != list_end {
+            if tcbs.len() == MAX_LIST_ITEMS {
+                bail!("Task list at 0x{:08x} looks corrupted", list);
+            }
+            tcbs.push(read_u32(uc, item + 12)?);
+            item = read_u32(uc, item + 4)?;
+        }
+        Ok(tcbs)
+    }
+
+    fn read_task(&self, uc: &Unicorn<()>, tcb: u32, state: TaskState) -> Result<Task> {
+        let max_name_len = self.config.max_task_name_len.unwrap_or(16);
+        let mut name = vec![0; max_name_len as usize];
+        uc.mem_read((tcb + self.config.tcb_name_offset.unwrap_or(52)) as u64, &mut name).map_err(UniErr)?;
+        let name = name.split(|c| *c == 0).next().unwrap_or_default();
+        let name = String::from_utf8_lossy(name).to_string();
+
+        let sp = if state == TaskState::Running {
+            uc.reg_read(RegisterARM::PSP).map_err(UniErr)? as u32
+        } else {
+            // pxTopOfStack is the first field of the TCB
+            read_u32(uc, tcb)?
+        };
+
+        Ok(Task {
+            tcb,
+            name,
+            state,
+            priority: read_u32(uc, tcb + self.config.tcb_priority_offset.unwrap_or(44))?,
+            sp,
+            stack_start: read_u32(uc, tcb + self.config.tcb_stack_offset.unwrap_or(48))?,
+        })
+    }
+
+    fn tasks(&self, uc: &Unicorn<()>) -> Result<Vec<Task>> {
+        let s = symbols();
+
+        let ready_lists = s.get("pxReadyTasksLists")
+            .ok_or_else(|| anyhow::anyhow!("Unknown symbol pxReadyTasksLists"))?;
+        let max_priorities = self.config.max_priorities
+            .unwrap_or(ready_lists.size / LIST_SIZE);
+        if max_priorities == 0 {
+            bail!("Unknown number of priorities, please set max_priorities");
+        }
+
+        let current_tcb = read_u32(uc, s.resolve("pxCurrentTCB")?)?;
+
+        let mut lists = vec![];
+        for i in 0..max_priorities {
+            lists.push((ready_lists.addr + i*LIST_SIZE, TaskState::Ready));
+        }
+        for (name, state) in [
+            ("xPendingReadyList", TaskState::Ready),
+            ("xDelayedTaskList1", TaskState::Blocked),
+            ("xDelayedTaskList2", TaskState::Blocked),
+            ("xSuspendedTaskList", TaskState::Suspended),
+            ("xTasksWaitingTermination", TaskState::Deleted),
+        ] {
+            // Some lists are compiled out depending on the FreeRTOS config
+            if let Some(sym) = s.get(name) {
+                lists.push((sym.addr, state));
+            }
+        }
+
+        let mut tasks = vec![];
+        for (list, state) in lists {
+            for tcb in Self::walk_list(uc, list)? {
+                let state = if tcb == current_tcb { TaskState::Running } else { state };
+                tasks.push(self.read_task(uc, tcb, state)?);
+            }
+        }
+
+        Ok(tasks)
+    }
+
+    /// Returns the number of bytes of the stack that were never used
+    fn stack_high_water_mark(uc: &Unicorn<()>, task: &Task) -> u32 {
+        let mut addr = task.stack_start;
+        let mut buf = [0; 64];
+        loop {
+            if uc.mem_read(addr as u64, &mut buf).is_err() {
+                break;
+            }
+            let n = buf.iter().take_while(|b| **b == STACK_FILL_BYTE).count();
+            addr += n as u32;
+            if n < buf.len() {
+                break;
+            }
+        }
+        addr - task.stack_start
+    }
+
+    pub fn print_tasks(&self, uc: &Unicorn<()>) {
+        let tasks = match self.tasks(uc) {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                warn!("Failed to list RTOS tasks: {}", e);
+                return;
+            }
+        };
+
+        info!("RTOS kind={:?} num_tasks={}", self.config.kind, tasks.len());
+        for task in &tasks {
+            let free = Self::stack_high_water_mark(uc, task);
+            info!("Task name={:?} state={:?} priority={} tcb=0x{:08x} sp=0x{:08x} stack=0x{:08x} stack_free={}",
+                task.name, task.state, task.priority, task.tcb, task.sp, task.stack_start, free);
+
+            if task.sp < task.stack_start {
+                warn!("Task name={:?} stack overflow by {} bytes", task.name, task.stack_start - task.sp);
+            } else if free == 0 {
+                warn!("Task name={:?} has no stack left, it has likely overflowed", task.name);
+            }
+        }
+    }
+}
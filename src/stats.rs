@@ -0,0 +1,97 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::BTreeMap, time::{Duration, Instant}};
+
+use crate::peripherals::Peripherals;
+
+// With --heartbeat SECS, a line reports the progress of the emulation every
+// SECS seconds of host time: the instructions executed, the emulated time, the
+// speed, and the busiest interrupts and peripherals since the previous line.
+// A stuck firmware shows up as a flat emulated time, or as a single peripheral
+// getting all the accesses. --stats (implied) prints the totals at exit.
+
+// How many interrupts and peripherals are listed per line
+const TOP: usize = 8;
+
+#[derive(Clone, Default)]
+struct Counters {
+    executed: u64,
+    emulated: Duration,
+    irqs: BTreeMap<i32, u64>,
+    accesses: BTreeMap<String, u64>,
+}
+
+pub struct Stats {
+    interval: Option<Duration>,
+    start: Instant,
+    current: Counters,
+    last_report: Instant,
+    last: Counters,
+}
+
+impl Stats {
+    pub fn new(interval: Option<f64>) -> Self {
+        let now = Instant::now();
+        Self {
+            interval: interval.map(Duration::from_secs_f64),
+            start: now,
+            current: Counters::default(),
+            last_report: now,
+            last: Counters::default(),
+        }
+    }
+
+    /// Called periodically from the emulation loop
+    pub fn update(&mut self, p: &Peripherals, executed: u64, emulated: Duration) {
+        self.current.executed = executed;
+        self.current.emulated = emulated;
+
+        let now = Instant::now();
+        if self.interval.is_some_and(|i| now.duration_since(self.last_report) >= i) {
+            self.snapshot(p);
+            let elapsed = now.duration_since(self.last_report);
+            info!("Heartbeat {}", Self::report(&self.current, &self.last, elapsed));
+            self.last = self.current.clone();
+            self.last_report = now;
+        }
+    }
+
+    fn snapshot(&mut self, p: &Peripherals) {
+        self.current.irqs = p.irq_counts.borrow().clone();
+        self.current.accesses = p.access_counts().into_iter()
+            .filter(|(_, n)| *n > 0)
+            .map(|(name, n)| (name.to_string(), n))
+            .collect();
+    }
+
+    /// The counters of `current` since `last`
+    fn report(current: &Counters, last: &Counters, elapsed: Duration) -> String {
+        let executed = current.executed - last.executed;
+        let mips = executed as f64 / elapsed.as_secs_f64().max(1e-9) / 1e6;
+        let emulated = current.emulated.saturating_sub(last.emulated);
+
+        let top = |current: &BTreeMap<String, u64>, last: &BTreeMap<String, u64>| {
+            let mut deltas = current.iter()
+                .map(|(k, n)| (k.as_str(), n - last.get(k).copied().unwrap_or(0)))
+                .filter(|(_, n)| *n > 0)
+                .collect::<Vec<_>>();
+            deltas.sort_by_key(|(_, n)| std::cmp::Reverse(*n));
+            deltas.iter().take(TOP).map(|(k, n)| format!("{}={}", k, n)).collect::<Vec<_>>().join(",")
+        };
+        let names = |irqs: &BTreeMap<i32, u64>| irqs.iter().map(|(irq, n)| (irq.to_string(), *n)).collect();
+        let num_irqs = current.irqs.values().sum::<u64>() - last.irqs.values().sum::<u64>();
+
+        format!("instructions={} emulated_ms={} mips={:.2} num_irqs={} irqs=[{}] accesses=[{}]",
+            executed, emulated.as_millis(), mips, num_irqs,
+            top(&names(&current.irqs), &names(&last.irqs)),
+            top(&current.accesses, &last.accesses))
+    }
+
+    /// The totals of the run
+    pub fn summary(&mut self, p: &Peripherals) {
+        self.snapshot(p);
+        let elapsed = self.start.elapsed();
+        info!("Stats elapsed_secs={:.3} {}", elapsed.as_secs_f64(),
+            Self::report(&self.current, &Counters::default(), elapsed));
+    }
+}
@@ -0,0 +1,60 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use serde::Deserialize;
+use unicorn_engine::{Unicorn, RegisterARM};
+use anyhow::{Result, bail};
+
+use crate::{symbols::symbols, util::UniErr};
+
+// Stubs replace firmware functions that can't work under emulation, like
+// hardware self-tests. When the function is entered, we return right away
+// to the caller, or jump elsewhere.
+
+#[derive(Debug, Deserialize)]
+pub struct StubConfig {
+    pub addr: Option<u32>,
+    /// Alternative to addr. Can be `symbol+offset`.
+    pub symbol: Option<String>,
+    /// Value returned in r0
+    pub r#return: Option<u32>,
+    /// Jump to this address instead of returning to the caller
+    pub jump: Option<u32>,
+}
+
+impl StubConfig {
+    fn resolve_addr(&self) -> Result<u32> {
+        let addr = match (self.addr, self.symbol.as_ref()) {
+            (Some(addr), None) => addr,
+            (None, Some(symbol)) => symbols().parse_addr(symbol)?,
+            _ => bail!("Stubs must have exactly one of addr or symbol"),
+        };
+        // Function addresses may come with the thumb bit
+        Ok(addr & !1)
+    }
+}
+
+pub fn install(uc: &mut Unicorn<()>, stubs: &[StubConfig]) -> Result<()> {
+    for stub in stubs {
+        let addr = stub.resolve_addr()?;
+        let ret = stub.r#return;
+        let jump = stub.jump;
+        let name = stub.symbol.clone().unwrap_or_else(|| format!("0x{:08x}", addr));
+
+        debug!("Installing stub name={} return={:?} jump={:?}", name, ret, jump);
+
+        uc.add_code_hook(addr as u64, addr as u64, move |uc, _pc, _size| {
+            trace!("Stub called name={}", name);
+            if let Some(ret) = ret {
+                uc.reg_write(RegisterARM::R0, ret as u64).unwrap();
+            }
+            // Writing the PC also sets the thumb state from bit 0
+            let pc = match jump {
+                Some(jump) => jump as u64 | 1,
+                None => uc.reg_read(RegisterARM::LR).unwrap(),
+            };
+            uc.reg_write(RegisterARM::PC, pc).unwrap();
+        }).map_err(UniErr)?;
+    }
+
+    Ok(())
+}
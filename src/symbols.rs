@@ -0,0 +1,206 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+
+use crate::config::Config;
+
+// Symbols come from the firmware ELF file, and from the config file for
+// firmwares that we only have as a binary.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SymbolKind {
+    Function,
+    Object,
+}
+
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    // The thumb bit is removed from function addresses
+    pub addr: u32,
+    pub size: u32,
+    pub kind: SymbolKind,
+}
+
+#[derive(Default)]
+pub struct Symbols {
+    // Sorted by address
+    symbols: Vec<Symbol>,
+    by_name: HashMap<String, usize>,
+}
+
+/// Returns the symbols of the firmware of the current emulator. Empty when
+/// none were given.
+pub fn symbols() -> Arc<Symbols> {
+    crate::context::current().symbols.clone()
+}
+
+/// Loads the symbols of the ELF file and of the config
+pub fn load(config: &Config) -> Result<Symbols> {
+    let mut symbols = match config.elf.as_ref() {
+        Some(elf) => Symbols::from_elf(elf)?,
+        None => Symbols::default(),
+    };
+    for (name, addr) in config.symbols.iter().flatten() {
+        symbols.add(name, *addr);
+    }
+    if config.elf.is_some() || config.symbols.is_some() {
+        info!("Loaded num_symbols={}", symbols.len());
+    }
+    Ok(symbols)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Option<u16> {
+    data.get(offset..offset+2).map(|v| u16::from_le_bytes(v.try_into().unwrap()))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    data.get(offset..offset+4).map(|v| u32::from_le_bytes(v.try_into().unwrap()))
+}
+
+impl Symbols {
+    /// Reads the symbol table of a 32-bit little endian ELF file
+    pub fn from_elf(file: &str) -> Result<Self> {
+        let data = crate::util::read_file(file)?;
+        Self::parse_elf(&data).with_context(|| format!("Failed to parse {}", file))
+    }
+
+    fn parse_elf(data: &[u8]) -> Result<Self> {
+        // e_ident: magic, ELFCLASS32, ELFDATA2LSB
+        if data.get(0..6) != Some(&[0x7F, b'E', b'L', b'F', 1, 1]) {
+            bail!("Not a 32-bit little endian ELF file");
+        }
+
+        let truncated = || anyhow::anyhow!("Truncated ELF file");
+
+        let shoff = read_u32(data, 0x20).ok_or_else(truncated)? as usize;
+        let shentsize = read_u16(data, 0x2E).ok_or_else(truncated)? as usize;
+        let shnum = read_u16(data, 0x30).ok_or_else(truncated)? as usize;
+
+        // Section header fields: sh_type at 4, sh_offset at 16, sh_size at 20, sh_link at 24
+        let section = |i: usize| -> Option<(u32, usize, usize, usize)> {
+            let h = shoff + i*shentsize;
+            Some((
+                read_u32(data, h+4)?,
+                read_u32(data, h+16)? as usize,
+                read_u32(data, h+20)? as usize,
+                read_u32(data, h+24)? as usize,
+            ))
+        };
+
+        const SHT_SYMTAB: u32 = 2;
+        const STT_OBJECT: u8 = 1;
+        const STT_FUNC: u8 = 2;
+
+        let mut self_ = Self::default();
+
+        for i in 0..shnum {
+            let (sh_type, offset, size, link) = section(i).ok_or_else(truncated)?;
+            if sh_type != SHT_SYMTAB {
+                continue;
+            }
+
+            let (_, str_offset, str_size, _) = section(link).ok_or_else(truncated)?;
+            let strtab = data.get(str_offset..str_offset+str_size).ok_or_else(truncated)?;
+            let symtab = data.get(offset..offset+size).ok_or_else(truncated)?;
+
+            // Symbol entries are 16 bytes: st_name, st_value, st_size, st_info, st_other, st_shndx
+            for sym in symtab.chunks_exact(16) {
+                let kind = match sym[12] & 0xF {
+                    STT_FUNC => SymbolKind::Function,
+                    STT_OBJECT => SymbolKind::Object,
+                    _ => continue,
+                };
+
+                let name_offset = read_u32(sym, 0).unwrap() as usize;
+                let name = strtab.get(name_offset..)
+                    .and_then(|s| s.split(|c| *c == 0).next())
+                    .map(|s| String::from_utf8_lossy(s).to_string())
+                    .unwrap_or_default();
+                if name.is_empty() {
+                    continue;
+                }
+
+                let mut addr = read_u32(sym, 4).unwrap();
+                if kind == SymbolKind::Function {
+                    addr &= !1;
+                }
+                let size = read_u32(sym, 8).unwrap();
+
+                self_.symbols.push(Symbol { name, addr, size, kind });
+            }
+        }
+
+        self_.reindex();
+        Ok(self_)
+    }
+
+    /// Adds symbols given in the config file. They take precedence over the ELF ones.
+    pub fn add(&mut self, name: &str, addr: u32) {
+        self.symbols.retain(|s| s.name != name);
+        // We don't know if it's code or data. Code is more useful to look up.
+        self.symbols.push(Symbol { name: name.to_string(), addr, size: 0, kind: SymbolKind::Function });
+        self.reindex();
+    }
+
+    fn reindex(&mut self) {
+        self.symbols.sort_by_key(|s| s.addr);
+        self.by_name = self.symbols.iter().enumerate()
+            .map(|(i, s)| (s.name.clone(), i))
+            .collect();
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn functions(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter().filter(|s| s.kind == SymbolKind::Function)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Symbol> {
+        self.by_name.get(name).map(|i| &self.symbols[*i])
+    }
+
+    pub fn resolve(&self, name: &str) -> Result<u32> {
+        self.get(name)
+            .map(|s| s.addr)
+            .with_context(|| format!("Unknown symbol {}", name))
+    }
+
+    /// Returns the function containing the address. Functions of unknown
+    /// size are assumed to extend to the next symbol.
+    pub fn find_function(&self, addr: u32) -> Option<&Symbol> {
+        let index = self.symbols.partition_point(|s| s.addr <= addr);
+        self.symbols[..index].iter().rev()
+            .find(|s| s.kind == SymbolKind::Function)
+            .filter(|s| s.size == 0 || addr < s.addr + s.size)
+    }
+
+    /// Like `func+0x12`, or `????` when the address isn't in a known function
+    pub fn describe(&self, addr: u32) -> String {
+        self.find_function(addr)
+            .map(|s| format!("{}+0x{:x}", s.name, addr - s.addr))
+            .unwrap_or_else(|| "????".to_string())
+    }
+
+    /// Parses an address given as a number, a symbol name, or `symbol+offset`
+    pub fn parse_addr(&self, s: &str) -> Result<u32> {
+        if let Ok(addr) = clap_num::maybe_hex::<u32>(s) {
+            return Ok(addr);
+        }
+
+        match s.split_once('+') {
+            Some((name, offset)) => {
+                let offset = clap_num::maybe_hex::<u32>(offset.trim())
+                    .map_err(|e| anyhow::anyhow!("Invalid offset in {}: {}", s, e))?;
+                Ok(self.resolve(name.trim())? + offset)
+            }
+            None => self.resolve(s),
+        }
+    }
+
+}
@@ -1,9 +1,9 @@
 // SPDX-License-Identifier: GPL-3.0-or-later
 
-use std::{rc::Rc, cell::RefCell};
+use std::{rc::Rc, cell::RefCell, collections::HashMap};
 use unicorn_engine::{Unicorn, unicorn_const::Permission};
-use crate::{peripherals::{Peripherals, gpio::GpioPorts}, ext_devices::ExtDevices, util::{UniErr, round_up, self}, config::Config, framebuffers::Framebuffers};
-use anyhow::{Context as _, Result};
+use crate::{peripherals::{Peripherals, gpio::GpioPorts}, ext_devices::{ExtDevices, CustomDevices}, util::{UniErr, round_up, self}, config::{Config, Region}, framebuffers::Framebuffers, cpu::CpuModel};
+use anyhow::{Context as _, Result, bail};
 use svd_parser::svd::Device as SvdDevice;
 
 // System is passed around during read/write hooks. It's more convenient than passing each thing individually.
@@ -53,44 +53,163 @@ impl<'a, 'b> System<'a, 'b> {
     }
 }
 
-fn load_memory_regions(uc: &mut Unicorn<()>, config: &Config) -> Result<()> {
-    for region in &config.regions {
+pub fn memory_regions(config: &mut Config, device_name: &str) -> Result<Vec<Region>> {
+    let auto_regions = config.regions.is_none() || config.cpu.auto_regions.unwrap_or_default();
+    let mut regions = config.regions.take().unwrap_or_default();
+    if auto_regions && !crate::family::complete_regions(&mut regions, device_name) {
+        bail!("Unknown memory layout for device={}, please specify the regions", device_name);
+    }
+    Ok(regions)
+}
+
+pub const FLASH_START: u32 = 0x0800_0000;
+
+/// The region to map at address 0 as well, which is the flash. Nothing else
+/// must be mapped there.
+pub fn boot_alias_region<'a>(regions: &'a [Region], config: &Config) -> Option<&'a Region> {
+    if !config.cpu.boot_alias.unwrap_or(true) {
+        return None;
+    }
+    let flash = regions.iter().find(|r| (r.start..r.start.saturating_add(r.size)).contains(&FLASH_START))?;
+    let overlaps = regions.iter().any(|r| r.start < flash.size);
+    (!overlaps).then_some(flash)
+}
+
+/// The memory of the regions aliased by others. It must outlive the Unicorn
+/// instance.
+pub type SharedMemory = Vec<Box<[u8]>>;
+
+fn map_memory_regions(uc: &mut Unicorn<()>, regions: &[Region], config: &Config) -> Result<SharedMemory> {
+    let boot_alias = boot_alias_region(regions, config);
+
+    // Regions aliased by others share their memory
+    let mut shared: HashMap<&str, Box<[u8]>> = HashMap::new();
+    for region in regions {
+        if let Some(ref name) = region.mirror_of {
+            let target = regions.iter().find(|r| r.name == *name && r.mirror_of.is_none())
+                .with_context(|| format!("Region {} mirrors unknown region {}", region.name, name))?;
+            if region.size > target.size {
+                bail!("Region {} is larger than the region {} it mirrors", region.name, name);
+            }
+            if region.load.is_some() {
+                bail!("Region {} mirrors {}, it can't load a file", region.name, name);
+            }
+        }
+    }
+    for region in regions {
+        let mirrored = regions.iter().any(|r| r.mirror_of.as_deref() == Some(region.name.as_str()));
+        if mirrored || boot_alias.is_some_and(|r| std::ptr::eq(r, region)) {
+            let size = round_up(region.size as usize, 4096); // magic number is from mem_map() documentation
+            shared.insert(&region.name, vec![0u8; size].into_boxed_slice());
+        }
+    }
+
+    let map_ptr = |uc: &mut Unicorn<()>, name: &str, start: u32, size: usize, memory: *mut u8| {
+        unsafe { uc.mem_map_ptr(start.into(), size, Permission::ALL, memory as _) }
+            .map_err(UniErr).with_context(||
+                format!("Memory mapping of peripheral={} at 0x{:08x} failed", name, start))
+    };
+
+    for region in regions {
         debug!("Mapping region start=0x{:08x} len=0x{:x} name={}",
             region.start, region.size, region.name);
 
         let size = round_up(region.size as usize, 4096); // magic number is from mem_map() documentation
-        uc.mem_map(region.start.into(), size, Permission::ALL)
-            .map_err(UniErr).with_context(||
-                format!("Memory mapping of peripheral={} failed", region.name))?;
-
-        if let Some(ref load) = region.load {
-            info!("Loading file={} at base=0x{:08x}", load, region.start);
-            let content = util::read_file(load)?;
-            let content = &content[0..content.len().min(size)];
-            uc.mem_write(region.start.into(), content).map_err(UniErr)?;
+        let backing = region.mirror_of.as_deref().unwrap_or(&region.name);
+        if let Some(memory) = shared.get_mut(backing) {
+            map_ptr(uc, &region.name, region.start, size, memory.as_mut_ptr())?;
+            if let Some(ref name) = region.mirror_of {
+                debug!("Mirroring region name={} at 0x{:08x}", name, region.start);
+            }
+            if boot_alias.is_some_and(|r| std::ptr::eq(r, region)) {
+                map_ptr(uc, &region.name, 0, size, memory.as_mut_ptr())?;
+                debug!("Aliasing region name={} at 0x00000000", region.name);
+            }
+        } else {
+            uc.mem_map(region.start.into(), size, Permission::ALL)
+                .map_err(UniErr).with_context(||
+                    format!("Memory mapping of peripheral={} failed", region.name))?;
         }
     }
 
-    for patch in config.patches.as_ref().unwrap_or(&vec![]) {
-        uc.mem_write(patch.start.into(), &patch.data)
-            .map_err(UniErr).with_context(||
-                format!("Failed to apply patch at addr={}", patch.start))?;
+    Ok(shared.into_values().collect())
+}
+
+/// The files loaded in memory, and the patches applied on top. They can be
+/// loaded again at runtime, to reload the firmware without restarting.
+pub struct FirmwareImages {
+    // addr, file, max size
+    files: Vec<(u32, String, usize)>,
+    // addr, data
+    patches: Vec<(u32, Vec<u8>)>,
+}
+
+impl FirmwareImages {
+    fn from_config(regions: &[Region], config: &Config) -> Result<Self> {
+        let mut files: Vec<_> = regions.iter()
+            .filter_map(|r| r.load.as_ref().map(|load| (r.start, load.clone(), round_up(r.size as usize, 4096))))
+            .collect();
+
+        if let Some(ref firmware) = config.cpu.firmware {
+            files.push((config.cpu.vector_table, firmware.clone(), usize::MAX));
+        }
+
+        let patches = config.patches.iter().flatten()
+            .map(|patch| Ok((patch.resolve_addr()?, patch.data.clone())))
+            .collect::<Result<_>>()?;
+
+        Ok(Self { files, patches })
     }
 
-    Ok(())
+    /// Reads the files from disk, and writes them in memory
+    pub fn load(&self, uc: &mut Unicorn<()>) -> Result<()> {
+        for (start, file, max_size) in &self.files {
+            info!("Loading file={} at base=0x{:08x}", file, start);
+            let content = util::read_file(file)?;
+            let content = &content[0..content.len().min(*max_size)];
+            uc.mem_write((*start).into(), content)
+                .map_err(UniErr).with_context(||
+                    format!("Failed to load {} at addr=0x{:08x}", file, start))?;
+        }
+
+        for (start, data) in &self.patches {
+            uc.mem_write((*start).into(), data)
+                .map_err(UniErr).with_context(||
+                    format!("Failed to apply patch at addr=0x{:08x}", start))?;
+        }
+
+        Ok(())
+    }
 }
 
-pub fn prepare<'a, 'b>(uc: &'a mut Unicorn<'b, ()>, config: Config, svd_device: SvdDevice)
--> Result<(System<'a, 'b>, Framebuffers)>
+pub fn prepare<'a, 'b>(uc: &'a mut Unicorn<'b, ()>, mut config: Config, svd_device: SvdDevice, cpu: CpuModel, custom_devices: CustomDevices)
+-> Result<(System<'a, 'b>, Framebuffers, FirmwareImages, SharedMemory)>
   {
-    load_memory_regions(uc, &config)?;
+    let regions = memory_regions(&mut config, &svd_device.name)?;
+    let memory = map_memory_regions(uc, &regions, &config)?;
+    crate::system_memory::map(uc, config.system_memory.take().unwrap_or_default(), &regions, &svd_device.name)?;
+    let firmware = FirmwareImages::from_config(&regions, &config)?;
+    firmware.load(uc)?;
 
-    let framebuffers = Framebuffers::from_config(config.framebuffers.unwrap_or_default());
+    let framebuffers = Framebuffers::from_config(config.framebuffers.unwrap_or_default())?;
     let mut gpio: GpioPorts = Default::default();
-    let ext_devices = config.devices.unwrap_or_default().into_ext_devices(&mut gpio, &framebuffers)?;
-    let peripherals = Peripherals::from_svd(svd_device, config.peripherals.unwrap_or_default(), gpio, &ext_devices);
+    #[cfg(feature = "sdl")]
+    framebuffers.connect_backlights(&mut gpio)?;
+    let mut ext_devices = config.devices.unwrap_or_default().into_ext_devices(&mut gpio, &framebuffers)?;
+    ext_devices.add_custom(custom_devices);
+    if let Some(board) = config.board.as_deref() {
+        crate::boards::find_board(board)?.wire(&mut gpio, &ext_devices);
+    }
+    let priority_bits = svd_device.cpu.as_ref()
+        .map(|c| c.nvic_priority_bits)
+        .unwrap_or_else(|| cpu.nvic_priority_bits());
+    let layout = crate::family::peripherals_layout(config.cpu.family.as_deref(), &svd_device.name)?;
+    info!("CPU model={:?} nvic_priority_bits={} peripherals_layout={:?}", cpu, priority_bits, layout);
+    let mut peripherals = Peripherals::from_svd(svd_device, config.peripherals.unwrap_or_default(), layout, gpio, &ext_devices)?;
+    peripherals.cpu = cpu;
+    peripherals.nvic.borrow_mut().priority_bits = Some(priority_bits);
 
     let mut system = System::new(uc, peripherals, ext_devices);
     system.bind_peripherals_to_unicorn()?;
-    Ok((system, framebuffers))
+    Ok((system, framebuffers, firmware, memory))
 }
@@ -0,0 +1,209 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use unicorn_engine::{Unicorn, unicorn_const::Permission};
+
+use crate::{bootloader::BootloaderConfig, config::Region, util::{UniErr, round_up}};
+
+// The read-only values that ST programs in the system memory: the 96-bit
+// unique ID, the flash size register, and the option bytes. Firmware reads
+// them to derive a serial number or a MAC address, or to size its storage.
+// They are mapped by default at the addresses of the family, and can be set:
+//
+//  system_memory:
+//    uid: [0x00360027, 0x34385104, 0x32363532]
+//    flash_size: 512           # KB, the size of the flash region by default
+//    option_bytes: [0x5513AAEC, 0xFFFFFFFF, 0xF0000FFF]
+//    uid_addr: 0x1FFF7A10      # for families the emulator doesn't know
+//    flash_size_addr: 0x1FFF7A22
+//    option_bytes_addr: 0x1FFFC000
+//
+// The pages holding them are only mapped when no region covers them, e.g. a
+// dump of the system memory can be loaded instead. Writes are ignored. With a
+// `bootloader` section, a stub of the system bootloader is mapped as well, see
+// bootloader.rs. The pages holding its code are read-only memory, writes there
+// fail.
+
+#[derive(Debug, Deserialize, Default)]
+pub struct SystemMemoryConfig {
+    /// Defaults to true
+    pub enabled: Option<bool>,
+    /// The three words of the unique ID
+    pub uid: Option<[u32; 3]>,
+    /// In KB
+    pub flash_size: Option<u16>,
+    /// The words at the option bytes address
+    pub option_bytes: Option<Vec<u32>>,
+    pub uid_addr: Option<u32>,
+    pub flash_size_addr: Option<u32>,
+    pub option_bytes_addr: Option<u32>,
+    pub bootloader: Option<BootloaderConfig>,
+}
+
+struct Layout {
+    prefixes: &'static [&'static str],
+    // The L0 and L1 have a gap between the second and third words
+    uid: [u32; 3],
+    flash_size: u32,
+    // addr, default words
+    option_bytes: Option<(u32, &'static [u32])>,
+    // The entry of the system bootloader, and the product ID it reports. The
+    // F0, F3 and F7 ones are of their most common lines.
+    bootloader: (u32, u16),
+}
+
+// The option bytes defaults are the factory values: no read protection, no
+// write protection, and the watchdogs started by software. Most families store
+// each value next to its complement.
+const LAYOUTS: &[Layout] = &[
+    Layout { prefixes: &["STM32F0"], uid: [0x1FFF_F7AC, 0x1FFF_F7B0, 0x1FFF_F7B4], flash_size: 0x1FFF_F7CC,
+        option_bytes: Some((0x1FFF_F800, &[0x00FF_55AA, 0x00FF_00FF, 0x00FF_00FF, 0x00FF_00FF])), bootloader: (0x1FFF_EC00, 0x440) },
+    Layout { prefixes: &["STM32F1"], uid: [0x1FFF_F7E8, 0x1FFF_F7EC, 0x1FFF_F7F0], flash_size: 0x1FFF_F7E0,
+        option_bytes: Some((0x1FFF_F800, &[0x00FF_5AA5, 0x00FF_00FF, 0x00FF_00FF, 0x00FF_00FF])), bootloader: (0x1FFF_F000, 0x410) },
+    Layout { prefixes: &["STM32F2"], uid: [0x1FFF_7A10, 0x1FFF_7A14, 0x1FFF_7A18], flash_size: 0x1FFF_7A22,
+        option_bytes: Some((0x1FFF_C000, &[0x5513_AAEC, 0xFFFF_FFFF, 0xF000_0FFF])), bootloader: (0x1FFF_0000, 0x411) },
+    Layout { prefixes: &["STM32F3"], uid: [0x1FFF_F7AC, 0x1FFF_F7B0, 0x1FFF_F7B4], flash_size: 0x1FFF_F7CC,
+        option_bytes: Some((0x1FFF_F800, &[0x00FF_55AA, 0x00FF_00FF, 0x00FF_00FF, 0x00FF_00FF])), bootloader: (0x1FFF_D800, 0x422) },
+    Layout { prefixes: &["STM32F4"], uid: [0x1FFF_7A10, 0x1FFF_7A14, 0x1FFF_7A18], flash_size: 0x1FFF_7A22,
+        option_bytes: Some((0x1FFF_C000, &[0x5513_AAEC, 0xFFFF_FFFF, 0xF000_0FFF])), bootloader: (0x1FFF_0000, 0x413) },
+    Layout { prefixes: &["STM32F7"], uid: [0x1FF0_F420, 0x1FF0_F424, 0x1FF0_F428], flash_size: 0x1FF0_F442,
+        option_bytes: Some((0x1FFF_0000, &[0x5503_AAFC, 0xFFFF_FFFF, 0xFF00_00FF])), bootloader: (0x1FF0_0000, 0x449) },
+    Layout { prefixes: &["STM32G0"], uid: [0x1FFF_7590, 0x1FFF_7594, 0x1FFF_7598], flash_size: 0x1FFF_75E0,
+        option_bytes: Some((0x1FFF_7800, &[0xFFFF_FEAA])), bootloader: (0x1FFF_0000, 0x460) },
+    Layout { prefixes: &["STM32G4"], uid: [0x1FFF_7590, 0x1FFF_7594, 0x1FFF_7598], flash_size: 0x1FFF_75E0,
+        option_bytes: Some((0x1FFF_7800, &[0xFFEF_F8AA])), bootloader: (0x1FFF_0000, 0x468) },
+    Layout { prefixes: &["STM32L4"], uid: [0x1FFF_7590, 0x1FFF_7594, 0x1FFF_7598], flash_size: 0x1FFF_75E0,
+        option_bytes: Some((0x1FFF_7800, &[0xFFEF_F8AA])), bootloader: (0x1FFF_0000, 0x415) },
+    Layout { prefixes: &["STM32L0"], uid: [0x1FF8_0050, 0x1FF8_0054, 0x1FF8_0064], flash_size: 0x1FF8_007C,
+        option_bytes: Some((0x1FF8_0000, &[0xFF55_00AA])), bootloader: (0x1FF0_0000, 0x417) },
+    // Categories 3 and up, the most common
+    Layout { prefixes: &["STM32L1"], uid: [0x1FF8_00D0, 0x1FF8_00D4, 0x1FF8_00E4], flash_size: 0x1FF8_00CC,
+        option_bytes: Some((0x1FF8_0000, &[0xFF55_00AA])), bootloader: (0x1FF0_0000, 0x416) },
+    // The option bytes are only accessible through the flash registers
+    Layout { prefixes: &["STM32H7"], uid: [0x1FF1_E800, 0x1FF1_E804, 0x1FF1_E808], flash_size: 0x1FF1_E880,
+        option_bytes: None, bootloader: (0x1FF0_9800, 0x450) },
+];
+
+// The wafer coordinates, the wafer number, and the lot number, like a real part
+const DEFAULT_UID: [u32; 3] = [0x0036_0027, 0x3438_5104, 0x3236_3532];
+
+const PAGE_SIZE: u32 = 4096; // magic number is from mem_map() documentation
+
+fn find_layout(device_name: &str) -> Option<&'static Layout> {
+    let device_name = device_name.to_uppercase();
+    LAYOUTS.iter()
+        .find(|l| l.prefixes.iter().any(|p| device_name.starts_with(p)))
+}
+
+/// The entry address and the product ID of the system bootloader
+pub fn bootloader(config: &BootloaderConfig, device_name: &str) -> Result<(u32, u16)> {
+    let layout = find_layout(device_name);
+    let addr = config.addr.or(layout.map(|l| l.bootloader.0))
+        .with_context(|| format!("Unknown bootloader address for device={}, set addr in the bootloader config", device_name))?;
+    let pid = config.pid.or(layout.map(|l| l.bootloader.1)).unwrap_or_default();
+    Ok((addr, pid))
+}
+
+/// The content of the pages holding the values, unprogrammed bytes read as 0xFF
+#[derive(Default)]
+struct Pages(BTreeMap<u32, Vec<u8>>);
+
+impl Pages {
+    fn write(&mut self, addr: u32, data: &[u8]) {
+        for (i, v) in data.iter().enumerate() {
+            let addr = addr + i as u32;
+            let page = self.0.entry(addr & !(PAGE_SIZE - 1)).or_insert_with(|| vec![0xFF; PAGE_SIZE as usize]);
+            page[(addr % PAGE_SIZE) as usize] = *v;
+        }
+    }
+}
+
+pub fn map(uc: &mut Unicorn<()>, config: SystemMemoryConfig, regions: &[Region], device_name: &str) -> Result<()> {
+    if !config.enabled.unwrap_or(true) {
+        return Ok(());
+    }
+
+    let layout = find_layout(device_name);
+    let uid_addrs = config.uid_addr.map(|a| [a, a+4, a+8]).or(layout.map(|l| l.uid));
+    let flash_size_addr = config.flash_size_addr.or(layout.map(|l| l.flash_size));
+    let option_bytes_addr = config.option_bytes_addr.or(layout.and_then(|l| l.option_bytes.map(|(addr, _)| addr)));
+    if uid_addrs.is_none() && flash_size_addr.is_none() && option_bytes_addr.is_none() && config.bootloader.is_none() {
+        debug!("Unknown system memory layout for device={}, set the addresses in system_memory", device_name);
+        return Ok(());
+    }
+
+    let mut pages = Pages::default();
+    // The pages holding code are memory, the others MMIO
+    let mut code_pages = BTreeSet::new();
+
+    if let Some(ref bootloader) = config.bootloader {
+        let (addr, _) = self::bootloader(bootloader, device_name)?;
+        let stub = crate::bootloader::stub(addr);
+        pages.write(addr, &stub);
+        for a in [addr, addr + stub.len() as u32 - 1] {
+            code_pages.insert(a & !(PAGE_SIZE - 1));
+        }
+    }
+
+    if let Some(addrs) = uid_addrs {
+        let uid = config.uid.unwrap_or(DEFAULT_UID);
+        for (addr, v) in addrs.iter().zip(uid) {
+            pages.write(*addr, &v.to_le_bytes());
+        }
+        debug!("System memory uid={:08x}{:08x}{:08x} addr=0x{:08x}", uid[2], uid[1], uid[0], addrs[0]);
+    }
+
+    let flash_kb = config.flash_size.or_else(|| {
+        let flash = regions.iter().find(|r| (r.start..r.start.saturating_add(r.size)).contains(&crate::system::FLASH_START))?;
+        Some((flash.size >> 10).min(u16::MAX as u32) as u16)
+    });
+    if let (Some(addr), Some(kb)) = (flash_size_addr, flash_kb) {
+        pages.write(addr, &kb.to_le_bytes());
+        debug!("System memory flash_size={}KB addr=0x{:08x}", kb, addr);
+    }
+
+    if let Some(addr) = option_bytes_addr {
+        let default = layout.and_then(|l| l.option_bytes).map(|(_, words)| words).unwrap_or_default();
+        let words = config.option_bytes.as_deref().unwrap_or(default);
+        for (i, v) in words.iter().enumerate() {
+            pages.write(addr + 4*i as u32, &v.to_le_bytes());
+        }
+        debug!("System memory option_bytes={:08x?} addr=0x{:08x}", words, addr);
+    }
+
+    for (start, data) in pages.0 {
+        let end = start as u64 + PAGE_SIZE as u64;
+        let covered = regions.iter().any(|r|
+            (r.start as u64) < end && (start as u64) < r.start as u64 + round_up(r.size as usize, PAGE_SIZE as usize) as u64
+        );
+        if covered {
+            debug!("System memory page start=0x{:08x} is covered by a region, not mapping it", start);
+            continue;
+        }
+
+        if code_pages.contains(&start) {
+            uc.mem_map(start as u64, PAGE_SIZE as usize, Permission::READ | Permission::EXEC)
+                .and_then(|_| uc.mem_write(start as u64, &data))
+                .map_err(UniErr).with_context(|| format!("Failed to map the system memory at 0x{:08x}", start))?;
+            continue;
+        }
+
+        let read_cb = move |_uc: &mut Unicorn<'_, ()>, addr: u64, size: usize| {
+            let offset = addr as usize;
+            let mut bytes = [0u8; 8];
+            let n = size.min(8).min(data.len() - offset);
+            bytes[..n].copy_from_slice(&data[offset..offset+n]);
+            u64::from_le_bytes(bytes)
+        };
+        let write_cb = move |_uc: &mut Unicorn<'_, ()>, addr: u64, size: usize, value: u64| {
+            warn!("Write to the read-only system memory addr=0x{:08x} size={} value=0x{:08x}", start as u64 + addr, size, value);
+        };
+        uc.mmio_map(start as u64, PAGE_SIZE as usize, Some(read_cb), Some(write_cb))
+            .map_err(UniErr).with_context(|| format!("Failed to map the system memory at 0x{:08x}", start))?;
+    }
+
+    Ok(())
+}
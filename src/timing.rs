@@ -0,0 +1,81 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::time::Duration;
+
+use crate::config::{Config, Region};
+
+// The emulated time is counted in cycles in the num_instructions of the
+// emulator context, which advances by one on each instruction by default.
+// Regions can be given a number of cycles per instruction, like the flash with
+// its wait states, to make the time run closer to the hardware. The SysTick
+// and the other time based devices follow. --max-instructions counts the
+// instructions, not the cycles.
+
+#[derive(Default)]
+pub struct InstructionTiming {
+    // start, end (exclusive), cycles
+    regions: Vec<(u32, u32, u32)>,
+    // The region of the last instruction, as the pc rarely leaves its region
+    last: Option<(u32, u32, u32)>,
+}
+
+impl InstructionTiming {
+    pub fn from_config(config: &Config) -> Self {
+        let regions = config.regions.as_deref().unwrap_or_default();
+
+        let mut timing = Self::default();
+        for region in regions {
+            if let Some(cycles) = region.cycles_per_instruction {
+                timing.add_region(region, region.start, cycles);
+            }
+        }
+
+        // The flash is executed from its alias at 0 as well
+        if let Some(flash) = crate::system::boot_alias_region(regions, config) {
+            if let Some(cycles) = flash.cycles_per_instruction {
+                timing.add_region(flash, 0, cycles);
+            }
+        }
+
+        timing
+    }
+
+    fn add_region(&mut self, region: &Region, start: u32, cycles: u32) {
+        debug!("Region timing name={} start=0x{:08x} cycles_per_instruction={}", region.name, start, cycles);
+        self.regions.push((start, start.saturating_add(region.size), cycles.max(1)));
+    }
+
+    /// Number of cycles taken by the instruction at pc
+    pub fn cycles(&mut self, pc: u32) -> u32 {
+        if self.regions.is_empty() {
+            return 1;
+        }
+
+        if let Some((start, end, cycles)) = self.last {
+            if (start..end).contains(&pc) {
+                return cycles;
+            }
+        }
+
+        self.last = self.regions.iter().find(|(start, end, _)| (*start..*end).contains(&pc)).cloned();
+        self.last.map(|(_, _, cycles)| cycles).unwrap_or(1)
+    }
+}
+
+/// Converts the emulated cycles into emulated time. The CPU clock changes as
+/// the firmware configures the RCC, so the time is accumulated as we go.
+#[derive(Default)]
+pub struct EmulatedClock {
+    last_cycles: u64,
+    elapsed_ns: u128,
+}
+
+impl EmulatedClock {
+    /// The cycles since the last update ran at hclk
+    pub fn update(&mut self, cycles: u64, hclk: u32) -> Duration {
+        let delta = cycles.saturating_sub(self.last_cycles) as u128;
+        self.elapsed_ns += delta * 1_000_000_000 / hclk.max(1) as u128;
+        self.last_cycles = cycles;
+        Duration::from_nanos(self.elapsed_ns.min(u64::MAX as u128) as u64)
+    }
+}
@@ -0,0 +1,213 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{fs::File, io::{prelude::*, BufReader, BufWriter}};
+
+use anyhow::{Context, Result};
+
+// With --record-trace FILE, the run is recorded as a compact binary trace: the
+// branch targets, the peripheral accesses, and the interrupt entries and exits.
+// `stm32-emulator diff-trace a.trace b.trace` then reports the first divergence
+// between two runs, e.g. a run that works with a config, and one that hangs
+// with another.
+//
+// The file starts with MAGIC, followed by records of RECORD_SIZE bytes, little
+// endian: kind (u8), clk (u64), pc (u32), a (u32), b (u32).
+// The clk is informational, it is not compared, as it depends on the timings.
+
+const MAGIC: &[u8; 8] = b"STMTRC01";
+const RECORD_SIZE: usize = 21;
+
+// How many matching records are printed before the divergence
+const DIFF_CONTEXT: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// The pc doesn't follow the previous instruction
+    Branch = 0,
+    /// a = addr, b = value
+    Read = 1,
+    /// a = addr, b = value
+    Write = 2,
+    /// a = irq
+    IrqEnter = 3,
+    /// a = irq
+    IrqExit = 4,
+}
+
+impl Kind {
+    fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => Self::Branch,
+            1 => Self::Read,
+            2 => Self::Write,
+            3 => Self::IrqEnter,
+            4 => Self::IrqExit,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Record {
+    kind: Kind,
+    clk: u64,
+    pc: u32,
+    a: u32,
+    b: u32,
+}
+
+impl Record {
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let mut b = [0; RECORD_SIZE];
+        b[0] = self.kind as u8;
+        b[1..9].copy_from_slice(&self.clk.to_le_bytes());
+        b[9..13].copy_from_slice(&self.pc.to_le_bytes());
+        b[13..17].copy_from_slice(&self.a.to_le_bytes());
+        b[17..21].copy_from_slice(&self.b.to_le_bytes());
+        b
+    }
+
+    fn parse(b: &[u8; RECORD_SIZE]) -> Result<Self> {
+        let u32_at = |i: usize| u32::from_le_bytes(b[i..i+4].try_into().unwrap());
+        Ok(Self {
+            kind: Kind::from_u8(b[0]).with_context(|| format!("Invalid record kind={}", b[0]))?,
+            clk: u64::from_le_bytes(b[1..9].try_into().unwrap()),
+            pc: u32_at(9),
+            a: u32_at(13),
+            b: u32_at(17),
+        })
+    }
+
+    /// Everything but the clk
+    fn same_as(&self, other: &Self) -> bool {
+        (self.kind, self.pc, self.a, self.b) == (other.kind, other.pc, other.a, other.b)
+    }
+
+    fn desc(&self) -> String {
+        let event = match self.kind {
+            Kind::Branch => "branch".to_string(),
+            Kind::Read => format!("read addr=0x{:08x} value=0x{:08x}", self.a, self.b),
+            Kind::Write => format!("write addr=0x{:08x} value=0x{:08x}", self.a, self.b),
+            Kind::IrqEnter => format!("irq={} enter", self.a as i32),
+            Kind::IrqExit => format!("irq={} exit", self.a as i32),
+        };
+        format!("clk={} pc=0x{:08x} {}", self.clk, self.pc, event)
+    }
+}
+
+pub struct TraceWriter {
+    path: String,
+    file: Option<BufWriter<File>>,
+    /// Where the next instruction is, when there's no branch
+    next_pc: u32,
+}
+
+impl TraceWriter {
+    pub fn new(path: &str) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)
+            .with_context(|| format!("Failed to create {}", path))?);
+        file.write_all(MAGIC)?;
+        info!("Recording the trace to {}", path);
+        Ok(Self { path: path.to_string(), file: Some(file), next_pc: 0 })
+    }
+
+    fn push(&mut self, kind: Kind, a: u32, b: u32) {
+        let clk = crate::context::now();
+        let pc = crate::context::last_pc();
+        if let Some(ref mut file) = self.file {
+            if let Err(e) = file.write_all(&Record { kind, clk, pc, a, b }.to_bytes()) {
+                warn!("Failed to write the trace to {}: {}", self.path, e);
+                self.file = None;
+            }
+        }
+    }
+
+    /// Called on each instruction, once the context has it as the last instruction
+    pub fn instruction(&mut self, pc: u32, size: u8) {
+        if pc != self.next_pc {
+            self.push(Kind::Branch, 0, 0);
+        }
+        self.next_pc = pc + size as u32;
+    }
+
+    pub fn access(&mut self, addr: u32, write: bool, value: u32) {
+        self.push(if write { Kind::Write } else { Kind::Read }, addr, value);
+    }
+
+    pub fn irq_enter(&mut self, irq: i32) {
+        self.push(Kind::IrqEnter, irq as u32, 0);
+    }
+
+    pub fn irq_exit(&mut self, irq: i32) {
+        self.push(Kind::IrqExit, irq as u32, 0);
+    }
+
+    /// Called when the emulation stops
+    pub fn flush(&mut self) -> Result<()> {
+        if let Some(ref mut file) = self.file {
+            file.flush().with_context(|| format!("Failed to write the trace to {}", self.path))?;
+        }
+        Ok(())
+    }
+}
+
+struct TraceReader {
+    file: BufReader<File>,
+}
+
+impl TraceReader {
+    fn open(path: &str) -> Result<Self> {
+        let mut file = BufReader::new(File::open(path)
+            .with_context(|| format!("Failed to open {}", path))?);
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic).with_context(|| format!("Failed to read {}", path))?;
+        if &magic != MAGIC {
+            anyhow::bail!("{} is not a trace recorded with --record-trace", path);
+        }
+        Ok(Self { file })
+    }
+
+    fn next(&mut self) -> Result<Option<Record>> {
+        let mut b = [0; RECORD_SIZE];
+        match self.file.read_exact(&mut b) {
+            Ok(()) => Ok(Some(Record::parse(&b)?)),
+            // A truncated record is the end of a trace that wasn't flushed whole
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Prints the first divergence between two traces. Returns whether they match.
+pub fn diff(path_a: &str, path_b: &str) -> Result<bool> {
+    let mut a = TraceReader::open(path_a)?;
+    let mut b = TraceReader::open(path_b)?;
+    let mut context = std::collections::VecDeque::with_capacity(DIFF_CONTEXT);
+
+    for index in 0u64.. {
+        let (ra, rb) = (a.next()?, b.next()?);
+        match (ra, rb) {
+            (None, None) => {
+                info!("Traces match num_records={}", index);
+                return Ok(true);
+            }
+            (Some(ra), Some(rb)) if ra.same_as(&rb) => {
+                if context.len() == DIFF_CONTEXT {
+                    context.pop_front();
+                }
+                context.push_back(ra);
+            }
+            _ => {
+                info!("Traces diverge at record={}", index);
+                for r in &context {
+                    info!("  {}", r.desc());
+                }
+                let desc = |r: Option<Record>| r.map(|r| r.desc()).unwrap_or_else(|| "end of trace".to_string());
+                info!("- {} {}", path_a, desc(ra));
+                info!("+ {} {}", path_b, desc(rb));
+                return Ok(false);
+            }
+        }
+    }
+    unreachable!()
+}
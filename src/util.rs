@@ -5,6 +5,7 @@ use std::io::prelude::*;
 use svd_parser::svd::{MaybeArray, RegisterInfo, PeripheralInfo};
 use unicorn_engine::unicorn_const::uc_error;
 use anyhow::{Context, Result};
+use serde::Deserialize;
 
 #[derive(Debug)]
 pub struct UniErr(pub uc_error);
@@ -31,6 +32,38 @@ pub fn read_file(path: &str) -> Result<Vec<u8>> {
 }
 
 
+/// When a memory backed by a file is written back to it
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PersistPolicy {
+    /// After every write, as if the power could be cut at any time
+    Always,
+    /// When the emulation stops cleanly. Not on a lockup, or when the time limit is reached.
+    #[default]
+    OnExit,
+    /// The file is never written
+    Never,
+}
+
+impl PersistPolicy {
+    pub fn on_exit(self, clean_exit: bool) -> bool {
+        match self {
+            Self::Always => true,
+            Self::OnExit => clean_exit,
+            Self::Never => false,
+        }
+    }
+}
+
+/// Writes to a temporary file that is renamed over the destination, so a
+/// write that gets interrupted never leaves a truncated file behind.
+pub fn write_file_atomic(path: &str, content: &[u8]) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, content)
+        .and_then(|_| std::fs::rename(&tmp, path))
+        .with_context(|| format!("Failed to write {}", path))
+}
+
 pub fn read_file_str(path: &str) -> Result<String> {
     let content = read_file(path)?;
     let str = String::from_utf8(content)?;
@@ -109,3 +142,30 @@ pub struct Rect {
     pub top: u16,
     pub bottom: u16,
 }
+
+/// A xorshift64* generator, for the reproducible randomness of the fault
+/// injection, the touchscreen noise, and the interrupt jitter
+#[derive(Debug, Clone)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift needs a non-zero state
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new(1)
+    }
+}
@@ -0,0 +1,86 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use std::{collections::VecDeque, sync::{Mutex, atomic::{AtomicBool, Ordering}}};
+
+use crate::symbols::symbols;
+
+// With --warn-context N, the last N peripheral accesses are kept, and printed
+// after each warning with the pc and the caller of the access in progress.
+// This tells what the firmware was doing when a peripheral complained about an
+// invalid offset or an unknown command.
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref CONTEXT: Mutex<WarnContext> = Mutex::new(WarnContext::default());
+}
+
+struct Access {
+    pc: u32,
+    lr: u32,
+    /// From Peripherals::addr_desc()
+    desc: String,
+    write: bool,
+    value: Option<u32>,
+}
+
+impl Access {
+    fn line(&self) -> String {
+        let value = self.value.map(|v| format!("0x{:08x}", v)).unwrap_or_else(|| "?".to_string());
+        format!("pc=0x{:08x} {} {}={}", self.pc, self.desc, if self.write { "write" } else { "read" }, value)
+    }
+}
+
+#[derive(Default)]
+struct WarnContext {
+    capacity: usize,
+    accesses: VecDeque<Access>,
+    /// The access the peripheral is handling
+    current: Option<Access>,
+}
+
+pub fn init(capacity: usize) {
+    CONTEXT.lock().unwrap().capacity = capacity;
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Called before the peripheral handles an access, with the written value.
+/// The value of reads is given to end().
+pub fn begin(pc: u32, lr: u32, desc: String, written: Option<u32>) {
+    CONTEXT.lock().unwrap().current = Some(Access { pc, lr, desc, write: written.is_some(), value: written });
+}
+
+pub fn end(value: u32) {
+    let mut ctx = CONTEXT.lock().unwrap();
+    if let Some(mut access) = ctx.current.take() {
+        access.value.get_or_insert(value);
+        if ctx.accesses.len() == ctx.capacity {
+            ctx.accesses.pop_front();
+        }
+        if ctx.capacity > 0 {
+            ctx.accesses.push_back(access);
+        }
+    }
+}
+
+/// The lines printed after a warning. Empty outside of peripheral accesses
+/// when nothing was recorded yet.
+pub fn lines() -> Vec<String> {
+    let ctx = CONTEXT.lock().unwrap();
+    let mut lines = vec![];
+    if let Some(ref current) = ctx.current {
+        lines.push(format!("  in {} caller=0x{:08x} {}", symbols().describe(current.pc),
+            current.lr & !1, symbols().describe(current.lr & !1)));
+    }
+    for access in &ctx.accesses {
+        lines.push(format!("  {}", access.line()));
+    }
+    if let Some(ref current) = ctx.current {
+        lines.push(format!("> {}", current.line()));
+    }
+    lines
+}
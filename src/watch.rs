@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+use serde::Deserialize;
+use unicorn_engine::Unicorn;
+use anyhow::{Result, bail};
+
+use crate::symbols::symbols;
+
+// Watch values are firmware variables logged periodically, to follow a state
+// machine or a sensor reading without a debugger:
+//
+//  watch_values:
+//    - symbol: g_state
+//      type: u8
+//    - symbol: g_temperature
+//      type: float
+//      every: 10000000    # instructions, defaults to 1000000
+//    - addr: 0x20000100
+//      type: cstring
+//      name: status_msg
+
+const DEFAULT_EVERY: u64 = 1_000_000;
+const MAX_CSTRING_LEN: usize = 64;
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum WatchType {
+    U8,
+    U16,
+    U32,
+    I8,
+    I16,
+    I32,
+    Float,
+    Cstring,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct WatchValueConfig {
+    pub addr: Option<u32>,
+    /// Alternative to addr. Can be `symbol+offset`.
+    pub symbol: Option<String>,
+    pub r#type: WatchType,
+    /// Defaults to the symbol, or the address
+    pub name: Option<String>,
+    pub every: Option<u64>,
+}
+
+struct Watch {
+    name: String,
+    addr: u32,
+    type_: WatchType,
+    every: u64,
+}
+
+impl Watch {
+    fn new(config: WatchValueConfig) -> Result<Self> {
+        let addr = match (config.addr, config.symbol.as_ref()) {
+            (Some(addr), None) => addr,
+            (None, Some(symbol)) => symbols().parse_addr(symbol)?,
+            _ => bail!("Watch values must have exactly one of addr or symbol"),
+        };
+        let name = config.name.or(config.symbol).unwrap_or_else(|| format!("0x{:08x}", addr));
+        let every = config.every.unwrap_or(DEFAULT_EVERY);
+        if every == 0 {
+            bail!("Watch value {} needs a non-zero every", name);
+        }
+        Ok(Self { name, addr, type_: config.r#type, every })
+    }
+
+    fn read(&self, uc: &Unicorn<()>) -> Option<String> {
+        let mut buf = [0u8; 4];
+        let n = match self.type_ {
+            WatchType::U8 | WatchType::I8 => 1,
+            WatchType::U16 | WatchType::I16 => 2,
+            WatchType::U32 | WatchType::I32 | WatchType::Float => 4,
+            WatchType::Cstring => {
+                let mut s = vec![0u8; MAX_CSTRING_LEN];
+                uc.mem_read(self.addr as u64, &mut s).ok()?;
+                let len = s.iter().position(|c| *c == 0).unwrap_or(s.len());
+                return Some(format!("{:?}", String::from_utf8_lossy(&s[..len])));
+            }
+        };
+        uc.mem_read(self.addr as u64, &mut buf[..n]).ok()?;
+        let v = u32::from_le_bytes(buf);
+        Some(match self.type_ {
+            WatchType::U8 | WatchType::U16 | WatchType::U32 => v.to_string(),
+            WatchType::I8 => (v as i8).to_string(),
+            WatchType::I16 => (v as i16).to_string(),
+            WatchType::I32 => (v as i32).to_string(),
+            WatchType::Float => f32::from_bits(v).to_string(),
+            WatchType::Cstring => unreachable!(),
+        })
+    }
+}
+
+pub struct Watches {
+    watches: Vec<Watch>,
+}
+
+impl Watches {
+    pub fn new(configs: Vec<WatchValueConfig>) -> Result<Self> {
+        let watches = configs.into_iter().map(Watch::new).collect::<Result<_>>()?;
+        Ok(Self { watches })
+    }
+
+    /// Called on each instruction
+    pub fn poll(&self, uc: &Unicorn<()>, num_executed: u64) {
+        for w in self.watches.iter().filter(|w| num_executed.is_multiple_of(w.every)) {
+            match w.read(uc) {
+                Some(value) => info!("Watch {}={} addr=0x{:08x}", w.name, value, w.addr),
+                None => warn!("Watch {} addr=0x{:08x} is not readable", w.name, w.addr),
+            }
+        }
+    }
+}